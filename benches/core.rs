@@ -0,0 +1,220 @@
+// Standard whole-emulator workloads, reported as emulated MHz and MIPS:
+//
+//   tight arithmetic loop   built in
+//   CPU exerciser           a CP/M .COM such as 8080EXM, from $CPU_EXERCISER
+//   Space Invaders attract  the 8 KB ROM, or a directory holding invaders.h,
+//                           .g, .f and .e, from $INVADERS_ROM
+//
+// Workloads whose inputs aren't given are skipped. Run with
+//
+//   cargo bench --bench core -- [--samples N] [--cycles N] [--warm-up-ms N]
+//       [--save-baseline NAME] [--baseline NAME] [FILTER]
+//
+// Saving a baseline before a change and comparing against it afterwards
+// reports the change in emulated MHz for each workload.
+
+extern crate virtual_8080;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use virtual_8080::bench::{new_state, step, Counts, Harness, Workload};
+use virtual_8080::cpu::request_interrupt;
+use virtual_8080::loader::{load_com, load_rom, BDOS_ENTRY};
+use virtual_8080::machine::{Machine, NoDevices};
+use virtual_8080::stack::Stack;
+use virtual_8080::state::State;
+
+// 16-bit adds and 8-bit arithmetic on registers only:
+//
+//   0000 LXI H,0000     0006 MVI B,00       000a MOV A,L
+//   0003 LXI D,0001     0008 DAD D          000b ADD E
+//                       0009 XCHG           000c ADC H
+//   000d SUI 03         000f MOV C,A        0010 DCR B
+//   0011 JNZ 0008       0014 JMP 0000
+const ARITHMETIC_LOOP: [u8; 23] = [
+    0x21, 0x00, 0x00, 0x11, 0x01, 0x00, 0x06, 0x00, 0x19, 0xeb, 0x7d, 0x83, 0x8c, 0xd6, 0x03, 0x4f,
+    0x05, 0xc2, 0x08, 0x00, 0xc3, 0x00, 0x00,
+];
+
+struct Program {
+    state: State,
+}
+
+impl Workload for Program {
+    fn name(&self) -> &str {
+        "tight arithmetic loop"
+    }
+
+    fn run(&mut self, cycles: u64) -> Counts {
+        let mut counts = Counts::default();
+        while counts.cycles < cycles {
+            step(&mut self.state, &mut NoDevices, &mut counts);
+        }
+        counts
+    }
+}
+
+// A CP/M program with console output thrown away. It is restarted each
+// time it finishes, so a sample can be longer than the whole program.
+struct Exerciser {
+    program: Vec<u8>,
+    state: State,
+}
+
+impl Exerciser {
+    fn new(program: Vec<u8>) -> Exerciser {
+        let mut exerciser = Exerciser {
+            program,
+            state: new_state(),
+        };
+        exerciser.restart();
+        exerciser
+    }
+
+    fn restart(&mut self) {
+        self.state = new_state();
+        load_com(&mut self.state, self.program.clone()).unwrap();
+    }
+}
+
+impl Workload for Exerciser {
+    fn name(&self) -> &str {
+        "CPU exerciser"
+    }
+
+    fn run(&mut self, cycles: u64) -> Counts {
+        let mut counts = Counts::default();
+        while counts.cycles < cycles {
+            if self.state.halted || (self.state.pc == BDOS_ENTRY && self.state.c == 0x00) {
+                self.restart();
+            } else if self.state.pc == BDOS_ENTRY {
+                // Returns straight to the caller instead of printing.
+                self.state.call_stack.ret(self.state.sp);
+                self.state.pc = self.state.pop16();
+            }
+            step(&mut self.state, &mut NoDevices, &mut counts);
+        }
+        counts
+    }
+}
+
+// The parts of the Space Invaders board the attract mode touches: the
+// hardware shift register and the two interrupts per frame, with the
+// inputs idle and no coin inserted.
+#[derive(Default)]
+struct Invaders {
+    shift: u16,
+    offset: u8,
+}
+
+impl Machine for Invaders {
+    fn input(&self, port: u8) -> u8 {
+        match port {
+            1 => 0x08,
+            3 => (self.shift << self.offset >> 8) as u8,
+            _ => 0,
+        }
+    }
+
+    fn output(&mut self, port: u8, val: u8) {
+        match port {
+            2 => self.offset = val & 0x07,
+            4 => self.shift = (self.shift >> 8) | (u16::from(val) << 8),
+            _ => (),
+        }
+    }
+}
+
+// A 2 MHz CPU at 60 frames a second, with RST 1 mid-screen and RST 2 at
+// the vertical blank.
+const HALF_FRAME_CYCLES: u64 = 2_000_000 / 120;
+
+struct Attract {
+    state: State,
+    machine: Invaders,
+    next_interrupt: u64,
+    interrupt: u16,
+}
+
+impl Attract {
+    fn new(rom: Vec<u8>) -> Attract {
+        let mut state = new_state();
+        load_rom(&mut state, rom, 0).unwrap();
+        Attract {
+            state,
+            machine: Invaders::default(),
+            next_interrupt: HALF_FRAME_CYCLES,
+            interrupt: 1,
+        }
+    }
+}
+
+impl Workload for Attract {
+    fn name(&self) -> &str {
+        "Space Invaders attract"
+    }
+
+    fn run(&mut self, cycles: u64) -> Counts {
+        let mut counts = Counts::default();
+        while counts.cycles < cycles {
+            if self.state.cycles >= self.next_interrupt {
+                request_interrupt(&mut self.state, self.interrupt);
+                self.interrupt ^= 3;
+                self.next_interrupt += HALF_FRAME_CYCLES;
+            }
+            step(&mut self.state, &mut self.machine, &mut counts);
+        }
+        counts
+    }
+}
+
+fn read_invaders(path: &Path) -> io::Result<Vec<u8>> {
+    if !path.is_dir() {
+        return fs::read(path);
+    }
+    let mut rom = Vec::new();
+    for part in ["invaders.h", "invaders.g", "invaders.f", "invaders.e"].iter() {
+        rom.extend(fs::read(path.join(part))?);
+    }
+    Ok(rom)
+}
+
+// Reads the input named by an environment variable, or says why the
+// workload can't run.
+fn input(variable: &str, read: impl Fn(&Path) -> io::Result<Vec<u8>>) -> Result<Vec<u8>, String> {
+    let path = env::var_os(variable).ok_or(format!("set {} to run it", variable))?;
+    let path = Path::new(&path);
+    read(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn main() {
+    let mut harness = Harness::from_args("core", env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let out = &mut io::stdout();
+
+    harness.write_header(out).unwrap();
+
+    let mut state = new_state();
+    state.memory.load(0, ARITHMETIC_LOOP.to_vec());
+    harness.run(&mut Program { state }, out).unwrap();
+
+    match input("CPU_EXERCISER", |path| fs::read(path)) {
+        Ok(program) => harness.run(&mut Exerciser::new(program), out).unwrap(),
+        Err(reason) => harness.skip("CPU exerciser", &reason, out).unwrap(),
+    }
+
+    match input("INVADERS_ROM", read_invaders) {
+        Ok(rom) => harness.run(&mut Attract::new(rom), out).unwrap(),
+        Err(reason) => harness
+            .skip("Space Invaders attract", &reason, out)
+            .unwrap(),
+    }
+
+    harness.finish(out).unwrap();
+}
@@ -0,0 +1,131 @@
+// Instruction dispatch throughput on small loops that each lean on a
+// different part of the instruction set, stepping one instruction at a time
+// and through the block cache. The block cache doesn't count instructions,
+// so its runs report emulated MHz only. Run with
+//
+//   cargo bench --bench dispatch -- [--samples N] [--cycles N]
+//       [--warm-up-ms N] [--save-baseline NAME] [--baseline NAME] [FILTER]
+
+extern crate virtual_8080;
+
+use std::env;
+use std::io;
+use std::process;
+
+use virtual_8080::bench::{new_state, step, Counts, Harness, Workload};
+use virtual_8080::blockcache::BlockCache;
+use virtual_8080::machine::NoDevices;
+use virtual_8080::state::State;
+
+const WORKLOADS: [(&str, &[u8]); 4] = [
+    (
+        "register ALU",
+        // MVI B,00; loop: ADD B; ADC C; SUB D; ANA E; XRA H; ORA L; CMP B;
+        // INR C; DCR B; JNZ loop; JMP 0000
+        &[
+            0x06, 0x00, 0x80, 0x89, 0x92, 0xa3, 0xac, 0xb5, 0xb8, 0x0c, 0x05, 0xc2, 0x02, 0x00,
+            0xc3, 0x00, 0x00,
+        ],
+    ),
+    (
+        "moves and immediates",
+        // MOV B,A; MOV C,B; MOV D,C; MOV E,D; MOV H,E; MOV L,H; MVI A,5a;
+        // ADI 01; ANI 7f; CPI 10; LXI D,1234; JMP 0000
+        &[
+            0x47, 0x48, 0x51, 0x5a, 0x63, 0x6c, 0x3e, 0x5a, 0xc6, 0x01, 0xe6, 0x7f, 0xfe, 0x10,
+            0x11, 0x34, 0x12, 0xc3, 0x00, 0x00,
+        ],
+    ),
+    (
+        "memory and stack",
+        // LXI SP,8000; loop: LXI H,4000; MOV M,A; INR M; MOV A,M; PUSH B;
+        // PUSH H; POP D; POP B; XCHG; LDA 4000; STA 4001; JMP loop
+        &[
+            0x31, 0x00, 0x80, 0x21, 0x00, 0x40, 0x77, 0x34, 0x7e, 0xc5, 0xe5, 0xd1, 0xc1, 0xeb,
+            0x3a, 0x00, 0x40, 0x32, 0x01, 0x40, 0xc3, 0x03, 0x00,
+        ],
+    ),
+    (
+        "calls and branches",
+        // LXI SP,8000; loop: CALL sub; CZ sub; JNC loop; JMP loop;
+        // sub: ORA A; RNZ; INR A; RET
+        &[
+            0x31, 0x00, 0x80, 0xcd, 0x10, 0x00, 0xcc, 0x10, 0x00, 0xd2, 0x03, 0x00, 0xc3, 0x03,
+            0x00, 0x00, 0xb7, 0xc0, 0x3c, 0xc9,
+        ],
+    ),
+];
+
+fn load(program: &[u8]) -> State {
+    let mut state = new_state();
+    state.rom_limit = None;
+    state.memory.load(0, program.to_vec());
+    state
+}
+
+struct Stepped {
+    name: String,
+    state: State,
+}
+
+impl Workload for Stepped {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&mut self, cycles: u64) -> Counts {
+        let mut counts = Counts::default();
+        while counts.cycles < cycles {
+            step(&mut self.state, &mut NoDevices, &mut counts);
+        }
+        counts
+    }
+}
+
+// The cache is kept between samples, so it is filled during the warm-up.
+struct Cached {
+    name: String,
+    state: State,
+    cache: BlockCache<NoDevices>,
+}
+
+impl Workload for Cached {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&mut self, cycles: u64) -> Counts {
+        let result = self
+            .cache
+            .run_for_cycles(&mut self.state, &mut NoDevices, cycles as usize);
+        Counts {
+            cycles: result.cycles as u64,
+            instructions: 0,
+        }
+    }
+}
+
+fn main() {
+    let mut harness = Harness::from_args("dispatch", env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let out = &mut io::stdout();
+
+    harness.write_header(out).unwrap();
+    for &(name, program) in WORKLOADS.iter() {
+        let mut stepped = Stepped {
+            name: name.to_string(),
+            state: load(program),
+        };
+        harness.run(&mut stepped, out).unwrap();
+
+        let mut cached = Cached {
+            name: format!("{} (cached)", name),
+            state: load(program),
+            cache: BlockCache::new(),
+        };
+        harness.run(&mut cached, out).unwrap();
+    }
+    harness.finish(out).unwrap();
+}
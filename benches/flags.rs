@@ -0,0 +1,133 @@
+// Table-driven flag updates against computing each flag, over every result
+// and operand pair, and the ALU operations as the CPU runs them. Each flag
+// update stands for a register ALU instruction, which takes four cycles, so
+// the MIPS column is millions of updates a second. Run with
+//
+//   cargo bench --bench flags -- [--samples N] [--cycles N] [--warm-up-ms N]
+//       [--save-baseline NAME] [--baseline NAME] [FILTER]
+
+extern crate virtual_8080;
+
+use std::env;
+use std::hint::black_box;
+use std::io;
+use std::process;
+
+use virtual_8080::bench::{Counts, Harness, Workload};
+use virtual_8080::flags::Flags;
+use virtual_8080::state::State;
+
+// A pass makes `updates` flag updates and is repeated until the sample's
+// cycles are used up.
+struct Updates<F> {
+    name: &'static str,
+    updates: u64,
+    pass: F,
+}
+
+impl<F: FnMut()> Workload for Updates<F> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&mut self, cycles: u64) -> Counts {
+        let mut counts = Counts::default();
+        while counts.cycles < cycles {
+            (self.pass)();
+            counts.instructions += self.updates;
+            counts.cycles += 4 * self.updates;
+        }
+        counts
+    }
+}
+
+fn main() {
+    let mut harness = Harness::from_args("flags", env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let out = &mut io::stdout();
+
+    harness.write_header(out).unwrap();
+
+    let mut flags = Flags::new();
+    let mut separate = Updates {
+        name: "S Z P computed",
+        updates: 256,
+        pass: || {
+            for n in 0..=255u8 {
+                let n = black_box(n);
+                flags.set_z(n);
+                flags.set_s(n);
+                flags.set_p(n);
+                black_box(&flags);
+            }
+        },
+    };
+    harness.run(&mut separate, out).unwrap();
+
+    let mut flags = Flags::new();
+    let mut table = Updates {
+        name: "S Z P table",
+        updates: 256,
+        pass: || {
+            for n in 0..=255u8 {
+                flags.set_szp(black_box(n));
+                black_box(&flags);
+            }
+        },
+    };
+    harness.run(&mut table, out).unwrap();
+
+    let mut flags = Flags::new();
+    let mut computed = Updates {
+        name: "ADD flags computed",
+        updates: 65536,
+        pass: || {
+            for x in 0..=255u8 {
+                for y in 0..=255u8 {
+                    let (x, y) = (black_box(x), black_box(y));
+                    let sum = u16::from(x) + u16::from(y);
+                    flags.set_z(sum as u8);
+                    flags.set_s(sum as u8);
+                    flags.set_p(sum as u8);
+                    flags.cy = sum > 0xff;
+                    flags.ac = (x & 0x0f) + (y & 0x0f) > 0x0f;
+                    black_box(sum as u8);
+                }
+            }
+        },
+    };
+    harness.run(&mut computed, out).unwrap();
+
+    let mut flags = Flags::new();
+    let mut table = Updates {
+        name: "ADD flags table",
+        updates: 65536,
+        pass: || {
+            for x in 0..=255u8 {
+                for y in 0..=255u8 {
+                    black_box(flags.add(black_box(x), black_box(y), false));
+                }
+            }
+        },
+    };
+    harness.run(&mut table, out).unwrap();
+
+    let mut state = State::new();
+    let mut alu = Updates {
+        name: "ALU operations",
+        updates: 65536,
+        pass: || {
+            for x in 0..=255u8 {
+                for y in 0..=255u8 {
+                    state.a = black_box(x);
+                    state.operate8(black_box(x & 0x38), black_box(y));
+                }
+            }
+        },
+    };
+    harness.run(&mut alu, out).unwrap();
+
+    harness.finish(out).unwrap();
+}
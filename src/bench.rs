@@ -0,0 +1,554 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use cpu::try_emulate_instruction;
+use machine::Machine;
+use state::State;
+use trace::Tracer;
+
+// A harness for emulator benchmarks. Each workload is warmed up, then run
+// for a fixed number of emulated cycles per sample, and reported as emulated
+// MHz and millions of instructions per second. Results can be saved under a
+// name and later runs compared against them, so that changes to dispatch,
+// flags or memory can be checked for regressions. Every benchmark in
+// benches/ is a suite of workloads run through one Harness.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub cycles: u64,
+    // Zero when the workload can't count them, as with the block cache.
+    pub instructions: u64,
+}
+
+pub trait Workload {
+    fn name(&self) -> &str;
+    // Runs for at least `cycles` emulated cycles, carrying on from where
+    // the previous call stopped.
+    fn run(&mut self, cycles: u64) -> Counts;
+}
+
+pub fn new_state() -> State {
+    let mut state = State::new();
+    // Keeping the crash history costs more than dispatch does.
+    state.tracer = Tracer::new(0);
+    state
+}
+
+// Steps one instruction, counting it. A halted CPU idling for an interrupt
+// counts as running NOPs.
+pub fn step(state: &mut State, machine: &mut impl Machine, counts: &mut Counts) {
+    counts.cycles += try_emulate_instruction(state, machine).unwrap() as u64;
+    counts.instructions += 1;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+pub fn summarize(samples: &[f64]) -> Summary {
+    assert!(!samples.is_empty(), "no samples to summarize");
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = sorted.len();
+    let mean = sorted.iter().sum::<f64>() / n as f64;
+    let median = if n % 2 == 0 {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+    } else {
+        sorted[n / 2]
+    };
+    let stddev = if n > 1 {
+        let squares: f64 = sorted.iter().map(|x| (x - mean) * (x - mean)).sum();
+        (squares / (n - 1) as f64).sqrt()
+    } else {
+        0.0
+    };
+    Summary {
+        mean,
+        median,
+        stddev,
+        min: sorted[0],
+        max: sorted[n - 1],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub name: String,
+    pub mhz: Summary,
+    pub mips: Option<Summary>,
+}
+
+impl Measurement {
+    pub fn new(name: &str, samples: &[(Counts, Duration)]) -> Measurement {
+        let rate = |n: u64, elapsed: Duration| n as f64 / elapsed.as_secs_f64() / 1e6;
+        let mhz: Vec<f64> = samples
+            .iter()
+            .map(|&(counts, elapsed)| rate(counts.cycles, elapsed))
+            .collect();
+        let mips: Vec<f64> = samples
+            .iter()
+            .map(|&(counts, elapsed)| rate(counts.instructions, elapsed))
+            .collect();
+        let counted = samples.iter().any(|&(counts, _)| counts.instructions > 0);
+        Measurement {
+            name: name.to_string(),
+            mhz: summarize(&mhz),
+            mips: if counted {
+                Some(summarize(&mips))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+// Only what a comparison needs is kept in a baseline: the emulated MHz mean
+// and spread for each workload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BaselineEntry {
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+pub type Baseline = BTreeMap<String, BaselineEntry>;
+
+// One "name<TAB>mean<TAB>stddev" line per workload.
+pub fn format_baseline(measurements: &[Measurement]) -> String {
+    measurements
+        .iter()
+        .map(|m| format!("{}\t{}\t{}\n", m.name, m.mhz.mean, m.mhz.stddev))
+        .collect()
+}
+
+pub fn parse_baseline(text: &str) -> Result<Baseline, String> {
+    let mut baseline = Baseline::new();
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parsed = match fields[..] {
+            [name, mean, stddev] => mean
+                .parse()
+                .and_then(|mean| stddev.parse().map(|stddev| (name, mean, stddev)))
+                .ok(),
+            _ => None,
+        };
+        match parsed {
+            Some((name, mean, stddev)) => {
+                baseline.insert(name.to_string(), BaselineEntry { mean, stddev });
+            }
+            None => return Err(format!("line {}: bad baseline entry", number + 1)),
+        }
+    }
+    Ok(baseline)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Improved(f64),
+    Regressed(f64),
+    NoChange(f64),
+}
+
+// Differences under 2% or within two standard errors of the combined noise
+// are reported as no change.
+pub fn compare(current: &Summary, baseline: &BaselineEntry) -> Change {
+    let percent = (current.mean - baseline.mean) / baseline.mean * 100.0;
+    let noise = 2.0 * (current.stddev.powi(2) + baseline.stddev.powi(2)).sqrt();
+    if percent.abs() < 2.0 || (current.mean - baseline.mean).abs() <= noise {
+        Change::NoChange(percent)
+    } else if percent > 0.0 {
+        Change::Improved(percent)
+    } else {
+        Change::Regressed(percent)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub samples: usize,
+    pub cycles: u64,
+    pub warm_up: Duration,
+    // Only workloads whose names contain this are run.
+    pub filter: Option<String>,
+    pub save_baseline: Option<String>,
+    pub baseline: Option<String>,
+    pub baseline_dir: PathBuf,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            samples: 10,
+            cycles: 20_000_000,
+            warm_up: Duration::from_millis(500),
+            filter: None,
+            save_baseline: None,
+            baseline: None,
+            baseline_dir: PathBuf::from("target/bench-baselines"),
+        }
+    }
+}
+
+impl Options {
+    // Parses the arguments after the program name. `cargo bench` passes
+    // `--bench` to every benchmark, which is accepted and ignored.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+            match arg.as_str() {
+                "--bench" => (),
+                "--samples" => {
+                    options.samples = value(&arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or("--samples needs a positive count")?
+                }
+                "--cycles" => {
+                    options.cycles = value(&arg)?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or("--cycles needs a positive count")?
+                }
+                "--warm-up-ms" => {
+                    let ms = value(&arg)?
+                        .parse()
+                        .map_err(|_| "--warm-up-ms needs a count")?;
+                    options.warm_up = Duration::from_millis(ms);
+                }
+                "--save-baseline" => options.save_baseline = Some(value(&arg)?),
+                "--baseline" => options.baseline = Some(value(&arg)?),
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ => options.filter = Some(arg),
+            }
+        }
+        Ok(options)
+    }
+}
+
+pub struct Harness {
+    options: Options,
+    // Baselines are kept apart for each suite, so that benchmarks saved
+    // under the same name don't overwrite each other.
+    dir: PathBuf,
+    baseline: Option<Baseline>,
+    measurements: Vec<Measurement>,
+}
+
+impl Harness {
+    pub fn new(suite: &str, options: Options) -> io::Result<Harness> {
+        let dir = options.baseline_dir.join(suite);
+        let baseline = match options.baseline {
+            Some(ref name) => {
+                let text = fs::read_to_string(dir.join(name))?;
+                let baseline = parse_baseline(&text)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Some(baseline)
+            }
+            None => None,
+        };
+        Ok(Harness {
+            options,
+            dir,
+            baseline,
+            measurements: Vec::new(),
+        })
+    }
+
+    // The harness for a benchmark binary, given the arguments after the
+    // program name.
+    pub fn from_args(
+        suite: &str,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Harness, String> {
+        let options = Options::parse(args)?;
+        Harness::new(suite, options).map_err(|e| format!("Can't read the baseline: {}", e))
+    }
+
+    pub fn write_header(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<32} {:>18} {:>10}  change",
+            "workload", "emulated MHz", "MIPS"
+        )
+    }
+
+    // Writes a line saying the workload was left out, so that a missing
+    // input file doesn't go unnoticed.
+    pub fn skip(&self, name: &str, reason: &str, out: &mut dyn Write) -> io::Result<()> {
+        if self.selects(name) {
+            writeln!(out, "{:<32} skipped: {}", name, reason)?;
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self, workload: &mut dyn Workload, out: &mut dyn Write) -> io::Result<()> {
+        if !self.selects(workload.name()) {
+            return Ok(());
+        }
+
+        // Warm-up runs in slices so that it stops close to the deadline.
+        let start = Instant::now();
+        while start.elapsed() < self.options.warm_up {
+            workload.run(self.options.cycles / 10);
+        }
+
+        let samples: Vec<(Counts, Duration)> = (0..self.options.samples)
+            .map(|_| {
+                let start = Instant::now();
+                let counts = workload.run(self.options.cycles);
+                (counts, start.elapsed())
+            })
+            .collect();
+        let measurement = Measurement::new(workload.name(), &samples);
+
+        let change = self
+            .baseline
+            .as_ref()
+            .and_then(|baseline| baseline.get(&measurement.name))
+            .map(|entry| match compare(&measurement.mhz, entry) {
+                Change::Improved(p) => format!("{:+.1}% (improved)", p),
+                Change::Regressed(p) => format!("{:+.1}% (regressed)", p),
+                Change::NoChange(p) => format!("{:+.1}% (no change)", p),
+            })
+            .unwrap_or_default();
+        let mips = measurement
+            .mips
+            .map(|mips| format!("{:.1}", mips.median))
+            .unwrap_or_else(|| "-".to_string());
+        let line = format!(
+            "{:<32} {:>9.1} ± {:>6.1} {:>10}  {}",
+            measurement.name, measurement.mhz.median, measurement.mhz.stddev, mips, change
+        );
+        writeln!(out, "{}", line.trim_end())?;
+        self.measurements.push(measurement);
+        Ok(())
+    }
+
+    pub fn measurements(&self) -> &[Measurement] {
+        &self.measurements
+    }
+
+    // Saves the baseline, if one was asked for.
+    pub fn finish(self, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(ref name) = self.options.save_baseline {
+            fs::create_dir_all(&self.dir)?;
+            let path = self.dir.join(name);
+            fs::write(&path, format_baseline(&self.measurements))?;
+            writeln!(out, "Saved baseline {}", path.display())?;
+        }
+        Ok(())
+    }
+
+    fn selects(&self, name: &str) -> bool {
+        self.options
+            .filter
+            .as_ref()
+            .map_or(true, |filter| name.contains(filter.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn summarize_test() {
+        let summary = summarize(&[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(summary.mean, 2.5);
+        assert_eq!(summary.median, 2.5);
+        assert_eq!(summary.min, 1.0);
+        assert_eq!(summary.max, 4.0);
+        assert!((summary.stddev - 1.290_994).abs() < 1e-6);
+
+        let summary = summarize(&[7.0]);
+        assert_eq!((summary.median, summary.stddev), (7.0, 0.0));
+        assert_eq!(summarize(&[3.0, 9.0, 1.0]).median, 3.0);
+    }
+
+    #[test]
+    fn measurement_test() {
+        let counts = Counts {
+            cycles: 2_000_000,
+            instructions: 500_000,
+        };
+        let m = Measurement::new(
+            "loop",
+            &[
+                (counts, Duration::from_millis(100)),
+                (counts, Duration::from_millis(200)),
+            ],
+        );
+        assert_eq!((m.mhz.max, m.mhz.min), (20.0, 10.0));
+        let mips = m.mips.unwrap();
+        assert_eq!((mips.max, mips.min), (5.0, 2.5));
+
+        let uncounted = Counts {
+            instructions: 0,
+            ..counts
+        };
+        let m = Measurement::new("cached", &[(uncounted, Duration::from_millis(100))]);
+        assert_eq!((m.mhz.mean, m.mips), (20.0, None));
+    }
+
+    #[test]
+    fn baseline_test() {
+        let m = Measurement::new(
+            "tight loop",
+            &[(
+                Counts {
+                    cycles: 1_500_000,
+                    instructions: 1,
+                },
+                Duration::from_secs(1),
+            )],
+        );
+        let text = format_baseline(&[m]);
+        assert_eq!(text, "tight loop\t1.5\t0\n");
+        let baseline = parse_baseline(&text).unwrap();
+        assert_eq!(
+            baseline["tight loop"],
+            BaselineEntry {
+                mean: 1.5,
+                stddev: 0.0
+            }
+        );
+
+        assert_eq!(
+            parse_baseline("a\t1\t0\nb\tfast\t0\n"),
+            Err("line 2: bad baseline entry".to_string())
+        );
+        assert!(parse_baseline("a 1 0").is_err());
+    }
+
+    #[test]
+    fn compare_test() {
+        let base = BaselineEntry {
+            mean: 100.0,
+            stddev: 1.0,
+        };
+        let at = |mean, stddev| {
+            compare(
+                &Summary {
+                    mean,
+                    median: mean,
+                    stddev,
+                    min: mean,
+                    max: mean,
+                },
+                &base,
+            )
+        };
+        assert_eq!(at(110.0, 1.0), Change::Improved(10.0));
+        assert_eq!(at(90.0, 1.0), Change::Regressed(-10.0));
+        assert_eq!(at(101.0, 0.0), Change::NoChange(1.0));
+        // Large, but no larger than the noise.
+        assert_eq!(at(95.0, 5.0), Change::NoChange(-5.0));
+    }
+
+    #[test]
+    fn options_test() {
+        assert_eq!(Options::parse(args("--bench")), Ok(Options::default()));
+
+        let options = Options::parse(args(
+            "--samples 3 --cycles 1000 --warm-up-ms 0 --baseline old --save-baseline new arith",
+        ))
+        .unwrap();
+        assert_eq!(options.samples, 3);
+        assert_eq!(options.cycles, 1000);
+        assert_eq!(options.warm_up, Duration::from_millis(0));
+        assert_eq!(options.baseline, Some("old".to_string()));
+        assert_eq!(options.save_baseline, Some("new".to_string()));
+        assert_eq!(options.filter, Some("arith".to_string()));
+
+        assert!(Options::parse(args("--samples 0")).is_err());
+        assert!(Options::parse(args("--cycles")).is_err());
+        assert!(Options::parse(args("--fast")).is_err());
+    }
+
+    struct Counter {
+        runs: u64,
+    }
+
+    impl Workload for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn run(&mut self, cycles: u64) -> Counts {
+            self.runs += 1;
+            Counts {
+                cycles,
+                instructions: cycles / 4,
+            }
+        }
+    }
+
+    #[test]
+    fn harness_test() {
+        let dir = env::temp_dir().join(format!("virtual_8080_bench_{}", std::process::id()));
+        let options = Options {
+            samples: 3,
+            cycles: 1000,
+            warm_up: Duration::from_millis(0),
+            save_baseline: Some("first".to_string()),
+            baseline_dir: dir.clone(),
+            ..Options::default()
+        };
+        let mut out = Vec::new();
+        let mut harness = Harness::new("unit", options.clone()).unwrap();
+        let mut counter = Counter { runs: 0 };
+        harness.run(&mut counter, &mut out).unwrap();
+        harness.skip("exerciser", "no file", &mut out).unwrap();
+        assert_eq!(counter.runs, 3);
+        assert_eq!(harness.measurements().len(), 1);
+        harness.finish(&mut out).unwrap();
+
+        let compared = Options {
+            save_baseline: None,
+            baseline: Some("first".to_string()),
+            filter: Some("count".to_string()),
+            ..options
+        };
+        let mut harness = Harness::new("unit", compared.clone()).unwrap();
+        harness.run(&mut counter, &mut out).unwrap();
+        harness.skip("exerciser", "no file", &mut out).unwrap();
+        harness.finish(&mut out).unwrap();
+        // Another suite doesn't see the first one's baselines.
+        assert!(dir.join("unit").join("first").is_file());
+        assert!(Harness::new("other", compared.clone()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("counter "));
+        assert_eq!(lines[1], format!("{:<32} skipped: no file", "exerciser"));
+        assert!(lines[2].starts_with("Saved baseline "));
+        assert!(lines[3].starts_with("counter ") && lines[3].contains('%'));
+
+        let missing = Options {
+            baseline: Some("missing".to_string()),
+            ..compared
+        };
+        assert!(Harness::new("unit", missing).is_err());
+        assert!(Harness::from_args("unit", args("--fast")).is_err());
+    }
+}
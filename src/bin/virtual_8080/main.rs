@@ -0,0 +1,127 @@
+extern crate virtual_8080;
+
+mod monitor;
+mod tui;
+
+use std::env;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::process;
+
+use monitor::{Monitor, Outcome};
+use virtual_8080::loader::Format;
+
+const USAGE: &str = "usage: virtual_8080 [--symbols FILE]... [--script FILE | --tui] [PROGRAM]";
+
+fn interact(monitor: &mut Monitor) -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut last = String::new();
+
+    loop {
+        print!("(8080) ");
+        stdout.lock().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        // An empty line repeats the previous command, so stepping is just
+        // a matter of pressing return.
+        if line.trim().is_empty() {
+            line = last.clone();
+        } else {
+            last = line.clone();
+        }
+
+        match monitor.execute(&line, &mut stdout.lock()) {
+            Ok(Outcome::Quit) => return Ok(()),
+            Ok(Outcome::Continue) => (),
+            Ok(Outcome::Tui) => {
+                if let Err(e) = tui::run(monitor) {
+                    eprintln!("error: {}", e);
+                }
+            }
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+fn main() {
+    let mut script = None;
+    let mut program = None;
+    let mut full_screen = false;
+    let mut symbols = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--script" | "-s" => match args.next() {
+                Some(path) => script = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "--symbols" => match args.next() {
+                Some(path) => symbols.push(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "--tui" => full_screen = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if program.is_none() && !arg.starts_with('-') => program = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
+    let mut monitor = Monitor::new();
+
+    for path in symbols {
+        if let Err(e) = monitor.load_symbols(Path::new(&path)) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(program) = program {
+        let path = Path::new(&program);
+        if let Err(e) = monitor.load(path, Format::from_path(path), 0) {
+            eprintln!("{}: {}", program, e);
+            process::exit(1);
+        }
+    }
+
+    // Scripts run to completion without dropping into the prompt, and any
+    // failing command fails the run.
+    if let Some(script) = script {
+        if let Err(e) = monitor.run_script(Path::new(&script), &mut io::stdout()) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if full_screen {
+        if let Err(e) = tui::run(&mut monitor) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = interact(&mut monitor) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
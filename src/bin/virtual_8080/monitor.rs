@@ -0,0 +1,1307 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use virtual_8080::callstack::format_backtrace;
+use virtual_8080::cfg::{default_entries, Cfg};
+use virtual_8080::coverage::{parse_listing, Coverage};
+use virtual_8080::cpu::{run_until, try_emulate_instruction, StopReason};
+use virtual_8080::disasm::{disassemble_with_symbols, format_listing_line};
+use virtual_8080::loader;
+use virtual_8080::loader::{Format, BDOS_ENTRY};
+use virtual_8080::machine::Machine;
+use virtual_8080::memory::MemoryWrite;
+use virtual_8080::profiler::Profiler;
+use virtual_8080::program::Program;
+use virtual_8080::recompile::write_rust;
+use virtual_8080::smc::SmcDetector;
+use virtual_8080::stack::Stack;
+use virtual_8080::state::{instruction_length, State};
+use virtual_8080::symbols::{SymbolError, Symbols};
+use virtual_8080::trace::Tracer;
+
+const HELP: &str = "\
+load rom|hex|com FILE [ADDR]  load a program (ROMs default to 0000)
+step|s [N]                    execute N instructions
+next|n                        step over CALL and RST
+continue|c [CYCLES]           run until a breakpoint, watchpoint or HLT
+break|b [ADDR]                set a breakpoint, or list them
+delete|d ADDR                 remove a breakpoint
+watch|w [ADDR]                stop when ADDR is written, or list watchpoints
+unwatch ADDR                  remove a watchpoint
+regs|r                        show registers
+backtrace|bt                  show the call stack
+mem|x [dump] ADDR [LEN]       dump memory
+disasm|u [ADDR] [N]           disassemble N instructions
+set REG VALUE                 set A-L, BC, DE, HL, SP, PC or PSW
+poke ADDR BYTE...             write bytes to memory
+trace on [FILE]|off           print each instruction as it executes
+profile on|off                start (from zero) or stop profiling
+profile flat|graph [FILE]     write a flat or call-graph profile
+profile folded FILE           write folded stacks for flamegraph tools
+coverage on|off               start (from zero) or stop recording coverage
+coverage listing [FILE]       write an annotated disassembly
+coverage lcov FILE [LISTING]  write LCOV data against an assembler listing,
+                              or against an annotated disassembly in FILE.lst
+symbols [FILE]                load a symbol file, or list symbols
+tui                           full-screen debugger
+smc on [stop]|off             detect self-modifying code, optionally stopping
+smc                           list self-modifying code events
+cfg [dot|rust FILE] [ADDR...] recover the control-flow graph from the reset
+                              and RST vectors (and ADDRs), as DOT, as Rust
+                              with a function per block, or a summary
+source FILE                   run commands from FILE
+quit|q                        exit
+Numbers are hex; a 0x or $ prefix is accepted. Addresses may also be
+given as NAME or NAME+OFFSET once symbols are loaded.";
+
+// How many of the hottest addresses the flat profile lists.
+const PROFILE_ADDRESSES: usize = 20;
+
+// How deeply scripts may source other scripts, so that one sourcing
+// itself fails instead of overflowing the stack.
+const MAX_SCRIPT_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum CommandError {
+    Usage(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage(message) => write!(f, "{}", message),
+            CommandError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::Io(e)
+    }
+}
+
+impl From<loader::LoadError> for CommandError {
+    fn from(e: loader::LoadError) -> Self {
+        CommandError::Usage(e.to_string())
+    }
+}
+
+impl From<SymbolError> for CommandError {
+    fn from(e: SymbolError) -> Self {
+        CommandError::Usage(e.to_string())
+    }
+}
+
+fn usage<T>(message: &str) -> Result<T, CommandError> {
+    Err(CommandError::Usage(message.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Continue,
+    Quit,
+    // Switch to the full-screen debugger.
+    Tui,
+}
+
+pub type CommandResult = Result<Outcome, CommandError>;
+
+// Port I/O is not connected to anything.
+pub struct Console;
+
+impl Machine for Console {
+    fn input(&self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _val: u8) {}
+}
+
+enum Stop {
+    Breakpoint(u16),
+    Watchpoint(MemoryWrite),
+    SelfModifying(String),
+    Halted,
+    Returned,
+    CycleLimit,
+    Error(String),
+}
+
+pub struct Monitor {
+    pub state: State,
+    machine: Console,
+    watchpoints: BTreeSet<u16>,
+    cpm: bool,
+    symbols: Arc<Symbols>,
+    // The last profile, kept after profiling stops so it can be reported.
+    profile: Option<Profiler>,
+    coverage: Option<Coverage>,
+    // Stop when the SMC detector reports something new.
+    smc_stop: bool,
+    // Scripts currently being run by `source`.
+    script_depth: usize,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        let mut state = State::new();
+        state.tracer = Tracer::disabled();
+        Monitor {
+            state,
+            machine: Console,
+            watchpoints: BTreeSet::new(),
+            cpm: false,
+            symbols: Arc::new(Symbols::new()),
+            profile: None,
+            coverage: None,
+            smc_stop: false,
+            script_depth: 0,
+        }
+    }
+
+    pub fn load(&mut self, path: &Path, format: Format, address: u16) -> Result<(), CommandError> {
+        loader::load_file(&mut self.state, path, format, address)?;
+        self.cpm = format == Format::Com;
+        Ok(())
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Symbols from several files accumulate; the tracer shares the table.
+    pub fn load_symbols(&mut self, path: &Path) -> Result<usize, CommandError> {
+        let loaded = Symbols::load(path)?;
+        let mut symbols = (*self.symbols).clone();
+        symbols.extend(&loaded);
+        self.symbols = Arc::new(symbols);
+        self.state.tracer.set_symbols(Some(self.symbols.clone()));
+        Ok(loaded.len())
+    }
+
+    // Blank lines and lines starting with '#' are ignored.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> CommandResult {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, _)) if command.starts_with('#') => return Ok(Outcome::Continue),
+            Some((command, args)) => (*command, args),
+            None => return Ok(Outcome::Continue),
+        };
+
+        match command {
+            "help" | "?" => writeln!(out, "{}", HELP)?,
+            "load" => self.load_command(args, out)?,
+            "step" | "s" => self.step_command(args, out)?,
+            "next" | "n" => self.next_command(out)?,
+            "continue" | "c" => self.continue_command(args, out)?,
+            "break" | "b" => match args {
+                [] => list_addresses(self.state.breakpoints.iter().cloned().collect(), out)?,
+                [address] => {
+                    let address = self.parse_address(address)?;
+                    self.state.breakpoints.insert(address);
+                }
+                _ => return usage("usage: break [ADDR]"),
+            },
+            "delete" | "d" => {
+                let address = self.parse_address(single(args)?)?;
+                if !self.state.breakpoints.remove(&address) {
+                    return usage("no breakpoint there");
+                }
+            }
+            "watch" | "w" => match args {
+                [] => list_addresses(self.watchpoints.clone(), out)?,
+                [address] => {
+                    let address = self.parse_address(address)?;
+                    self.watchpoints.insert(address);
+                }
+                _ => return usage("usage: watch [ADDR]"),
+            },
+            "unwatch" => {
+                let address = self.parse_address(single(args)?)?;
+                if !self.watchpoints.remove(&address) {
+                    return usage("no watchpoint there");
+                }
+            }
+            "regs" | "r" => self.print_registers(out)?,
+            "backtrace" | "bt" => self.print_backtrace(out)?,
+            "mem" | "x" => self.mem_command(args, out)?,
+            "disasm" | "u" => self.disasm_command(args, out)?,
+            "set" => self.set_command(args)?,
+            "poke" => {
+                if args.len() < 2 {
+                    return usage("usage: poke ADDR BYTE...");
+                }
+                let address = self.parse_address(args[0])?;
+                for (offset, arg) in args[1..].iter().enumerate() {
+                    let byte = parse_byte(arg)?;
+                    self.state
+                        .memory
+                        .set(address.wrapping_add(offset as u16), byte);
+                }
+            }
+            "trace" => self.trace_command(args)?,
+            "profile" => self.profile_command(args, out)?,
+            "coverage" => self.coverage_command(args, out)?,
+            "cfg" => self.cfg_command(args, out)?,
+            "smc" => self.smc_command(args, out)?,
+            "symbols" | "sym" => match args {
+                [] => {
+                    for (address, name) in self.symbols.iter() {
+                        writeln!(out, "{:04x} {}", address, name)?;
+                    }
+                }
+                [path] => {
+                    let count = self.load_symbols(Path::new(path))?;
+                    writeln!(out, "Loaded {} symbols from {}", count, path)?;
+                }
+                _ => return usage("usage: symbols [FILE]"),
+            },
+            "source" => {
+                let path = single(args)?;
+                return self.run_script(Path::new(path), out);
+            }
+            "tui" => return Ok(Outcome::Tui),
+            "quit" | "q" | "exit" => return Ok(Outcome::Quit),
+            _ => return usage(&format!("unknown command '{}'; try 'help'", command)),
+        }
+        Ok(Outcome::Continue)
+    }
+
+    // Runs each line of a script, echoing it first. The first failing
+    // command aborts the script.
+    pub fn run_script(&mut self, path: &Path, out: &mut dyn Write) -> CommandResult {
+        if self.script_depth == MAX_SCRIPT_DEPTH {
+            return usage(&format!(
+                "scripts nested more than {} deep",
+                MAX_SCRIPT_DEPTH
+            ));
+        }
+        let script = fs::read_to_string(path)?;
+        self.script_depth += 1;
+        let result = self.run_lines(path, &script, out);
+        self.script_depth -= 1;
+        result
+    }
+
+    fn run_lines(&mut self, path: &Path, script: &str, out: &mut dyn Write) -> CommandResult {
+        for (index, line) in script.lines().enumerate() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            writeln!(out, "> {}", line.trim())?;
+            match self.execute(line, out) {
+                Ok(Outcome::Quit) => return Ok(Outcome::Quit),
+                Ok(Outcome::Continue) => (),
+                Ok(Outcome::Tui) => return usage("the tui command is interactive only"),
+                Err(e) => {
+                    return Err(CommandError::Usage(format!(
+                        "{}:{}: {}",
+                        path.display(),
+                        index + 1,
+                        e
+                    )))
+                }
+            }
+        }
+        Ok(Outcome::Continue)
+    }
+
+    fn load_command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (format, path, address) = match args {
+            [kind, path] => (parse_format(kind)?, path, 0),
+            ["rom", path, address] => (Format::Rom, path, self.parse_address(address)?),
+            _ => return usage("usage: load rom|hex|com FILE [ADDR]"),
+        };
+        self.load(Path::new(path), format, address)?;
+        writeln!(out, "Loaded {}, PC={:04x}", path, self.state.pc)?;
+        Ok(())
+    }
+
+    fn step_command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let count = match args {
+            [] => 1,
+            [count] => parse_word(count)?,
+            _ => return usage("usage: step [N]"),
+        };
+        for _ in 0..count {
+            if let Some(stop) = self.step_one(out)? {
+                return self.report(stop, out);
+            }
+        }
+        self.print_current(out)
+    }
+
+    fn next_command(&mut self, out: &mut dyn Write) -> Result<(), CommandError> {
+        let opcode = self.state.get_opcode();
+        // CALL (and its undocumented aliases), conditional calls and RST.
+        let is_call = opcode & 0xcf == 0xcd || opcode & 0xc7 == 0xc4 || opcode & 0xc7 == 0xc7;
+        if !is_call {
+            return self.step_command(&[], out);
+        }
+
+        let return_address = self.state.pc.wrapping_add(instruction_length(opcode));
+        let sp = self.state.sp;
+        let stop = self.resume(out, |s| s.pc == return_address && s.sp >= sp)?;
+        self.report(stop, out)
+    }
+
+    fn continue_command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let limit = match args {
+            [] => None,
+            [cycles] => Some(self.state.cycles + parse_number(cycles)?),
+            _ => return usage("usage: continue [CYCLES]"),
+        };
+        let stop = self.resume(out, |s| limit.is_some_and(|limit| s.cycles >= limit))?;
+        let stop = match stop {
+            Stop::Returned => Stop::CycleLimit,
+            stop => stop,
+        };
+        self.report(stop, out)
+    }
+
+    // Executes one instruction, or one BDOS call when running a CP/M program.
+    fn step_one(&mut self, out: &mut dyn Write) -> Result<Option<Stop>, CommandError> {
+        if self.state.halted {
+            return Ok(Some(Stop::Halted));
+        }
+        if self.cpm && self.state.pc == BDOS_ENTRY {
+            self.bdos(out)?;
+            return Ok(None);
+        }
+        let seen = self.smc_events();
+        if let Err(e) = try_emulate_instruction(&mut self.state, &mut self.machine) {
+            return Ok(Some(Stop::Error(e.to_string())));
+        }
+        if self.state.halted {
+            return Ok(Some(Stop::Halted));
+        }
+        if let Some(event) = self.new_smc_event(seen) {
+            return Ok(Some(event));
+        }
+        let watchpoints = &self.watchpoints;
+        Ok(self
+            .state
+            .memory
+            .writes()
+            .iter()
+            .find(|w| watchpoints.contains(&w.address))
+            .map(|w| Stop::Watchpoint(*w)))
+    }
+
+    fn smc_events(&self) -> usize {
+        self.state.smc.as_ref().map_or(0, |d| d.events().len())
+    }
+
+    fn new_smc_event(&self, seen: usize) -> Option<Stop> {
+        if !self.smc_stop {
+            return None;
+        }
+        let detector = self.state.smc.as_ref()?;
+        detector
+            .events()
+            .get(seen)
+            .map(|event| Stop::SelfModifying(event.to_string()))
+    }
+
+    fn resume(
+        &mut self,
+        out: &mut dyn Write,
+        done: impl Fn(&State) -> bool,
+    ) -> Result<Stop, CommandError> {
+        // Move off the current instruction first so that a breakpoint here
+        // doesn't stop us immediately.
+        if let Some(stop) = self.step_one(out)? {
+            return Ok(stop);
+        }
+
+        loop {
+            if done(&self.state) {
+                return Ok(Stop::Returned);
+            }
+            if self.state.breakpoints.contains(&self.state.pc) {
+                return Ok(Stop::Breakpoint(self.state.pc));
+            }
+            if self.cpm && self.state.pc == BDOS_ENTRY {
+                self.bdos(out)?;
+                continue;
+            }
+
+            let watchpoints = &self.watchpoints;
+            let cpm = self.cpm;
+            let seen = self.smc_events();
+            let smc_stop = self.smc_stop;
+            let mut hit = None;
+            let result = run_until(&mut self.state, &mut self.machine, |s| {
+                hit = s
+                    .memory
+                    .writes()
+                    .iter()
+                    .find(|w| watchpoints.contains(&w.address))
+                    .cloned();
+                let smc = smc_stop && s.smc.as_ref().is_some_and(|d| d.events().len() > seen);
+                hit.is_some() || smc || done(s) || (cpm && s.pc == BDOS_ENTRY)
+            });
+
+            match result.reason {
+                StopReason::Predicate => {
+                    if let Some(write) = hit {
+                        return Ok(Stop::Watchpoint(write));
+                    }
+                    if let Some(event) = self.new_smc_event(seen) {
+                        return Ok(event);
+                    }
+                }
+                StopReason::Breakpoint(address) => return Ok(Stop::Breakpoint(address)),
+                StopReason::Halted => return Ok(Stop::Halted),
+                StopReason::Error(e) => return Ok(Stop::Error(e.to_string())),
+                StopReason::BudgetExhausted => unreachable!(),
+            }
+        }
+    }
+
+    // Handles the console BDOS functions test programs rely on, then
+    // returns to the caller.
+    fn bdos(&mut self, out: &mut dyn Write) -> Result<(), CommandError> {
+        let s = &mut self.state;
+        match s.c {
+            0x00 => s.halted = true,
+            0x02 => out.write_all(&[s.e])?,
+            0x09 => {
+                let mut address = s.get_de();
+                let mut text = Vec::new();
+                while s.memory.get(address) != b'$' && text.len() < 0x10000 {
+                    text.push(s.memory.get(address));
+                    address = address.wrapping_add(1);
+                }
+                out.write_all(&text)?;
+            }
+            _ => (),
+        }
+        out.flush()?;
+        s.call_stack.ret(s.sp);
+        s.pc = s.pop16();
+        Ok(())
+    }
+
+    fn report(&mut self, stop: Stop, out: &mut dyn Write) -> Result<(), CommandError> {
+        match stop {
+            Stop::Breakpoint(address) => {
+                writeln!(out, "Breakpoint at {}", self.describe(address))?;
+                self.print_current(out)?;
+                if self.state.call_stack.depth() > 0 {
+                    self.print_backtrace(out)?;
+                }
+                return Ok(());
+            }
+            Stop::Watchpoint(write) => writeln!(
+                out,
+                "Watchpoint {}: {:02x} -> {:02x}",
+                self.describe(write.address),
+                write.old,
+                write.new
+            )?,
+            Stop::SelfModifying(event) => writeln!(out, "Self-modifying code: {}", event)?,
+            Stop::Halted => writeln!(out, "Halted")?,
+            Stop::CycleLimit => writeln!(out, "Cycle limit reached")?,
+            Stop::Error(message) => writeln!(out, "Stopped: {}", message)?,
+            Stop::Returned => (),
+        }
+        self.print_current(out)
+    }
+
+    fn print_current(&self, out: &mut dyn Write) -> Result<(), CommandError> {
+        let pc = self.state.pc;
+        let instruction = disassemble_with_symbols(&self.state.memory, pc, &self.symbols);
+        match self.symbols.lookup(pc) {
+            Some(_) => writeln!(
+                out,
+                "{}  ; {}",
+                format_listing_line(&instruction),
+                self.symbols.format(pc)
+            )?,
+            None => writeln!(out, "{}", format_listing_line(&instruction))?,
+        }
+        Ok(())
+    }
+
+    fn print_backtrace(&self, out: &mut dyn Write) -> Result<(), CommandError> {
+        let s = &self.state;
+        let locations = s.call_stack.backtrace(s.pc, &s.memory);
+        for line in format_backtrace(&locations, &self.symbols) {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // `01e4 <DrawSprite>`, or just the address when no symbol precedes it.
+    fn describe(&self, address: u16) -> String {
+        match self.symbols.lookup(address) {
+            Some(_) => format!("{:04x} <{}>", address, self.symbols.format(address)),
+            None => format!("{:04x}", address),
+        }
+    }
+
+    fn parse_address(&self, text: &str) -> Result<u16, CommandError> {
+        match self.symbols.resolve(text) {
+            Some(address) => Ok(address),
+            None => usage(&format!("'{}' is not an address or symbol", text)),
+        }
+    }
+
+    fn print_registers(&self, out: &mut dyn Write) -> Result<(), CommandError> {
+        let s = &self.state;
+        let flags: String = [
+            (s.cc.s, 'S'),
+            (s.cc.z, 'Z'),
+            (s.cc.ac, 'A'),
+            (s.cc.p, 'P'),
+            (s.cc.cy, 'C'),
+        ]
+        .iter()
+        .map(|&(set, name)| if set { name } else { '-' })
+        .collect();
+
+        writeln!(
+            out,
+            "A={:02x} BC={:04x} DE={:04x} HL={:04x} SP={:04x} PC={:04x} [{}]{}{}",
+            s.a,
+            s.get_bc(),
+            s.get_de(),
+            s.get_hl_address(),
+            s.sp,
+            s.pc,
+            flags,
+            if s.int_enable { " EI" } else { "" },
+            if s.halted { " HALT" } else { "" },
+        )?;
+        writeln!(out, "cycles={}", s.cycles)?;
+        Ok(())
+    }
+
+    fn mem_command(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let args = match args.first() {
+            Some(&"dump") => &args[1..],
+            _ => args,
+        };
+        let (start, len) = match args {
+            [address] => (self.parse_address(address)?, 0x40),
+            [address, len] => (self.parse_address(address)?, parse_word(len)?),
+            _ => return usage("usage: mem [dump] ADDR [LEN]"),
+        };
+
+        let mut offset = 0;
+        while offset < len {
+            let address = start.wrapping_add(offset);
+            let row: Vec<u8> = (0..16.min(len - offset))
+                .map(|i| self.state.memory.get(address.wrapping_add(i)))
+                .collect();
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = row
+                .iter()
+                .map(|&b| {
+                    if (0x20..0x7f).contains(&b) {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(out, "{:04x}  {:<47}  {}", address, hex.join(" "), ascii)?;
+            offset += row.len() as u16;
+        }
+        Ok(())
+    }
+
+    fn disasm_command(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (start, count) = match args {
+            [] => (self.state.pc, 10),
+            [address] => (self.parse_address(address)?, 10),
+            [address, count] => (self.parse_address(address)?, parse_word(count)?),
+            _ => return usage("usage: disasm [ADDR] [N]"),
+        };
+        let mut address = start;
+        for _ in 0..count {
+            let instruction = disassemble_with_symbols(&self.state.memory, address, &self.symbols);
+            address = instruction.next_address();
+            if let Some(name) = self.symbols.name(instruction.address) {
+                writeln!(out, "{}:", name)?;
+            }
+            let marker = if instruction.address == self.state.pc {
+                "=>"
+            } else {
+                "  "
+            };
+            writeln!(out, "{} {}", marker, format_listing_line(&instruction))?;
+        }
+        Ok(())
+    }
+
+    fn set_command(&mut self, args: &[&str]) -> Result<(), CommandError> {
+        let (register, value) = match args {
+            [register, value] => (register.to_ascii_lowercase(), self.parse_address(value)?),
+            _ => return usage("usage: set REG VALUE"),
+        };
+        let s = &mut self.state;
+        let byte = || {
+            if value > 0xff {
+                usage("value does not fit in a byte")
+            } else {
+                Ok(value as u8)
+            }
+        };
+
+        match register.as_str() {
+            "a" => s.a = byte()?,
+            "b" => s.b = byte()?,
+            "c" => s.c = byte()?,
+            "d" => s.d = byte()?,
+            "e" => s.e = byte()?,
+            "h" => s.h = byte()?,
+            "l" => s.l = byte()?,
+            "bc" => s.set_bc(value),
+            "de" => s.set_de(value),
+            "hl" => s.set_hl(value),
+            "sp" => s.sp = value,
+            "pc" => {
+                s.pc = value;
+                s.halted = false;
+            }
+            "psw" => {
+                s.a = (value >> 8) as u8;
+                s.cc.set_psw(value as u8);
+            }
+            _ => return usage(&format!("unknown register '{}'", register)),
+        }
+        Ok(())
+    }
+
+    fn trace_command(&mut self, args: &[&str]) -> Result<(), CommandError> {
+        let tracer = &mut self.state.tracer;
+        match args {
+            ["on"] => tracer.stream_to(Box::new(io::stdout())),
+            ["on", path] => tracer.stream_to(Box::new(BufWriter::new(File::create(path)?))),
+            ["off"] => {
+                tracer.stop_streaming();
+                if let Some(e) = tracer.take_error() {
+                    return Err(CommandError::Io(e));
+                }
+            }
+            _ => return usage("usage: trace on [FILE]|off"),
+        }
+        Ok(())
+    }
+
+    fn smc_command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        match args {
+            ["on"] | ["on", "stop"] => {
+                self.state.smc = Some(SmcDetector::new());
+                self.smc_stop = args.len() == 2;
+            }
+            ["off"] => {
+                self.state.smc = None;
+                self.smc_stop = false;
+            }
+            [] => {
+                let detector = match self.state.smc.as_ref() {
+                    Some(detector) => detector,
+                    None => return usage("detection is off; try 'smc on'"),
+                };
+                for event in detector.events() {
+                    writeln!(out, "{}", event)?;
+                }
+                if detector.dropped() > 0 {
+                    writeln!(out, "... and {} more", detector.dropped())?;
+                }
+            }
+            _ => return usage("usage: smc [on [stop]|off]"),
+        }
+        Ok(())
+    }
+
+    // Loaded ROMs bound the region; anything else is searched in full.
+    fn cfg_command(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (output, args) = match args {
+            [format @ "dot", path, rest @ ..] | [format @ "rust", path, rest @ ..] => {
+                (Some((*format, *path)), rest)
+            }
+            ["dot"] | ["rust"] => return usage("usage: cfg [dot|rust FILE] [ADDR...]"),
+            _ => (None, args),
+        };
+        let mut entries = default_entries();
+        for arg in args {
+            entries.push(self.parse_address(arg)?);
+        }
+
+        let end = self.state.rom_limit.unwrap_or(0xffff);
+        let cfg = Cfg::build(&self.state.memory, &entries, 0, end);
+        match output {
+            Some((format, path)) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                if format == "dot" {
+                    cfg.write_dot(&mut file, &self.state.memory, &self.symbols)?;
+                } else {
+                    write_rust(&mut file, &self.state.memory, &cfg, "virtual_8080")?;
+                }
+                file.flush()?;
+                writeln!(out, "Wrote {} blocks to {}", cfg.blocks.len(), path)?;
+            }
+            None => cfg.write_summary(out, &self.symbols)?,
+        }
+        Ok(())
+    }
+
+    fn coverage_command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        match args {
+            ["on"] => {
+                self.state.coverage = Some(Coverage::new());
+                return Ok(());
+            }
+            ["off"] => {
+                if let Some(coverage) = self.state.coverage.take() {
+                    self.coverage = Some(coverage);
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        let coverage = match self.state.coverage.as_ref().or(self.coverage.as_ref()) {
+            Some(coverage) => coverage,
+            None => return usage("no coverage has been recorded; try 'coverage on'"),
+        };
+        let (start, end) = match coverage.span() {
+            Some(span) => span,
+            None => return usage("nothing has run yet"),
+        };
+        let memory = &self.state.memory;
+        match args {
+            ["listing"] => coverage.write_listing(out, memory, start, end, &self.symbols)?,
+            ["listing", path] => {
+                let mut file = BufWriter::new(File::create(path)?);
+                coverage.write_listing(&mut file, memory, start, end, &self.symbols)?;
+                file.flush()?;
+            }
+            ["lcov", path] => {
+                let source = format!("{}.lst", path);
+                let (lines, addresses) = coverage.annotate(memory, start, end, &self.symbols);
+                fs::write(&source, lines.join("\n") + "\n")?;
+                let mut file = BufWriter::new(File::create(path)?);
+                coverage.write_lcov(&mut file, memory, &source, &addresses, &self.symbols)?;
+                file.flush()?;
+            }
+            ["lcov", path, listing] => {
+                let addresses = parse_listing(&fs::read_to_string(listing)?);
+                let mut file = BufWriter::new(File::create(path)?);
+                coverage.write_lcov(&mut file, memory, listing, &addresses, &self.symbols)?;
+                file.flush()?;
+            }
+            _ => return usage("usage: coverage on|off|listing [FILE]|lcov FILE [LISTING]"),
+        }
+        Ok(())
+    }
+
+    fn profile_command(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (report, path) = match args {
+            ["on"] => {
+                self.state.profiler = Some(Profiler::new(&self.state.call_stack));
+                return Ok(());
+            }
+            ["off"] => {
+                if let Some(profiler) = self.state.profiler.take() {
+                    self.profile = Some(profiler);
+                }
+                return Ok(());
+            }
+            [report] => (*report, None),
+            [report, path] => (*report, Some(*path)),
+            _ => return usage("usage: profile on|off|flat|graph|folded [FILE]"),
+        };
+
+        let profiler = match self.state.profiler.as_ref().or(self.profile.as_ref()) {
+            Some(profiler) => profiler,
+            None => return usage("nothing has been profiled; try 'profile on'"),
+        };
+        let mut file = match path {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let out: &mut dyn Write = match file.as_mut() {
+            Some(file) => file,
+            None => out,
+        };
+        match report {
+            "flat" => profiler.write_flat(out, &self.symbols, PROFILE_ADDRESSES)?,
+            "graph" => profiler.write_call_graph(out, &self.symbols)?,
+            "folded" if path.is_some() => profiler.write_folded(out, &self.symbols)?,
+            "folded" => return usage("usage: profile folded FILE"),
+            _ => return usage("report must be flat, graph or folded"),
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn list_addresses(addresses: BTreeSet<u16>, out: &mut dyn Write) -> Result<(), CommandError> {
+    for address in addresses {
+        writeln!(out, "{:04x}", address)?;
+    }
+    Ok(())
+}
+
+fn single<'a>(args: &[&'a str]) -> Result<&'a str, CommandError> {
+    match args {
+        [arg] => Ok(arg),
+        _ => usage("expected a single argument"),
+    }
+}
+
+fn parse_format(kind: &str) -> Result<Format, CommandError> {
+    match kind {
+        "rom" => Ok(Format::Rom),
+        "hex" => Ok(Format::Hex),
+        "com" => Ok(Format::Com),
+        _ => usage("format must be rom, hex or com"),
+    }
+}
+
+// Numbers are hex everywhere; cycle counts can run past a word.
+fn parse_number(text: &str) -> Result<u64, CommandError> {
+    let digits = text
+        .trim_start_matches("0x")
+        .trim_start_matches('$')
+        .trim_end_matches('h');
+    u64::from_str_radix(digits, 16).or_else(|_| usage(&format!("'{}' is not a hex number", text)))
+}
+
+pub fn parse_word(text: &str) -> Result<u16, CommandError> {
+    match parse_number(text)? {
+        value if value <= 0xffff => Ok(value as u16),
+        _ => usage(&format!("'{}' does not fit in a word", text)),
+    }
+}
+
+fn parse_byte(text: &str) -> Result<u8, CommandError> {
+    match parse_word(text)? {
+        value if value <= 0xff => Ok(value as u8),
+        _ => usage(&format!("'{}' does not fit in a byte", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn run(monitor: &mut Monitor, commands: &[&str]) -> String {
+        let mut out = Vec::new();
+        for command in commands {
+            monitor.execute(command, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("virtual_8080_{}_{}", process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    // 0000: LXI SP,2400
+    // 0003: CALL 000a
+    // 0006: STA 2000
+    // 0009: HLT
+    // 000a: MVI A,07
+    // 000c: RET
+    fn monitor_with_program() -> Monitor {
+        let mut monitor = Monitor::new();
+        monitor.state.memory.load(
+            0,
+            vec![
+                0x31, 0x00, 0x24, 0xcd, 0x0a, 0x00, 0x32, 0x00, 0x20, 0x76, 0x3e, 0x07, 0xc9,
+            ],
+        );
+        monitor.state.rom_limit = None;
+        monitor
+    }
+
+    #[test]
+    fn parse_word_test() {
+        assert_eq!(parse_word("1f").unwrap(), 0x1f);
+        assert_eq!(parse_word("0x2400").unwrap(), 0x2400);
+        assert_eq!(parse_word("$ffff").unwrap(), 0xffff);
+        assert_eq!(parse_word("100h").unwrap(), 0x100);
+        assert!(parse_word("zz").is_err());
+        assert!(parse_byte("100").is_err());
+        assert!(parse_word("10000").is_err());
+        assert_eq!(parse_number("186a0").unwrap(), 0x186a0);
+        assert!(parse_number("-1").is_err());
+    }
+
+    #[test]
+    fn step_and_next_test() {
+        let mut monitor = monitor_with_program();
+
+        let output = run(&mut monitor, &["step"]);
+        assert_eq!(output, "0003  cd 0a 00  CALL $000a\n");
+
+        let output = run(&mut monitor, &["next"]);
+        assert_eq!(output, "0006  32 00 20  STA $2000\n");
+        assert_eq!(monitor.state.a, 0x07);
+
+        let output = run(&mut monitor, &["s 2"]);
+        assert_eq!(output, "Halted\n000a  3e 07     MVI A,$07\n");
+    }
+
+    #[test]
+    fn breakpoint_test() {
+        let mut monitor = monitor_with_program();
+
+        let output = run(&mut monitor, &["break c", "b 6", "b", "continue"]);
+        assert_eq!(
+            output,
+            "0006\n000c\nBreakpoint at 000c\n000c  c9        RET\n#0  000c\n#1  0006\n"
+        );
+
+        let output = run(&mut monitor, &["step", "bt"]);
+        assert_eq!(output, "0006  32 00 20  STA $2000\n#0  0006\n");
+
+        let output = run(&mut monitor, &["delete 6", "c"]);
+        assert_eq!(output, "Halted\n000a  3e 07     MVI A,$07\n");
+        assert!(monitor.execute("delete 6", &mut io::sink()).is_err());
+    }
+
+    #[test]
+    fn watchpoint_test() {
+        let mut monitor = monitor_with_program();
+
+        let output = run(&mut monitor, &["watch 2000", "c"]);
+        assert_eq!(output, "Watchpoint 2000: 00 -> 07\n0009  76        HLT\n");
+
+        // Return addresses pushed by CALL are writes too.
+        let mut monitor = monitor_with_program();
+        let output = run(&mut monitor, &["w 23ff", "c"]);
+        assert_eq!(
+            output,
+            "Watchpoint 23ff: 00 -> 00\n000a  3e 07     MVI A,$07\n"
+        );
+    }
+
+    #[test]
+    fn cycle_limit_test() {
+        let mut monitor = Monitor::new();
+        // JMP 0000 forever.
+        monitor.state.memory.load(0, vec![0xc3, 0x00, 0x00]);
+
+        let output = run(&mut monitor, &["continue 64"]);
+        assert_eq!(output, "Cycle limit reached\n0000  c3 00 00  JMP $0000\n");
+        assert!(monitor.state.cycles >= 0x64);
+
+        // More cycles than fit in a word.
+        let output = run(&mut monitor, &["continue 186a0"]);
+        assert!(output.starts_with("Cycle limit reached\n"));
+        assert!(monitor.state.cycles >= 0x64 + 0x186a0);
+    }
+
+    #[test]
+    fn registers_test() {
+        let mut monitor = Monitor::new();
+
+        let output = run(
+            &mut monitor,
+            &[
+                "set a 12",
+                "set BC 3456",
+                "set psw 00c5",
+                "set pc 100",
+                "regs",
+            ],
+        );
+        assert_eq!(
+            output,
+            "A=00 BC=3456 DE=0000 HL=0000 SP=0000 PC=0100 [SZ-PC]\ncycles=0\n"
+        );
+        assert!(monitor.execute("set a 100", &mut io::sink()).is_err());
+        assert!(monitor.execute("set q 1", &mut io::sink()).is_err());
+    }
+
+    #[test]
+    fn memory_test() {
+        let mut monitor = Monitor::new();
+
+        let output = run(&mut monitor, &["poke 2400 48 49 00 ff", "mem dump 2400 4"]);
+        assert_eq!(output, format!("2400  {:<47}  HI..\n", "48 49 00 ff"));
+
+        let output = run(&mut monitor, &["x 23f8 18"]);
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.starts_with("23f8  00 00 00 00 00 00 00 00 48 49 00 ff"));
+    }
+
+    #[test]
+    fn disasm_test() {
+        let mut monitor = monitor_with_program();
+
+        let output = run(&mut monitor, &["disasm 0 3"]);
+        assert_eq!(
+            output,
+            "=> 0000  31 00 24  LXI SP,$2400\n   0003  cd 0a 00  CALL $000a\n   0006  32 00 20  STA $2000\n"
+        );
+    }
+
+    #[test]
+    fn symbols_test() {
+        let mut monitor = monitor_with_program();
+        let path = temp_file("program.sym", b"0000 Start\n000a Helper\n2000 Result\n");
+        let output = run(
+            &mut monitor,
+            &[&format!("symbols {}", path.display()), "symbols"],
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(output.starts_with("Loaded 3 symbols from "));
+        assert!(output.ends_with("0000 Start\n000a Helper\n2000 Result\n"));
+
+        let output = run(&mut monitor, &["break Helper+2", "watch Result", "c"]);
+        assert_eq!(
+            output,
+            "Breakpoint at 000c <Helper+0x2>\n000c  c9        RET  ; Helper+0x2\n#0  000c  Helper+0x2\n#1  0006  Start+0x6\n"
+        );
+
+        let output = run(&mut monitor, &["c"]);
+        assert_eq!(
+            output,
+            "Watchpoint 2000 <Result>: 00 -> 07\n0009  76        HLT  ; Start+0x9\n"
+        );
+
+        let output = run(&mut monitor, &["disasm Start+3 1", "u Helper 1"]);
+        assert_eq!(
+            output,
+            "   0003  cd 0a 00  CALL Helper\nHelper:\n   000a  3e 07     MVI A,$07\n"
+        );
+        assert!(monitor.execute("break Nowhere", &mut io::sink()).is_err());
+    }
+
+    #[test]
+    fn profile_test() {
+        let mut monitor = monitor_with_program();
+        assert!(monitor.execute("profile flat", &mut io::sink()).is_err());
+
+        let folded = temp_file("profile.folded", b"");
+        let output = run(
+            &mut monitor,
+            &[
+                "profile on",
+                "c",
+                "profile off",
+                "step",
+                "profile graph",
+                &format!("profile folded {}", folded.display()),
+            ],
+        );
+        let stacks = fs::read_to_string(&folded).unwrap();
+        fs::remove_file(&folded).unwrap();
+
+        assert!(output.contains("[top]  total 64 (100.0%)  self 47 (73.4%)  calls 0\n"));
+        assert!(output.contains("$000a  total 17 (26.6%)  self 17 (26.6%)  calls 1\n"));
+        assert_eq!(stacks, "[top] 47\n[top];$000a 17\n");
+    }
+
+    #[test]
+    fn coverage_test() {
+        let mut monitor = monitor_with_program();
+        assert!(monitor
+            .execute("coverage listing", &mut io::sink())
+            .is_err());
+
+        let output = run(
+            &mut monitor,
+            &["coverage on", "c", "coverage off", "coverage listing"],
+        );
+        assert!(output.ends_with(
+            "        1:  0009  76        HLT\n        1:  000a  3e 07     MVI A,$07\n        1:  000c  c9        RET\n"
+        ));
+
+        let lcov = temp_file("coverage.info", b"");
+        let listing = temp_file(
+            "coverage.prn",
+            b"   3 0003 CD0A00   CALL SUB\n   9 000a 3E07   SUB: MVI A,7\n",
+        );
+        run(
+            &mut monitor,
+            &[&format!(
+                "coverage lcov {} {}",
+                lcov.display(),
+                listing.display()
+            )],
+        );
+        let report = fs::read_to_string(&lcov).unwrap();
+        assert!(report.contains("\nDA:1,1\nDA:2,1\nLF:2\nLH:2\n"));
+
+        run(
+            &mut monitor,
+            &[&format!("coverage lcov {}", lcov.display())],
+        );
+        let report = fs::read_to_string(&lcov).unwrap();
+        let annotated = format!("{}.lst", lcov.display());
+        assert!(report.contains(&format!("SF:{}\n", annotated)));
+        assert!(report.contains("\nLF:6\nLH:6\n"));
+        assert!(fs::read_to_string(&annotated)
+            .unwrap()
+            .starts_with("        1:  0000  31 00 24"));
+
+        fs::remove_file(&lcov).unwrap();
+        fs::remove_file(&listing).unwrap();
+        fs::remove_file(&annotated).unwrap();
+    }
+
+    #[test]
+    fn cfg_test() {
+        let mut monitor = monitor_with_program();
+        monitor.state.rom_limit = Some(0x000f);
+
+        let output = run(&mut monitor, &["cfg"]);
+        // The RST 1 vector lands in the middle of STA 2000.
+        assert_eq!(
+            output,
+            "4 blocks, 7 instructions\ndata 000d-000f (3 bytes)\noverlapping instruction at 0008\n"
+        );
+
+        let dot = temp_file("cfg.dot", b"");
+        let output = run(&mut monitor, &[&format!("cfg dot {} d", dot.display())]);
+        let graph = fs::read_to_string(&dot).unwrap();
+        fs::remove_file(&dot).unwrap();
+
+        assert!(output.starts_with("Wrote 5 blocks to "));
+        assert!(graph.contains("    b0000 -> b000a [style=dashed];\n"));
+
+        let rust = temp_file("cfg.rs", b"");
+        let output = run(&mut monitor, &[&format!("cfg rust {}", rust.display())]);
+        let source = fs::read_to_string(&rust).unwrap();
+        fs::remove_file(&rust).unwrap();
+
+        assert!(output.starts_with("Wrote 4 blocks to "));
+        assert!(source.contains("use virtual_8080::state::State;\n"));
+        assert!(source.contains("\nfn block_0000<M: Machine>(s: &mut State, "));
+    }
+
+    #[test]
+    fn smc_test() {
+        let mut monitor = Monitor::new();
+        // 0000: MVI A,76; STA 0008; JMP 0008
+        monitor
+            .state
+            .memory
+            .load(0, vec![0x3e, 0x76, 0x32, 0x08, 0x00, 0xc3, 0x08, 0x00]);
+        monitor.state.rom_limit = None;
+
+        let output = run(&mut monitor, &["smc on stop", "c", "c", "c", "smc"]);
+        assert_eq!(
+            output,
+            "Self-modifying code: executed 0008, written by 0002\n0009  00        NOP\n\
+             Halted\n0009  00        NOP\n\
+             Halted\n0009  00        NOP\n\
+             executed 0008, written by 0002\n"
+        );
+        assert!(monitor.execute("smc off", &mut io::sink()).is_ok());
+        assert!(monitor.execute("smc", &mut io::sink()).is_err());
+    }
+
+    #[test]
+    fn errors_test() {
+        let mut monitor = Monitor::new();
+        let mut out = Vec::new();
+
+        for command in ["frobnicate", "step x", "poke 10", "load tape x", "mem"].iter() {
+            assert!(monitor.execute(command, &mut out).is_err(), "{}", command);
+        }
+        assert_eq!(
+            monitor.execute("# comment", &mut out).unwrap(),
+            Outcome::Continue
+        );
+        assert_eq!(monitor.execute("", &mut out).unwrap(), Outcome::Continue);
+        assert_eq!(monitor.execute("quit", &mut out).unwrap(), Outcome::Quit);
+    }
+
+    #[test]
+    fn cpm_test() {
+        // 0100: MVI C,09; LXI D,0110; CALL 0005
+        // 0108: MVI C,02; MVI E,'!'; CALL 0005
+        // 010f: JMP 0000 (warm boot)
+        let mut program = vec![
+            0x0e, 0x09, 0x11, 0x13, 0x01, 0xcd, 0x05, 0x00, 0x0e, 0x02, 0x1e, 0x21, 0xcd, 0x05,
+            0x00, 0xc3, 0x00, 0x00, 0x00,
+        ];
+        program.extend_from_slice(b"HELLO$");
+        let path = temp_file("hello.com", &program);
+
+        let mut monitor = Monitor::new();
+        let output = run(
+            &mut monitor,
+            &[&format!("load com {}", path.display()), "continue"],
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(output.ends_with("PC=0100\nHELLO!Halted\n0001  00        NOP\n"));
+    }
+
+    #[test]
+    fn script_test() {
+        let rom = temp_file("script.rom", &[0x3e, 0x42, 0x76]);
+        let script = temp_file(
+            "script.txt",
+            format!(
+                "# set up\nload rom {} 1000\n\nstep\nregs\nbogus\nregs\n",
+                rom.display()
+            )
+            .as_bytes(),
+        );
+
+        let mut monitor = Monitor::new();
+        let mut out = Vec::new();
+        let error = monitor
+            .execute(&format!("source {}", script.display()), &mut out)
+            .unwrap_err();
+        fs::remove_file(&rom).unwrap();
+        fs::remove_file(&script).unwrap();
+
+        assert!(error
+            .to_string()
+            .ends_with(":6: unknown command 'bogus'; try 'help'"));
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.contains("> step\n1002  76        HLT\n> regs\nA=42 "));
+        assert_eq!(output.matches("> regs").count(), 1);
+    }
+
+    #[test]
+    fn nested_script_test() {
+        let script = env::temp_dir().join(format!("virtual_8080_{}_nested.txt", process::id()));
+        fs::write(&script, format!("source {}\n", script.display())).unwrap();
+
+        let mut monitor = Monitor::new();
+        let mut out = Vec::new();
+        let error = monitor
+            .execute(&format!("source {}", script.display()), &mut out)
+            .unwrap_err();
+        fs::remove_file(&script).unwrap();
+
+        assert!(error
+            .to_string()
+            .ends_with("scripts nested more than 16 deep"));
+        assert_eq!(monitor.script_depth, 0);
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output.matches("> source").count(), MAX_SCRIPT_DEPTH);
+    }
+}
@@ -0,0 +1,555 @@
+use std::io;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use monitor::Monitor;
+use virtual_8080::disasm::disassemble_with_symbols;
+use virtual_8080::memory::Memory;
+use virtual_8080::state::{instruction_length, Snapshot, State};
+use virtual_8080::symbols::Symbols;
+
+const CLEAR: &str = "\x1b[2J\x1b[H";
+const HIDE_CURSOR: &str = "\x1b[?25l";
+const SHOW_CURSOR: &str = "\x1b[?25h";
+const CHANGED: &str = "\x1b[1;33m";
+const CURRENT: &str = "\x1b[7m";
+const BREAKPOINT: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+const LEFT_WIDTH: usize = 44;
+const LISTING_LINES: usize = 14;
+const LISTING_CONTEXT: usize = 4;
+const STACK_LINES: usize = 5;
+const MEMORY_ROWS: u16 = 6;
+const CONTINUE_SLICE: &str = "continue 186a0";
+
+const KEYS: &str =
+    "s/enter step  n next  c continue  b breakpoint  [ ] memory  h memory@HL  q quit";
+
+// Register contents before the last command, and the memory the panes
+// showed then, so the panes can highlight what it changed. The stack is
+// kept for a few pushes either side of SP.
+struct Previous {
+    snapshot: Snapshot,
+    psw: u8,
+    // Start address and contents of each window.
+    memory: Vec<(u16, Vec<u8>)>,
+}
+
+impl Previous {
+    fn capture(s: &State, memory_base: u16) -> Previous {
+        let window = |start: u16, len: u16| {
+            let bytes = (0..len).map(|i| s.memory.get(start.wrapping_add(i)));
+            (start, bytes.collect())
+        };
+        let stack_bytes = 2 * STACK_LINES as u16;
+        Previous {
+            snapshot: s.snapshot(),
+            psw: s.cc.to_psw(),
+            memory: vec![
+                window(memory_base, MEMORY_ROWS * 16),
+                window(s.sp.wrapping_sub(stack_bytes), 2 * stack_bytes + 1),
+            ],
+        }
+    }
+
+    // None outside the captured windows.
+    fn byte(&self, address: u16) -> Option<u8> {
+        self.memory
+            .iter()
+            .find_map(|(start, bytes)| bytes.get(address.wrapping_sub(*start) as usize).cloned())
+    }
+}
+
+pub struct View {
+    previous: Option<Previous>,
+    memory_base: u16,
+    status: String,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl View {
+    pub fn new() -> View {
+        View {
+            previous: None,
+            memory_base: 0x0000,
+            status: String::new(),
+        }
+    }
+}
+
+fn highlight(text: String, changed: bool) -> String {
+    if changed {
+        format!("{}{}{}", CHANGED, text, RESET)
+    } else {
+        text
+    }
+}
+
+// Length as displayed, ignoring escape sequences.
+fn visible_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut escape = false;
+    for c in text.chars() {
+        match c {
+            '\x1b' => escape = true,
+            c if escape => escape = !c.is_ascii_alphabetic(),
+            _ => len += 1,
+        }
+    }
+    len
+}
+
+// There is no reliable way to disassemble backwards, so this looks for an
+// earlier address whose instructions line up exactly with PC, preferring
+// the one that shows the most context.
+fn listing_start(memory: &Memory, pc: u16, context: usize) -> u16 {
+    for back in (1..=3 * context as u16).rev() {
+        let start = pc.wrapping_sub(back);
+        let mut offset = 0;
+        let mut count = 0;
+        while offset < back {
+            offset += instruction_length(memory.get(start.wrapping_add(offset)));
+            count += 1;
+        }
+        if offset == back && count <= context {
+            return start;
+        }
+    }
+    pc
+}
+
+fn disassembly_pane(s: &State, symbols: &Symbols) -> Vec<String> {
+    let mut lines = vec!["Disassembly".to_string()];
+    let mut address = listing_start(&s.memory, s.pc, LISTING_CONTEXT);
+    for _ in 0..LISTING_LINES {
+        let instruction = disassemble_with_symbols(&s.memory, address, symbols);
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let marker = if s.breakpoints.contains(&address) {
+            format!("{}*{}", BREAKPOINT, RESET)
+        } else {
+            " ".to_string()
+        };
+        let text = format!(
+            "{:04x}  {:<9} {:<20}",
+            address,
+            bytes.join(" "),
+            instruction.text
+        );
+        if address == s.pc {
+            lines.push(format!("{}{}{}{}", marker, CURRENT, text, RESET));
+        } else {
+            lines.push(format!("{}{}", marker, text));
+        }
+        address = instruction.next_address();
+    }
+    lines
+}
+
+fn register_pane(s: &State, previous: Option<&Previous>) -> Vec<String> {
+    let now = s.snapshot();
+    let before = previous.map(|p| p.snapshot).unwrap_or(now);
+    let psw_before = previous.map(|p| p.psw).unwrap_or_else(|| s.cc.to_psw());
+    let psw = s.cc.to_psw();
+
+    let pair = |name: &str, value: u16, old: u16| {
+        highlight(format!("{}={:04x}", name, value), value != old)
+    };
+    let word = |high: u8, low: u8| u16::from(high) << 8 | u16::from(low);
+
+    let flags: Vec<String> = [
+        (0x80, 'S'),
+        (0x40, 'Z'),
+        (0x10, 'A'),
+        (0x04, 'P'),
+        (0x01, 'C'),
+    ]
+    .iter()
+    .map(|&(bit, name)| {
+        let text = if psw & bit != 0 { name } else { '-' };
+        highlight(text.to_string(), (psw ^ psw_before) & bit != 0)
+    })
+    .collect();
+
+    vec![
+        "Registers".to_string(),
+        format!(
+            "{}  {}",
+            highlight(format!("A={:02x}", now.a), now.a != before.a),
+            pair("BC", word(now.b, now.c), word(before.b, before.c))
+        ),
+        format!(
+            "{} {}",
+            pair("DE", word(now.d, now.e), word(before.d, before.e)),
+            pair("HL", word(now.h, now.l), word(before.h, before.l))
+        ),
+        format!(
+            "{} {}",
+            pair("SP", now.sp, before.sp),
+            pair("PC", now.pc, before.pc)
+        ),
+        format!(
+            "[{}]{}{}",
+            flags.join(""),
+            if s.int_enable { " EI" } else { "" },
+            if s.halted { " HALT" } else { "" }
+        ),
+        format!("cycles={}", s.cycles),
+    ]
+}
+
+fn stack_pane(s: &State, symbols: &Symbols, previous: Option<&Previous>) -> Vec<String> {
+    let mut lines = vec!["Stack".to_string()];
+    for (address, value) in s.stack_view(STACK_LINES) {
+        let changed =
+            previous.is_some_and(
+                |p| match (p.byte(address), p.byte(address.wrapping_add(1))) {
+                    (Some(low), Some(high)) => u16::from(high) << 8 | u16::from(low) != value,
+                    _ => false,
+                },
+            );
+        // Most words worth naming on the stack are return addresses.
+        let name = match symbols.lookup(value) {
+            Some(_) => format!(" {}", symbols.format(value)),
+            None => String::new(),
+        };
+        lines.push(format!(
+            "{:04x}  {}{}",
+            address,
+            highlight(format!("{:04x}", value), changed),
+            name
+        ));
+    }
+    lines
+}
+
+fn memory_pane(s: &State, base: u16, previous: Option<&Previous>) -> Vec<String> {
+    let mut lines = vec!["Memory".to_string()];
+    for row in 0..MEMORY_ROWS {
+        let address = base.wrapping_add(row * 16);
+        let mut hex = Vec::new();
+        let mut ascii = String::new();
+        for offset in 0..16 {
+            let a = address.wrapping_add(offset);
+            let byte = s.memory.get(a);
+            let changed = previous.is_some_and(|p| p.byte(a).is_some_and(|old| old != byte));
+            hex.push(highlight(format!("{:02x}", byte), changed));
+            ascii.push(if (0x20..0x7f).contains(&byte) {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        lines.push(format!("{:04x}  {}  {}", address, hex.join(" "), ascii));
+    }
+    lines
+}
+
+pub fn render(s: &State, symbols: &Symbols, view: &View) -> String {
+    let previous = view.previous.as_ref();
+    let left = disassembly_pane(s, symbols);
+    let mut right = register_pane(s, previous);
+    right.push(String::new());
+    right.extend(stack_pane(s, symbols, previous));
+
+    let mut screen = String::from(CLEAR);
+    for i in 0..left.len().max(right.len()) {
+        let l = left.get(i).map(|l| l.as_str()).unwrap_or("");
+        let r = right.get(i).map(|r| r.as_str()).unwrap_or("");
+        let padding = LEFT_WIDTH.saturating_sub(visible_len(l));
+        screen.push_str(&format!("{}{}{}\r\n", l, " ".repeat(padding), r));
+    }
+    screen.push_str("\r\n");
+    for line in memory_pane(s, view.memory_base, previous) {
+        screen.push_str(&line);
+        screen.push_str("\r\n");
+    }
+    screen.push_str(&format!("\r\n{}\r\n{}", view.status, KEYS));
+    screen
+}
+
+// Puts the terminal into raw mode for the lifetime of the guard, with reads
+// timing out after a tenth of a second so the run loop can poll for keys.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enter() -> io::Result<RawMode> {
+        let output = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "stdin is not a terminal",
+            ));
+        }
+        let saved = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        stty(&["raw", "-echo", "min", "0", "time", "1"])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<()> {
+    Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .status()
+        .map(|_| ())
+}
+
+fn read_key() -> io::Result<Option<u8>> {
+    let mut key = [0];
+    match io::stdin().read(&mut key)? {
+        0 => Ok(None),
+        _ => Ok(Some(key[0])),
+    }
+}
+
+fn wait_for_key() -> io::Result<u8> {
+    loop {
+        if let Some(key) = read_key()? {
+            return Ok(key);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Runs a monitor command, keeping only its first line of output (the stop
+// reason) when it printed more than the current instruction.
+fn command(monitor: &mut Monitor, view: &mut View, line: &str) {
+    let mut output = Vec::new();
+    view.status = match monitor.execute(line, &mut output) {
+        Ok(_) => {
+            let text = String::from_utf8_lossy(&output);
+            let lines: Vec<&str> = text.lines().collect();
+            if lines.len() > 1 {
+                lines[0].to_string()
+            } else {
+                String::new()
+            }
+        }
+        Err(e) => format!("error: {}", e),
+    };
+}
+
+// Acts on a key, returning false to quit. `pending` polls for a key
+// pressed while the CPU is running.
+fn press(
+    monitor: &mut Monitor,
+    view: &mut View,
+    key: u8,
+    mut pending: impl FnMut() -> io::Result<Option<u8>>,
+) -> io::Result<bool> {
+    match key {
+        b's' | b' ' | b'\r' | b'n' | b'c' => {
+            view.previous = Some(Previous::capture(&monitor.state, view.memory_base));
+        }
+        _ => (),
+    }
+
+    match key {
+        b's' | b' ' | b'\r' => command(monitor, view, "step"),
+        b'n' => command(monitor, view, "next"),
+        b'c' => loop {
+            // Runs in slices so that any key can interrupt.
+            command(monitor, view, CONTINUE_SLICE);
+            if view.status != "Cycle limit reached" {
+                break;
+            }
+            if pending()?.is_some() {
+                view.status = "Interrupted".to_string();
+                break;
+            }
+        },
+        b'b' => {
+            let pc = monitor.state.pc;
+            if !monitor.state.breakpoints.remove(&pc) {
+                monitor.state.breakpoints.insert(pc);
+            }
+        }
+        b'[' => view.memory_base = view.memory_base.wrapping_sub(MEMORY_ROWS * 16),
+        b']' => view.memory_base = view.memory_base.wrapping_add(MEMORY_ROWS * 16),
+        b'h' => view.memory_base = monitor.state.get_hl_address() & 0xfff0,
+        b'q' | 0x03 => return Ok(false),
+        _ => (),
+    }
+    Ok(true)
+}
+
+pub fn run(monitor: &mut Monitor) -> io::Result<()> {
+    let _raw = RawMode::enter()?;
+    let stdout = io::stdout();
+    let mut view = View::new();
+    write!(stdout.lock(), "{}", HIDE_CURSOR)?;
+
+    loop {
+        write!(
+            stdout.lock(),
+            "{}",
+            render(&monitor.state, monitor.symbols(), &view)
+        )?;
+        stdout.lock().flush()?;
+
+        let key = wait_for_key()?;
+        if !press(monitor, &mut view, key, read_key)? {
+            break;
+        }
+    }
+
+    write!(stdout.lock(), "{}{}", CLEAR, SHOW_CURSOR)?;
+    stdout.lock().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(text: &str) -> String {
+        let mut plain = String::new();
+        let mut escape = false;
+        for c in text.chars() {
+            match c {
+                '\x1b' => escape = true,
+                c if escape => escape = !c.is_ascii_alphabetic(),
+                c => plain.push(c),
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn visible_len_test() {
+        assert_eq!(visible_len("abc"), 3);
+        assert_eq!(visible_len(&highlight("A=00".to_string(), true)), 4);
+        assert_eq!(visible_len("\x1b[31m*\x1b[0m 0000"), 6);
+    }
+
+    #[test]
+    fn listing_start_test() {
+        let mut memory = Memory::new();
+        // 0100: LXI H,0000; MVI A,01; NOP; JMP 0100
+        memory.load(
+            0x100,
+            vec![0x21, 0x00, 0x00, 0x3e, 0x01, 0x00, 0xc3, 0x00, 0x01],
+        );
+
+        assert_eq!(listing_start(&memory, 0x106, 3), 0x100);
+        assert_eq!(listing_start(&memory, 0x106, 2), 0x103);
+        // Zeroes before the code disassemble as NOPs.
+        assert_eq!(listing_start(&memory, 0x106, 4), 0x0ff);
+        assert_eq!(listing_start(&memory, 0x103, 0), 0x103);
+    }
+
+    #[test]
+    fn continue_test() {
+        let mut monitor = Monitor::new();
+        // 0000: JMP 0000
+        monitor.state.memory.load(0, vec![0xc3, 0x00, 0x00]);
+        let mut view = View::new();
+
+        // Interrupted by a key after the second slice.
+        let mut polls = 0;
+        let pending = || {
+            polls += 1;
+            Ok(if polls == 2 { Some(b'x') } else { None })
+        };
+        assert!(press(&mut monitor, &mut view, b'c', pending).unwrap());
+        assert_eq!(view.status, "Interrupted");
+        assert!(monitor.state.cycles >= 2 * 0x186a0);
+        assert!(view.previous.is_some());
+
+        // Runs until the program stops without waiting for a key.
+        monitor.state.memory.load(0x10, vec![0x76]);
+        monitor.state.memory.load(0, vec![0xc3, 0x10, 0x00]);
+        monitor.state.pc = 0;
+        press(&mut monitor, &mut view, b'c', || Ok(None)).unwrap();
+        assert_eq!(view.status, "Halted");
+        assert_eq!(monitor.state.pc, 0x11);
+
+        assert!(!press(&mut monitor, &mut view, b'q', || Ok(None)).unwrap());
+    }
+
+    #[test]
+    fn render_test() {
+        let mut monitor = Monitor::new();
+        monitor
+            .state
+            .memory
+            .load(0, vec![0x31, 0x00, 0x24, 0x06, 0x41, 0xc5, 0x76]);
+        monitor.state.breakpoints.insert(0x0005);
+
+        let mut view = View::new();
+        let screen = render(&monitor.state, monitor.symbols(), &view);
+        assert!(screen.starts_with(CLEAR));
+        assert!(screen.contains(&format!("{}0000  31 00 24  LXI SP,$2400", CURRENT)));
+        assert!(screen.contains(&format!("{}*{}0005  c5", BREAKPOINT, RESET)));
+        assert!(!screen.contains(CHANGED));
+
+        let plain = strip(&screen);
+        assert!(plain.contains("A=00  BC=0000"));
+        assert!(plain.contains("0000  31 00 24 06 41 c5 76 00"));
+        assert!(plain.contains("1.$.A.v"));
+
+        view.memory_base = 0x23f0;
+        for _ in 0..3 {
+            view.previous = Some(Previous::capture(&monitor.state, view.memory_base));
+            monitor.execute("step", &mut io::sink()).unwrap();
+        }
+        let screen = render(&monitor.state, monitor.symbols(), &view);
+
+        // PUSH B changed SP, PC and the top of the stack, but not BC.
+        assert!(screen.contains(&format!("{}SP=23fe{}", CHANGED, RESET)));
+        assert!(screen.contains(&format!("{}PC=0006{}", CHANGED, RESET)));
+        assert!(screen.contains("  BC=4100\r\n"));
+        assert!(screen.contains(&format!("23fe  {}4100{}", CHANGED, RESET)));
+        assert!(screen.contains(&format!(" 00 {}41{}", CHANGED, RESET)));
+        assert!(screen.contains(&format!("{}0006  76", CURRENT)));
+    }
+
+    #[test]
+    fn render_symbols_test() {
+        let mut monitor = Monitor::new();
+        // 0000: LXI SP,2400; CALL 0007; HLT; RET
+        monitor
+            .state
+            .memory
+            .load(0, vec![0x31, 0x00, 0x24, 0xcd, 0x07, 0x00, 0x76, 0xc9]);
+        let path =
+            std::env::temp_dir().join(format!("virtual_8080_{}_render.sym", std::process::id()));
+        std::fs::write(
+            &path,
+            "0000 Start
+0007 Helper
+",
+        )
+        .unwrap();
+        monitor.load_symbols(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        monitor.execute("s 2", &mut io::sink()).unwrap();
+        let plain = strip(&render(&monitor.state, monitor.symbols(), &View::new()));
+
+        assert!(plain.contains("0003  cd 07 00  CALL Helper"));
+        assert!(plain.contains("23fe  0006 Start+0x6"));
+    }
+}
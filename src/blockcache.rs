@@ -0,0 +1,435 @@
+use std::mem;
+
+use cpu::{
+    needs_single_step, opcode_cycles, stays_halted, try_emulate_instruction, RunResult, StopReason,
+};
+use dispatch::{handler, Handler};
+use machine::Machine;
+use memory::Memory;
+use state::{instruction_length, State};
+
+const ADDRESS_SPACE: usize = 0x10000;
+const PAGES: usize = 0x100;
+
+// Long straight-line runs are split so that a block never covers more than
+// two pages.
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+struct Op<M> {
+    handler: Handler<M>,
+    opcode: u8,
+    cycles: usize,
+}
+
+struct Block<M> {
+    // The last byte of the last instruction.
+    end: u16,
+    ops: Vec<Op<M>>,
+}
+
+// Anything that can send PC somewhere other than the next instruction, and
+// the instructions that change whether an interrupt can be taken.
+fn ends_block(opcode: u8) -> bool {
+    match opcode {
+        0x76 | 0xc3 | 0xc9 | 0xcb | 0xcd | 0xd9 | 0xdd | 0xe9 | 0xed | 0xf3 | 0xfb | 0xfd => true,
+        _ => matches!(opcode & 0xc7, 0xc0 | 0xc2 | 0xc4 | 0xc7),
+    }
+}
+
+// Straight-line runs of instructions decoded once, keyed by their start
+// address, and dropped when memory they cover changes. A cache belongs to
+// one State: it watches that State's memory for changes.
+//
+// Whenever needs_single_step holds, instructions go through the plain
+// interpreter instead, and `fallback_steps` counts them. That includes a
+// State with its tracer on, which State::new's is, so callers wanting the
+// speedup should set `state.tracer = Tracer::disabled()`.
+pub struct BlockCache<M> {
+    blocks: Vec<Option<Box<Block<M>>>>,
+    // The start of every cached block that covers each page.
+    pages: Vec<Vec<u16>>,
+    rom_limit: Option<u16>,
+    decoded: u64,
+    invalidated: u64,
+    fallback_steps: u64,
+}
+
+impl<M: Machine> Default for BlockCache<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Machine> BlockCache<M> {
+    pub fn new() -> BlockCache<M> {
+        BlockCache {
+            blocks: (0..ADDRESS_SPACE).map(|_| None).collect(),
+            pages: vec![Vec::new(); PAGES],
+            rom_limit: None,
+            decoded: 0,
+            invalidated: 0,
+            fallback_steps: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for page in self.pages.iter_mut() {
+            for start in page.drain(..) {
+                self.blocks[start as usize] = None;
+            }
+        }
+    }
+
+    pub fn cached_blocks(&self) -> usize {
+        self.blocks.iter().filter(|b| b.is_some()).count()
+    }
+
+    pub fn decoded(&self) -> u64 {
+        self.decoded
+    }
+
+    pub fn invalidated(&self) -> u64 {
+        self.invalidated
+    }
+
+    // Instructions run one at a time because needs_single_step held.
+    pub fn fallback_steps(&self) -> u64 {
+        self.fallback_steps
+    }
+
+    fn invalidate(&mut self, memory: &mut Memory) {
+        for page in memory.take_dirty_pages() {
+            for start in mem::take(&mut self.pages[page as usize]) {
+                if let Some(block) = self.blocks[start as usize].take() {
+                    self.invalidated += 1;
+                    for other in (start >> 8)..=(block.end >> 8) {
+                        if other != u16::from(page) {
+                            self.pages[other as usize].retain(|&s| s != start);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Stops short of the ROM limit and of the top of memory, so that the
+    // plain interpreter reports the former and handles the wrap of the
+    // latter. That can leave a block empty.
+    fn decode(&mut self, memory: &mut Memory, start: u16) -> Box<Block<M>> {
+        let mut ops = Vec::new();
+        let mut address = start as usize;
+        while ops.len() < MAX_BLOCK_INSTRUCTIONS {
+            if self.rom_limit.is_some_and(|limit| address > limit as usize) {
+                break;
+            }
+            let opcode = memory.get(address as u16);
+            let next = address + instruction_length(opcode) as usize;
+            if next > ADDRESS_SPACE {
+                break;
+            }
+            ops.push(Op {
+                handler: handler::<M>(opcode),
+                opcode,
+                cycles: opcode_cycles(opcode),
+            });
+            address = next;
+            if ends_block(opcode) || address == ADDRESS_SPACE {
+                break;
+            }
+        }
+
+        let end = if ops.is_empty() {
+            start
+        } else {
+            (address - 1) as u16
+        };
+        memory.watch(start, end);
+        for page in (start >> 8)..=(end >> 8) {
+            self.pages[page as usize].push(start);
+        }
+        self.decoded += 1;
+        Box::new(Block { end, ops })
+    }
+
+    fn run(
+        &mut self,
+        s: &mut State,
+        m: &mut M,
+        budget: Option<usize>,
+        mut predicate: impl FnMut(&State) -> bool,
+    ) -> RunResult {
+        if s.rom_limit != self.rom_limit {
+            self.clear();
+            self.rom_limit = s.rom_limit;
+        }
+        let budget = budget.unwrap_or(usize::MAX);
+        let mut cycles = 0;
+
+        loop {
+            if stays_halted(s) {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Halted,
+                };
+            }
+            if cycles >= budget {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::BudgetExhausted,
+                };
+            }
+            if cycles > 0 && s.breakpoints.contains(&s.pc) {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Breakpoint(s.pc),
+                };
+            }
+
+            if needs_single_step(s) {
+                self.fallback_steps += 1;
+            } else {
+                self.invalidate(&mut s.memory);
+                let pc = s.pc as usize;
+                if self.blocks[pc].is_none() {
+                    self.blocks[pc] = Some(self.decode(&mut s.memory, s.pc));
+                }
+                let block = self.blocks[pc].as_ref().unwrap();
+
+                if !block.ops.is_empty() {
+                    // The same steps as try_emulate_instruction, less the
+                    // ones needs_single_step rules out.
+                    for op in block.ops.iter() {
+                        s.memory.clear_writes();
+                        let extra = (op.handler)(s, m);
+                        s.call_stack.unwind(s.sp);
+                        s.advance(op.opcode);
+                        let n = op.cycles + extra;
+                        s.cycles += n as u64;
+                        cycles += n;
+
+                        if predicate(s) {
+                            return RunResult {
+                                cycles,
+                                reason: StopReason::Predicate,
+                            };
+                        }
+                        // A write to cached code may have changed the rest
+                        // of this block.
+                        if cycles >= budget || s.memory.has_dirty_pages() {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            match try_emulate_instruction(s, m) {
+                Ok(n) => cycles += n,
+                Err(e) => {
+                    return RunResult {
+                        cycles,
+                        reason: StopReason::Error(e),
+                    }
+                }
+            }
+            if predicate(s) {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Predicate,
+                };
+            }
+        }
+    }
+
+    // Same results as cpu::run_for_cycles.
+    pub fn run_for_cycles(&mut self, s: &mut State, m: &mut M, budget: usize) -> RunResult {
+        self.run(s, m, Some(budget), |_| false)
+    }
+
+    pub fn run_until(
+        &mut self,
+        s: &mut State,
+        m: &mut M,
+        predicate: impl FnMut(&State) -> bool,
+    ) -> RunResult {
+        self.run(s, m, None, predicate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu;
+    use fuzz::Rng;
+    use testing::{state_with_program, TestMachine};
+    use trace::Tracer;
+
+    // Tracing forces single steps, so it's off here.
+    fn untraced(program: Vec<u8>) -> State {
+        let mut state = state_with_program(program);
+        state.tracer = Tracer::disabled();
+        state
+    }
+
+    fn assert_same(plain: &State, cached: &State) {
+        assert_eq!(plain.snapshot(), cached.snapshot());
+        assert_eq!(plain.cc.to_psw(), cached.cc.to_psw());
+        assert_eq!(plain.cycles, cached.cycles);
+        assert_eq!(plain.halted, cached.halted);
+        assert_eq!(plain.int_enable, cached.int_enable);
+        assert!(plain.memory.as_slice() == cached.memory.as_slice());
+    }
+
+    #[test]
+    fn ends_block_test() {
+        let enders: Vec<u8> = (0..=255).filter(|&op| ends_block(op)).collect();
+        assert_eq!(enders.len(), 8 * 4 + 12);
+        assert!(ends_block(0xc3) && ends_block(0xe9) && ends_block(0xff));
+        assert!(!ends_block(0xe3) && !ends_block(0xf9) && !ends_block(0xd3));
+    }
+
+    #[test]
+    fn loop_test() {
+        // LXI H,0100; MVI B,10; loop: MOV A,B; ADD M; MOV M,A; INX H;
+        // DCR B; JNZ loop; HLT
+        let program = vec![
+            0x21, 0x00, 0x01, 0x06, 0x10, 0x78, 0x86, 0x77, 0x23, 0x05, 0xc2, 0x05, 0x00, 0x76,
+        ];
+        let mut plain = untraced(program.clone());
+        let mut cached = untraced(program);
+        let mut cache = BlockCache::new();
+
+        let expected = cpu::run_for_cycles(&mut plain, &mut TestMachine::default(), 10000);
+        let result = cache.run_for_cycles(&mut cached, &mut TestMachine::default(), 10000);
+        assert_eq!(result, expected);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_same(&plain, &cached);
+
+        // The entry block, the loop body and the HLT.
+        assert_eq!(cache.decoded(), 3);
+        assert_eq!(cache.cached_blocks(), 3);
+        assert_eq!(cache.invalidated(), 0);
+        assert_eq!(cache.fallback_steps(), 0);
+    }
+
+    #[test]
+    fn fallback_test() {
+        // State::new keeps trace history, which needs single steps.
+        let mut state = state_with_program(vec![0x00, 0x00, 0x76]);
+        let mut cache = BlockCache::new();
+
+        cache.run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(state.pc, 3);
+        assert_eq!(cache.fallback_steps(), 3);
+        assert_eq!(cache.decoded(), 0);
+    }
+
+    #[test]
+    fn halt_waits_for_interrupt_test() {
+        // EI; HLT, with INR A; HLT at 0008.
+        let mut state = untraced(vec![0xfb, 0x76, 0, 0, 0, 0, 0, 0, 0x3c, 0x76]);
+        let mut cache = BlockCache::new();
+
+        let result = cache.run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+        cpu::request_interrupt(&mut state, 1);
+        let result = cache.run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!((state.a, state.pc), (1, 0x0a));
+    }
+
+    #[test]
+    fn self_modifying_test() {
+        // MVI A,3c; STA 0006; NOP; NOP (becomes INR A); HLT
+        let program = vec![0x3e, 0x3c, 0x32, 0x06, 0x00, 0x00, 0x00, 0x76];
+        let mut state = untraced(program);
+        let mut cache = BlockCache::new();
+
+        let result = cache.run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(state.a, 0x3d);
+        assert_eq!(cache.invalidated(), 1);
+
+        // Outside writes are noticed too.
+        state.halted = false;
+        state.pc = 0x0005;
+        state.memory.set(0x0005, 0x3c);
+        cache.run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(state.a, 0x3f);
+    }
+
+    #[test]
+    fn budget_and_breakpoint_test() {
+        let mut plain = untraced(vec![0x00; 40]);
+        let mut cached = untraced(vec![0x00; 40]);
+        let mut cache = BlockCache::new();
+
+        let expected = cpu::run_for_cycles(&mut plain, &mut TestMachine::default(), 10);
+        assert_eq!(
+            cache.run_for_cycles(&mut cached, &mut TestMachine::default(), 10),
+            expected
+        );
+        assert_same(&plain, &cached);
+
+        plain.breakpoints.insert(20);
+        cached.breakpoints.insert(20);
+        let expected = cpu::run_for_cycles(&mut plain, &mut TestMachine::default(), 1000);
+        assert_eq!(expected.reason, StopReason::Breakpoint(20));
+        assert_eq!(
+            cache.run_for_cycles(&mut cached, &mut TestMachine::default(), 1000),
+            expected
+        );
+        assert_same(&plain, &cached);
+
+        let expected = cpu::run_until(&mut plain, &mut TestMachine::default(), |s| s.pc == 30);
+        assert_eq!(
+            cache.run_until(&mut cached, &mut TestMachine::default(), |s| s.pc == 30),
+            expected
+        );
+        assert_same(&plain, &cached);
+    }
+
+    #[test]
+    fn rom_limit_test() {
+        // JMP 3000
+        let mut state = untraced(vec![0xc3, 0x00, 0x30]);
+        let mut cache = BlockCache::new();
+
+        let result = cache.run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.cycles, 10);
+        assert!(matches!(result.reason, StopReason::Error(_)));
+
+        state.rom_limit = None;
+        let result = cache.run_for_cycles(&mut state, &mut TestMachine::default(), 4);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(state.pc, 0x3001);
+    }
+
+    // Random bytes make for plenty of jumps into the middle of blocks,
+    // stores into code and wild stack pointers.
+    #[test]
+    fn random_program_test() {
+        let mut rng = Rng::new(0x8080);
+        for _ in 0..200 {
+            let program: Vec<u8> = (0..0x400).map(|_| rng.byte()).collect();
+            let mut plain = untraced(program.clone());
+            let mut cached = untraced(program);
+            plain.rom_limit = None;
+            cached.rom_limit = None;
+            let mut cache = BlockCache::new();
+
+            for _ in 0..5 {
+                let expected = cpu::run_for_cycles(&mut plain, &mut TestMachine::default(), 2000);
+                let result = cache.run_for_cycles(&mut cached, &mut TestMachine::default(), 2000);
+                assert_eq!(result, expected);
+                assert_same(&plain, &cached);
+
+                // Take an interrupt out of a halt or in the middle of a run.
+                cpu::request_interrupt(&mut plain, 7);
+                cpu::request_interrupt(&mut cached, 7);
+                plain.int_enable = true;
+                cached.int_enable = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use memory::Memory;
+use symbols::Symbols;
+
+// Programs that switch stacks without ever returning would otherwise grow
+// the shadow stack forever.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+// One entry into a subroutine. The return address was pushed at `slot`,
+// so the frame is live for as long as SP stays at or below it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub call_site: u16,
+    pub target: u16,
+    pub return_address: u16,
+    pub slot: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub address: u16,
+    // How execution left this location, for every frame but the innermost.
+    pub kind: Option<FrameKind>,
+    // Set when the return address on the stack no longer matches the one
+    // pushed, e.g. after XTHL or a POP and PUSH of something else.
+    pub clobbered: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        Default::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Innermost frame last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn enter(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // A RET popping the word at `slot` leaves every frame at or below it,
+    // including ones whose return address was dropped from the stack by
+    // hand.
+    pub fn ret(&mut self, slot: u16) {
+        while self.frames.last().is_some_and(|f| f.slot <= slot) {
+            self.frames.pop();
+        }
+    }
+
+    // Drops frames whose return address is no longer on the stack because
+    // SP moved above it (POP, INX SP, SPHL or LXI SP).
+    pub fn unwind(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|f| f.slot < sp) {
+            self.frames.pop();
+        }
+    }
+
+    // Where execution is now, then where each caller will resume.
+    pub fn backtrace(&self, pc: u16, memory: &Memory) -> Vec<Location> {
+        let mut locations = vec![Location {
+            address: pc,
+            kind: None,
+            clobbered: None,
+        }];
+        for frame in self.frames.iter().rev() {
+            let on_stack = u16::from(memory.get(frame.slot.wrapping_add(1))) << 8
+                | u16::from(memory.get(frame.slot));
+            locations.push(Location {
+                address: frame.return_address,
+                kind: Some(frame.kind),
+                clobbered: if on_stack == frame.return_address {
+                    None
+                } else {
+                    Some(on_stack)
+                },
+            });
+        }
+        locations
+    }
+}
+
+// `#1  0006  Start+0x6`, with a note for interrupted code and modified
+// return addresses.
+pub fn format_backtrace(locations: &[Location], symbols: &Symbols) -> Vec<String> {
+    locations
+        .iter()
+        .enumerate()
+        .map(|(index, location)| {
+            let mut line = format!("#{:<2} {:04x}", index, location.address);
+            if symbols.lookup(location.address).is_some() {
+                line.push_str(&format!("  {}", symbols.format(location.address)));
+            }
+            if location.kind == Some(FrameKind::Interrupt) {
+                line.push_str("  [interrupted]");
+            }
+            if let Some(address) = location.clobbered {
+                line.push_str(&format!("  [return address now {:04x}]", address));
+            }
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: FrameKind, return_address: u16, slot: u16) -> Frame {
+        Frame {
+            kind,
+            call_site: return_address.wrapping_sub(3),
+            target: 0,
+            return_address,
+            slot,
+        }
+    }
+
+    #[test]
+    fn ret_and_unwind_test() {
+        let mut stack = CallStack::new();
+        stack.enter(frame(FrameKind::Call, 0x0106, 0x23fe));
+        stack.enter(frame(FrameKind::Call, 0x0206, 0x23fa));
+        stack.enter(frame(FrameKind::Rst, 0x0301, 0x23f8));
+
+        // Popping the return address of the middle frame by hand and then
+        // returning from the outer one.
+        stack.unwind(0x23f8);
+        assert_eq!(stack.depth(), 3);
+        stack.unwind(0x23fa);
+        assert_eq!(stack.depth(), 2);
+        stack.ret(0x23fe);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn max_depth_test() {
+        let mut stack = CallStack::new();
+        for i in 0..MAX_DEPTH as u16 + 10 {
+            stack.enter(frame(FrameKind::Call, i, 0xfffe - 2 * i));
+        }
+        assert_eq!(stack.depth(), MAX_DEPTH);
+        assert_eq!(stack.frames()[0].return_address, 10);
+    }
+
+    #[test]
+    fn backtrace_test() {
+        let mut memory = Memory::new();
+        memory.load(0x23fa, vec![0x38, 0x00, 0xff, 0xff, 0x06, 0x01]);
+
+        let mut stack = CallStack::new();
+        stack.enter(frame(FrameKind::Call, 0x0106, 0x23fe));
+        stack.enter(frame(FrameKind::Call, 0x0206, 0x23fc));
+        stack.enter(frame(FrameKind::Interrupt, 0x0038, 0x23fa));
+
+        let locations = stack.backtrace(0x0010, &memory);
+        assert_eq!(locations.len(), 4);
+        assert_eq!(locations[2].clobbered, Some(0xffff));
+
+        let mut symbols = Symbols::new();
+        symbols.insert(0x0100, "Start");
+        assert_eq!(
+            format_backtrace(&locations, &symbols),
+            vec![
+                "#0  0010",
+                "#1  0038  [interrupted]",
+                "#2  0206  Start+0x106  [return address now ffff]",
+                "#3  0106  Start+0x6",
+            ]
+        );
+    }
+}
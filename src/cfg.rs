@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::Write;
+
+use bytes::assemble_word;
+use disasm::{disassemble_with_symbols, format_listing_line};
+use memory::Memory;
+use state::instruction_length;
+use symbols::Symbols;
+
+// Jump tables longer than this are more likely to be a misread.
+const MAX_TABLE_ENTRIES: usize = 64;
+
+// Reset and the eight RST vectors.
+pub fn default_entries() -> Vec<u16> {
+    (0..8).map(|n| 0x08 * n).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    // The taken side of a conditional jump.
+    Branch,
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    Next,
+    Jump(u16),
+    Branch(u16),
+    Call(u16),
+    Return,
+    ConditionalReturn,
+    Computed,
+    Halt,
+}
+
+fn flow(memory: &Memory, address: u16) -> Flow {
+    let opcode = memory.get(address);
+    let target = || {
+        assemble_word(
+            memory.get(address.wrapping_add(2)),
+            memory.get(address.wrapping_add(1)),
+        )
+    };
+    match opcode {
+        0x76 => Flow::Halt,
+        0xc3 | 0xcb => Flow::Jump(target()),
+        0xc9 | 0xd9 => Flow::Return,
+        0xcd | 0xdd | 0xed | 0xfd => Flow::Call(target()),
+        0xe9 => Flow::Computed,
+        _ => match opcode & 0xc7 {
+            0xc0 => Flow::ConditionalReturn,
+            0xc2 => Flow::Branch(target()),
+            0xc4 => Flow::Call(target()),
+            0xc7 => Flow::Call(u16::from(opcode & 0x38)),
+            _ => Flow::Next,
+        },
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub instructions: Vec<u16>,
+    pub successors: Vec<(u16, EdgeKind)>,
+    // Ends in PCHL, so the successors are unknown.
+    pub computed: bool,
+}
+
+impl Block {
+    pub fn last(&self) -> u16 {
+        *self.instructions.last().unwrap()
+    }
+}
+
+// Words read from a table whose address is loaded just before a PCHL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpTable {
+    pub jump: u16,
+    pub base: u16,
+    pub entries: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,
+    // Inclusive ranges inside the region that no path reaches.
+    pub data: Vec<(u16, u16)>,
+    // Targets outside the region, e.g. code copied to RAM.
+    pub external: BTreeSet<u16>,
+    // Instructions that start inside another instruction.
+    pub overlaps: BTreeSet<u16>,
+    pub tables: Vec<JumpTable>,
+}
+
+impl Cfg {
+    // Recursively disassembles start..=end from the entry points, following
+    // every jump and call.
+    pub fn build(memory: &Memory, entries: &[u16], start: u16, end: u16) -> Cfg {
+        let mut cfg = Cfg::default();
+        let in_region = |address: u16| address >= start && address <= end;
+
+        let mut lengths: BTreeMap<u16, u16> = BTreeMap::new();
+        let mut leaders: BTreeSet<u16> = BTreeSet::new();
+        let mut work: Vec<u16> = entries.iter().cloned().filter(|&a| in_region(a)).collect();
+        leaders.extend(work.iter().cloned());
+
+        while let Some(mut address) = work.pop() {
+            while in_region(address) && !lengths.contains_key(&address) {
+                let length = instruction_length(memory.get(address));
+                if (address as usize + length as usize - 1) > end as usize {
+                    break;
+                }
+                lengths.insert(address, length);
+                let next = address.wrapping_add(length);
+
+                let mut target = |target: u16| {
+                    if in_region(target) {
+                        leaders.insert(target);
+                        work.push(target);
+                    } else {
+                        cfg.external.insert(target);
+                    }
+                };
+                match flow(memory, address) {
+                    Flow::Next => (),
+                    Flow::Jump(t) => {
+                        target(t);
+                        break;
+                    }
+                    Flow::Branch(t) | Flow::Call(t) => {
+                        target(t);
+                        leaders.insert(next);
+                    }
+                    Flow::ConditionalReturn | Flow::Halt => {
+                        leaders.insert(next);
+                    }
+                    Flow::Return | Flow::Computed => break,
+                }
+                if next < address {
+                    break;
+                }
+                address = next;
+            }
+        }
+
+        let mut covered_to = 0;
+        for (&address, &length) in lengths.iter() {
+            if (address as usize) < covered_to {
+                cfg.overlaps.insert(address);
+            }
+            covered_to = covered_to.max(address as usize + length as usize);
+        }
+
+        cfg.split_blocks(memory, &lengths, &leaders);
+        cfg.find_data(&lengths, start, end);
+        cfg.find_tables(memory, &lengths, start, end);
+        cfg
+    }
+
+    fn split_blocks(
+        &mut self,
+        memory: &Memory,
+        lengths: &BTreeMap<u16, u16>,
+        leaders: &BTreeSet<u16>,
+    ) {
+        let mut current: Option<Block> = None;
+        for (&address, &length) in lengths.iter() {
+            let mut block = match current.take() {
+                Some(block) if !leaders.contains(&address) => block,
+                previous => {
+                    if let Some(previous) = previous {
+                        self.finish(previous, address, memory);
+                    }
+                    Block {
+                        start: address,
+                        instructions: Vec::new(),
+                        successors: Vec::new(),
+                        computed: false,
+                    }
+                }
+            };
+            block.instructions.push(address);
+
+            let next = address.wrapping_add(length);
+            let ends = flow(memory, address) != Flow::Next || !lengths.contains_key(&next);
+            if ends {
+                self.finish(block, next, memory);
+            } else {
+                current = Some(block);
+            }
+        }
+        if let Some(block) = current {
+            let next = block.last().wrapping_add(lengths[&block.last()]);
+            self.finish(block, next, memory);
+        }
+    }
+
+    fn finish(&mut self, mut block: Block, next: u16, memory: &Memory) {
+        let fallthrough = (next, EdgeKind::Fallthrough);
+        block.successors = match flow(memory, block.last()) {
+            Flow::Next | Flow::Halt | Flow::ConditionalReturn => vec![fallthrough],
+            Flow::Jump(t) => vec![(t, EdgeKind::Jump)],
+            Flow::Branch(t) => vec![(t, EdgeKind::Branch), fallthrough],
+            Flow::Call(t) => vec![(t, EdgeKind::Call), fallthrough],
+            Flow::Return => vec![],
+            Flow::Computed => {
+                block.computed = true;
+                vec![]
+            }
+        };
+        self.blocks.insert(block.start, block);
+    }
+
+    fn find_data(&mut self, lengths: &BTreeMap<u16, u16>, start: u16, end: u16) {
+        let mut covered = vec![false; end as usize - start as usize + 1];
+        for (&address, &length) in lengths.iter() {
+            for a in address as usize..address as usize + length as usize {
+                covered[a - start as usize] = true;
+            }
+        }
+
+        let mut run_start = None;
+        for (offset, &code) in covered.iter().enumerate() {
+            let address = (start as usize + offset) as u16;
+            match (code, run_start) {
+                (false, None) => run_start = Some(address),
+                (true, Some(first)) => {
+                    self.data.push((first, address - 1));
+                    run_start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(first) = run_start {
+            self.data.push((first, end));
+        }
+    }
+
+    // A PCHL block that loads an address with LXI is probably indexing a
+    // table of code addresses there. Entries are read until one points
+    // outside the region or the table runs into code.
+    fn find_tables(&mut self, memory: &Memory, lengths: &BTreeMap<u16, u16>, start: u16, end: u16) {
+        let mut tables = Vec::new();
+        for block in self.blocks.values().filter(|b| b.computed) {
+            for &address in block.instructions.iter() {
+                if !matches!(memory.get(address), 0x01 | 0x11 | 0x21) {
+                    continue;
+                }
+                let base = assemble_word(
+                    memory.get(address.wrapping_add(2)),
+                    memory.get(address.wrapping_add(1)),
+                );
+                let mut entries = Vec::new();
+                let mut entry = base;
+                while entries.len() < MAX_TABLE_ENTRIES
+                    && entry >= start
+                    && entry < end
+                    && !lengths.contains_key(&entry)
+                {
+                    let target = assemble_word(memory.get(entry + 1), memory.get(entry));
+                    if target < start || target > end {
+                        break;
+                    }
+                    entries.push(target);
+                    entry = match entry.checked_add(2) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+                if entries.len() >= 2 {
+                    tables.push(JumpTable {
+                        jump: block.last(),
+                        base,
+                        entries,
+                    });
+                }
+            }
+        }
+        self.tables = tables;
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.blocks.values().map(|b| b.instructions.len()).sum()
+    }
+
+    pub fn computed_jumps(&self) -> Vec<u16> {
+        self.blocks
+            .values()
+            .filter(|b| b.computed)
+            .map(|b| b.last())
+            .collect()
+    }
+
+    pub fn write_summary(&self, out: &mut dyn Write, symbols: &Symbols) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} blocks, {} instructions",
+            self.blocks.len(),
+            self.instruction_count()
+        )?;
+        for &(first, last) in self.data.iter() {
+            writeln!(
+                out,
+                "data {:04x}-{:04x} ({} bytes)",
+                first,
+                last,
+                last - first + 1
+            )?;
+        }
+        for address in self.computed_jumps() {
+            writeln!(out, "computed jump at {}", describe(address, symbols))?;
+        }
+        for table in self.tables.iter() {
+            writeln!(
+                out,
+                "possible jump table at {:04x} for {:04x}: {} entries",
+                table.base,
+                table.jump,
+                table.entries.len()
+            )?;
+        }
+        for &address in self.overlaps.iter() {
+            writeln!(out, "overlapping instruction at {:04x}", address)?;
+        }
+        for &address in self.external.iter() {
+            writeln!(out, "external target {:04x}", address)?;
+        }
+        Ok(())
+    }
+
+    // Graphviz DOT with one node per block. Calls are dashed, taken
+    // branches green, computed jumps red and jump table guesses dotted.
+    pub fn write_dot(
+        &self,
+        out: &mut dyn Write,
+        memory: &Memory,
+        symbols: &Symbols,
+    ) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if let Some(name) = symbols.name(block.start) {
+                label.push_str(&format!("{}:\\l", escape(name)));
+            }
+            for &address in block.instructions.iter() {
+                let instruction = disassemble_with_symbols(memory, address, symbols);
+                label.push_str(&escape(&format_listing_line(&instruction)));
+                label.push_str("\\l");
+            }
+            let color = if block.computed { ", color=red" } else { "" };
+            writeln!(
+                out,
+                "    b{:04x} [label=\"{}\"{}];",
+                block.start, label, color
+            )?;
+        }
+
+        for &address in self.external.iter() {
+            writeln!(
+                out,
+                "    b{:04x} [label=\"{}\", shape=ellipse, style=dashed];",
+                address,
+                escape(&symbols.format(address))
+            )?;
+        }
+
+        for block in self.blocks.values() {
+            for &(target, kind) in block.successors.iter() {
+                let style = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [style=bold]",
+                    EdgeKind::Branch => " [color=green]",
+                    EdgeKind::Call => " [style=dashed]",
+                };
+                writeln!(out, "    b{:04x} -> b{:04x}{};", block.start, target, style)?;
+            }
+        }
+        for table in self.tables.iter() {
+            let from = self
+                .blocks
+                .values()
+                .find(|b| b.last() == table.jump)
+                .map(|b| b.start)
+                .unwrap_or(table.jump);
+            for &entry in table.entries.iter() {
+                if self.blocks.contains_key(&entry) {
+                    writeln!(out, "    b{:04x} -> b{:04x} [style=dotted];", from, entry)?;
+                }
+            }
+        }
+
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
+
+fn describe(address: u16, symbols: &Symbols) -> String {
+    match symbols.lookup(address) {
+        Some(_) => format!("{:04x} <{}>", address, symbols.format(address)),
+        None => format!("{:04x}", address),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_with(program: Vec<u8>) -> Memory {
+        let mut memory = Memory::new();
+        memory.load(0, program);
+        memory
+    }
+
+    // 0000: MVI B,03
+    // 0002: DCR B
+    // 0003: JNZ 0002
+    // 0006: CALL 000c
+    // 0009: JMP 0006
+    // 000c: RET
+    // 000d: "HI" (data)
+    fn sample() -> Memory {
+        memory_with(vec![
+            0x06, 0x03, 0x05, 0xc2, 0x02, 0x00, 0xcd, 0x0c, 0x00, 0xc3, 0x06, 0x00, 0xc9, b'H',
+            b'I',
+        ])
+    }
+
+    #[test]
+    fn blocks_test() {
+        let cfg = Cfg::build(&sample(), &[0], 0, 0x0e);
+
+        let starts: Vec<u16> = cfg.blocks.keys().cloned().collect();
+        assert_eq!(starts, vec![0x00, 0x02, 0x06, 0x09, 0x0c]);
+        assert_eq!(cfg.instruction_count(), 6);
+        assert_eq!(
+            cfg.blocks[&0x02].successors,
+            vec![(0x02, EdgeKind::Branch), (0x06, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(
+            cfg.blocks[&0x06].successors,
+            vec![(0x0c, EdgeKind::Call), (0x09, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(cfg.blocks[&0x09].successors, vec![(0x06, EdgeKind::Jump)]);
+        assert_eq!(cfg.blocks[&0x0c].successors, vec![]);
+        assert_eq!(cfg.data, vec![(0x0d, 0x0e)]);
+    }
+
+    #[test]
+    fn external_and_overlap_test() {
+        // 0000: JZ 2000; JMP 0004 (into the middle of itself)
+        let memory = memory_with(vec![0xca, 0x00, 0x20, 0xc3, 0x04, 0x00]);
+        let cfg = Cfg::build(&memory, &[0], 0, 0x1fff);
+
+        assert!(cfg.external.contains(&0x2000));
+        assert!(cfg.overlaps.contains(&0x0004));
+    }
+
+    #[test]
+    fn jump_table_test() {
+        // 0000: LXI H,0010; MOV E,A; MVI D,0; DAD D; DAD D; MOV A,M; INX H;
+        //       MOV H,M; MOV L,A; PCHL
+        // 0010: table of 0020, 0021
+        let mut program = vec![
+            0x21, 0x10, 0x00, 0x5f, 0x16, 0x00, 0x19, 0x19, 0x7e, 0x23, 0x66, 0x6f, 0xe9,
+        ];
+        program.resize(0x10, 0);
+        program.extend_from_slice(&[0x20, 0x00, 0x21, 0x00]);
+        program.resize(0x20, 0xff);
+        program.extend_from_slice(&[0x76, 0xc9]);
+        let memory = memory_with(program);
+        let cfg = Cfg::build(&memory, &[0], 0, 0x21);
+
+        assert_eq!(cfg.computed_jumps(), vec![0x000c]);
+        assert_eq!(
+            cfg.tables,
+            vec![JumpTable {
+                jump: 0x000c,
+                base: 0x0010,
+                entries: vec![0x0020, 0x0021],
+            }]
+        );
+    }
+
+    #[test]
+    fn jump_table_at_top_test() {
+        // As above, but with LXI H,fff8 and the table running to the very
+        // end of memory.
+        let mut program = vec![
+            0x21, 0xf8, 0xff, 0x5f, 0x16, 0x00, 0x19, 0x19, 0x7e, 0x23, 0x66, 0x6f, 0xe9,
+        ];
+        program.resize(0x20, 0xff);
+        program.extend_from_slice(&[0x76, 0xc9]);
+        let mut memory = memory_with(program);
+        for address in 0xfff8..=0xffff {
+            memory.set(address, if address % 2 == 0 { 0x20 } else { 0x00 });
+        }
+        let cfg = Cfg::build(&memory, &[0], 0, 0xffff);
+
+        assert_eq!(cfg.tables.len(), 1);
+        assert_eq!(cfg.tables[0].base, 0xfff8);
+        assert_eq!(cfg.tables[0].entries, vec![0x0020; 4]);
+    }
+
+    #[test]
+    fn rst_and_default_entries_test() {
+        // RST 1 from 0000 reaches 0008 even without listing it as an entry.
+        let memory = memory_with(vec![0xcf, 0x76, 0, 0, 0, 0, 0, 0, 0xc9]);
+        let cfg = Cfg::build(&memory, &[0], 0, 8);
+        assert_eq!(cfg.blocks[&0].successors[0], (0x08, EdgeKind::Call));
+        assert!(cfg.blocks.contains_key(&0x08));
+
+        assert_eq!(default_entries(), vec![0, 8, 16, 24, 32, 40, 48, 56]);
+    }
+
+    #[test]
+    fn dot_test() {
+        let memory = sample();
+        let cfg = Cfg::build(&memory, &[0], 0, 0x0e);
+        let mut symbols = Symbols::new();
+        symbols.insert(0x0c, "Sub");
+
+        let mut out = Vec::new();
+        cfg.write_dot(&mut out, &memory, &symbols).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("    b000c [label=\"Sub:\\l000c  c9        RET\\l\"];\n"));
+        assert!(dot.contains("    b0006 -> b000c [style=dashed];\n"));
+        assert!(dot.contains("    b0002 -> b0002 [color=green];\n"));
+        assert!(dot.ends_with("}\n"));
+
+        let mut out = Vec::new();
+        cfg.write_summary(&mut out, &symbols).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "5 blocks, 6 instructions\ndata 000d-000e (2 bytes)\n"
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+
+use disasm::{disassemble_with_symbols, format_listing_line};
+use memory::Memory;
+use symbols::Symbols;
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+// Jcc, Ccc and Rcc; the unconditional forms go through the same code but
+// have only one outcome.
+pub fn is_conditional(opcode: u8) -> bool {
+    matches!(opcode & 0xc7, 0xc0 | 0xc2 | 0xc4)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+// Which instructions ran, how often, and which way each conditional branch
+// went.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    counts: Vec<u64>,
+    branches: BTreeMap<u16, Branch>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            counts: vec![0; ADDRESS_SPACE],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, pc: u16) {
+        self.counts[pc as usize] += 1;
+    }
+
+    pub fn record_branch(&mut self, pc: u16, taken: bool) {
+        let branch = self.branches.entry(pc).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts[address as usize]
+    }
+
+    pub fn branch(&self, address: u16) -> Option<Branch> {
+        self.branches.get(&address).cloned()
+    }
+
+    pub fn executed(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..ADDRESS_SPACE)
+            .filter(move |&a| self.counts[a] > 0)
+            .map(|a| a as u16)
+    }
+
+    // The lowest and highest addresses that ran.
+    pub fn span(&self) -> Option<(u16, u16)> {
+        let first = self.executed().next()?;
+        let last = (0..ADDRESS_SPACE).rev().find(|&a| self.counts[a] > 0)? as u16;
+        Some((first, last))
+    }
+
+    // A gcov-style listing of start..=end: execution counts, `#####` for
+    // instructions that never ran and branch outcomes, plus the line each
+    // instruction landed on for LCOV. Disassembly resynchronizes at every
+    // executed address, so data mixed in with code only garbles itself.
+    pub fn annotate(
+        &self,
+        memory: &Memory,
+        start: u16,
+        end: u16,
+        symbols: &Symbols,
+    ) -> (Vec<String>, Vec<(u16, usize)>) {
+        let mut lines = Vec::new();
+        let mut addresses = Vec::new();
+        let mut address = start as usize;
+
+        while address <= end as usize {
+            let instruction = disassemble_with_symbols(memory, address as u16, symbols);
+            if let Some(name) = symbols.name(address as u16) {
+                lines.push(format!("{:>10}  {}:", "", name));
+            }
+
+            let count = match self.count(address as u16) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            let mut line = format!("{:>9}:  {}", count, format_listing_line(&instruction));
+            if let Some(branch) = self.branch(address as u16) {
+                line = format!(
+                    "{:<44}; taken {}, not taken {}",
+                    line, branch.taken, branch.not_taken
+                );
+            }
+            lines.push(line);
+            addresses.push((address as u16, lines.len()));
+
+            let next = address + instruction.bytes.len();
+            address = (address + 1..next.min(end as usize + 1))
+                .find(|&a| self.counts[a] > 0)
+                .unwrap_or(next);
+        }
+        (lines, addresses)
+    }
+
+    pub fn write_listing(
+        &self,
+        out: &mut dyn Write,
+        memory: &Memory,
+        start: u16,
+        end: u16,
+        symbols: &Symbols,
+    ) -> io::Result<()> {
+        for line in self.annotate(memory, start, end, symbols).0 {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // One LCOV record for `source`, where `lines` says which line of it
+    // each instruction is on. Symbols become functions at the line of
+    // their address. Conditional branches are found from the opcodes in
+    // `memory`, so ones that never ran are reported too.
+    pub fn write_lcov(
+        &self,
+        out: &mut dyn Write,
+        memory: &Memory,
+        source: &str,
+        lines: &[(u16, usize)],
+        symbols: &Symbols,
+    ) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", source)?;
+
+        let functions: Vec<(&str, usize, u64)> = lines
+            .iter()
+            .filter_map(|&(address, line)| {
+                symbols
+                    .name(address)
+                    .map(|name| (name, line, self.count(address)))
+            })
+            .collect();
+        for &(name, line, _) in functions.iter() {
+            writeln!(out, "FN:{},{}", line, name)?;
+        }
+        for &(name, _, count) in functions.iter() {
+            writeln!(out, "FNDA:{},{}", count, name)?;
+        }
+        writeln!(out, "FNF:{}", functions.len())?;
+        writeln!(out, "FNH:{}", functions.iter().filter(|f| f.2 > 0).count())?;
+
+        let (mut found, mut hit) = (0, 0);
+        for &(address, line) in lines.iter() {
+            if !is_conditional(memory.get(address)) {
+                continue;
+            }
+            let branch = self.branch(address).unwrap_or_default();
+            let outcome = |n: u64| {
+                if self.count(address) == 0 {
+                    "-".to_string()
+                } else {
+                    n.to_string()
+                }
+            };
+            writeln!(out, "BRDA:{},0,0,{}", line, outcome(branch.taken))?;
+            writeln!(out, "BRDA:{},0,1,{}", line, outcome(branch.not_taken))?;
+            found += 2;
+            hit += (branch.taken > 0) as usize + (branch.not_taken > 0) as usize;
+        }
+        writeln!(out, "BRF:{}", found)?;
+        writeln!(out, "BRH:{}", hit)?;
+
+        for &(address, line) in lines.iter() {
+            writeln!(out, "DA:{},{}", line, self.count(address))?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(
+            out,
+            "LH:{}",
+            lines.iter().filter(|&&(a, _)| self.count(a) > 0).count()
+        )?;
+        writeln!(out, "end_of_record")?;
+        Ok(())
+    }
+}
+
+fn is_hex(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// The address of each instruction in an assembler listing and the line it
+// is on. Most 8080 assemblers print an optional line number, a four digit
+// address and the assembled bytes before the source text; lines without
+// bytes (comments, labels, EQUs) are skipped.
+pub fn parse_listing(text: &str) -> Vec<(u16, usize)> {
+    let mut lines: BTreeMap<u16, usize> = BTreeMap::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace().peekable();
+        if words.peek().is_some_and(|w| {
+            w.len() != 4 && w.trim_end_matches('+').chars().all(|c| c.is_ascii_digit())
+        }) {
+            words.next();
+        }
+        let address = match words.next() {
+            Some(word) if word.len() == 4 && is_hex(word) => word,
+            _ => continue,
+        };
+        match words.next() {
+            Some(bytes) if bytes.len() % 2 == 0 && bytes.len() <= 8 && is_hex(bytes) => (),
+            _ => continue,
+        }
+        let address = u16::from_str_radix(address, 16).unwrap();
+        lines.entry(address).or_insert(index + 1);
+    }
+
+    lines.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0100: MVI B,02
+    // 0102: DCR B
+    // 0103: JNZ 0102
+    // 0106: RZ
+    // 0107: HLT
+    fn sample() -> (Memory, Coverage) {
+        let mut memory = Memory::new();
+        memory.load(0x100, vec![0x06, 0x02, 0x05, 0xc2, 0x02, 0x01, 0xc8, 0x76]);
+
+        let mut coverage = Coverage::new();
+        for &pc in [0x100, 0x102, 0x103, 0x102, 0x103, 0x106].iter() {
+            coverage.record(pc);
+        }
+        coverage.record_branch(0x103, true);
+        coverage.record_branch(0x103, false);
+        coverage.record_branch(0x106, true);
+        (memory, coverage)
+    }
+
+    #[test]
+    fn conditional_test() {
+        assert!(is_conditional(0xc2));
+        assert!(is_conditional(0xdc));
+        assert!(is_conditional(0xf8));
+        assert!(!is_conditional(0xc3));
+        assert!(!is_conditional(0xcd));
+        assert!(!is_conditional(0xc9));
+    }
+
+    #[test]
+    fn record_test() {
+        let (_, coverage) = sample();
+
+        assert_eq!(coverage.count(0x102), 2);
+        assert_eq!(coverage.count(0x107), 0);
+        assert_eq!(coverage.span(), Some((0x100, 0x106)));
+        assert_eq!(
+            coverage.branch(0x103),
+            Some(Branch {
+                taken: 1,
+                not_taken: 1,
+            })
+        );
+        assert_eq!(Coverage::new().span(), None);
+    }
+
+    #[test]
+    fn annotate_test() {
+        let (memory, coverage) = sample();
+        let mut symbols = Symbols::new();
+        symbols.insert(0x102, "Loop");
+
+        let (lines, addresses) = coverage.annotate(&memory, 0x100, 0x107, &symbols);
+        assert_eq!(
+            lines,
+            vec![
+                "        1:  0100  06 02     MVI B,$02",
+                "            Loop:",
+                "        2:  0102  05        DCR B",
+                "        2:  0103  c2 02 01  JNZ Loop        ; taken 1, not taken 1",
+                "        1:  0106  c8        RZ              ; taken 1, not taken 0",
+                "    #####:  0107  76        HLT",
+            ]
+        );
+        assert_eq!(
+            addresses,
+            vec![(0x100, 1), (0x102, 3), (0x103, 4), (0x106, 5), (0x107, 6)]
+        );
+    }
+
+    #[test]
+    fn resynchronize_test() {
+        let mut memory = Memory::new();
+        // A stray LXI opcode in front of code that was jumped into.
+        memory.load(0, vec![0x01, 0x3e, 0x05, 0x76]);
+        let mut coverage = Coverage::new();
+        coverage.record(1);
+        coverage.record(3);
+
+        let (_, addresses) = coverage.annotate(&memory, 0, 3, &Symbols::new());
+        assert_eq!(addresses, vec![(0, 1), (1, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn lcov_test() {
+        let (memory, coverage) = sample();
+        let mut symbols = Symbols::new();
+        symbols.insert(0x100, "Start");
+        let (_, lines) = coverage.annotate(&memory, 0x100, 0x107, &symbols);
+
+        let mut out = Vec::new();
+        coverage
+            .write_lcov(&mut out, &memory, "program.cov", &lines, &symbols)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "TN:\nSF:program.cov\nFN:2,Start\nFNDA:1,Start\nFNF:1\nFNH:1\n\
+             BRDA:4,0,0,1\nBRDA:4,0,1,1\nBRDA:5,0,0,1\nBRDA:5,0,1,0\nBRF:4\nBRH:3\n\
+             DA:2,1\nDA:3,2\nDA:4,2\nDA:5,1\nDA:6,0\nLF:5\nLH:4\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn lcov_unexecuted_branch_test() {
+        let (mut memory, coverage) = sample();
+        // A CNZ in place of the HLT, which never ran.
+        memory.load(0x107, vec![0xc4, 0x00, 0x01]);
+        let lines = [(0x103, 1), (0x106, 2), (0x107, 3)];
+
+        let mut out = Vec::new();
+        coverage
+            .write_lcov(&mut out, &memory, "program.cov", &lines, &Symbols::new())
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("BRDA:3,0,0,-\nBRDA:3,0,1,-\nBRF:6\nBRH:3\n"));
+    }
+
+    #[test]
+    fn parse_listing_test() {
+        let text = "\
+                        ; test program
+   1 0000                       ORG 100H
+   2 0100 0602          START:  MVI B,2
+   3 0102               LOOP:
+   4 0102 05                    DCR B
+   5 0103 C20201                JNZ LOOP
+0106 C8                         RZ
+   7 0005 =             BDOS    EQU 5
+";
+        assert_eq!(
+            parse_listing(text),
+            vec![(0x100, 3), (0x102, 5), (0x103, 6), (0x106, 7)]
+        );
+    }
+}
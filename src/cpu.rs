@@ -1,9 +1,14 @@
-use bytes::*;
-use machine::Machine;
+use std::fmt;
+
+use callstack::FrameKind;
+use dispatch::handler;
+use machine::{Machine, NoDevices};
 use program::Program;
-use stack::Stack;
-use state::State;
+use state::{instruction_length, State};
+use trace::TraceEntry;
 
+// Conditional CALLs and RETs are listed at their not-taken cost; taking the
+// branch adds CONDITIONAL_TAKEN_EXTRA cycles.
 static OPCODE_TIMING: [usize; 256] = [
     4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x00..0x0f
     4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x10..0x1f
@@ -14,442 +19,614 @@ static OPCODE_TIMING: [usize; 256] = [
     7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5, 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7,
     4, //0x80..8x4f
     4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10,
-    10, 17, 7, 11, //0xc0..0xcf
-    11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10, 10, 17, 7, 11, 11, 10, 10, 18, 17, 11, 7, 11,
-    11, 5, 10, 5, 17, 17, 7, 11, 11, 10, 10, 4, 17, 11, 7, 11, 11, 5, 10, 4, 17, 17, 7, 11,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10,
+    11, 17, 7, 11, //0xc0..0xcf
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11, 5, 10, 10, 18, 11, 11, 7, 11, 5, 5,
+    10, 4, 11, 17, 7, 11, 5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
 ];
 
-fn unimplemented_instruction(s: &mut State) {
-    println!(
-        "Error: unimplemented instruction 0x{:02x} at 0x{:04x}",
-        s.get_opcode(),
-        s.pc
-    );
-    println!("{:?}", s);
-    panic!("unimplemented");
+pub const CONDITIONAL_TAKEN_EXTRA: usize = 6;
+
+pub fn opcode_cycles(opcode: u8) -> usize {
+    OPCODE_TIMING[opcode as usize]
 }
 
-pub fn emulate_group0(opcode: u8, s: &mut State) {
-    match opcode & 0x3f {
-        0x00 => (), // NOP
-        0x01 => {
-            // LXI B,word
-            s.set_bc(s.get_arg16());
-        }
-        0x02 => {
-            // STAX B
-            s.memory.set(s.get_bc(), s.a);
-        }
-        0x03 => {
-            // INX B
-            s.set_bc(s.get_bc().wrapping_add(1));
-        }
-        0x04 => {
-            // INR B
-            s.b = s.b.wrapping_add(1);
-            s.set_flags_no_carry(s.b);
-        }
-        0x05 => {
-            // DCR B
-            s.b = s.b.wrapping_sub(1);
-            s.set_flags_no_carry(s.b);
-        }
-        0x06 => {
-            s.b = s.get_arg8();
-        } // MVI B,byte
-        0x07 => {
-            // RLC
-            let x = s.a;
-            s.a = x.rotate_left(1);
-            s.cc.cy = (x & 0x80) != 0;
-        }
-        0x08 => (), // NOP
-        0x09 => {
-            // DAD B
-            s.add16(s.get_bc());
-        }
-        0x0a => {
-            // LDAX B
-            s.a = s.memory.get(s.get_bc());
-        }
-        0x0b => {
-            // DCX B
-            s.set_bc(s.get_bc().wrapping_sub(1));
-        }
-        0x0c => {
-            // INR C
-            s.c = s.c.wrapping_add(1);
-            s.set_flags_no_carry(s.c);
-        }
-        0x0d => {
-            // DCR C
-            s.c = s.c.wrapping_sub(1);
-            s.set_flags_no_carry(s.c);
-        }
-        0x0e => {
-            s.c = s.get_arg8();
-        } // MVI C,byte
-        0x0f => {
-            // RRC
-            let x = s.a;
-            s.a = x.rotate_right(1);
-            s.cc.cy = (x & 0x01) == 1;
-        }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmulationError {
+    PcOutOfRom(u16),
+}
 
-        0x10 => (), // NOP
-        0x11 => {
-            // LXI D,word
-            s.set_de(s.get_arg16());
-        }
-        0x12 => {
-            // STAX D
-            s.memory.set(s.get_de(), s.a);
-        }
-        0x13 => {
-            // INX D
-            s.set_de(s.get_de().wrapping_add(1));
-        }
-        0x14 => {
-            // INR D
-            s.d = s.d.wrapping_add(1);
-            s.set_flags_no_carry(s.d);
-        }
-        0x15 => {
-            // DCR D
-            s.d = s.d.wrapping_sub(1);
-            s.set_flags_no_carry(s.d);
-        }
-        0x16 => {
-            s.d = s.get_arg8();
-        } // MVI D,byte
-        0x17 => {
-            // RAL
-            let x = s.a;
-            s.a = (s.cc.cy as u8) | (x << 1);
-            s.cc.cy = (x & 0x80) != 0;
-        }
-        0x18 => (), // NOP
-        0x19 => {
-            // DAD D
-            s.add16(s.get_de());
-        }
-        0x1a => {
-            // LDAX D
-            s.a = s.memory.get(s.get_de());
-        }
-        0x1b => {
-            // DCX D
-            s.set_de(s.get_de().wrapping_sub(1));
-        }
-        0x1c => {
-            // INR E
-            s.e = s.e.wrapping_add(1);
-            s.set_flags_no_carry(s.e);
-        }
-        0x1d => {
-            // DCR E
-            s.e = s.e.wrapping_sub(1);
-            s.set_flags_no_carry(s.e);
-        }
-        0x1e => {
-            s.e = s.get_arg8();
-        } // MVI E,byte
-        0x1f => {
-            // RAR
-            let x = s.a;
-            s.a = ((s.cc.cy as u8) << 7) | (x >> 1);
-            s.cc.cy = (x & 0x01) == 1;
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulationError::PcOutOfRom(pc) => write!(f, "PC out of ROM at 0x{:04x}", pc),
         }
+    }
+}
 
-        0x20 => (), // NOP
-        0x21 => {
-            // LXI H,word
-            s.set_hl(s.get_arg16());
-        }
-        0x22 => {
-            // SHLD a16
-            let address = s.get_arg16();
-            s.memory.set(address, s.l);
-            s.memory.set(address + 1, s.h);
-        }
-        0x23 => {
-            // INX H
-            s.set_hl(s.get_hl_address().wrapping_add(1));
-        }
-        0x24 => {
-            // INR H
-            s.h = s.h.wrapping_add(1);
-            s.set_flags_no_carry(s.h);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    BudgetExhausted,
+    Breakpoint(u16),
+    Halted,
+    Predicate,
+    Error(EmulationError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunResult {
+    pub cycles: usize,
+    pub reason: StopReason,
+}
+
+// Runs one of the opcodes 00-3f without advancing PC. None of them use the
+// ports, so any machine will do.
+pub fn emulate_group0(opcode: u8, s: &mut State) {
+    handler::<NoDevices>(opcode & 0x3f)(s, &mut NoDevices);
+}
+
+pub fn try_emulate_instruction<M: Machine>(
+    s: &mut State,
+    m: &mut M,
+) -> Result<usize, EmulationError> {
+    s.memory.clear_writes();
+    let pc = s.pc;
+
+    if s.int_enable {
+        if let Some(n) = s.pending_interrupt.take() {
+            trigger_interrupt(s, u16::from(n));
+            check_writes(s, pc);
+            let cycles = OPCODE_TIMING[0xc7];
+            s.cycles += cycles as u64;
+            profile(s, pc, cycles);
+            return Ok(cycles);
         }
-        0x25 => {
-            // DCR H
-            s.h = s.h.wrapping_sub(1);
-            s.set_flags_no_carry(s.h);
+    }
+
+    if s.halted {
+        // The CPU idles until an interrupt arrives.
+        s.cycles += OPCODE_TIMING[0x00] as u64;
+        profile(s, pc, OPCODE_TIMING[0x00]);
+        return Ok(OPCODE_TIMING[0x00]);
+    }
+
+    let opcode = s.get_opcode();
+
+    if s.tracer.is_enabled() {
+        let entry = TraceEntry::capture(s);
+        s.tracer.record(entry);
+    }
+
+    if let Some(limit) = s.rom_limit {
+        if s.pc > limit {
+            return Err(EmulationError::PcOutOfRom(s.pc));
         }
-        0x26 => {
-            s.h = s.get_arg8();
-        } // MVI H,byte
-        0x27 => {
-            // DAA
-            if (s.a & 0x0f) > 9 {
-                s.a += 6;
-            }
-            if (s.a & 0xf0) > 0x90 {
-                s.add8(0x60);
+    }
+
+    if let Some(coverage) = s.coverage.as_mut() {
+        coverage.record(pc);
+    }
+    if let Some(smc) = s.smc.as_mut() {
+        smc.execute(pc, instruction_length(opcode));
+    }
+
+    let extra = handler::<M>(opcode)(s, m);
+
+    s.call_stack.unwind(s.sp);
+    check_writes(s, pc);
+    s.advance(opcode);
+    let cycles = OPCODE_TIMING[opcode as usize] + extra;
+    s.cycles += cycles as u64;
+    profile(s, pc, cycles);
+    Ok(cycles)
+}
+
+// Hooks that want to see every instruction, and the instruction-boundary
+// events that runners of whole blocks skip checking for. Any of these
+// makes them fall back to single steps.
+pub fn needs_single_step(s: &State) -> bool {
+    (s.int_enable && s.pending_interrupt.is_some())
+        || s.tracer.is_enabled()
+        || s.profiler.is_some()
+        || s.coverage.is_some()
+        || s.smc.is_some()
+        || !s.breakpoints.is_empty()
+}
+
+fn check_writes(s: &mut State, pc: u16) {
+    if let Some(smc) = s.smc.as_mut() {
+        smc.written(pc, s.memory.writes());
+    }
+}
+
+fn profile(s: &mut State, pc: u16, cycles: usize) {
+    if let Some(profiler) = s.profiler.as_mut() {
+        profiler.record(pc, cycles, &s.call_stack);
+    }
+}
+
+pub fn emulate_instruction(s: &mut State, m: &mut impl Machine) -> usize {
+    match try_emulate_instruction(s, m) {
+        Ok(cycles) => cycles,
+        Err(e) => {
+            for entry in s.tracer.history() {
+                println!("{}", entry.format(true));
             }
+            panic!("{}", e);
         }
-        0x28 => (), // NOP
-        0x29 => {
-            // DAD H
-            s.add16(s.get_hl_address());
-        }
-        0x2a => {
-            // LHLD a16
-            let address = s.get_arg16();
-            s.l = s.memory.get(address);
-            s.h = s.memory.get(address + 1);
-        }
-        0x2b => {
-            // DCX H
-            s.set_hl(s.get_hl_address().wrapping_sub(1));
-        }
-        0x2c => {
-            // INR L
-            s.l = s.l.wrapping_add(1);
-            s.set_flags_no_carry(s.l);
-        }
-        0x2d => {
-            // DCR L
-            s.l = s.l.wrapping_sub(1);
-            s.set_flags_no_carry(s.l);
-        }
-        0x2e => {
-            s.l = s.get_arg8();
-        } // MVI L,byte
-        0x2f => {
-            s.a = !s.a;
-        } // CMA
-
-        0x30 => (), // NOP
-        0x31 => {
-            // LXI SP,word
-            s.sp = s.get_arg16();
-        }
-        0x32 => {
-            // STA a16
-            s.memory.set(s.get_arg16(), s.a);
-        }
-        0x33 => {
-            s.sp += 1;
-        } // INX SP
-        0x34 => {
-            // INR M
-            let new_value = s.get_m().wrapping_add(1);
-            s.set_flags_no_carry(new_value);
-            s.set_m(new_value);
-        }
-        0x35 => {
-            // DCR M
-            let new_value = s.get_m().wrapping_sub(1);
-            s.set_flags_no_carry(new_value);
-            s.set_m(new_value);
-        }
-        0x36 => {
-            // MVI M,byte
-            s.set_m(s.get_arg8());
-        }
-        0x37 => {
-            s.cc.cy = true;
-        } // STC
-        0x38 => (), // NOP
-        0x39 => {
-            // DAD SP
-            s.add16(s.sp);
-        }
-        0x3a => {
-            // LDA a16
-            s.a = s.memory.get(s.get_arg16());
+    }
+}
+
+fn run(
+    s: &mut State,
+    m: &mut impl Machine,
+    budget: Option<usize>,
+    mut predicate: impl FnMut(&State) -> bool,
+) -> RunResult {
+    let mut cycles = 0;
+
+    loop {
+        if stays_halted(s) {
+            return RunResult {
+                cycles,
+                reason: StopReason::Halted,
+            };
+        }
+        if budget.is_some_and(|budget| cycles >= budget) {
+            return RunResult {
+                cycles,
+                reason: StopReason::BudgetExhausted,
+            };
+        }
+        // A breakpoint at the starting PC is ignored so that a stopped
+        // caller can resume without first stepping past it.
+        if cycles > 0 && s.breakpoints.contains(&s.pc) {
+            return RunResult {
+                cycles,
+                reason: StopReason::Breakpoint(s.pc),
+            };
         }
-        0x3b => {
-            s.sp -= 1;
-        } // DCX SP
-        0x3c => {
-            // INR A
-            s.a = s.a.wrapping_add(1);
-            s.set_flags_no_carry(s.a);
+
+        match try_emulate_instruction(s, m) {
+            Ok(n) => cycles += n,
+            Err(e) => {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Error(e),
+                }
+            }
         }
-        0x3d => {
-            // DCR A
-            s.a = s.a.wrapping_sub(1);
-            s.set_flags_no_carry(s.a);
+
+        if predicate(s) {
+            return RunResult {
+                cycles,
+                reason: StopReason::Predicate,
+            };
         }
-        0x3e => {
-            s.a = s.get_arg(1);
-        } // MVI A,byte
-        0x3f => s.cc.cy = !s.cc.cy, // CMC
-        _ => panic!("Unknown opcode"),
     }
 }
 
-fn emulate_group3(opcode: u8, s: &mut State, m: &mut impl Machine) {
-    match opcode & 0x7 {
-        0x0 => s.ret_if(State::predicate_for(opcode)),
-        0x1 => match (opcode >> 3) & 0x7 {
-            0x0 => {
-                // POP B
-                let val = s.pop16();
-                s.set_bc(val);
-            }
-            0x1 => {
-                // RET
-                s.ret_if(State::unconditionally);
-            }
-            0x2 => {
-                // POP D
-                let new_de = s.pop16();
-                s.set_de(new_de);
-            }
-            0x3 => {
-                // RET
-                s.ret_if(State::unconditionally);
-            }
-            0x4 => {
-                // POP H
-                let new_hl = s.pop16();
-                s.set_hl(new_hl);
-            }
-            0x5 => {
-                // PCHL
-                s.pc = s.get_hl_address();
-                s.jumped = true;
-            }
-            0x6 => {
-                // POP PSW
-                let word = s.pop16();
-                s.a = high_order_byte(word);
-                s.cc.deserialize(low_order_byte(word));
-            }
-            0x7 => {
-                // SPHL
-                s.sp = s.get_hl_address();
-            }
-            _ => panic!("Shouldn't happen"),
-        },
-        0x2 => s.jump_if(State::predicate_for(opcode)),
-        0x3 => match (opcode >> 3) & 0x7 {
-            0x0 => {
-                // JMP a16
-                s.jump_if(State::unconditionally);
-            }
-            0x1 => {
-                // JMP a16
-                s.jump_if(State::unconditionally);
-            }
-            0x2 => {
-                // OUT byte
-                m.output(s.get_arg(1), s.a);
-            }
-            0x3 => {
-                // IN byte
-                s.a = m.input(s.get_arg(1));
-            }
-            0x4 => {
-                // XTHL
-                let new_hl = s.pop16();
-                let old_hl = s.get_hl_address();
-                s.push16(old_hl);
-                s.set_hl(new_hl);
-            }
-            0x5 => {
-                // XCHG
-                std::mem::swap(&mut s.d, &mut s.h);
-                std::mem::swap(&mut s.e, &mut s.l);
-            }
-            0x6 => {
-                // DI
-                s.int_enable = false;
-            }
+pub fn run_for_cycles(s: &mut State, m: &mut impl Machine, budget: usize) -> RunResult {
+    run(s, m, Some(budget), |_| false)
+}
 
-            0x7 => {
-                // EI
-                s.int_enable = true;
-            }
-            _ => panic!("Shouldn't happen"),
-        },
-        0x4 => s.call_if(State::predicate_for(opcode)),
-        0x5 => match (opcode >> 3) & 0x7 {
-            0x0 => {
-                // PUSH B
-                s.push16(s.get_bc());
-            }
-            0x1 => {
-                // CALL a16
-                s.call_if(State::unconditionally);
-            }
-            0x2 => {
-                // PUSH D
-                s.push16(s.get_de());
-            }
-            0x3 => {
-                // CALL a16
-                s.call_if(State::unconditionally);
-            }
-            0x4 => {
-                // PUSH H
-                s.push16(s.get_hl_address());
-            }
-            0x5 => {
-                // CALL a16
-                s.call_if(State::unconditionally);
-            }
-            0x6 => {
-                // PUSH PSW
-                s.push16(assemble_word(s.a, s.cc.serialize()));
-            }
-            0x7 => {
-                // CALL a16
-                s.call_if(State::unconditionally);
+pub fn run_until(
+    s: &mut State,
+    m: &mut impl Machine,
+    predicate: impl FnMut(&State) -> bool,
+) -> RunResult {
+    run(s, m, None, predicate)
+}
+
+// A halted CPU can only be woken by an interrupt it is able to take. Run
+// loops stop on this rather than on `halted`, so that `EI; HLT` waits for
+// a latched interrupt instead of returning Halted with it still pending.
+pub fn stays_halted(s: &State) -> bool {
+    s.halted && !(s.int_enable && s.pending_interrupt.is_some())
+}
+
+// Latches an interrupt to be taken as an RST at the next instruction
+// boundary where interrupts are enabled.
+pub fn request_interrupt(s: &mut State, n: u16) {
+    s.pending_interrupt = Some(n as u8);
+}
+
+pub fn trigger_interrupt(s: &mut State, n: u16) {
+    s.enter(FrameKind::Interrupt, 0x08 * n, 0);
+    s.jumped = false;
+    s.int_enable = false;
+    s.halted = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coverage::{Branch, Coverage};
+    use profiler::Profiler;
+    use smc::{SmcDetector, SmcEvent};
+    use stack::Stack;
+    use testing::{state_with_program, TestMachine};
+    use trace::Tracer;
+
+    #[test]
+    fn test_emulate_instruction_cycles() {
+        // MVI A,1; LXI H,1234; NOP
+        let mut state = state_with_program(vec![0x3e, 0x01, 0x21, 0x34, 0x12, 0x00]);
+
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            7
+        );
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            10
+        );
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            4
+        );
+        assert_eq!(state.cycles, 21);
+        assert_eq!(state.pc, 6);
+    }
+
+    #[test]
+    fn emulate_group0_test() {
+        // LXI B,1234
+        let mut state = state_with_program(vec![0x01, 0x34, 0x12]);
+        emulate_group0(0x01, &mut state);
+        assert_eq!((state.get_bc(), state.pc), (0x1234, 0));
+        // Only the low six bits pick the instruction: INR B.
+        emulate_group0(0x44, &mut state);
+        assert_eq!((state.b, state.pc), (0x13, 0));
+    }
+
+    #[test]
+    fn test_run_for_cycles_budget() {
+        // Ten NOPs.
+        let mut state = state_with_program(vec![0x00; 10]);
+
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 10);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(result.cycles, 12);
+        assert_eq!(state.pc, 3);
+
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 0);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(result.cycles, 0);
+        assert_eq!(state.pc, 3);
+    }
+
+    #[test]
+    fn test_run_for_cycles_halt() {
+        // NOP; HLT; NOP
+        let mut state = state_with_program(vec![0x00, 0x76, 0x00]);
+
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(result.cycles, 11);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.halted, true);
+
+        trigger_interrupt(&mut state, 1);
+        assert_eq!(state.halted, false);
+        assert_eq!(state.pc, 0x08);
+    }
+
+    #[test]
+    fn test_request_interrupt() {
+        let mut state = state_with_program(vec![0x00; 4]);
+
+        request_interrupt(&mut state, 2);
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            4
+        );
+        assert_eq!(state.pc, 1);
+        assert_eq!(state.pending_interrupt, Some(2));
+
+        state.int_enable = true;
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            11
+        );
+        assert_eq!(state.pc, 0x10);
+        assert_eq!(state.call_stack.frames()[0].kind, FrameKind::Interrupt);
+        assert_eq!(state.pop16(), 1);
+        assert_eq!(state.int_enable, false);
+        assert_eq!(state.pending_interrupt, None);
+    }
+
+    #[test]
+    fn test_halt_waits_for_interrupt() {
+        // EI; HLT, with INR A at 0008.
+        let mut state = state_with_program(vec![0xfb, 0x76, 0, 0, 0, 0, 0, 0, 0x3c]);
+        let mut machine = TestMachine::default();
+
+        let result = run_for_cycles(&mut state, &mut machine, 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(state.pc, 2);
+
+        request_interrupt(&mut state, 1);
+        let result = run_for_cycles(&mut state, &mut machine, 16);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(result.cycles, 16);
+        assert_eq!(state.halted, false);
+        assert_eq!((state.a, state.pc), (1, 0x09));
+        assert_eq!(state.pop16(), 2);
+
+        // With interrupts off, HLT can't be woken.
+        state.pc = 1;
+        state.halted = false;
+        run_for_cycles(&mut state, &mut machine, 1000);
+        request_interrupt(&mut state, 1);
+        let result = run_for_cycles(&mut state, &mut machine, 1000);
+        assert_eq!((result.cycles, result.reason), (0, StopReason::Halted));
+    }
+
+    #[test]
+    fn test_call_stack() {
+        // 0000: CALL 0010; HLT
+        // 0008: RET (RST 1 handler)
+        // 0010: RST 1; XTHL; POP B; RET
+        let mut program = vec![0x00; 0x14];
+        program[0..4].copy_from_slice(&[0xcd, 0x10, 0x00, 0x76]);
+        program[0x08] = 0xc9;
+        program[0x10..0x14].copy_from_slice(&[0xcf, 0xe3, 0xc1, 0xc9]);
+        let mut state = state_with_program(program);
+
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        let kinds: Vec<FrameKind> = state.call_stack.frames().iter().map(|f| f.kind).collect();
+        assert_eq!(kinds, vec![FrameKind::Call, FrameKind::Rst]);
+        assert_eq!(state.call_stack.frames()[1].return_address, 0x0011);
+
+        // RET from RST 1.
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        assert_eq!(state.call_stack.depth(), 1);
+        assert_eq!(state.pc, 0x0011);
+
+        // XTHL swaps the return address out, which the backtrace notices.
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        let backtrace = state.call_stack.backtrace(state.pc, &state.memory);
+        assert_eq!(backtrace[1].address, 0x0003);
+        assert_eq!(backtrace[1].clobbered, Some(0x0000));
+
+        // POP B drops it from the stack entirely.
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        assert_eq!(state.call_stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_profiler() {
+        // 0000: CALL 0010; HLT
+        // 0010: NOP; RET
+        let mut program = vec![0x00; 0x12];
+        program[0..4].copy_from_slice(&[0xcd, 0x10, 0x00, 0x76]);
+        program[0x11] = 0xc9;
+        let mut state = state_with_program(program);
+        state.profiler = Some(Profiler::new(&state.call_stack));
+
+        run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        let profiler = state.profiler.unwrap();
+        assert_eq!(profiler.total_instructions(), 4);
+        assert_eq!(profiler.total_cycles(), state.cycles);
+
+        let functions = profiler.functions();
+        assert_eq!(functions[1].entry, Some(0x0010));
+        assert_eq!(functions[1].calls, 1);
+        assert_eq!(functions[1].self_cycles, 14);
+    }
+
+    #[test]
+    fn test_coverage() {
+        // 0000: MVI B,02; DCR B; JNZ 0002; CZ 0000; HLT
+        let mut state = state_with_program(vec![
+            0x06, 0x02, 0x05, 0xc2, 0x02, 0x00, 0xc4, 0x00, 0x00, 0x76,
+        ]);
+        state.coverage = Some(Coverage::new());
+
+        run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        let coverage = state.coverage.unwrap();
+        assert_eq!(coverage.count(0x0002), 2);
+        assert_eq!(coverage.count(0x0009), 1);
+        assert_eq!(coverage.count(0x0001), 0);
+        assert_eq!(
+            coverage.branch(0x0003),
+            Some(Branch {
+                taken: 1,
+                not_taken: 1,
+            })
+        );
+        assert_eq!(
+            coverage.branch(0x0006),
+            Some(Branch {
+                taken: 0,
+                not_taken: 1,
+            })
+        );
+        assert_eq!(coverage.branch(0x0000), None);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // 0000: MVI A,05; STA 0001; JMP 0000
+        let mut state = state_with_program(vec![0x3e, 0x05, 0x32, 0x01, 0x00, 0xc3, 0x00, 0x00]);
+        state.smc = Some(SmcDetector::new());
+
+        for _ in 0..4 {
+            emulate_instruction(&mut state, &mut TestMachine::default());
+        }
+
+        let smc = state.smc.unwrap();
+        match smc.events() {
+            [SmcEvent::Patched { write, writer }, executed] => {
+                assert_eq!((write.address, write.new, *writer), (0x0001, 0x05, 0x0002));
+                assert_eq!(
+                    *executed,
+                    SmcEvent::ExecutedData {
+                        pc: 0x0000,
+                        address: 0x0001,
+                        writer: 0x0002,
+                    }
+                );
             }
-            _ => panic!("Shouldn't happen"),
-        },
-        0x6 => s.operate8(opcode, s.get_arg8()),
-        0x7 => s.rst_to(u16::from(opcode & 0x38)),
-        _ => panic!("Shouldn't happen"),
+            other => panic!("unexpected {:?}", other),
+        }
     }
-}
 
-pub fn emulate_instruction(s: &mut State, m: &mut impl Machine) -> usize {
-    let opcode = s.get_opcode();
+    #[test]
+    fn test_run_for_cycles_breakpoint() {
+        let mut state = state_with_program(vec![0x00; 10]);
+        state.breakpoints.insert(4);
+
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.reason, StopReason::Breakpoint(4));
+        assert_eq!(result.cycles, 16);
+        assert_eq!(state.pc, 4);
+
+        // Resuming steps over the breakpoint we stopped at.
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 8);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(state.pc, 6);
+    }
+
+    #[test]
+    fn test_run_for_cycles_error() {
+        // JMP 3000
+        let mut state = state_with_program(vec![0xc3, 0x00, 0x30]);
 
-    s.trace_history.push_front(s.snapshot());
-    s.trace_history.truncate(50);
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(
+            result.reason,
+            StopReason::Error(EmulationError::PcOutOfRom(0x3000))
+        );
+        assert_eq!(result.cycles, 10);
 
-    if s.pc > 0x2000 {
-        for snap in s.trace_history.iter() {
-            println!("{:x?}", snap);
+        state.rom_limit = None;
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 4);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(state.pc, 0x3001);
+    }
+
+    #[test]
+    fn test_run_until() {
+        // MVI B,3; loop: DCR B; JNZ loop; NOP
+        let mut state = state_with_program(vec![0x06, 0x03, 0x05, 0xc2, 0x02, 0x00, 0x00]);
+
+        let result = run_until(&mut state, &mut TestMachine::default(), |s| s.pc == 6);
+        assert_eq!(result.reason, StopReason::Predicate);
+        assert_eq!(result.cycles, 7 + 3 * (5 + 10));
+        assert_eq!(state.b, 0);
+    }
+
+    #[test]
+    fn test_conditional_call_timing() {
+        // CNZ 0010; CZ 0010; HLT ... 0010: RET
+        let mut state = state_with_program(vec![0xc4, 0x10, 0x00, 0xcc, 0x10, 0x00, 0x76]);
+        state.memory.set(0x10, 0xc9);
+
+        state.cc.z = true;
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            11
+        );
+        assert_eq!(state.pc, 0x03);
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            17
+        );
+        assert_eq!(state.pc, 0x10);
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            10
+        );
+        assert_eq!(state.pc, 0x06);
+    }
+
+    #[test]
+    fn test_conditional_return_timing() {
+        // RZ; RNZ
+        let mut state = state_with_program(vec![0xc8, 0xc0]);
+        state.push16(0x1234);
+
+        state.cc.z = false;
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            5
+        );
+        assert_eq!(state.pc, 0x01);
+        assert_eq!(
+            emulate_instruction(&mut state, &mut TestMachine::default()),
+            11
+        );
+        assert_eq!(state.pc, 0x1234);
+    }
+
+    #[test]
+    fn test_conditional_timing_table() {
+        for condition in 0..8 {
+            let ret = 0xc0 | (condition << 3);
+            let call = 0xc4 | (condition << 3);
+
+            assert_eq!(OPCODE_TIMING[ret as usize], 5);
+            assert_eq!(OPCODE_TIMING[call as usize], 11);
         }
-        panic!("PC out of ROM");
+        assert_eq!(OPCODE_TIMING[0xc9], 10);
+        assert_eq!(OPCODE_TIMING[0xcd], 17);
     }
 
-    match opcode {
-        0x00..=0x3f => emulate_group0(opcode, s),
-        0x76 => unimplemented_instruction(s), // HLT
-        0x40..=0x7f => s.set_register(opcode, s.get_operand(opcode)),
-        0x80..=0xbf => s.operate8(opcode, s.get_operand(opcode)),
-        0xc0..=0xff => emulate_group3(opcode, s, m),
+    #[test]
+    fn test_sequence_cycles() {
+        // 0000: LXI SP,0100
+        // 0003: MVI B,02
+        // 0005: CALL 0010
+        // 0008: DCR B
+        // 0009: JNZ 0005
+        // 000c: HLT
+        // 0010: MOV A,B
+        // 0011: CPI 01
+        // 0013: RZ
+        // 0014: ORA A
+        // 0015: RNZ
+        let mut state = state_with_program(vec![
+            0x31, 0x00, 0x01, 0x06, 0x02, 0xcd, 0x10, 0x00, 0x05, 0xc2, 0x05, 0x00, 0x76,
+        ]);
+        state
+            .memory
+            .load(0x10, vec![0x78, 0xfe, 0x01, 0xc8, 0xb7, 0xc0]);
 
-        _ => unimplemented_instruction(s),
+        let result = run_for_cycles(&mut state, &mut TestMachine::default(), 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+
+        // First pass: B=2, RZ not taken, RNZ taken.
+        let first = 17 + 5 + 7 + 5 + 4 + 11 + 5 + 10;
+        // Second pass: B=1, RZ taken.
+        let second = 17 + 5 + 7 + 11 + 5 + 10;
+        assert_eq!(result.cycles, 10 + 7 + first + second + 7);
+        assert_eq!(state.cycles, result.cycles as u64);
     }
 
-    s.advance(opcode);
-    OPCODE_TIMING[opcode as usize]
-}
+    #[test]
+    fn test_tracer() {
+        let mut state = state_with_program(vec![0x3e, 0x01, 0x00]);
 
-pub fn trigger_interrupt(s: &mut State, n: u16) {
-    let pc = s.pc;
-    s.push16(pc);
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        let pcs: Vec<u16> = state.tracer.history().map(|e| e.snapshot.pc).collect();
+        assert_eq!(pcs, vec![0, 2]);
 
-    s.pc = 0x08 * n;
-    s.int_enable = false;
+        state.tracer = Tracer::disabled();
+        state.pc = 0;
+        emulate_instruction(&mut state, &mut TestMachine::default());
+        assert_eq!(state.tracer.count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "PC out of ROM")]
+    fn test_emulate_instruction_out_of_rom() {
+        let mut state = State::new();
+        state.pc = 0x2001;
+
+        emulate_instruction(&mut state, &mut TestMachine::default());
+    }
 }
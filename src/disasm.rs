@@ -0,0 +1,301 @@
+use bytes::assemble_word;
+use memory::Memory;
+use state::instruction_length;
+use symbols::Symbols;
+
+static REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+static PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+static PAIRS_PSW: [&str; 4] = ["B", "D", "H", "PSW"];
+static CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+static ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+static ALU_IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.len())
+    }
+}
+
+// Formats the instruction starting at bytes[0]. Missing operand bytes are
+// treated as zero.
+pub fn mnemonic(bytes: &[u8]) -> String {
+    let opcode = bytes[0];
+    let byte = || bytes.get(1).cloned().unwrap_or(0);
+    let word = || assemble_word(bytes.get(2).cloned().unwrap_or(0), byte());
+
+    let dst = REGISTERS[((opcode >> 3) & 0x7) as usize];
+    let src = REGISTERS[(opcode & 0x7) as usize];
+    let pair = PAIRS[((opcode >> 4) & 0x3) as usize];
+    let condition = CONDITIONS[((opcode >> 3) & 0x7) as usize];
+
+    match opcode {
+        0x00 => "NOP".to_string(),
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => "*NOP".to_string(),
+        0x01 | 0x11 | 0x21 | 0x31 => format!("LXI {},${:04x}", pair, word()),
+        0x02 | 0x12 => format!("STAX {}", pair),
+        0x0a | 0x1a => format!("LDAX {}", pair),
+        0x03 | 0x13 | 0x23 | 0x33 => format!("INX {}", pair),
+        0x0b | 0x1b | 0x2b | 0x3b => format!("DCX {}", pair),
+        0x09 | 0x19 | 0x29 | 0x39 => format!("DAD {}", pair),
+        0x22 => format!("SHLD ${:04x}", word()),
+        0x2a => format!("LHLD ${:04x}", word()),
+        0x32 => format!("STA ${:04x}", word()),
+        0x3a => format!("LDA ${:04x}", word()),
+        0x07 => "RLC".to_string(),
+        0x0f => "RRC".to_string(),
+        0x17 => "RAL".to_string(),
+        0x1f => "RAR".to_string(),
+        0x27 => "DAA".to_string(),
+        0x2f => "CMA".to_string(),
+        0x37 => "STC".to_string(),
+        0x3f => "CMC".to_string(),
+        0x04..=0x3e => match opcode & 0x7 {
+            0x4 => format!("INR {}", dst),
+            0x5 => format!("DCR {}", dst),
+            _ => format!("MVI {},${:02x}", dst, byte()),
+        },
+        0x76 => "HLT".to_string(),
+        0x40..=0x7f => format!("MOV {},{}", dst, src),
+        0x80..=0xbf => format!("{} {}", ALU[((opcode >> 3) & 0x7) as usize], src),
+        0xc9 => "RET".to_string(),
+        0xd9 => "*RET".to_string(),
+        0xc3 => format!("JMP ${:04x}", word()),
+        0xcb => format!("*JMP ${:04x}", word()),
+        0xcd => format!("CALL ${:04x}", word()),
+        0xdd | 0xed | 0xfd => format!("*CALL ${:04x}", word()),
+        0xd3 => format!("OUT ${:02x}", byte()),
+        0xdb => format!("IN ${:02x}", byte()),
+        0xe3 => "XTHL".to_string(),
+        0xe9 => "PCHL".to_string(),
+        0xeb => "XCHG".to_string(),
+        0xf3 => "DI".to_string(),
+        0xf9 => "SPHL".to_string(),
+        0xfb => "EI".to_string(),
+        0xc0..=0xff => match opcode & 0x7 {
+            0x0 => format!("R{}", condition),
+            0x1 => format!("POP {}", PAIRS_PSW[((opcode >> 4) & 0x3) as usize]),
+            0x2 => format!("J{} ${:04x}", condition, word()),
+            0x4 => format!("C{} ${:04x}", condition, word()),
+            0x5 => format!("PUSH {}", PAIRS_PSW[((opcode >> 4) & 0x3) as usize]),
+            0x6 => format!(
+                "{} ${:02x}",
+                ALU_IMMEDIATE[((opcode >> 3) & 0x7) as usize],
+                byte()
+            ),
+            0x7 => format!("RST {}", (opcode >> 3) & 0x7),
+            _ => unreachable!(),
+        },
+    }
+}
+
+// Replaces an address operand with its symbol. Jump and call targets may be
+// shown relative to the nearest symbol, but other operands could just as
+// well be constants, so they are only replaced on an exact match.
+pub fn mnemonic_with_symbols(bytes: &[u8], symbols: &Symbols) -> String {
+    let text = mnemonic(bytes);
+    let opcode = bytes[0];
+    if instruction_length(opcode) != 3 {
+        return text;
+    }
+
+    let word = assemble_word(
+        bytes.get(2).cloned().unwrap_or(0),
+        bytes.get(1).cloned().unwrap_or(0),
+    );
+    let name = if opcode >= 0xc0 {
+        symbols.lookup(word).map(|_| symbols.format(word))
+    } else {
+        symbols.name(word).map(|name| name.to_string())
+    };
+    match name {
+        Some(name) => text.replace(&format!("${:04x}", word), &name),
+        None => text,
+    }
+}
+
+pub fn disassemble_with_symbols(memory: &Memory, address: u16, symbols: &Symbols) -> Instruction {
+    let mut instruction = disassemble(memory, address);
+    instruction.text = mnemonic_with_symbols(&instruction.bytes, symbols);
+    instruction
+}
+
+pub fn disassemble(memory: &Memory, address: u16) -> Instruction {
+    let opcode = memory.get(address);
+    let bytes: Vec<u8> = (0..instruction_length(opcode))
+        .map(|offset| memory.get(address.wrapping_add(offset)))
+        .collect();
+    let text = mnemonic(&bytes);
+
+    Instruction {
+        address,
+        bytes,
+        text,
+    }
+}
+
+pub fn disassemble_range(memory: &Memory, start: u16, count: usize) -> Vec<Instruction> {
+    let mut address = start;
+    let mut listing = Vec::with_capacity(count);
+    for _ in 0..count {
+        let instruction = disassemble(memory, address);
+        address = instruction.next_address();
+        listing.push(instruction);
+    }
+    listing
+}
+
+pub fn format_listing_line(instruction: &Instruction) -> String {
+    let bytes: Vec<String> = instruction
+        .bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!(
+        "{:04x}  {:<9} {}",
+        instruction.address,
+        bytes.join(" "),
+        instruction.text
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonic_test() {
+        assert_eq!(mnemonic(&[0x00]), "NOP");
+        assert_eq!(mnemonic(&[0x01, 0x34, 0x12]), "LXI B,$1234");
+        assert_eq!(mnemonic(&[0x31, 0x00, 0x24]), "LXI SP,$2400");
+        assert_eq!(mnemonic(&[0x12]), "STAX D");
+        assert_eq!(mnemonic(&[0x2b]), "DCX H");
+        assert_eq!(mnemonic(&[0x34]), "INR M");
+        assert_eq!(mnemonic(&[0x3d]), "DCR A");
+        assert_eq!(mnemonic(&[0x3e, 0x7f]), "MVI A,$7f");
+        assert_eq!(mnemonic(&[0x22, 0xcd, 0xab]), "SHLD $abcd");
+        assert_eq!(mnemonic(&[0x27]), "DAA");
+        assert_eq!(mnemonic(&[0x76]), "HLT");
+        assert_eq!(mnemonic(&[0x78]), "MOV A,B");
+        assert_eq!(mnemonic(&[0x77]), "MOV M,A");
+        assert_eq!(mnemonic(&[0x86]), "ADD M");
+        assert_eq!(mnemonic(&[0xbf]), "CMP A");
+        assert_eq!(mnemonic(&[0xc0]), "RNZ");
+        assert_eq!(mnemonic(&[0xf1]), "POP PSW");
+        assert_eq!(mnemonic(&[0xca, 0x00, 0x01]), "JZ $0100");
+        assert_eq!(mnemonic(&[0xc3, 0xd4, 0x18]), "JMP $18d4");
+        assert_eq!(mnemonic(&[0xfc, 0x10, 0x00]), "CM $0010");
+        assert_eq!(mnemonic(&[0xcd, 0x05, 0x00]), "CALL $0005");
+        assert_eq!(mnemonic(&[0xe5]), "PUSH H");
+        assert_eq!(mnemonic(&[0xfe, 0x01]), "CPI $01");
+        assert_eq!(mnemonic(&[0xd3, 0x06]), "OUT $06");
+        assert_eq!(mnemonic(&[0xcf]), "RST 1");
+        assert_eq!(mnemonic(&[0xe9]), "PCHL");
+        assert_eq!(mnemonic(&[0xfb]), "EI");
+    }
+
+    #[test]
+    fn undocumented_mnemonic_test() {
+        assert_eq!(mnemonic(&[0x08]), "*NOP");
+        assert_eq!(mnemonic(&[0xcb, 0x00, 0x10]), "*JMP $1000");
+        assert_eq!(mnemonic(&[0xd9]), "*RET");
+        assert_eq!(mnemonic(&[0xfd, 0x00, 0x10]), "*CALL $1000");
+    }
+
+    #[test]
+    fn short_buffer_test() {
+        assert_eq!(mnemonic(&[0xc3]), "JMP $0000");
+        assert_eq!(mnemonic(&[0xc3, 0x12]), "JMP $0012");
+    }
+
+    #[test]
+    fn every_opcode_test() {
+        for opcode in 0..=255u8 {
+            assert!(!mnemonic(&[opcode, 0, 0]).is_empty());
+        }
+    }
+
+    #[test]
+    fn disassemble_test() {
+        let mut memory = Memory::new();
+        memory.load(0x100, vec![0x3e, 0x01, 0xc3, 0x00, 0x01, 0x00]);
+
+        let instruction = disassemble(&memory, 0x102);
+        assert_eq!(instruction.address, 0x102);
+        assert_eq!(instruction.bytes, vec![0xc3, 0x00, 0x01]);
+        assert_eq!(instruction.text, "JMP $0100");
+        assert_eq!(instruction.next_address(), 0x105);
+
+        let listing = disassemble_range(&memory, 0x100, 3);
+        assert_eq!(listing.len(), 3);
+        assert_eq!(listing[2].text, "NOP");
+        assert_eq!(
+            format_listing_line(&listing[0]),
+            "0100  3e 01     MVI A,$01"
+        );
+    }
+
+    #[test]
+    fn symbols_test() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x01e4, "DrawSprite");
+        symbols.insert(0x2400, "VRAM");
+
+        assert_eq!(
+            mnemonic_with_symbols(&[0xcd, 0xe4, 0x01], &symbols),
+            "CALL DrawSprite"
+        );
+        assert_eq!(
+            mnemonic_with_symbols(&[0xc2, 0xf6, 0x01], &symbols),
+            "JNZ DrawSprite+0x12"
+        );
+        assert_eq!(
+            mnemonic_with_symbols(&[0x21, 0x00, 0x24], &symbols),
+            "LXI H,VRAM"
+        );
+        // Not every 16-bit immediate is an address.
+        assert_eq!(
+            mnemonic_with_symbols(&[0x21, 0x01, 0x24], &symbols),
+            "LXI H,$2401"
+        );
+        assert_eq!(
+            mnemonic_with_symbols(&[0xc3, 0x00, 0x01], &symbols),
+            "JMP $0100"
+        );
+        assert_eq!(mnemonic_with_symbols(&[0x3e, 0xe4], &symbols), "MVI A,$e4");
+
+        let mut memory = Memory::new();
+        memory.load(0, vec![0xcd, 0xe4, 0x01]);
+        assert_eq!(
+            disassemble_with_symbols(&memory, 0, &symbols).text,
+            "CALL DrawSprite"
+        );
+    }
+
+    #[test]
+    fn disassemble_wraps_test() {
+        let mut memory = Memory::new();
+        memory.load(0xfffe, vec![0xc3, 0x34]);
+        memory.set(0x0000, 0x12);
+
+        assert_eq!(disassemble(&memory, 0xfffe).text, "JMP $1234");
+    }
+}
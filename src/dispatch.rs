@@ -0,0 +1,626 @@
+use std::marker::PhantomData;
+
+use bytes::*;
+use cpu::CONDITIONAL_TAKEN_EXTRA;
+use machine::Machine;
+use program::Program;
+use stack::Stack;
+use state::State;
+
+// Each opcode has its own handler, reached with one indexed call. The
+// handlers are generic over the register, pair or condition they work on,
+// so the matches in State's accessors fold away for each instantiation.
+// A handler returns the cycles it took beyond the opcode's base timing.
+pub type Handler<M> = fn(&mut State, &mut M) -> usize;
+
+// Register codes as in the opcode: B C D E H L M A. Pair codes: BC DE HL
+// and SP, or PSW for PUSH and POP.
+const PAIR_SP_OR_PSW: u8 = 3;
+
+fn conditional_extra(taken: bool) -> usize {
+    if taken {
+        CONDITIONAL_TAKEN_EXTRA
+    } else {
+        0
+    }
+}
+
+fn get_pair(s: &State, p: u8) -> u16 {
+    match p {
+        0 => s.get_bc(),
+        1 => s.get_de(),
+        2 => s.get_hl_address(),
+        _ => s.sp,
+    }
+}
+
+fn set_pair(s: &mut State, p: u8, value: u16) {
+    match p {
+        0 => s.set_bc(value),
+        1 => s.set_de(value),
+        2 => s.set_hl(value),
+        _ => s.sp = value,
+    }
+}
+
+fn nop<M: Machine>(_s: &mut State, _m: &mut M) -> usize {
+    0
+}
+
+fn lxi<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    let value = s.get_arg16();
+    set_pair(s, P, value);
+    0
+}
+
+fn stax<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.memory.set(get_pair(s, P), s.a);
+    0
+}
+
+fn ldax<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.a = s.memory.get(get_pair(s, P));
+    0
+}
+
+fn inx<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    let value = get_pair(s, P).wrapping_add(1);
+    set_pair(s, P, value);
+    0
+}
+
+fn dcx<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    let value = get_pair(s, P).wrapping_sub(1);
+    set_pair(s, P, value);
+    0
+}
+
+fn dad<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.add16(get_pair(s, P));
+    0
+}
+
+fn inr<M: Machine, const R: u8>(s: &mut State, _m: &mut M) -> usize {
+    let value = s.inr(s.get_operand(R));
+    s.set_register(R << 3, value);
+    0
+}
+
+fn dcr<M: Machine, const R: u8>(s: &mut State, _m: &mut M) -> usize {
+    let value = s.dcr(s.get_operand(R));
+    s.set_register(R << 3, value);
+    0
+}
+
+fn mvi<M: Machine, const R: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.set_register(R << 3, s.get_arg8());
+    0
+}
+
+fn rlc<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.cc.cy = (s.a & 0x80) != 0;
+    s.a = s.a.rotate_left(1);
+    0
+}
+
+fn rrc<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.cc.cy = (s.a & 0x01) != 0;
+    s.a = s.a.rotate_right(1);
+    0
+}
+
+fn ral<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    let x = s.a;
+    s.a = (s.cc.cy as u8) | (x << 1);
+    s.cc.cy = (x & 0x80) != 0;
+    0
+}
+
+fn rar<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    let x = s.a;
+    s.a = ((s.cc.cy as u8) << 7) | (x >> 1);
+    s.cc.cy = (x & 0x01) != 0;
+    0
+}
+
+fn shld<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    let address = s.get_arg16();
+    s.memory.set(address, s.l);
+    s.memory.set(address.wrapping_add(1), s.h);
+    0
+}
+
+fn lhld<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    let address = s.get_arg16();
+    s.l = s.memory.get(address);
+    s.h = s.memory.get(address.wrapping_add(1));
+    0
+}
+
+fn sta<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.memory.set(s.get_arg16(), s.a);
+    0
+}
+
+fn lda<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.a = s.memory.get(s.get_arg16());
+    0
+}
+
+fn daa<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.daa();
+    0
+}
+
+fn cma<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.a = !s.a;
+    0
+}
+
+fn stc<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.cc.cy = true;
+    0
+}
+
+fn cmc<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.cc.cy = !s.cc.cy;
+    0
+}
+
+fn mov<M: Machine, const D: u8, const R: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.set_register(D << 3, s.get_operand(R));
+    0
+}
+
+fn hlt<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.halted = true;
+    0
+}
+
+fn alu<M: Machine, const OP: u8, const R: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.operate8(OP << 3, s.get_operand(R));
+    0
+}
+
+fn alu_immediate<M: Machine, const OP: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.operate8(OP << 3, s.get_arg8());
+    0
+}
+
+fn ret_if<M: Machine, const C: u8>(s: &mut State, _m: &mut M) -> usize {
+    conditional_extra(s.ret_if(State::predicate_for(C << 3)))
+}
+
+fn ret<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.ret_if(State::unconditionally);
+    0
+}
+
+fn jump_if<M: Machine, const C: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.jump_if(State::predicate_for(C << 3));
+    0
+}
+
+fn jmp<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.jump_if(State::unconditionally);
+    0
+}
+
+fn call_if<M: Machine, const C: u8>(s: &mut State, _m: &mut M) -> usize {
+    conditional_extra(s.call_if(State::predicate_for(C << 3)))
+}
+
+fn call<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.call_if(State::unconditionally);
+    0
+}
+
+fn rst<M: Machine, const N: u8>(s: &mut State, _m: &mut M) -> usize {
+    s.rst_to(u16::from(N) << 3);
+    0
+}
+
+fn pop<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    let word = s.pop16();
+    if P == PAIR_SP_OR_PSW {
+        s.a = high_order_byte(word);
+        s.cc.set_psw(low_order_byte(word));
+    } else {
+        set_pair(s, P, word);
+    }
+    0
+}
+
+fn push<M: Machine, const P: u8>(s: &mut State, _m: &mut M) -> usize {
+    let word = if P == PAIR_SP_OR_PSW {
+        assemble_word(s.a, s.cc.to_psw())
+    } else {
+        get_pair(s, P)
+    };
+    s.push16(word);
+    0
+}
+
+fn pchl<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.pc = s.get_hl_address();
+    s.jumped = true;
+    0
+}
+
+fn sphl<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.sp = s.get_hl_address();
+    0
+}
+
+fn xthl<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    let new_hl = s.pop16();
+    let old_hl = s.get_hl_address();
+    s.push16(old_hl);
+    s.set_hl(new_hl);
+    0
+}
+
+fn xchg<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    std::mem::swap(&mut s.d, &mut s.h);
+    std::mem::swap(&mut s.e, &mut s.l);
+    0
+}
+
+fn output<M: Machine>(s: &mut State, m: &mut M) -> usize {
+    m.output(s.get_arg8(), s.a);
+    0
+}
+
+fn input<M: Machine>(s: &mut State, m: &mut M) -> usize {
+    s.a = m.input(s.get_arg8());
+    0
+}
+
+fn di<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.int_enable = false;
+    0
+}
+
+fn ei<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    s.int_enable = true;
+    0
+}
+
+struct Dispatch<M>(PhantomData<M>);
+
+impl<M: Machine> Dispatch<M> {
+    const TABLE: [Handler<M>; 256] = [
+        nop,                   // 00 NOP
+        lxi::<_, 0>,           // 01 LXI B,word
+        stax::<_, 0>,          // 02 STAX B
+        inx::<_, 0>,           // 03 INX B
+        inr::<_, 0>,           // 04 INR B
+        dcr::<_, 0>,           // 05 DCR B
+        mvi::<_, 0>,           // 06 MVI B,byte
+        rlc,                   // 07 RLC
+        nop,                   // 08 NOP (undocumented)
+        dad::<_, 0>,           // 09 DAD B
+        ldax::<_, 0>,          // 0a LDAX B
+        dcx::<_, 0>,           // 0b DCX B
+        inr::<_, 1>,           // 0c INR C
+        dcr::<_, 1>,           // 0d DCR C
+        mvi::<_, 1>,           // 0e MVI C,byte
+        rrc,                   // 0f RRC
+        nop,                   // 10 NOP (undocumented)
+        lxi::<_, 1>,           // 11 LXI D,word
+        stax::<_, 1>,          // 12 STAX D
+        inx::<_, 1>,           // 13 INX D
+        inr::<_, 2>,           // 14 INR D
+        dcr::<_, 2>,           // 15 DCR D
+        mvi::<_, 2>,           // 16 MVI D,byte
+        ral,                   // 17 RAL
+        nop,                   // 18 NOP (undocumented)
+        dad::<_, 1>,           // 19 DAD D
+        ldax::<_, 1>,          // 1a LDAX D
+        dcx::<_, 1>,           // 1b DCX D
+        inr::<_, 3>,           // 1c INR E
+        dcr::<_, 3>,           // 1d DCR E
+        mvi::<_, 3>,           // 1e MVI E,byte
+        rar,                   // 1f RAR
+        nop,                   // 20 NOP (undocumented)
+        lxi::<_, 2>,           // 21 LXI H,word
+        shld,                  // 22 SHLD a16
+        inx::<_, 2>,           // 23 INX H
+        inr::<_, 4>,           // 24 INR H
+        dcr::<_, 4>,           // 25 DCR H
+        mvi::<_, 4>,           // 26 MVI H,byte
+        daa,                   // 27 DAA
+        nop,                   // 28 NOP (undocumented)
+        dad::<_, 2>,           // 29 DAD H
+        lhld,                  // 2a LHLD a16
+        dcx::<_, 2>,           // 2b DCX H
+        inr::<_, 5>,           // 2c INR L
+        dcr::<_, 5>,           // 2d DCR L
+        mvi::<_, 5>,           // 2e MVI L,byte
+        cma,                   // 2f CMA
+        nop,                   // 30 NOP (undocumented)
+        lxi::<_, 3>,           // 31 LXI SP,word
+        sta,                   // 32 STA a16
+        inx::<_, 3>,           // 33 INX SP
+        inr::<_, 6>,           // 34 INR M
+        dcr::<_, 6>,           // 35 DCR M
+        mvi::<_, 6>,           // 36 MVI M,byte
+        stc,                   // 37 STC
+        nop,                   // 38 NOP (undocumented)
+        dad::<_, 3>,           // 39 DAD SP
+        lda,                   // 3a LDA a16
+        dcx::<_, 3>,           // 3b DCX SP
+        inr::<_, 7>,           // 3c INR A
+        dcr::<_, 7>,           // 3d DCR A
+        mvi::<_, 7>,           // 3e MVI A,byte
+        cmc,                   // 3f CMC
+        mov::<_, 0, 0>,        // 40 MOV B,B
+        mov::<_, 0, 1>,        // 41 MOV B,C
+        mov::<_, 0, 2>,        // 42 MOV B,D
+        mov::<_, 0, 3>,        // 43 MOV B,E
+        mov::<_, 0, 4>,        // 44 MOV B,H
+        mov::<_, 0, 5>,        // 45 MOV B,L
+        mov::<_, 0, 6>,        // 46 MOV B,M
+        mov::<_, 0, 7>,        // 47 MOV B,A
+        mov::<_, 1, 0>,        // 48 MOV C,B
+        mov::<_, 1, 1>,        // 49 MOV C,C
+        mov::<_, 1, 2>,        // 4a MOV C,D
+        mov::<_, 1, 3>,        // 4b MOV C,E
+        mov::<_, 1, 4>,        // 4c MOV C,H
+        mov::<_, 1, 5>,        // 4d MOV C,L
+        mov::<_, 1, 6>,        // 4e MOV C,M
+        mov::<_, 1, 7>,        // 4f MOV C,A
+        mov::<_, 2, 0>,        // 50 MOV D,B
+        mov::<_, 2, 1>,        // 51 MOV D,C
+        mov::<_, 2, 2>,        // 52 MOV D,D
+        mov::<_, 2, 3>,        // 53 MOV D,E
+        mov::<_, 2, 4>,        // 54 MOV D,H
+        mov::<_, 2, 5>,        // 55 MOV D,L
+        mov::<_, 2, 6>,        // 56 MOV D,M
+        mov::<_, 2, 7>,        // 57 MOV D,A
+        mov::<_, 3, 0>,        // 58 MOV E,B
+        mov::<_, 3, 1>,        // 59 MOV E,C
+        mov::<_, 3, 2>,        // 5a MOV E,D
+        mov::<_, 3, 3>,        // 5b MOV E,E
+        mov::<_, 3, 4>,        // 5c MOV E,H
+        mov::<_, 3, 5>,        // 5d MOV E,L
+        mov::<_, 3, 6>,        // 5e MOV E,M
+        mov::<_, 3, 7>,        // 5f MOV E,A
+        mov::<_, 4, 0>,        // 60 MOV H,B
+        mov::<_, 4, 1>,        // 61 MOV H,C
+        mov::<_, 4, 2>,        // 62 MOV H,D
+        mov::<_, 4, 3>,        // 63 MOV H,E
+        mov::<_, 4, 4>,        // 64 MOV H,H
+        mov::<_, 4, 5>,        // 65 MOV H,L
+        mov::<_, 4, 6>,        // 66 MOV H,M
+        mov::<_, 4, 7>,        // 67 MOV H,A
+        mov::<_, 5, 0>,        // 68 MOV L,B
+        mov::<_, 5, 1>,        // 69 MOV L,C
+        mov::<_, 5, 2>,        // 6a MOV L,D
+        mov::<_, 5, 3>,        // 6b MOV L,E
+        mov::<_, 5, 4>,        // 6c MOV L,H
+        mov::<_, 5, 5>,        // 6d MOV L,L
+        mov::<_, 5, 6>,        // 6e MOV L,M
+        mov::<_, 5, 7>,        // 6f MOV L,A
+        mov::<_, 6, 0>,        // 70 MOV M,B
+        mov::<_, 6, 1>,        // 71 MOV M,C
+        mov::<_, 6, 2>,        // 72 MOV M,D
+        mov::<_, 6, 3>,        // 73 MOV M,E
+        mov::<_, 6, 4>,        // 74 MOV M,H
+        mov::<_, 6, 5>,        // 75 MOV M,L
+        hlt,                   // 76 HLT
+        mov::<_, 6, 7>,        // 77 MOV M,A
+        mov::<_, 7, 0>,        // 78 MOV A,B
+        mov::<_, 7, 1>,        // 79 MOV A,C
+        mov::<_, 7, 2>,        // 7a MOV A,D
+        mov::<_, 7, 3>,        // 7b MOV A,E
+        mov::<_, 7, 4>,        // 7c MOV A,H
+        mov::<_, 7, 5>,        // 7d MOV A,L
+        mov::<_, 7, 6>,        // 7e MOV A,M
+        mov::<_, 7, 7>,        // 7f MOV A,A
+        alu::<_, 0, 0>,        // 80 ADD B
+        alu::<_, 0, 1>,        // 81 ADD C
+        alu::<_, 0, 2>,        // 82 ADD D
+        alu::<_, 0, 3>,        // 83 ADD E
+        alu::<_, 0, 4>,        // 84 ADD H
+        alu::<_, 0, 5>,        // 85 ADD L
+        alu::<_, 0, 6>,        // 86 ADD M
+        alu::<_, 0, 7>,        // 87 ADD A
+        alu::<_, 1, 0>,        // 88 ADC B
+        alu::<_, 1, 1>,        // 89 ADC C
+        alu::<_, 1, 2>,        // 8a ADC D
+        alu::<_, 1, 3>,        // 8b ADC E
+        alu::<_, 1, 4>,        // 8c ADC H
+        alu::<_, 1, 5>,        // 8d ADC L
+        alu::<_, 1, 6>,        // 8e ADC M
+        alu::<_, 1, 7>,        // 8f ADC A
+        alu::<_, 2, 0>,        // 90 SUB B
+        alu::<_, 2, 1>,        // 91 SUB C
+        alu::<_, 2, 2>,        // 92 SUB D
+        alu::<_, 2, 3>,        // 93 SUB E
+        alu::<_, 2, 4>,        // 94 SUB H
+        alu::<_, 2, 5>,        // 95 SUB L
+        alu::<_, 2, 6>,        // 96 SUB M
+        alu::<_, 2, 7>,        // 97 SUB A
+        alu::<_, 3, 0>,        // 98 SBB B
+        alu::<_, 3, 1>,        // 99 SBB C
+        alu::<_, 3, 2>,        // 9a SBB D
+        alu::<_, 3, 3>,        // 9b SBB E
+        alu::<_, 3, 4>,        // 9c SBB H
+        alu::<_, 3, 5>,        // 9d SBB L
+        alu::<_, 3, 6>,        // 9e SBB M
+        alu::<_, 3, 7>,        // 9f SBB A
+        alu::<_, 4, 0>,        // a0 ANA B
+        alu::<_, 4, 1>,        // a1 ANA C
+        alu::<_, 4, 2>,        // a2 ANA D
+        alu::<_, 4, 3>,        // a3 ANA E
+        alu::<_, 4, 4>,        // a4 ANA H
+        alu::<_, 4, 5>,        // a5 ANA L
+        alu::<_, 4, 6>,        // a6 ANA M
+        alu::<_, 4, 7>,        // a7 ANA A
+        alu::<_, 5, 0>,        // a8 XRA B
+        alu::<_, 5, 1>,        // a9 XRA C
+        alu::<_, 5, 2>,        // aa XRA D
+        alu::<_, 5, 3>,        // ab XRA E
+        alu::<_, 5, 4>,        // ac XRA H
+        alu::<_, 5, 5>,        // ad XRA L
+        alu::<_, 5, 6>,        // ae XRA M
+        alu::<_, 5, 7>,        // af XRA A
+        alu::<_, 6, 0>,        // b0 ORA B
+        alu::<_, 6, 1>,        // b1 ORA C
+        alu::<_, 6, 2>,        // b2 ORA D
+        alu::<_, 6, 3>,        // b3 ORA E
+        alu::<_, 6, 4>,        // b4 ORA H
+        alu::<_, 6, 5>,        // b5 ORA L
+        alu::<_, 6, 6>,        // b6 ORA M
+        alu::<_, 6, 7>,        // b7 ORA A
+        alu::<_, 7, 0>,        // b8 CMP B
+        alu::<_, 7, 1>,        // b9 CMP C
+        alu::<_, 7, 2>,        // ba CMP D
+        alu::<_, 7, 3>,        // bb CMP E
+        alu::<_, 7, 4>,        // bc CMP H
+        alu::<_, 7, 5>,        // bd CMP L
+        alu::<_, 7, 6>,        // be CMP M
+        alu::<_, 7, 7>,        // bf CMP A
+        ret_if::<_, 0>,        // c0 RNZ
+        pop::<_, 0>,           // c1 POP B
+        jump_if::<_, 0>,       // c2 JNZ a16
+        jmp,                   // c3 JMP a16
+        call_if::<_, 0>,       // c4 CNZ a16
+        push::<_, 0>,          // c5 PUSH B
+        alu_immediate::<_, 0>, // c6 ADI byte
+        rst::<_, 0>,           // c7 RST 0
+        ret_if::<_, 1>,        // c8 RZ
+        ret,                   // c9 RET
+        jump_if::<_, 1>,       // ca JZ a16
+        jmp,                   // cb JMP a16 (undocumented)
+        call_if::<_, 1>,       // cc CZ a16
+        call,                  // cd CALL a16
+        alu_immediate::<_, 1>, // ce ACI byte
+        rst::<_, 1>,           // cf RST 1
+        ret_if::<_, 2>,        // d0 RNC
+        pop::<_, 1>,           // d1 POP D
+        jump_if::<_, 2>,       // d2 JNC a16
+        output,                // d3 OUT byte
+        call_if::<_, 2>,       // d4 CNC a16
+        push::<_, 1>,          // d5 PUSH D
+        alu_immediate::<_, 2>, // d6 SUI byte
+        rst::<_, 2>,           // d7 RST 2
+        ret_if::<_, 3>,        // d8 RC
+        ret,                   // d9 RET (undocumented)
+        jump_if::<_, 3>,       // da JC a16
+        input,                 // db IN byte
+        call_if::<_, 3>,       // dc CC a16
+        call,                  // dd CALL a16 (undocumented)
+        alu_immediate::<_, 3>, // de SBI byte
+        rst::<_, 3>,           // df RST 3
+        ret_if::<_, 4>,        // e0 RPO
+        pop::<_, 2>,           // e1 POP H
+        jump_if::<_, 4>,       // e2 JPO a16
+        xthl,                  // e3 XTHL
+        call_if::<_, 4>,       // e4 CPO a16
+        push::<_, 2>,          // e5 PUSH H
+        alu_immediate::<_, 4>, // e6 ANI byte
+        rst::<_, 4>,           // e7 RST 4
+        ret_if::<_, 5>,        // e8 RPE
+        pchl,                  // e9 PCHL
+        jump_if::<_, 5>,       // ea JPE a16
+        xchg,                  // eb XCHG
+        call_if::<_, 5>,       // ec CPE a16
+        call,                  // ed CALL a16 (undocumented)
+        alu_immediate::<_, 5>, // ee XRI byte
+        rst::<_, 5>,           // ef RST 5
+        ret_if::<_, 6>,        // f0 RP
+        pop::<_, 3>,           // f1 POP PSW
+        jump_if::<_, 6>,       // f2 JP a16
+        di,                    // f3 DI
+        call_if::<_, 6>,       // f4 CP a16
+        push::<_, 3>,          // f5 PUSH PSW
+        alu_immediate::<_, 6>, // f6 ORI byte
+        rst::<_, 6>,           // f7 RST 6
+        ret_if::<_, 7>,        // f8 RM
+        sphl,                  // f9 SPHL
+        jump_if::<_, 7>,       // fa JM a16
+        ei,                    // fb EI
+        call_if::<_, 7>,       // fc CM a16
+        call,                  // fd CALL a16 (undocumented)
+        alu_immediate::<_, 7>, // fe CPI byte
+        rst::<_, 7>,           // ff RST 7
+    ];
+}
+
+pub fn handler<M: Machine>(opcode: u8) -> Handler<M> {
+    Dispatch::<M>::TABLE[opcode as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TestMachine;
+
+    fn execute(s: &mut State, program: &[u8]) -> usize {
+        s.memory.load(s.pc, program.to_vec());
+        let mut machine = TestMachine::default();
+        handler(program[0])(s, &mut machine)
+    }
+
+    #[test]
+    fn register_handlers_test() {
+        let mut s = State::new();
+        s.c = 0x42;
+        execute(&mut s, &[0x41]); // MOV B,C
+        assert_eq!(s.b, 0x42);
+
+        s.set_hl(0x2400);
+        execute(&mut s, &[0x70]); // MOV M,B
+        assert_eq!(s.memory.get(0x2400), 0x42);
+
+        execute(&mut s, &[0x34]); // INR M
+        assert_eq!(s.memory.get(0x2400), 0x43);
+
+        s.a = 0x01;
+        execute(&mut s, &[0x86]); // ADD M
+        assert_eq!(s.a, 0x44);
+
+        execute(&mut s, &[0x31, 0x00, 0x30]); // LXI SP,3000
+        assert_eq!(s.sp, 0x3000);
+    }
+
+    #[test]
+    fn stack_handlers_test() {
+        let mut s = State::new();
+        s.sp = 0x3000;
+        s.a = 0x12;
+        s.cc.cy = true;
+        execute(&mut s, &[0xf5]); // PUSH PSW
+        execute(&mut s, &[0xc1]); // POP B
+        assert_eq!(s.get_bc(), 0x1203);
+        assert_eq!(s.sp, 0x3000);
+    }
+
+    #[test]
+    fn conditional_handlers_test() {
+        let mut s = State::new();
+        s.sp = 0x3000;
+        s.cc.z = true;
+        assert_eq!(execute(&mut s, &[0xc4, 0x00, 0x10]), 0); // CNZ 1000
+        assert_eq!(s.jumped, false);
+        assert_eq!(
+            execute(&mut s, &[0xcc, 0x00, 0x10]), // CZ 1000
+            CONDITIONAL_TAKEN_EXTRA
+        );
+        assert_eq!(s.pc, 0x1000);
+    }
+
+    #[test]
+    fn io_handlers_test() {
+        let mut s = State::new();
+        let mut machine = TestMachine::default();
+        s.memory.load(0, vec![0xdb, 0x0f, 0xd3, 0x10]);
+        handler(0xdb)(&mut s, &mut machine); // IN 0f
+        assert_eq!(s.a, 0xf0);
+        s.pc = 2;
+        handler(0xd3)(&mut s, &mut machine); // OUT 10
+        assert_eq!(machine.outputs, vec![(0x10, 0xf0)]);
+    }
+}
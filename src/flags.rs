@@ -1,3 +1,45 @@
+#[derive(Clone, Copy)]
+struct ResultFlags {
+    s: bool,
+    z: bool,
+    p: bool,
+    cy: bool,
+}
+
+// S, Z, P and CY for every 9-bit sum, so an ALU operation sets them with a
+// lookup instead of testing the result bit by bit.
+static RESULT_FLAGS: [ResultFlags; 512] = result_flags();
+
+const fn result_flags() -> [ResultFlags; 512] {
+    let mut table = [ResultFlags {
+        s: false,
+        z: false,
+        p: false,
+        cy: false,
+    }; 512];
+    let mut n = 0;
+    while n < 512 {
+        let result = n as u8;
+        table[n] = ResultFlags {
+            s: result & 0x80 != 0,
+            z: result == 0,
+            p: result.count_ones() & 0x01 == 0,
+            cy: n > 0xff,
+        };
+        n += 1;
+    }
+    table
+}
+
+// Whether an addition carried out of bit 3, indexed by bit 3 of each
+// operand and of the sum: the carry in is whatever makes the sum bit come
+// out.
+static HALF_CARRY: [bool; 8] = [false, false, true, false, true, false, true, true];
+
+fn half_carry(x: u8, y: u8, sum: u8) -> bool {
+    HALF_CARRY[((x & 0x08) >> 1 | (y & 0x08) >> 2 | (sum & 0x08) >> 3) as usize]
+}
+
 #[derive(PartialEq, Debug, Default)]
 pub struct Flags {
     pub z: bool,
@@ -28,6 +70,25 @@ impl Flags {
         self.ac = (flags & 0x10) != 0;
     }
 
+    // The flags byte in the layout the 8080 pushes with PUSH PSW:
+    // S Z 0 AC 0 P 1 CY, from bit 7 down to bit 0.
+    pub fn to_psw(&self) -> u8 {
+        (self.s as u8) << 7
+            | (self.z as u8) << 6
+            | (self.ac as u8) << 4
+            | (self.p as u8) << 2
+            | 0x02
+            | (self.cy as u8)
+    }
+
+    pub fn set_psw(&mut self, psw: u8) {
+        self.s = (psw & 0x80) != 0;
+        self.z = (psw & 0x40) != 0;
+        self.ac = (psw & 0x10) != 0;
+        self.p = (psw & 0x04) != 0;
+        self.cy = (psw & 0x01) != 0;
+    }
+
     pub fn set_z(&mut self, n: u8) {
         self.z = n == 0;
     }
@@ -39,6 +100,35 @@ impl Flags {
     pub fn set_p(&mut self, n: u8) {
         self.p = (n.count_ones() & 0x01) == 0;
     }
+
+    // set_z, set_s and set_p in one table lookup.
+    pub fn set_szp(&mut self, n: u8) {
+        let flags = RESULT_FLAGS[n as usize];
+        self.s = flags.s;
+        self.z = flags.z;
+        self.p = flags.p;
+    }
+
+    // x + y + carry, setting all five flags.
+    pub fn add(&mut self, x: u8, y: u8, carry: bool) -> u8 {
+        let sum = (usize::from(x) + usize::from(y) + carry as usize) & 0x1ff;
+        let flags = RESULT_FLAGS[sum];
+        self.s = flags.s;
+        self.z = flags.z;
+        self.p = flags.p;
+        self.cy = flags.cy;
+        self.ac = half_carry(x, y, sum as u8);
+        sum as u8
+    }
+
+    // x - y - borrow, setting all five flags. The 8080 adds the complement
+    // with the carry inverted, so AC is that addition's carry and CY its
+    // inverse.
+    pub fn subtract(&mut self, x: u8, y: u8, borrow: bool) -> u8 {
+        let difference = self.add(x, !y, !borrow);
+        self.cy = !self.cy;
+        difference
+    }
 }
 
 #[cfg(test)]
@@ -85,6 +175,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn to_psw_test() {
+        let mut flags = Flags::new();
+
+        assert_eq!(flags.to_psw(), 0x02);
+
+        flags.cy = true;
+        assert_eq!(flags.to_psw(), 0x03);
+
+        flags.s = true;
+        flags.z = true;
+        flags.ac = true;
+        flags.p = true;
+        assert_eq!(flags.to_psw(), 0xd7);
+    }
+
+    #[test]
+    fn set_psw_test() {
+        let mut flags = Flags::new();
+
+        flags.set_psw(0xff);
+        assert_eq!(flags.to_psw(), 0xd7);
+
+        flags.set_psw(0x44);
+        assert_eq!(
+            flags,
+            Flags {
+                z: true,
+                p: true,
+                ..Default::default()
+            }
+        );
+    }
+
     #[test]
     fn set_z_test() {
         let mut flags = Flags::new();
@@ -113,6 +237,50 @@ mod tests {
         assert_eq!(flags.s, true);
     }
 
+    #[test]
+    fn set_szp_test() {
+        let mut table = Flags::new();
+        let mut separate = Flags::new();
+        for n in 0..=255 {
+            table.set_szp(n);
+            separate.set_z(n);
+            separate.set_s(n);
+            separate.set_p(n);
+            assert_eq!(table, separate, "{:02x}", n);
+        }
+    }
+
+    // Every operand pair and carry against the arithmetic the tables
+    // replace.
+    #[test]
+    fn add_subtract_test() {
+        let mut flags = Flags::new();
+        for x in 0..=255u8 {
+            for y in 0..=255u8 {
+                for &carry in [false, true].iter() {
+                    let sum = u16::from(x) + u16::from(y) + u16::from(carry);
+                    assert_eq!(flags.add(x, y, carry), sum as u8);
+                    let mut expected = Flags::new();
+                    expected.set_z(sum as u8);
+                    expected.set_s(sum as u8);
+                    expected.set_p(sum as u8);
+                    expected.cy = sum > 0xff;
+                    expected.ac = (x & 0x0f) + (y & 0x0f) + carry as u8 > 0x0f;
+                    assert_eq!(flags, expected);
+
+                    let difference = x.wrapping_sub(y).wrapping_sub(carry as u8);
+                    assert_eq!(flags.subtract(x, y, carry), difference);
+                    expected.set_z(difference);
+                    expected.set_s(difference);
+                    expected.set_p(difference);
+                    expected.cy = u16::from(x) < u16::from(y) + u16::from(carry);
+                    expected.ac = (x & 0x0f) + (!y & 0x0f) + !carry as u8 > 0x0f;
+                    assert_eq!(flags, expected);
+                }
+            }
+        }
+    }
+
     #[test]
     fn set_p_test() {
         let mut flags = Flags::new();
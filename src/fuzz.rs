@@ -0,0 +1,558 @@
+use std::fmt;
+use std::panic;
+
+use cpu::try_emulate_instruction;
+use machine::Machine;
+use reference::Cpu;
+use state::State;
+
+// Differential fuzzing: random CPU states and instruction streams run on
+// both the emulator and the reference model, compared after every
+// instruction. A mismatch is shrunk to the smallest case that still shows
+// it.
+
+pub const DEFAULT_CASES: usize = 5000;
+pub const MAX_STEPS: usize = 8;
+const PROGRAM_LENGTH: usize = 16;
+
+// Values around the edges of the address space, where wrapping bugs live.
+const EDGES: [u16; 6] = [0x0000, 0x0001, 0x0002, 0xfffe, 0xffff, 0x00ff];
+
+// xorshift64*, so runs are reproducible from a seed without a dependency.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn word(&mut self) -> u16 {
+        if self.below(8) == 0 {
+            EDGES[self.below(EDGES.len())]
+        } else {
+            (self.next_u64() >> 48) as u16
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    // In PUSH PSW layout.
+    pub f: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub inte: bool,
+    // Loaded at PC, over `memory`.
+    pub program: Vec<u8>,
+    pub memory: Vec<(u16, u8)>,
+    pub steps: usize,
+}
+
+impl Case {
+    pub fn random(rng: &mut Rng) -> Case {
+        let mut case = Case {
+            a: rng.byte(),
+            b: rng.byte(),
+            c: rng.byte(),
+            d: rng.byte(),
+            e: rng.byte(),
+            h: rng.byte(),
+            l: rng.byte(),
+            f: rng.byte() & 0xd5 | 0x02,
+            sp: rng.word(),
+            pc: rng.word(),
+            inte: rng.below(2) == 1,
+            program: (0..PROGRAM_LENGTH).map(|_| rng.byte()).collect(),
+            memory: Vec::new(),
+            steps: 1 + rng.below(MAX_STEPS),
+        };
+        // Something other than zeros wherever the registers point.
+        let pointers = [
+            u16::from(case.b) << 8 | u16::from(case.c),
+            u16::from(case.d) << 8 | u16::from(case.e),
+            u16::from(case.h) << 8 | u16::from(case.l),
+            case.sp,
+        ];
+        for &pointer in pointers.iter() {
+            for offset in 0..4 {
+                case.memory.push((pointer.wrapping_add(offset), rng.byte()));
+            }
+        }
+        case
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a={:02x} f={:02x} b={:02x} c={:02x} d={:02x} e={:02x} h={:02x} l={:02x} \
+             sp={:04x} pc={:04x} inte={} steps={}\nprogram:",
+            self.a,
+            self.f,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.sp,
+            self.pc,
+            self.inte as u8,
+            self.steps
+        )?;
+        for byte in self.program.iter() {
+            write!(f, " {:02x}", byte)?;
+        }
+        if !self.memory.is_empty() {
+            write!(f, "\nmemory:")?;
+            for &(address, value) in self.memory.iter() {
+                write!(f, " {:04x}={:02x}", address, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    // The instruction, counting from 0, after which the two disagreed.
+    pub step: usize,
+    pub field: &'static str,
+    // The reference model's value, then the emulator's.
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "step {}: {} expected {}, got {}",
+            self.step, self.field, self.expected, self.actual
+        )
+    }
+}
+
+// Ports answer with a fixed function of the port number and remember what
+// was written to them.
+#[derive(Default)]
+struct Ports {
+    writes: Vec<(u8, u8)>,
+}
+
+impl Machine for Ports {
+    fn input(&self, port: u8) -> u8 {
+        port.wrapping_mul(0x25) ^ 0xa5
+    }
+
+    fn output(&mut self, port: u8, val: u8) {
+        self.writes.push((port, val));
+    }
+}
+
+fn setup(case: &Case) -> (Cpu, State) {
+    let mut cpu = Cpu::new();
+    let mut s = State::new();
+    s.rom_limit = None;
+
+    for &(address, value) in case.memory.iter() {
+        cpu.memory[address as usize] = value;
+        s.memory.set(address, value);
+    }
+    for (offset, &byte) in case.program.iter().enumerate() {
+        let address = case.pc.wrapping_add(offset as u16);
+        cpu.memory[address as usize] = byte;
+        s.memory.set(address, byte);
+    }
+
+    cpu.a = case.a;
+    cpu.b = case.b;
+    cpu.c = case.c;
+    cpu.d = case.d;
+    cpu.e = case.e;
+    cpu.h = case.h;
+    cpu.l = case.l;
+    cpu.f = case.f;
+    cpu.sp = case.sp;
+    cpu.pc = case.pc;
+    cpu.inte = case.inte;
+
+    s.a = case.a;
+    s.b = case.b;
+    s.c = case.c;
+    s.d = case.d;
+    s.e = case.e;
+    s.h = case.h;
+    s.l = case.l;
+    s.cc.set_psw(case.f);
+    s.sp = case.sp;
+    s.pc = case.pc;
+    s.int_enable = case.inte;
+    (cpu, s)
+}
+
+fn compare(step: usize, cpu: &Cpu, s: &State) -> Option<Mismatch> {
+    let byte = |n: u8| format!("{:02x}", n);
+    let word = |n: u16| format!("{:04x}", n);
+    let fields = [
+        ("pc", word(cpu.pc), word(s.pc)),
+        ("sp", word(cpu.sp), word(s.sp)),
+        ("a", byte(cpu.a), byte(s.a)),
+        ("f", byte(cpu.f), byte(s.cc.to_psw())),
+        ("b", byte(cpu.b), byte(s.b)),
+        ("c", byte(cpu.c), byte(s.c)),
+        ("d", byte(cpu.d), byte(s.d)),
+        ("e", byte(cpu.e), byte(s.e)),
+        ("h", byte(cpu.h), byte(s.h)),
+        ("l", byte(cpu.l), byte(s.l)),
+        ("inte", cpu.inte.to_string(), s.int_enable.to_string()),
+        ("halted", cpu.halted.to_string(), s.halted.to_string()),
+    ];
+    fields
+        .iter()
+        .find(|f| f.1 != f.2)
+        .map(|(field, expected, actual)| Mismatch {
+            step,
+            field,
+            expected: expected.clone(),
+            actual: actual.clone(),
+        })
+}
+
+fn format_writes(writes: &[(u16, u8)]) -> String {
+    let formatted: Vec<String> = writes
+        .iter()
+        .map(|&(address, value)| format!("{:04x}={:02x}", address, value))
+        .collect();
+    format!("[{}]", formatted.join(" "))
+}
+
+// Runs the case on both and returns the first difference. A panic in the
+// emulator counts as one.
+pub fn run_case(case: &Case) -> Option<Mismatch> {
+    let (mut cpu, mut s) = setup(case);
+    let mut expected_ports = Ports::default();
+    let mut actual_ports = Ports::default();
+
+    for step in 0..case.steps {
+        let expected_cycles = cpu.step(&mut expected_ports);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            try_emulate_instruction(&mut s, &mut actual_ports)
+        }));
+        let actual_cycles = match result {
+            Ok(Ok(cycles)) => cycles,
+            Ok(Err(e)) => {
+                return Some(Mismatch {
+                    step,
+                    field: "error",
+                    expected: "none".to_string(),
+                    actual: e.to_string(),
+                })
+            }
+            Err(e) => {
+                let message = e
+                    .downcast_ref::<&str>()
+                    .map(|m| m.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                return Some(Mismatch {
+                    step,
+                    field: "panic",
+                    expected: "none".to_string(),
+                    actual: message,
+                });
+            }
+        };
+
+        if let Some(mismatch) = compare(step, &cpu, &s) {
+            return Some(mismatch);
+        }
+        if expected_cycles != actual_cycles {
+            return Some(Mismatch {
+                step,
+                field: "cycles",
+                expected: expected_cycles.to_string(),
+                actual: actual_cycles.to_string(),
+            });
+        }
+
+        // Write order within an instruction is not architectural.
+        let mut expected_writes = cpu.writes.clone();
+        let mut actual_writes: Vec<(u16, u8)> = s
+            .memory
+            .writes()
+            .iter()
+            .map(|w| (w.address, w.new))
+            .collect();
+        expected_writes.sort();
+        actual_writes.sort();
+        if expected_writes != actual_writes {
+            return Some(Mismatch {
+                step,
+                field: "writes",
+                expected: format_writes(&expected_writes),
+                actual: format_writes(&actual_writes),
+            });
+        }
+        if expected_ports.writes != actual_ports.writes {
+            return Some(Mismatch {
+                step,
+                field: "out",
+                expected: format!("{:02x?}", expected_ports.writes),
+                actual: format!("{:02x?}", actual_ports.writes),
+            });
+        }
+        if cpu.halted {
+            break;
+        }
+    }
+    None
+}
+
+// Simpler versions of a case, most drastic first.
+fn candidates(case: &Case) -> Vec<Case> {
+    let mut candidates = Vec::new();
+    let mut with = |change: &dyn Fn(&mut Case)| {
+        let mut candidate = case.clone();
+        change(&mut candidate);
+        if candidate != *case {
+            candidates.push(candidate);
+        }
+    };
+
+    with(&|c| c.steps = 1);
+    with(&|c| c.steps -= 1);
+    with(&|c| c.memory.clear());
+    with(&|c| c.program.truncate(c.program.len() / 2));
+    with(&|c| {
+        c.program.pop();
+    });
+    for index in 0..case.program.len() {
+        with(&|c| {
+            c.program.remove(index);
+        });
+        with(&|c| {
+            c.program.remove(index);
+            c.steps = c.steps.saturating_sub(1).max(1);
+        });
+    }
+    for index in 0..case.memory.len() {
+        with(&|c| {
+            c.memory.remove(index);
+        });
+    }
+    with(&|c| c.pc = 0);
+    with(&|c| c.sp = 0);
+    with(&|c| c.f = 0x02);
+    with(&|c| c.inte = false);
+    for register in 0..7 {
+        with(&|c| {
+            let value = match register {
+                0 => &mut c.a,
+                1 => &mut c.b,
+                2 => &mut c.c,
+                3 => &mut c.d,
+                4 => &mut c.e,
+                5 => &mut c.h,
+                _ => &mut c.l,
+            };
+            *value = 0;
+        });
+    }
+    // Clearing individual flags and zeroing program bytes past the first.
+    for &bit in [0x80, 0x40, 0x10, 0x04, 0x01].iter() {
+        with(&|c| c.f &= !bit);
+    }
+    for index in 1..case.program.len() {
+        with(&|c| c.program[index] = 0);
+    }
+    candidates
+}
+
+// Greedily takes any simplification that still fails the same way, until
+// none does.
+pub fn shrink(case: &Case, mismatch: &Mismatch) -> (Case, Mismatch) {
+    shrink_with(case, mismatch, &run_case)
+}
+
+fn shrink_with(
+    case: &Case,
+    mismatch: &Mismatch,
+    run: &dyn Fn(&Case) -> Option<Mismatch>,
+) -> (Case, Mismatch) {
+    let mut best = (case.clone(), mismatch.clone());
+    'outer: loop {
+        for candidate in candidates(&best.0) {
+            if let Some(found) = run(&candidate) {
+                if found.field == best.1.field {
+                    best = (candidate, found);
+                    continue 'outer;
+                }
+            }
+        }
+        return best;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub seed: u64,
+    pub case: Case,
+    pub mismatch: Mismatch,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "seed {}: {}\n{}", self.seed, self.mismatch, self.case)
+    }
+}
+
+// Runs `cases` random cases, each seeded from `seed` and its index so any
+// one can be rerun alone, and returns the first failure, shrunk.
+// A panicking case is reported as a failure with its message, though the
+// panic hook still prints each one as it happens.
+pub fn fuzz(seed: u64, cases: usize) -> Option<Failure> {
+    for index in 0..cases as u64 {
+        let case_seed = seed.wrapping_add(index);
+        let case = Case::random(&mut Rng::new(case_seed));
+        if let Some(mismatch) = run_case(&case) {
+            let (case, mismatch) = shrink(&case, &mismatch);
+            return Some(Failure {
+                seed: case_seed,
+                case,
+                mismatch,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(program: Vec<u8>) -> Case {
+        Case {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            f: 0x02,
+            sp: 0x100,
+            pc: 0,
+            inte: false,
+            program,
+            memory: Vec::new(),
+            steps: 4,
+        }
+    }
+
+    #[test]
+    fn rng_test() {
+        let mut a = Rng::new(1);
+        let mut b = Rng::new(1);
+        assert_eq!(a.next_u64(), b.next_u64());
+        assert_ne!(a.next_u64(), Rng::new(2).next_u64());
+        assert!((0..100).all(|_| a.below(6) < 6));
+    }
+
+    #[test]
+    fn agree_test() {
+        // MVI A,3a; ADI c7; DAA; PUSH PSW
+        assert_eq!(
+            run_case(&case(vec![0x3e, 0x3a, 0xc6, 0xc7, 0x27, 0xf5])),
+            None
+        );
+    }
+
+    // DAA has few enough inputs to check them all.
+    #[test]
+    fn daa_test() {
+        for a in 0..=255 {
+            for &f in &[0x02, 0x03, 0x12, 0x13] {
+                let mut daa = case(vec![0x27]);
+                daa.a = a;
+                daa.f = f;
+                daa.steps = 1;
+                assert_eq!(run_case(&daa), None, "A={:02x} F={:02x}", a, f);
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_test() {
+        // A stand-in bug: INR A with A ending in f after some other
+        // instruction has run.
+        let buggy = |c: &Case| {
+            let inr = c.program.iter().position(|&b| b == 0x3c)?;
+            if inr > 0 && inr < c.steps && c.a & 0x0f == 0x0f {
+                Some(Mismatch {
+                    step: inr,
+                    field: "f",
+                    expected: "12".to_string(),
+                    actual: "02".to_string(),
+                })
+            } else {
+                None
+            }
+        };
+        let mut failing = case(vec![0x37, 0x00, 0x3c, 0x00, 0x3c, 0x76]);
+        failing.memory.push((0x1234, 0x56));
+        failing.a = 0x3f;
+        failing.f = 0xd7;
+        failing.sp = 0xfffe;
+        failing.steps = 6;
+        let mismatch = buggy(&failing).unwrap();
+
+        let (shrunk, _) = shrink_with(&failing, &mismatch, &buggy);
+        let mut expected = case(vec![0x00, 0x3c]);
+        expected.a = 0x3f;
+        expected.sp = 0;
+        expected.steps = 2;
+        assert_eq!(shrunk, expected);
+    }
+
+    // FUZZ_SEED and FUZZ_CASES run a different or longer campaign, e.g.
+    // FUZZ_CASES=1000000 cargo test --release fuzz_test.
+    #[test]
+    fn fuzz_test() {
+        let var = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let seed = var("FUZZ_SEED", 0x8080);
+        let cases = var("FUZZ_CASES", DEFAULT_CASES as u64) as usize;
+        if let Some(failure) = fuzz(seed, cases) {
+            panic!("{}", failure);
+        }
+    }
+}
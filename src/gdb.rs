@@ -0,0 +1,711 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use cpu::{run_for_cycles, try_emulate_instruction, StopReason};
+use machine::Machine;
+use state::State;
+
+// GDB has no 8080 target, so registers are exposed as six 16-bit pairs in
+// target (little-endian) byte order: AF, BC, DE, HL, SP, PC.
+const REGISTER_COUNT: usize = 6;
+
+// Continue runs in slices of this many cycles, checking between them
+// whether the client has asked to interrupt.
+const CONTINUE_SLICE: usize = 10_000;
+
+// The largest packet the stub accepts or sends, as advertised to the
+// client. A memory read replies with two hex digits a byte, so it can ask
+// for at most half this.
+const PACKET_SIZE: usize = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// Waits for a single debugger connection on localhost.
+pub fn listen_tcp(port: u16) -> io::Result<Server<TcpStream>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    // Acks and replies are tiny writes; don't let Nagle hold them back.
+    stream.set_nodelay(true)?;
+    Ok(Server::new(stream))
+}
+
+#[cfg(unix)]
+pub fn listen_unix(path: impl AsRef<Path>) -> io::Result<Server<UnixStream>> {
+    let listener = UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    Ok(Server::new(stream))
+}
+
+pub struct Server<C: Connection> {
+    connection: C,
+    input: VecDeque<u8>,
+    ack: bool,
+    last_reply: Vec<u8>,
+}
+
+impl<C: Connection> Server<C> {
+    pub fn new(connection: C) -> Server<C> {
+        Server {
+            connection,
+            input: VecDeque::new(),
+            ack: true,
+            last_reply: Vec::new(),
+        }
+    }
+
+    // Serves requests until the debugger kills or detaches from the
+    // target, or closes the connection. Closing it while the target runs
+    // is an UnexpectedEof error.
+    pub fn serve(&mut self, s: &mut State, m: &mut impl Machine) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match packet.first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ if packet == b"QStartNoAckMode" => {
+                    // The OK itself is still acknowledged.
+                    self.send("OK")?;
+                    self.ack = false;
+                }
+                _ => {
+                    let reply = self.handle(&packet, s, m)?;
+                    self.send(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &[u8], s: &mut State, m: &mut impl Machine) -> io::Result<String> {
+        let command = match packet.first() {
+            Some(&command) => command,
+            None => return Ok(String::new()),
+        };
+        let args = String::from_utf8_lossy(&packet[1..]);
+        let args = args.as_ref();
+
+        let reply = match command {
+            b'?' => stop_reply(SIGTRAP),
+            b'g' => read_registers(s),
+            b'G' => ok_or_error(write_registers(s, args)),
+            b'p' => match parse_hex(args) {
+                Some(n) if (n as usize) < REGISTER_COUNT => {
+                    encode_word(get_register(s, n as usize))
+                }
+                _ => "E01".to_string(),
+            },
+            b'P' => ok_or_error(write_register(s, args)),
+            b'm' => read_memory(s, args).unwrap_or_else(|| "E01".to_string()),
+            b'M' => ok_or_error(write_memory(s, args)),
+            b's' => {
+                resume_at(s, args);
+                self.step(s, m)
+            }
+            b'c' => {
+                resume_at(s, args);
+                self.resume(s, m)?
+            }
+            b'Z' | b'z' => match parse_breakpoint(args) {
+                Some(address) => {
+                    if command == b'Z' {
+                        s.breakpoints.insert(address);
+                    } else {
+                        s.breakpoints.remove(&address);
+                    }
+                    "OK".to_string()
+                }
+                // Watchpoints are not supported.
+                None => String::new(),
+            },
+            b'H' => "OK".to_string(),
+            b'q' if args.starts_with("Supported") => {
+                format!("PacketSize={:x};QStartNoAckMode+", PACKET_SIZE)
+            }
+            b'q' if args == "Attached" => "1".to_string(),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn step(&mut self, s: &mut State, m: &mut impl Machine) -> String {
+        match try_emulate_instruction(s, m) {
+            Ok(_) => stop_reply(SIGTRAP),
+            Err(_) => stop_reply(SIGILL),
+        }
+    }
+
+    fn resume(&mut self, s: &mut State, m: &mut impl Machine) -> io::Result<String> {
+        loop {
+            let result = run_for_cycles(s, m, CONTINUE_SLICE);
+            match result.reason {
+                StopReason::BudgetExhausted => {
+                    // Each slice ignores a breakpoint at its starting PC, so
+                    // one landed on exactly at a slice boundary is caught here.
+                    if s.breakpoints.contains(&s.pc) {
+                        return Ok(stop_reply(SIGTRAP));
+                    }
+                    if self.interrupt_requested()? {
+                        return Ok(stop_reply(SIGINT));
+                    }
+                }
+                StopReason::Error(_) => return Ok(stop_reply(SIGILL)),
+                _ => return Ok(stop_reply(SIGTRAP)),
+            }
+        }
+    }
+
+    // Drains whatever the client has sent without blocking, looking for the
+    // 0x03 interrupt byte. Anything else is kept for the next packet.
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let mut buffer = [0; 256];
+        let result = loop {
+            match self.connection.read(&mut buffer) {
+                // Nobody is left to report the stop to.
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.input.extend(&buffer[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.connection.set_nonblocking(false)?;
+        result?;
+
+        match self.input.iter().position(|&b| b == 0x03) {
+            Some(index) => {
+                self.input.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.input.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns the payload of the next well-formed packet, or None once the
+    // connection closes.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(b'-') => {
+                    let reply = self.last_reply.clone();
+                    self.connection.write_all(&reply)?;
+                    continue;
+                }
+                // Acks and stray interrupts between packets.
+                Some(_) => continue,
+            }
+
+            // Anything past PACKET_SIZE is read and thrown away, and the
+            // packet refused once it ends.
+            let mut payload = Vec::new();
+            let mut overflow = false;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(_) if payload.len() == PACKET_SIZE => overflow = true,
+                    Some(byte) => payload.push(byte),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+
+            if overflow {
+                if self.ack {
+                    self.connection.write_all(b"+")?;
+                }
+                self.send("E01")?;
+                continue;
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected == Some(checksum_of(&payload)) {
+                if self.ack {
+                    self.connection.write_all(b"+")?;
+                }
+                return Ok(Some(payload));
+            } else if self.ack {
+                self.connection.write_all(b"-")?;
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", reply, checksum_of(reply.as_bytes()));
+        self.last_reply = packet.into_bytes();
+        self.connection.write_all(&self.last_reply)?;
+        self.connection.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn encode_word(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xff, value >> 8)
+}
+
+fn decode_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn get_register(s: &State, n: usize) -> u16 {
+    match n {
+        0 => u16::from(s.a) << 8 | u16::from(s.cc.to_psw()),
+        1 => s.get_bc(),
+        2 => s.get_de(),
+        3 => s.get_hl_address(),
+        4 => s.sp,
+        5 => s.pc,
+        _ => unreachable!(),
+    }
+}
+
+fn set_register(s: &mut State, n: usize, value: u16) {
+    match n {
+        0 => {
+            s.a = (value >> 8) as u8;
+            s.cc.set_psw(value as u8);
+        }
+        1 => s.set_bc(value),
+        2 => s.set_de(value),
+        3 => s.set_hl(value),
+        4 => s.sp = value,
+        5 => s.pc = value,
+        _ => unreachable!(),
+    }
+}
+
+fn read_registers(s: &State) -> String {
+    (0..REGISTER_COUNT)
+        .map(|n| encode_word(get_register(s, n)))
+        .collect()
+}
+
+fn write_registers(s: &mut State, args: &str) -> Option<()> {
+    let bytes = decode_bytes(args)?;
+    if bytes.len() < REGISTER_COUNT * 2 {
+        return None;
+    }
+    for n in 0..REGISTER_COUNT {
+        let value = u16::from(bytes[2 * n]) | u16::from(bytes[2 * n + 1]) << 8;
+        set_register(s, n, value);
+    }
+    Some(())
+}
+
+fn write_register(s: &mut State, args: &str) -> Option<()> {
+    let mut parts = args.splitn(2, '=');
+    let n = parse_hex(parts.next()?)? as usize;
+    let bytes = decode_bytes(parts.next()?)?;
+    if n >= REGISTER_COUNT || bytes.len() != 2 {
+        return None;
+    }
+    set_register(s, n, u16::from(bytes[0]) | u16::from(bytes[1]) << 8);
+    Some(())
+}
+
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let address = parse_hex(parts.next()?)?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, length))
+}
+
+fn read_memory(s: &State, args: &str) -> Option<String> {
+    let (address, length) = parse_range(args)?;
+    if length > PACKET_SIZE / 2 {
+        return None;
+    }
+    Some(
+        (0..length)
+            .map(|offset| format!("{:02x}", s.memory.get(address.wrapping_add(offset as u16))))
+            .collect(),
+    )
+}
+
+fn write_memory(s: &mut State, args: &str) -> Option<()> {
+    let mut parts = args.splitn(2, ':');
+    let (address, length) = parse_range(parts.next()?)?;
+    let bytes = decode_bytes(parts.next()?)?;
+    if bytes.len() != length {
+        return None;
+    }
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        s.memory.set(address.wrapping_add(offset as u16), byte);
+    }
+    Some(())
+}
+
+// Software (Z0) and hardware (Z1) breakpoints both map onto the emulator's
+// breakpoint set, so no memory is patched.
+fn parse_breakpoint(args: &str) -> Option<u16> {
+    let mut parts = args.split(',');
+    match parts.next()? {
+        "0" | "1" => parse_hex(parts.next()?),
+        _ => None,
+    }
+}
+
+fn resume_at(s: &mut State, args: &str) {
+    if let Some(address) = parse_hex(args) {
+        s.pc = address;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use testing::{state_with_program, TestMachine};
+
+    struct Client<S: Read + Write> {
+        stream: S,
+    }
+
+    impl<S: Read + Write> Client<S> {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, packet: &str) {
+            let data = format!("${}#{:02x}", packet, checksum_of(packet.as_bytes()));
+            self.stream.write_all(data.as_bytes()).unwrap();
+        }
+
+        fn receive(&mut self) -> String {
+            while self.read_byte() != b'$' {}
+            let mut payload = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => payload.push(byte),
+                }
+            }
+            self.read_byte();
+            self.read_byte();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(payload).unwrap()
+        }
+
+        fn kill(&mut self) {
+            self.send("k");
+            assert_eq!(self.read_byte(), b'+');
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            assert_eq!(self.read_byte(), b'+');
+            self.receive()
+        }
+    }
+
+    // 0000: MVI A,05
+    // 0002: DCR A
+    // 0003: JNZ 0002
+    // 0006: HLT
+    fn program_state() -> State {
+        let mut state = state_with_program(vec![0x3e, 0x05, 0x3d, 0xc2, 0x02, 0x00, 0x76]);
+        state.sp = 0x2400;
+        state
+    }
+
+    fn serve_tcp(script: impl FnOnce(&mut Client<TcpStream>) + Send + 'static) -> State {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            script(&mut Client { stream });
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut state = program_state();
+        Server::new(stream)
+            .serve(&mut state, &mut TestMachine::default())
+            .unwrap();
+        client.join().unwrap();
+        state
+    }
+
+    #[test]
+    fn checksum_test() {
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+        assert_eq!(checksum_of(b""), 0);
+    }
+
+    #[test]
+    fn registers_test() {
+        let state = serve_tcp(|client| {
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("g"), "020000000000000000240000");
+            assert_eq!(client.request("P3=3412"), "OK");
+            assert_eq!(client.request("p3"), "3412");
+            assert_eq!(client.request("p9"), "E01");
+            assert_eq!(client.request("Gd54102000000000000240001"), "OK");
+            assert_eq!(client.request("g"), "d74102000000000000240001");
+            client.kill();
+        });
+
+        assert_eq!(state.a, 0x41);
+        assert_eq!(state.cc.z, true);
+        assert_eq!(state.cc.ac, true);
+        assert_eq!(state.cc.cy, true);
+        assert_eq!(state.get_bc(), 0x0002);
+        assert_eq!(state.pc, 0x0100);
+    }
+
+    #[test]
+    fn memory_test() {
+        let state = serve_tcp(|client| {
+            assert_eq!(client.request("m0,7"), "3e053dc2020076");
+            assert_eq!(client.request("M2400,3:aabbcc"), "OK");
+            assert_eq!(client.request("m23ff,5"), "00aabbcc00");
+            assert_eq!(client.request("M2400,2:aa"), "E01");
+            // Replies larger than the advertised packet size are refused.
+            assert_eq!(client.request("m0,800").len(), 0x1000);
+            assert_eq!(client.request("m0,801"), "E01");
+            assert_eq!(client.request("mffff,ffffffff"), "E01");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        assert_eq!(state.memory.get(0x2401), 0xbb);
+    }
+
+    #[test]
+    fn step_and_continue_test() {
+        let state = serve_tcp(|client| {
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p5"), "0200");
+            assert_eq!(client.request("p0"), "0205");
+
+            assert_eq!(client.request("Z0,3,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "0300");
+            // DCR A from 05 sets AC: no borrow out of the low nibble.
+            assert_eq!(client.request("p0"), "1204");
+
+            assert_eq!(client.request("z0,3,1"), "OK");
+            assert_eq!(client.request("Z1,6,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "0600");
+
+            // Running into the HLT also stops.
+            assert_eq!(client.request("z1,6,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "0700");
+            client.kill();
+        });
+
+        assert_eq!(state.halted, true);
+        assert_eq!(state.breakpoints.is_empty(), true);
+    }
+
+    #[test]
+    fn unsupported_test() {
+        serve_tcp(|client| {
+            assert_eq!(client.request("Z2,2400,1"), "");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+            assert_eq!(client.request("Hg0"), "OK");
+            assert_eq!(
+                client.request("qSupported:swbreak+"),
+                "PacketSize=1000;QStartNoAckMode+"
+            );
+            client.kill();
+        });
+    }
+
+    #[test]
+    fn malformed_packet_test() {
+        serve_tcp(|client| {
+            client.stream.write_all(b"$#00").unwrap();
+            assert_eq!(client.read_byte(), b'+');
+            assert_eq!(client.receive(), "");
+            // A first byte that isn't a whole character.
+            assert_eq!(client.request("\u{e9}g"), "");
+            client.stream.write_all(b"$\xff#ff").unwrap();
+            assert_eq!(client.read_byte(), b'+');
+            assert_eq!(client.receive(), "");
+            assert_eq!(client.request("p4"), "0024");
+            client.kill();
+        });
+    }
+
+    #[test]
+    fn oversized_packet_test() {
+        serve_tcp(|client| {
+            let packet = format!("M2400,{:x}:{}", PACKET_SIZE, "00".repeat(PACKET_SIZE));
+            assert_eq!(client.request(&packet), "E01");
+            assert_eq!(client.request("p4"), "0024");
+            client.kill();
+        });
+    }
+
+    #[test]
+    fn bad_checksum_test() {
+        serve_tcp(|client| {
+            client.stream.write_all(b"$g#00").unwrap();
+            assert_eq!(client.read_byte(), b'-');
+            assert_eq!(client.request("p4"), "0024");
+            client.kill();
+        });
+    }
+
+    #[test]
+    fn interrupt_test() {
+        let mut state = State::new();
+        // JMP 0000 forever.
+        state.memory.load(0, vec![0xc3, 0x00, 0x00]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut client = Client { stream };
+            client.send("c");
+            assert_eq!(client.read_byte(), b'+');
+            client.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(client.receive(), "S02");
+            client.kill();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        Server::new(stream)
+            .serve(&mut state, &mut TestMachine::default())
+            .unwrap();
+        client.join().unwrap();
+        assert!(state.cycles > 0);
+    }
+
+    #[test]
+    fn disconnect_test() {
+        let mut state = State::new();
+        // JMP 0000 forever.
+        state.memory.load(0, vec![0xc3, 0x00, 0x00]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut client = Client { stream };
+            client.send("c");
+            assert_eq!(client.read_byte(), b'+');
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let result = Server::new(stream).serve(&mut state, &mut TestMachine::default());
+        client.join().unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn no_ack_mode_test() {
+        serve_tcp(|client| {
+            assert_eq!(client.request("QStartNoAckMode"), "OK");
+            client.send("p4");
+            assert_eq!(client.receive(), "0024");
+            client.send("k");
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_test() {
+        let path = std::env::temp_dir().join(format!("virtual_8080_gdb_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let mut stream = None;
+            while stream.is_none() {
+                stream = UnixStream::connect(&client_path).ok();
+                thread::yield_now();
+            }
+            let mut client = Client {
+                stream: stream.unwrap(),
+            };
+            assert_eq!(client.request("m0,2"), "3e05");
+            client.kill();
+        });
+
+        let mut state = program_state();
+        listen_unix(&path)
+            .unwrap()
+            .serve(&mut state, &mut TestMachine::default())
+            .unwrap();
+        client.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
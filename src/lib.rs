@@ -1,10 +1,36 @@
-#![feature(nll)]
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
 
+pub mod bench;
+pub mod blockcache;
 pub mod bytes;
+pub mod callstack;
+pub mod cfg;
+pub mod coverage;
 pub mod cpu;
+pub mod disasm;
+pub mod dispatch;
 pub mod flags;
+pub mod fuzz;
+pub mod gdb;
+pub mod json;
+pub mod loader;
 pub mod machine;
 pub mod memory;
+pub mod profiler;
 pub mod program;
+pub mod recompile;
+pub mod reference;
+pub mod rewind;
+pub mod savestate;
+pub mod scheduler;
+pub mod singlestep;
+pub mod smc;
 pub mod stack;
 pub mod state;
+pub mod symbols;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+pub mod trace;
+pub mod tracediff;
+pub mod video;
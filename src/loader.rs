@@ -0,0 +1,294 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use state::State;
+
+// CP/M programs are loaded at the start of the transient program area and
+// call the BDOS through the jump at 0x0005.
+pub const COM_START: u16 = 0x0100;
+pub const BDOS_ENTRY: u16 = 0x0005;
+const BDOS_TOP: u16 = 0xfe00;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Rom,
+    Hex,
+    Com,
+}
+
+impl Format {
+    // Guesses the format from the file extension, defaulting to a raw ROM.
+    pub fn from_path(path: &Path) -> Format {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hex") | Some("ihx") => Format::Hex,
+            Some("com") => Format::Com,
+            _ => Format::Rom,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    BadHex { line: usize, reason: String },
+    TooLarge { address: u16, len: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "I/O error: {}", e),
+            LoadError::BadHex { line, reason } => write!(f, "line {}: {}", line, reason),
+            LoadError::TooLarge { address, len } => {
+                write!(f, "{} bytes at 0x{:04x} do not fit in memory", len, address)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+fn check_fits(address: u16, len: usize) -> Result<(), LoadError> {
+    if address as usize + len > 0x10000 {
+        return Err(LoadError::TooLarge { address, len });
+    }
+    Ok(())
+}
+
+// Loads a raw image and starts execution at its first byte. Running off the
+// end of the image is reported as an error.
+pub fn load_rom(s: &mut State, data: Vec<u8>, address: u16) -> Result<(), LoadError> {
+    check_fits(address, data.len())?;
+    if data.is_empty() {
+        return Ok(());
+    }
+    s.rom_limit = Some(address + (data.len() - 1) as u16);
+    s.memory.load(address, data);
+    s.pc = address;
+    Ok(())
+}
+
+// Parses Intel HEX data records. Only 16-bit addressing is meaningful on
+// the 8080, so extended address records must be zero.
+pub fn parse_hex(text: &str) -> Result<Vec<Record>, LoadError> {
+    let mut records = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let bad = |reason: &str| LoadError::BadHex {
+            line: line_number,
+            reason: reason.to_string(),
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(bad("missing ':'"));
+        }
+        let digits = &line[1..];
+        // Checked first, as the pairs below are sliced by byte.
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(bad("invalid hex digit"));
+        }
+        if digits.len() % 2 != 0 {
+            return Err(bad("odd number of hex digits"));
+        }
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| bad("invalid hex digit"))?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(bad("wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(bad("bad checksum"));
+        }
+
+        let address = u16::from(bytes[1]) << 8 | u16::from(bytes[2]);
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => {
+                check_fits(address, data.len())?;
+                records.push(Record {
+                    address,
+                    data: data.to_vec(),
+                });
+            }
+            0x01 => return Ok(records),
+            0x02 | 0x04 => {
+                if data.iter().any(|&b| b != 0) {
+                    return Err(bad("extended address out of range"));
+                }
+            }
+            // Start address records; execution starts where the user says.
+            0x03 | 0x05 => (),
+            _ => return Err(bad("unknown record type")),
+        }
+    }
+
+    Ok(records)
+}
+
+// Returns the lowest address loaded, where execution starts.
+pub fn load_hex(s: &mut State, text: &str) -> Result<u16, LoadError> {
+    let records = parse_hex(text)?;
+    let start = records.iter().map(|r| r.address).min().unwrap_or(0);
+    for record in records {
+        s.memory.load(record.address, record.data);
+    }
+    s.rom_limit = None;
+    s.pc = start;
+    Ok(start)
+}
+
+// Sets up a minimal CP/M environment: a warm boot at 0x0000 halts, and the
+// BDOS jump at 0x0005 points at the top of the TPA so that programs sizing
+// memory from it see a sensible value. BDOS calls themselves have to be
+// intercepted by the caller when PC reaches BDOS_ENTRY.
+pub fn load_com(s: &mut State, data: Vec<u8>) -> Result<(), LoadError> {
+    check_fits(COM_START, data.len())?;
+    s.memory.load(0x0000, vec![0x76]);
+    s.memory.load(
+        BDOS_ENTRY,
+        vec![0xc3, BDOS_TOP as u8, (BDOS_TOP >> 8) as u8],
+    );
+    s.memory.load(BDOS_TOP, vec![0xc9]);
+    s.memory.load(COM_START, data);
+    s.rom_limit = None;
+    s.pc = COM_START;
+    s.sp = BDOS_TOP;
+    Ok(())
+}
+
+pub fn load_file(
+    s: &mut State,
+    path: &Path,
+    format: Format,
+    address: u16,
+) -> Result<(), LoadError> {
+    match format {
+        Format::Rom => load_rom(s, fs::read(path)?, address),
+        Format::Hex => load_hex(s, &fs::read_to_string(path)?).map(|_| ()),
+        Format::Com => load_com(s, fs::read(path)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_test() {
+        assert_eq!(Format::from_path(Path::new("a/b/TEST.COM")), Format::Com);
+        assert_eq!(Format::from_path(Path::new("prog.hex")), Format::Hex);
+        assert_eq!(Format::from_path(Path::new("prog.ihx")), Format::Hex);
+        assert_eq!(Format::from_path(Path::new("invaders.h")), Format::Rom);
+        assert_eq!(Format::from_path(Path::new("invaders")), Format::Rom);
+    }
+
+    #[test]
+    fn load_rom_test() {
+        let mut state = State::new();
+        load_rom(&mut state, vec![0x3e, 0x01, 0x76], 0x1000).unwrap();
+
+        assert_eq!(state.pc, 0x1000);
+        assert_eq!(state.rom_limit, Some(0x1002));
+        assert_eq!(state.memory.get(0x1001), 0x01);
+
+        match load_rom(&mut state, vec![0; 0x20], 0xfff0) {
+            Err(LoadError::TooLarge {
+                address: 0xfff0,
+                len: 0x20,
+            }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_hex_test() {
+        let text = ":0300300002337A1E\n:03010000C3000138\n:00000001FF\n:0100000000FF\n";
+        let records = parse_hex(text).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    address: 0x0030,
+                    data: vec![0x02, 0x33, 0x7a],
+                },
+                Record {
+                    address: 0x0100,
+                    data: vec![0xc3, 0x00, 0x01],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_hex_errors_test() {
+        let cases = [
+            ("0300300002337A1E", "missing ':'"),
+            (":0300300002337A1F", "bad checksum"),
+            (":0400300002337A1E", "wrong record length"),
+            (":03003000023G7A1E", "invalid hex digit"),
+            (":a\u{e9}0000001FF", "invalid hex digit"),
+            (":+1", "invalid hex digit"),
+            (":020000040001F9", "extended address out of range"),
+            (":00000009F7", "unknown record type"),
+        ];
+        for &(line, reason) in cases.iter() {
+            let text = format!(":00000003FD\n{}\n", line);
+            match parse_hex(&text) {
+                Err(LoadError::BadHex {
+                    line: 2,
+                    reason: ref r,
+                }) if r == reason => (),
+                other => panic!("{}: unexpected {:?}", line, other),
+            }
+        }
+    }
+
+    #[test]
+    fn load_hex_test() {
+        let mut state = State::new();
+        let start = load_hex(&mut state, ":03010000C3000138\n:00000001FF\n").unwrap();
+
+        assert_eq!(start, 0x0100);
+        assert_eq!(state.pc, 0x0100);
+        assert_eq!(state.rom_limit, None);
+        assert_eq!(state.memory.get(0x0100), 0xc3);
+    }
+
+    #[test]
+    fn load_com_test() {
+        let mut state = State::new();
+        load_com(&mut state, vec![0x0e, 0x02, 0xcd, 0x05, 0x00]).unwrap();
+
+        assert_eq!(state.pc, COM_START);
+        assert_eq!(state.sp, 0xfe00);
+        assert_eq!(state.rom_limit, None);
+        assert_eq!(state.memory.get(0x0000), 0x76);
+        assert_eq!(state.memory.view(0x0005, 0x0007), &[0xc3, 0x00, 0xfe]);
+        assert_eq!(state.memory.get(0x0102), 0xcd);
+    }
+}
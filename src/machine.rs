@@ -1,4 +1,29 @@
 pub trait Machine {
     fn input(&self, port: u8) -> u8;
     fn output(&mut self, port: u8, val: u8);
+
+    // Device state included in save states. Machines without any state
+    // beyond the CPU and memory can rely on the defaults.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("unexpected {} bytes of device state", data.len()))
+        }
+    }
+}
+
+// A board with nothing on its ports: reads give 0 and writes are dropped.
+pub struct NoDevices;
+
+impl Machine for NoDevices {
+    fn input(&self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _val: u8) {}
 }
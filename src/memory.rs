@@ -1,12 +1,50 @@
 use std::fmt;
+use std::mem;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+const PAGES: usize = 256;
+
+// Far more than one instruction writes, so only writes made outside
+// stepping, which nothing clears, are ever dropped.
+const WRITE_LOG_LIMIT: usize = 1024;
 
 pub struct Memory {
     m: Vec<u8>,
+    writes: Vec<MemoryWrite>,
+    // 256-byte pages someone asked to hear about, and the ones among them
+    // changed since the last `take_dirty_pages`. A page is unwatched when
+    // it is first changed, so it is reported once until watched again.
+    watched: Vec<bool>,
+    dirty: Vec<u8>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Memory {
-        Memory { m: vec![0; 65536] }
+        Memory {
+            m: vec![0; 65536],
+            writes: Vec::new(),
+            watched: vec![false; PAGES],
+            dirty: Vec::new(),
+        }
+    }
+
+    fn touch(&mut self, page: usize) {
+        if self.watched[page] {
+            self.watched[page] = false;
+            self.dirty.push(page as u8);
+        }
     }
 
     pub fn get(&self, addr: u16) -> u8 {
@@ -14,24 +52,94 @@ impl Memory {
     }
 
     pub fn set(&mut self, addr: u16, data: u8) {
+        if self.writes.len() == WRITE_LOG_LIMIT {
+            self.writes.drain(..WRITE_LOG_LIMIT / 2);
+        }
+        self.writes.push(MemoryWrite {
+            address: addr,
+            old: self.m[addr as usize],
+            new: data,
+        });
+        self.touch(addr as usize >> 8);
         self.m[addr as usize] = data;
     }
 
+    // Writes made through `set` since the last call to `clear_writes`. The
+    // CPU clears this at the start of every instruction, so after a step it
+    // holds exactly that instruction's writes. Otherwise only the most
+    // recent writes are kept.
+    pub fn writes(&self) -> &[MemoryWrite] {
+        &self.writes
+    }
+
+    pub fn clear_writes(&mut self) {
+        self.writes.clear();
+    }
+
+    // Unlike `writes`, this also covers `load` and `restore`, and survives
+    // until the watcher collects it.
+    pub fn watch(&mut self, start: u16, end: u16) {
+        for page in (start as usize >> 8)..=(end as usize >> 8) {
+            self.watched[page] = true;
+        }
+    }
+
+    pub fn has_dirty_pages(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    pub fn take_dirty_pages(&mut self) -> Vec<u8> {
+        mem::take(&mut self.dirty)
+    }
+
     pub fn load(&mut self, base: u16, data: Vec<u8>) {
-        let mut addr = base;
-        for byte in data.iter() {
-            self.m[addr as usize] = *byte;
-            addr += 1;
+        let start = base as usize;
+        for page in (start >> 8)..(start + data.len() + 255) / 256 {
+            self.touch(page);
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.m[start + i] = *byte;
         }
     }
 
     pub fn view(&self, start: u16, end: u16) -> &[u8] {
         &self.m[(start as usize)..=(end as usize)]
     }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.m
+    }
+
+    pub fn restore(&mut self, data: &[u8]) {
+        assert_eq!(data.len(), self.m.len(), "bad memory image size");
+        for page in 0..PAGES {
+            self.touch(page);
+        }
+        self.m.copy_from_slice(data);
+    }
 }
 
+// Prints a hexdump of the non-zero parts of memory, with runs of all-zero
+// rows collapsed into a single "*" line.
 impl fmt::Debug for Memory {
-    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut skipping = false;
+        for (row, bytes) in self.m.chunks(16).enumerate() {
+            if bytes.iter().all(|b| *b == 0) {
+                if !skipping {
+                    writeln!(f, "*")?;
+                    skipping = true;
+                }
+                continue;
+            }
+            skipping = false;
+
+            write!(f, "{:04x}:", row * 16)?;
+            for byte in bytes {
+                write!(f, " {:02x}", byte)?;
+            }
+            writeln!(f)?;
+        }
         Ok(())
     }
 }
@@ -62,6 +170,69 @@ mod tests {
         assert_eq!(mem.m[0x8080], 0xbc);
     }
 
+    #[test]
+    fn writes_test() {
+        let mut mem = Memory::new();
+
+        mem.set(0x1000, 0x11);
+        mem.set(0x1000, 0x22);
+        mem.load(0x2000, vec![0x33]);
+        assert_eq!(
+            mem.writes(),
+            &[
+                MemoryWrite {
+                    address: 0x1000,
+                    old: 0x00,
+                    new: 0x11,
+                },
+                MemoryWrite {
+                    address: 0x1000,
+                    old: 0x11,
+                    new: 0x22,
+                },
+            ]
+        );
+
+        mem.clear_writes();
+        assert!(mem.writes().is_empty());
+    }
+
+    #[test]
+    fn write_log_limit_test() {
+        let mut mem = Memory::new();
+
+        for n in 0..10 * WRITE_LOG_LIMIT {
+            mem.set(n as u16, 0x11);
+        }
+        assert!(mem.writes().len() <= WRITE_LOG_LIMIT);
+        let last = mem.writes().last().unwrap();
+        assert_eq!(last.address as usize, 10 * WRITE_LOG_LIMIT - 1);
+    }
+
+    #[test]
+    fn dirty_pages_test() {
+        let mut mem = Memory::new();
+
+        mem.set(0x1000, 0x11);
+        assert!(!mem.has_dirty_pages());
+
+        mem.watch(0x10f0, 0x1110);
+        mem.set(0x1000, 0x22);
+        mem.set(0x1001, 0x33);
+        mem.set(0x1234, 0x44);
+        mem.load(0x10ff, vec![0x55, 0x66]);
+        assert!(mem.has_dirty_pages());
+        assert_eq!(mem.take_dirty_pages(), vec![0x10, 0x11]);
+        assert!(!mem.has_dirty_pages());
+
+        // Reported once until watched again.
+        mem.set(0x1000, 0x77);
+        assert!(mem.take_dirty_pages().is_empty());
+        mem.watch(0xff00, 0xffff);
+        mem.restore(&[0; 65536]);
+        assert_eq!(mem.take_dirty_pages(), vec![0xff]);
+    }
+
     #[test]
     fn load_test() {
         let mut mem = Memory::new();
@@ -72,6 +243,10 @@ mod tests {
         assert_eq!(mem.m[0xabce], 0x08);
         assert_eq!(mem.m[0xabcf], 0x22);
         assert_eq!(mem.m[0xabd0], 0x8a);
+
+        // Up to the last byte of memory.
+        mem.load(0xfffe, vec![0x11, 0x22]);
+        assert_eq!(mem.m[0xffff], 0x22);
     }
 
     #[test]
@@ -90,4 +265,30 @@ mod tests {
         assert_eq!(buffer[3], 0x14);
         assert_eq!(buffer.len(), 4);
     }
+    #[test]
+    fn restore_test() {
+        let mut mem = Memory::new();
+        let mut image = vec![0; 65536];
+        image[0x1234] = 0x56;
+
+        mem.restore(&image);
+        assert_eq!(mem.get(0x1234), 0x56);
+        assert_eq!(mem.as_slice(), &image[..]);
+    }
+
+    #[test]
+    fn debug_test() {
+        let mut mem = Memory::new();
+        mem.set(0x0010, 0xab);
+        mem.set(0xfff0, 0x01);
+
+        let dump = format!("{:?}", mem);
+        assert_eq!(
+            dump,
+            "*\n\
+             0010: ab 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+             *\n\
+             fff0: 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n"
+        );
+    }
 }
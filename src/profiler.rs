@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use callstack::CallStack;
+use symbols::Symbols;
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+// A chain of subroutine entry points from the outermost call inwards, and
+// what ran while it was the current stack.
+#[derive(Debug, Clone)]
+struct Path {
+    frames: Vec<u16>,
+    instructions: u64,
+    cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AddressProfile {
+    pub address: u16,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+// Subroutines are identified by their entry point; `None` is code that
+// runs outside any tracked call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FunctionProfile {
+    pub entry: Option<u16>,
+    pub calls: u64,
+    pub instructions: u64,
+    pub self_cycles: u64,
+    pub total_cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub caller: Option<u16>,
+    pub callee: u16,
+    pub calls: u64,
+    pub cycles: u64,
+}
+
+// Counts instructions and cycles per PC and per call path. Subroutines come
+// from the shadow call stack, so CALL, RST and interrupts all count as
+// calls.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    instructions: Vec<u64>,
+    cycles: Vec<u64>,
+    paths: Vec<Path>,
+    index: HashMap<Vec<u16>, usize>,
+    current: usize,
+    // Enough of the call stack to notice when the current path changes.
+    key: (usize, Option<(u16, u16)>),
+    calls: HashMap<(Option<u16>, u16), u64>,
+}
+
+fn stack_key(stack: &CallStack) -> (usize, Option<(u16, u16)>) {
+    (
+        stack.depth(),
+        stack.frames().last().map(|f| (f.slot, f.target)),
+    )
+}
+
+fn function_name(entry: Option<u16>, symbols: &Symbols) -> String {
+    match entry {
+        Some(address) => symbols.format(address),
+        None => "[top]".to_string(),
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profiler {
+    // Starts attributing time to the calls already on the stack.
+    pub fn new(stack: &CallStack) -> Profiler {
+        let mut profiler = Profiler {
+            instructions: vec![0; ADDRESS_SPACE],
+            cycles: vec![0; ADDRESS_SPACE],
+            paths: Vec::new(),
+            index: HashMap::new(),
+            current: 0,
+            key: stack_key(stack),
+            calls: HashMap::new(),
+        };
+        profiler.current = profiler.intern(stack);
+        profiler
+    }
+
+    fn intern(&mut self, stack: &CallStack) -> usize {
+        let frames: Vec<u16> = stack.frames().iter().map(|f| f.target).collect();
+        if let Some(&index) = self.index.get(&frames) {
+            return index;
+        }
+        self.paths.push(Path {
+            frames: frames.clone(),
+            instructions: 0,
+            cycles: 0,
+        });
+        self.index.insert(frames, self.paths.len() - 1);
+        self.paths.len() - 1
+    }
+
+    // Called after each instruction with the PC it ran at, the cycles it
+    // took and the call stack it left behind.
+    pub fn record(&mut self, pc: u16, cycles: usize, stack: &CallStack) {
+        let cycles = cycles as u64;
+        self.instructions[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+        let path = &mut self.paths[self.current];
+        path.instructions += 1;
+        path.cycles += cycles;
+
+        let key = stack_key(stack);
+        if key == self.key {
+            return;
+        }
+        if let Some(frame) = stack.frames().last() {
+            if key.0 > self.key.0 && frame.call_site == pc {
+                let caller = self.paths[self.current].frames.last().cloned();
+                *self.calls.entry((caller, frame.target)).or_insert(0) += 1;
+            }
+        }
+        self.key = key;
+        self.current = self.intern(stack);
+    }
+
+    pub fn total_instructions(&self) -> u64 {
+        self.paths.iter().map(|p| p.instructions).sum()
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.paths.iter().map(|p| p.cycles).sum()
+    }
+
+    // Every address that ran, hottest first.
+    pub fn addresses(&self) -> Vec<AddressProfile> {
+        let mut addresses: Vec<AddressProfile> = (0..ADDRESS_SPACE)
+            .filter(|&a| self.instructions[a] > 0)
+            .map(|a| AddressProfile {
+                address: a as u16,
+                instructions: self.instructions[a],
+                cycles: self.cycles[a],
+            })
+            .collect();
+        addresses.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.address.cmp(&b.address)));
+        addresses
+    }
+
+    // Recursive calls only count once towards a function's total.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: HashMap<Option<u16>, FunctionProfile> = HashMap::new();
+        let blank = |entry| FunctionProfile {
+            entry,
+            calls: 0,
+            instructions: 0,
+            self_cycles: 0,
+            total_cycles: 0,
+        };
+
+        for path in self.paths.iter() {
+            let innermost = path.frames.last().cloned();
+            let f = functions
+                .entry(innermost)
+                .or_insert_with(|| blank(innermost));
+            f.instructions += path.instructions;
+            f.self_cycles += path.cycles;
+
+            let mut seen: Vec<Option<u16>> = vec![None];
+            seen.extend(path.frames.iter().map(|&a| Some(a)));
+            seen.sort();
+            seen.dedup();
+            for function in seen {
+                let f = functions.entry(function).or_insert_with(|| blank(function));
+                f.total_cycles += path.cycles;
+            }
+        }
+        for (&(_, callee), &calls) in self.calls.iter() {
+            let f = functions
+                .entry(Some(callee))
+                .or_insert_with(|| blank(Some(callee)));
+            f.calls += calls;
+        }
+
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            b.total_cycles
+                .cmp(&a.total_cycles)
+                .then(b.self_cycles.cmp(&a.self_cycles))
+                .then(a.entry.cmp(&b.entry))
+        });
+        functions
+    }
+
+    // Caller to callee arcs, with the cycles spent in the callee (and
+    // below) when called from that caller.
+    pub fn edges(&self) -> Vec<Edge> {
+        let mut edges: HashMap<(Option<u16>, u16), Edge> = HashMap::new();
+        for (&(caller, callee), &calls) in self.calls.iter() {
+            edges.insert(
+                (caller, callee),
+                Edge {
+                    caller,
+                    callee,
+                    calls,
+                    cycles: 0,
+                },
+            );
+        }
+        for path in self.paths.iter() {
+            let mut seen = Vec::new();
+            let mut caller = None;
+            for &callee in path.frames.iter() {
+                if !seen.contains(&(caller, callee)) {
+                    seen.push((caller, callee));
+                    edges
+                        .entry((caller, callee))
+                        .or_insert(Edge {
+                            caller,
+                            callee,
+                            calls: 0,
+                            cycles: 0,
+                        })
+                        .cycles += path.cycles;
+                }
+                caller = Some(callee);
+            }
+        }
+
+        let mut edges: Vec<Edge> = edges.into_values().collect();
+        edges.sort_by(|a, b| {
+            b.cycles
+                .cmp(&a.cycles)
+                .then(a.caller.cmp(&b.caller))
+                .then(a.callee.cmp(&b.callee))
+        });
+        edges
+    }
+
+    // Functions by self time, then the hottest `limit` addresses.
+    pub fn write_flat(
+        &self,
+        out: &mut dyn Write,
+        symbols: &Symbols,
+        limit: usize,
+    ) -> io::Result<()> {
+        let total = self.total_cycles();
+        writeln!(
+            out,
+            "{} instructions, {} cycles",
+            self.total_instructions(),
+            total
+        )?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>6} {:>12} {:>12} {:>8}  function",
+            "self%", "self", "total", "calls"
+        )?;
+        let mut functions = self.functions();
+        functions.sort_by(|a, b| {
+            b.self_cycles
+                .cmp(&a.self_cycles)
+                .then(a.entry.cmp(&b.entry))
+        });
+        for f in functions.iter() {
+            writeln!(
+                out,
+                "{:>5.1}% {:>12} {:>12} {:>8}  {}",
+                percent(f.self_cycles, total),
+                f.self_cycles,
+                f.total_cycles,
+                f.calls,
+                function_name(f.entry, symbols)
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "{:>6} {:>12} {:>10}  address", "%", "cycles", "count")?;
+        for a in self.addresses().iter().take(limit) {
+            let name = match symbols.lookup(a.address) {
+                Some(_) => format!("  {}", symbols.format(a.address)),
+                None => String::new(),
+            };
+            writeln!(
+                out,
+                "{:>5.1}% {:>12} {:>10}  {:04x}{}",
+                percent(a.cycles, total),
+                a.cycles,
+                a.instructions,
+                a.address,
+                name
+            )?;
+        }
+        Ok(())
+    }
+
+    // One block per function, by total time, listing who called it and what
+    // it called.
+    pub fn write_call_graph(&self, out: &mut dyn Write, symbols: &Symbols) -> io::Result<()> {
+        let total = self.total_cycles();
+        let edges = self.edges();
+        for f in self.functions() {
+            writeln!(
+                out,
+                "{}  total {} ({:.1}%)  self {} ({:.1}%)  calls {}",
+                function_name(f.entry, symbols),
+                f.total_cycles,
+                percent(f.total_cycles, total),
+                f.self_cycles,
+                percent(f.self_cycles, total),
+                f.calls
+            )?;
+            for e in edges.iter().filter(|e| Some(e.callee) == f.entry) {
+                writeln!(
+                    out,
+                    "    <- {}  calls {}  cycles {}",
+                    function_name(e.caller, symbols),
+                    e.calls,
+                    e.cycles
+                )?;
+            }
+            for e in edges.iter().filter(|e| e.caller == f.entry) {
+                writeln!(
+                    out,
+                    "    -> {}  calls {}  cycles {}",
+                    function_name(Some(e.callee), symbols),
+                    e.calls,
+                    e.cycles
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // `[top];Outer;Inner CYCLES` lines, as read by flamegraph.pl and
+    // inferno.
+    pub fn write_folded(&self, out: &mut dyn Write, symbols: &Symbols) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .paths
+            .iter()
+            .filter(|p| p.cycles > 0)
+            .map(|p| {
+                let mut names = vec![function_name(None, symbols)];
+                names.extend(p.frames.iter().map(|&a| function_name(Some(a), symbols)));
+                format!("{} {}", names.join(";"), p.cycles)
+            })
+            .collect();
+        lines.sort();
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use callstack::{Frame, FrameKind};
+
+    fn call(stack: &mut CallStack, call_site: u16, target: u16) {
+        let slot = 0x2400 - 2 * (stack.depth() as u16 + 1);
+        stack.enter(Frame {
+            kind: FrameKind::Call,
+            call_site,
+            target,
+            return_address: call_site + 3,
+            slot,
+        });
+    }
+
+    fn ret(stack: &mut CallStack) {
+        let slot = stack.frames().last().unwrap().slot;
+        stack.ret(slot);
+    }
+
+    // [top] calls 0100 twice, which calls 0200 once each time.
+    fn sample() -> Profiler {
+        let mut stack = CallStack::new();
+        let mut profiler = Profiler::new(&stack);
+
+        for _ in 0..2 {
+            profiler.record(0x0010, 4, &stack);
+            call(&mut stack, 0x0011, 0x0100);
+            profiler.record(0x0011, 17, &stack);
+            profiler.record(0x0100, 7, &stack);
+            call(&mut stack, 0x0101, 0x0200);
+            profiler.record(0x0101, 17, &stack);
+            profiler.record(0x0200, 10, &stack);
+            ret(&mut stack);
+            profiler.record(0x0201, 10, &stack);
+            ret(&mut stack);
+            profiler.record(0x0104, 10, &stack);
+        }
+        profiler
+    }
+
+    #[test]
+    fn addresses_test() {
+        let profiler = sample();
+
+        assert_eq!(profiler.total_instructions(), 14);
+        assert_eq!(profiler.total_cycles(), 150);
+        let addresses = profiler.addresses();
+        assert_eq!(addresses.len(), 7);
+        assert_eq!(
+            addresses[0],
+            AddressProfile {
+                address: 0x0011,
+                instructions: 2,
+                cycles: 34,
+            }
+        );
+    }
+
+    #[test]
+    fn functions_test() {
+        let profiler = sample();
+        let functions = profiler.functions();
+
+        assert_eq!(
+            functions,
+            vec![
+                FunctionProfile {
+                    entry: None,
+                    calls: 0,
+                    instructions: 4,
+                    self_cycles: 42,
+                    total_cycles: 150,
+                },
+                FunctionProfile {
+                    entry: Some(0x0100),
+                    calls: 2,
+                    instructions: 6,
+                    self_cycles: 68,
+                    total_cycles: 108,
+                },
+                FunctionProfile {
+                    entry: Some(0x0200),
+                    calls: 2,
+                    instructions: 4,
+                    self_cycles: 40,
+                    total_cycles: 40,
+                },
+            ]
+        );
+
+        let edges = profiler.edges();
+        assert_eq!(edges.len(), 2);
+        assert_eq!(
+            edges[0],
+            Edge {
+                caller: None,
+                callee: 0x0100,
+                calls: 2,
+                cycles: 108,
+            }
+        );
+    }
+
+    #[test]
+    fn recursion_test() {
+        let mut stack = CallStack::new();
+        let mut profiler = Profiler::new(&stack);
+        call(&mut stack, 0x0000, 0x0100);
+        profiler.record(0x0000, 17, &stack);
+        call(&mut stack, 0x0100, 0x0100);
+        profiler.record(0x0100, 17, &stack);
+        profiler.record(0x0103, 4, &stack);
+
+        let functions = profiler.functions();
+        let recursive = functions.iter().find(|f| f.entry == Some(0x0100)).unwrap();
+        assert_eq!(recursive.calls, 2);
+        assert_eq!(recursive.total_cycles, 21);
+    }
+
+    #[test]
+    fn reports_test() {
+        let profiler = sample();
+        let mut symbols = Symbols::new();
+        symbols.insert(0x0100, "Outer");
+        symbols.insert(0x0200, "Inner");
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, &symbols).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "[top] 42\n[top];Outer 68\n[top];Outer;Inner 40\n"
+        );
+
+        let mut graph = Vec::new();
+        profiler.write_call_graph(&mut graph, &symbols).unwrap();
+        let graph = String::from_utf8(graph).unwrap();
+        assert!(graph.contains(
+            "Outer  total 108 (72.0%)  self 68 (45.3%)  calls 2\n    <- [top]  calls 2  cycles 108\n    -> Inner  calls 2  cycles 40\n"
+        ));
+
+        let mut flat = Vec::new();
+        profiler.write_flat(&mut flat, &symbols, 2).unwrap();
+        let flat = String::from_utf8(flat).unwrap();
+        assert!(flat.starts_with("14 instructions, 150 cycles\n"));
+        assert!(flat.contains(" 45.3%           68          108        2  Outer\n"));
+        assert!(flat.ends_with(" 22.7%           34          2  0011\n 22.7%           34          2  0101  Outer+0x1\n"));
+    }
+}
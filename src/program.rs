@@ -19,7 +19,7 @@ pub trait Program {
 
 impl Program for State {
     fn get_arg(&self, offset: u16) -> u8 {
-        self.memory.get(self.pc + offset)
+        self.memory.get(self.pc.wrapping_add(offset))
     }
 }
 
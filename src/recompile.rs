@@ -0,0 +1,808 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+
+use cfg::{Block, Cfg};
+use cpu::{
+    needs_single_step, opcode_cycles, stays_halted, try_emulate_instruction, EmulationError,
+    RunResult, StopReason, CONDITIONAL_TAKEN_EXTRA,
+};
+use disasm::disassemble;
+use machine::Machine;
+use memory::Memory;
+use state::{instruction_length, State};
+
+static REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "m", "a"];
+static GET_PAIR: [&str; 4] = ["s.get_bc()", "s.get_de()", "s.get_hl_address()", "s.sp"];
+static SET_PAIR: [&str; 4] = ["s.set_bc", "s.set_de", "s.set_hl", ""];
+static ALU: [&str; 8] = [
+    "add8", "adc8", "sub8", "sbb8", "and8", "xor8", "or8", "cmp8",
+];
+static CONDITIONS: [&str; 8] = [
+    "is_nz",
+    "is_z",
+    "is_nc",
+    "is_c",
+    "is_parity_odd",
+    "is_parity_even",
+    "is_plus",
+    "is_minus",
+];
+
+const PAIR_SP_OR_PSW: usize = 3;
+
+// One basic block of generated code, with the bytes it was generated from
+// so that a runner can tell when the program has changed them.
+pub struct CompiledBlock<M> {
+    pub start: u16,
+    pub bytes: &'static [u8],
+    // Returns the cycles taken and leaves PC at the next instruction.
+    pub run: fn(&mut State, &mut M) -> usize,
+}
+
+impl<M> CompiledBlock<M> {
+    pub fn new(
+        start: u16,
+        bytes: &'static [u8],
+        run: fn(&mut State, &mut M) -> usize,
+    ) -> CompiledBlock<M> {
+        CompiledBlock { start, bytes, run }
+    }
+
+    pub fn end(&self) -> u16 {
+        self.start + (self.bytes.len() - 1) as u16
+    }
+}
+
+// The `block` function of a generated module.
+pub type Lookup<M> = fn(u16) -> Option<CompiledBlock<M>>;
+
+fn register(r: usize) -> String {
+    if r == 6 {
+        "s.get_m()".to_string()
+    } else {
+        format!("s.{}", REGISTERS[r])
+    }
+}
+
+fn set_register(r: usize, value: &str) -> String {
+    if r == 6 {
+        format!("s.set_m({});", value)
+    } else {
+        format!("s.{} = {};", REGISTERS[r], value)
+    }
+}
+
+fn set_pair(p: usize, value: &str) -> String {
+    if p == PAIR_SP_OR_PSW {
+        format!("s.sp = {};", value)
+    } else {
+        format!("{}({});", SET_PAIR[p], value)
+    }
+}
+
+fn writes_memory(opcode: u8) -> bool {
+    match opcode {
+        0x02 | 0x12 | 0x22 | 0x32 | 0x34 | 0x35 | 0x36 | 0xe3 => true,
+        0x70..=0x77 => opcode != 0x76,
+        _ => opcode & 0xcf == 0xc5,
+    }
+}
+
+// SP moving up can drop frames from the shadow call stack.
+fn raises_sp(opcode: u8) -> bool {
+    matches!(opcode, 0x31 | 0x33 | 0xf9) || opcode & 0xcf == 0xc1
+}
+
+struct Generator {
+    uses: BTreeSet<&'static str>,
+    lines: Vec<String>,
+    machine: bool,
+}
+
+impl Generator {
+    fn line(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    // Code for an instruction that falls through to the next one.
+    fn straight_line(&mut self, opcode: u8, byte: u8, word: u16) {
+        let (r, p) = (
+            ((opcode >> 3) & 0x07) as usize,
+            ((opcode >> 4) & 0x03) as usize,
+        );
+        let source = (opcode & 0x07) as usize;
+        let code = match opcode {
+            0x01 | 0x11 | 0x21 | 0x31 => set_pair(p, &format!("0x{:04x}", word)),
+            0x02 | 0x12 => format!("s.memory.set({}, s.a);", GET_PAIR[p]),
+            0x0a | 0x1a => format!("s.a = s.memory.get({});", GET_PAIR[p]),
+            0x03 | 0x13 | 0x23 | 0x33 => set_pair(p, &format!("{}.wrapping_add(1)", GET_PAIR[p])),
+            0x0b | 0x1b | 0x2b | 0x3b => set_pair(p, &format!("{}.wrapping_sub(1)", GET_PAIR[p])),
+            0x09 | 0x19 | 0x29 | 0x39 => format!("s.add16({});", GET_PAIR[p]),
+            0x34 | 0x35 => {
+                let method = if opcode == 0x34 { "inr" } else { "dcr" };
+                self.line(format!("let value = s.{}(s.get_m());", method));
+                "s.set_m(value);".to_string()
+            }
+            _ if opcode & 0xc6 == 0x04 => {
+                let method = if opcode & 0x01 == 0 { "inr" } else { "dcr" };
+                set_register(r, &format!("s.{}({})", method, register(r)))
+            }
+            _ if opcode & 0xc7 == 0x06 => set_register(r, &format!("0x{:02x}", byte)),
+            0x07 => {
+                self.line("s.cc.cy = (s.a & 0x80) != 0;".to_string());
+                "s.a = s.a.rotate_left(1);".to_string()
+            }
+            0x0f => {
+                self.line("s.cc.cy = (s.a & 0x01) != 0;".to_string());
+                "s.a = s.a.rotate_right(1);".to_string()
+            }
+            0x17 => {
+                self.line("let x = s.a;".to_string());
+                self.line("s.a = (s.cc.cy as u8) | (x << 1);".to_string());
+                "s.cc.cy = (x & 0x80) != 0;".to_string()
+            }
+            0x1f => {
+                self.line("let x = s.a;".to_string());
+                self.line("s.a = ((s.cc.cy as u8) << 7) | (x >> 1);".to_string());
+                "s.cc.cy = (x & 0x01) != 0;".to_string()
+            }
+            0x22 => {
+                self.line(format!("s.memory.set(0x{:04x}, s.l);", word));
+                format!("s.memory.set(0x{:04x}, s.h);", word.wrapping_add(1))
+            }
+            0x2a => {
+                self.line(format!("s.l = s.memory.get(0x{:04x});", word));
+                format!("s.h = s.memory.get(0x{:04x});", word.wrapping_add(1))
+            }
+            0x32 => format!("s.memory.set(0x{:04x}, s.a);", word),
+            0x3a => format!("s.a = s.memory.get(0x{:04x});", word),
+            0x27 => "s.daa();".to_string(),
+            0x2f => "s.a = !s.a;".to_string(),
+            0x37 => "s.cc.cy = true;".to_string(),
+            0x3f => "s.cc.cy = !s.cc.cy;".to_string(),
+            // MOV r,r does nothing.
+            0x40..=0x7f if r == source => return,
+            0x40..=0x7f => set_register(r, &register(source)),
+            0x80..=0xbf => format!("s.{}({});", ALU[r], register(source)),
+            _ if opcode & 0xc7 == 0xc6 => format!("s.{}(0x{:02x});", ALU[r], byte),
+            _ if opcode & 0xcf == 0xc1 => {
+                self.uses.insert("stack::Stack");
+                self.line("let word = s.pop16();".to_string());
+                if p == PAIR_SP_OR_PSW {
+                    self.uses.insert("bytes::high_order_byte");
+                    self.uses.insert("bytes::low_order_byte");
+                    self.line("s.a = high_order_byte(word);".to_string());
+                    "s.cc.set_psw(low_order_byte(word));".to_string()
+                } else {
+                    set_pair(p, "word")
+                }
+            }
+            _ if opcode & 0xcf == 0xc5 => {
+                self.uses.insert("stack::Stack");
+                if p == PAIR_SP_OR_PSW {
+                    self.uses.insert("bytes::assemble_word");
+                    "s.push16(assemble_word(s.a, s.cc.to_psw()));".to_string()
+                } else {
+                    format!("s.push16({});", GET_PAIR[p])
+                }
+            }
+            0xe3 => {
+                self.uses.insert("stack::Stack");
+                self.line("let word = s.pop16();".to_string());
+                self.line("s.push16(s.get_hl_address());".to_string());
+                "s.set_hl(word);".to_string()
+            }
+            0xeb => {
+                self.line("std::mem::swap(&mut s.d, &mut s.h);".to_string());
+                "std::mem::swap(&mut s.e, &mut s.l);".to_string()
+            }
+            0xf9 => "s.sp = s.get_hl_address();".to_string(),
+            0xd3 => {
+                self.machine = true;
+                format!("m.output(0x{:02x}, s.a);", byte)
+            }
+            0xdb => {
+                self.machine = true;
+                format!("s.a = m.input(0x{:02x});", byte)
+            }
+            0xf3 => "s.int_enable = false;".to_string(),
+            0xfb => "s.int_enable = true;".to_string(),
+            // The NOPs, documented or not.
+            _ => return,
+        };
+        self.line(code);
+        if raises_sp(opcode) {
+            self.line("s.call_stack.unwind(s.sp);".to_string());
+        }
+    }
+
+    // Code that leaves the block early, as the interpreter would see the
+    // next instruction differently.
+    fn early_exit(&mut self, condition: &str, next: u16, cycles: usize) {
+        self.line(format!("if {} {{", condition));
+        self.line(format!("    s.pc = 0x{:04x};", next));
+        self.line(format!("    return {};", cycles));
+        self.line("}".to_string());
+    }
+
+    fn enter(&mut self, kind: &str, address: u16, target: u16, length: u16) -> Vec<String> {
+        self.uses.insert("callstack::FrameKind");
+        vec![
+            format!("s.pc = 0x{:04x};", address),
+            format!(
+                "s.enter(FrameKind::{}, 0x{:04x}, {});",
+                kind, target, length
+            ),
+            "s.jumped = false;".to_string(),
+        ]
+    }
+
+    fn ret(&mut self) -> Vec<String> {
+        self.uses.insert("stack::Stack");
+        vec![
+            "s.call_stack.ret(s.sp);".to_string(),
+            "s.pc = s.pop16();".to_string(),
+            "s.call_stack.unwind(s.sp);".to_string(),
+        ]
+    }
+
+    fn taken_if(&mut self, condition: usize, taken: Vec<String>, cycles: usize) {
+        self.line(format!("if State::{}(s) {{", CONDITIONS[condition]));
+        for line in taken {
+            self.line(format!("    {}", line));
+        }
+        self.line(format!("    return {};", cycles + CONDITIONAL_TAKEN_EXTRA));
+        self.line("}".to_string());
+    }
+
+    // Code for the last instruction, which sets PC for whatever comes next.
+    fn last(&mut self, address: u16, opcode: u8, byte: u8, word: u16, cycles: usize) {
+        let next = address.wrapping_add(instruction_length(opcode));
+        let condition = ((opcode >> 3) & 0x07) as usize;
+        match opcode {
+            0x76 => {
+                self.line("s.halted = true;".to_string());
+                self.line(format!("s.pc = 0x{:04x};", next));
+            }
+            0xc3 | 0xcb => self.line(format!("s.pc = 0x{:04x};", word)),
+            0xc9 | 0xd9 => {
+                for line in self.ret() {
+                    self.line(line);
+                }
+            }
+            0xcd | 0xdd | 0xed | 0xfd => {
+                for line in self.enter("Call", address, word, 3) {
+                    self.line(line);
+                }
+            }
+            0xe9 => self.line("s.pc = s.get_hl_address();".to_string()),
+            _ => match opcode & 0xc7 {
+                0xc0 => {
+                    let taken = self.ret();
+                    self.taken_if(condition, taken, cycles);
+                    self.line(format!("s.pc = 0x{:04x};", next));
+                }
+                // A jump to the next instruction still reads as a branch to
+                // the CFG, but there is nothing to choose between.
+                0xc2 if word == next => self.line(format!("s.pc = 0x{:04x};", next)),
+                0xc2 => self.line(format!(
+                    "s.pc = if State::{}(s) {{ 0x{:04x} }} else {{ 0x{:04x} }};",
+                    CONDITIONS[condition], word, next
+                )),
+                0xc4 => {
+                    let taken = self.enter("Call", address, word, 3);
+                    self.taken_if(condition, taken, cycles);
+                    self.line(format!("s.pc = 0x{:04x};", next));
+                }
+                0xc7 => {
+                    for line in self.enter("Rst", address, u16::from(opcode & 0x38), 1) {
+                        self.line(line);
+                    }
+                }
+                _ => {
+                    self.straight_line(opcode, byte, word);
+                    self.line(format!("s.pc = 0x{:04x};", next));
+                }
+            },
+        }
+        self.line(cycles.to_string());
+    }
+}
+
+fn function_name(start: u16) -> String {
+    format!("block_{:04x}", start)
+}
+
+fn generate_block(memory: &Memory, block: &Block) -> Generator {
+    let mut generator = Generator {
+        uses: BTreeSet::new(),
+        lines: Vec::new(),
+        machine: false,
+    };
+    let mut cycles = 0;
+    for (i, &address) in block.instructions.iter().enumerate() {
+        let instruction = disassemble(memory, address);
+        let opcode = instruction.opcode();
+        let byte = memory.get(address.wrapping_add(1));
+        let word = u16::from(memory.get(address.wrapping_add(2))) << 8 | u16::from(byte);
+        let next = instruction.next_address();
+        generator.line(format!("// {:04x}: {}", address, instruction.text));
+        cycles += opcode_cycles(opcode);
+
+        if i + 1 == block.instructions.len() {
+            generator.last(address, opcode, byte, word, cycles);
+        } else {
+            generator.straight_line(opcode, byte, word);
+            if writes_memory(opcode) {
+                generator.early_exit("s.memory.has_dirty_pages()", next, cycles);
+            } else if opcode == 0xfb {
+                generator.early_exit("s.pending_interrupt.is_some()", next, cycles);
+            }
+        }
+    }
+    generator
+}
+
+// Writes a module with a function per block of the CFG and a `block`
+// function to look them up by start address. `krate` is the path the
+// module should use for this crate, such as "virtual_8080".
+pub fn write_rust(out: &mut dyn Write, memory: &Memory, cfg: &Cfg, krate: &str) -> io::Result<()> {
+    let functions: Vec<(&Block, Generator)> = cfg
+        .blocks
+        .values()
+        .map(|block| (block, generate_block(memory, block)))
+        .collect();
+    let mut uses: BTreeSet<&'static str> = [
+        "machine::Machine",
+        "recompile::CompiledBlock",
+        "state::State",
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    for (_, generator) in functions.iter() {
+        uses.extend(generator.uses.iter().cloned());
+    }
+
+    writeln!(
+        out,
+        "// Generated from {} blocks. Do not edit.",
+        cfg.blocks.len()
+    )?;
+    writeln!(out)?;
+    for path in uses.iter() {
+        writeln!(out, "use {}::{};", krate, path)?;
+    }
+    writeln!(out)?;
+
+    writeln!(
+        out,
+        "pub fn block<M: Machine>(pc: u16) -> Option<CompiledBlock<M>> {{"
+    )?;
+    writeln!(out, "    Some(match pc {{")?;
+    for (block, _) in functions.iter() {
+        let last = block.last();
+        let end = last.wrapping_add(instruction_length(memory.get(last)) - 1);
+        let bytes: Vec<String> = memory
+            .view(block.start, end)
+            .iter()
+            .map(|b| format!("0x{:02x}", b))
+            .collect();
+        writeln!(
+            out,
+            "        0x{:04x} => CompiledBlock::new(0x{:04x}, &[{}], {}),",
+            block.start,
+            block.start,
+            bytes.join(", "),
+            function_name(block.start)
+        )?;
+    }
+    writeln!(out, "        _ => return None,")?;
+    writeln!(out, "    }})")?;
+    writeln!(out, "}}")?;
+
+    for (block, generator) in functions.iter() {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "fn {}<M: Machine>(s: &mut State, {}: &mut M) -> usize {{",
+            function_name(block.start),
+            if generator.machine { "m" } else { "_m" }
+        )?;
+        for line in generator.lines.iter() {
+            writeln!(out, "    {}", line)?;
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+// Runs generated blocks where it can and the interpreter everywhere else:
+// at addresses with no block, such as the targets of computed jumps, in
+// blocks whose code has been changed since it was generated, and whenever
+// needs_single_step says so. The last of these includes a State with its
+// tracer on, which State::new's is, so callers wanting the speedup should
+// set `state.tracer = Tracer::disabled()`; `fallback_steps` counts the
+// instructions that went to the interpreter for that reason.
+pub struct Recompiled<M> {
+    lookup: Lookup<M>,
+    // Whether the pages of every block are watched yet.
+    watching: bool,
+    // Pages written at some point. Blocks on them are checked against the
+    // bytes they were generated from before they run.
+    modified: Vec<bool>,
+    compiled: u64,
+    interpreted: u64,
+    fallback_steps: u64,
+}
+
+impl<M: Machine> Recompiled<M> {
+    pub fn new(lookup: Lookup<M>) -> Recompiled<M> {
+        Recompiled {
+            lookup,
+            watching: false,
+            modified: vec![false; 0x100],
+            compiled: 0,
+            interpreted: 0,
+            fallback_steps: 0,
+        }
+    }
+
+    // Blocks run as generated code.
+    pub fn compiled(&self) -> u64 {
+        self.compiled
+    }
+
+    // Instructions run by the interpreter.
+    pub fn interpreted(&self) -> u64 {
+        self.interpreted
+    }
+
+    // Instructions run by the interpreter because needs_single_step held.
+    pub fn fallback_steps(&self) -> u64 {
+        self.fallback_steps
+    }
+
+    // Watches the pages of every block, so that any write to them, whether
+    // from the program, a device or a loader, marks them modified. Blocks
+    // that no longer match by then are marked straight away.
+    fn watch_blocks(&mut self, memory: &mut Memory) {
+        for pc in 0..=0xffff {
+            if let Some(block) = (self.lookup)(pc) {
+                let (start, end) = (block.start, block.end());
+                memory.watch(start, end);
+                if memory.view(start, end) != block.bytes {
+                    for page in (start >> 8)..=(end >> 8) {
+                        self.modified[page as usize] = true;
+                    }
+                }
+            }
+        }
+        self.watching = true;
+    }
+
+    fn intact_block(&mut self, s: &mut State) -> Option<CompiledBlock<M>> {
+        if !self.watching {
+            self.watch_blocks(&mut s.memory);
+        }
+        for page in s.memory.take_dirty_pages() {
+            self.modified[page as usize] = true;
+        }
+        if s.halted {
+            return None;
+        }
+        if needs_single_step(s) {
+            self.fallback_steps += 1;
+            return None;
+        }
+        let block = (self.lookup)(s.pc)?;
+        let (start, end) = (block.start, block.end());
+        let modified = ((start >> 8)..=(end >> 8)).any(|page| self.modified[page as usize]);
+        if modified && s.memory.view(start, end) != block.bytes {
+            return None;
+        }
+        Some(block)
+    }
+
+    // Runs one block, or one instruction if there is no intact block at PC.
+    // Blocks end early after writing to code, so that the next step sees
+    // the change.
+    pub fn step(&mut self, s: &mut State, m: &mut M) -> Result<usize, EmulationError> {
+        match self.intact_block(s) {
+            Some(block) => {
+                s.memory.clear_writes();
+                s.memory.watch(block.start, block.end());
+                let cycles = (block.run)(s, m);
+                s.cycles += cycles as u64;
+                self.compiled += 1;
+                Ok(cycles)
+            }
+            None => {
+                self.interpreted += 1;
+                try_emulate_instruction(s, m)
+            }
+        }
+    }
+
+    // Like cpu::run_for_cycles, but the budget is only checked between
+    // blocks.
+    pub fn run_for_cycles(&mut self, s: &mut State, m: &mut M, budget: usize) -> RunResult {
+        let mut cycles = 0;
+        loop {
+            if stays_halted(s) {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Halted,
+                };
+            }
+            if cycles >= budget {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::BudgetExhausted,
+                };
+            }
+            if cycles > 0 && s.breakpoints.contains(&s.pc) {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Breakpoint(s.pc),
+                };
+            }
+            match self.step(s, m) {
+                Ok(n) => cycles += n,
+                Err(e) => {
+                    return RunResult {
+                        cycles,
+                        reason: StopReason::Error(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfg::Cfg;
+    use cpu;
+    use fuzz::Rng;
+    use std::env;
+    use std::fs;
+    use testing::{state_with_program, TestMachine};
+    use trace::Tracer;
+
+    // Generated by golden_test from the programs below.
+    mod sample {
+        include!("../testdata/recompile/sample.rs");
+    }
+    mod opcodes {
+        include!("../testdata/recompile/opcodes.rs");
+    }
+
+    // A loop with calls, a conditional call and return, a jump through a
+    // table into code the CFG does not reach, an interrupt taken just
+    // after EI, and code patching another block, the rest of its own block
+    // and a block on another page that hasn't run yet.
+    fn sample_program() -> Vec<u8> {
+        let mut program = vec![0x00; 0x102];
+        let code: [(usize, &[u8]); 12] = [
+            // 0000: JMP 0040
+            (0x0000, &[0xc3, 0x40, 0x00]),
+            // 0008: MVI D,99; RET
+            (0x0008, &[0x16, 0x99, 0xc9]),
+            // 0040: LXI SP,2400; EI; MVI B,05
+            // 0046: CALL 0060; STA 0061; DCR B; JNZ 0046
+            // 0050: CZ 0070; PUSH PSW; XTHL; POP D
+            // 0056: LXI H,0080; MOV E,M; INX H; MOV D,M; XCHG; PCHL
+            (
+                0x0040,
+                &[
+                    0x31, 0x00, 0x24, 0xfb, 0x06, 0x05, 0xcd, 0x60, 0x00, 0x32, 0x61, 0x00, 0x05,
+                    0xc2, 0x46, 0x00, 0xcc, 0x70, 0x00, 0xf5, 0xe3, 0xd1, 0x21, 0x80, 0x00, 0x5e,
+                    0x23, 0x56, 0xeb, 0xe9,
+                ],
+            ),
+            // 0060: MVI A,01; ADD B; RNC; INR C; RET
+            (0x0060, &[0x3e, 0x01, 0x80, 0xd0, 0x0c, 0xc9]),
+            // 0070: MVI A,99; ADI 01; DAA; RET
+            (0x0070, &[0x3e, 0x99, 0xc6, 0x01, 0x27, 0xc9]),
+            // 0080: DW 0090, 0098
+            (0x0080, &[0x90, 0x00, 0x98, 0x00]),
+            // 0090: MVI A,42; OUT 01; JMP 00a0
+            (0x0090, &[0x3e, 0x42, 0xd3, 0x01, 0xc3, 0xa0, 0x00]),
+            // 0098: HLT
+            (0x0098, &[0x76]),
+            // 00a0: IN 02; MOV C,A; MVI A,3c; STA 00aa
+            (0x00a0, &[0xdb, 0x02, 0x4f, 0x3e, 0x3c, 0x32, 0xaa, 0x00]),
+            // 00a8: NOP; NOP; NOP (becomes INR A); RLC; MOV B,A
+            (0x00a8, &[0x00, 0x00, 0x00, 0x07, 0x47]),
+            // 00ad: MVI A,3c; STA 0100; JMP 0100
+            (0x00ad, &[0x3e, 0x3c, 0x32, 0x00, 0x01, 0xc3, 0x00, 0x01]),
+            // 0100: NOP (becomes INR A); HLT
+            (0x0100, &[0x00, 0x76]),
+        ];
+        for (address, bytes) in code.iter() {
+            program[*address..*address + bytes.len()].copy_from_slice(bytes);
+        }
+        program
+    }
+
+    fn ends_block(opcode: u8) -> bool {
+        matches!(
+            opcode,
+            0x76 | 0xc3 | 0xc9 | 0xcb | 0xcd | 0xd9 | 0xdd | 0xe9 | 0xed | 0xfd
+        ) || matches!(opcode & 0xc7, 0xc0 | 0xc2 | 0xc4 | 0xc7)
+    }
+
+    // Every opcode that does not end a block, in order, then each
+    // conditional jump, call and return taken and not, an RST and a HLT.
+    // Immediate addresses point at RAM.
+    fn opcodes_program() -> Vec<u8> {
+        let mut program = vec![0x00; 0x100];
+        // 0038: RET
+        program[0x38] = 0xc9;
+        for opcode in (0..=0xff).filter(|&op| !ends_block(op)) {
+            program.push(opcode);
+            match instruction_length(opcode) {
+                2 => program.push(0x80),
+                3 => program.extend_from_slice(&[0x80, 0x80]),
+                _ => (),
+            }
+        }
+        let subroutine = 0x0400;
+        for condition in 0..8 {
+            // Jcc skip; C(not cc) subroutine; Ccc subroutine; skip:
+            let skip = program.len() as u16 + 9;
+            program.extend_from_slice(&[0xc2 | condition << 3, skip as u8, (skip >> 8) as u8]);
+            program.extend_from_slice(&[0xc4 | (condition ^ 1) << 3, 0x00, 0x04]);
+            program.extend_from_slice(&[0xc4 | condition << 3, 0x00, 0x04]);
+        }
+        // RST 7; HLT
+        program.extend_from_slice(&[0xff, 0x76]);
+        program.resize(subroutine, 0x00);
+        // subroutine: RNZ; RZ
+        program.extend_from_slice(&[0xc0, 0xc8]);
+        program
+    }
+
+    fn load(program: &[u8]) -> State {
+        let mut state = state_with_program(program.to_vec());
+        state.rom_limit = Some(program.len() as u16 - 1);
+        state.tracer = Tracer::disabled();
+        state
+    }
+
+    fn generate(program: &[u8], entries: &[u16]) -> String {
+        let state = load(program);
+        let end = program.len() as u16 - 1;
+        let cfg = Cfg::build(&state.memory, entries, 0, end);
+        let mut out = Vec::new();
+        write_rust(&mut out, &state.memory, &cfg, "crate").unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn assert_same(plain: &State, compiled: &State) {
+        assert_eq!(plain.snapshot(), compiled.snapshot());
+        assert_eq!(plain.cc.to_psw(), compiled.cc.to_psw());
+        assert_eq!(plain.cycles, compiled.cycles);
+        assert_eq!(plain.halted, compiled.halted);
+        assert_eq!(plain.int_enable, compiled.int_enable);
+        assert_eq!(plain.pending_interrupt, compiled.pending_interrupt);
+        assert_eq!(plain.jumped, compiled.jumped);
+        assert_eq!(plain.call_stack.frames(), compiled.call_stack.frames());
+        assert!(plain.memory.as_slice() == compiled.memory.as_slice());
+    }
+
+    // Steps the interpreter up to the same cycle count after every block.
+    fn run_side_by_side(
+        mut plain: State,
+        mut compiled: State,
+        lookup: Lookup<TestMachine>,
+    ) -> (State, Recompiled<TestMachine>) {
+        let mut plain_machine = TestMachine::default();
+        let mut compiled_machine = TestMachine::default();
+        let mut recompiled = Recompiled::new(lookup);
+
+        while !compiled.halted {
+            recompiled
+                .step(&mut compiled, &mut compiled_machine)
+                .unwrap();
+            while plain.cycles < compiled.cycles {
+                cpu::emulate_instruction(&mut plain, &mut plain_machine);
+            }
+            assert_same(&plain, &compiled);
+            assert_eq!(plain_machine.outputs, compiled_machine.outputs);
+        }
+        (compiled, recompiled)
+    }
+
+    #[test]
+    fn golden_test() {
+        let files = [
+            (
+                "sample",
+                generate(&sample_program(), &[0x0000, 0x0008, 0x00a0]),
+            ),
+            ("opcodes", generate(&opcodes_program(), &[0x0100, 0x0038])),
+        ];
+        for (name, generated) in files.iter() {
+            let path = format!("testdata/recompile/{}.rs", name);
+            if env::var_os("UPDATE_GOLDEN").is_some() {
+                fs::write(&path, generated).unwrap();
+            }
+            assert!(
+                fs::read_to_string(&path).unwrap() == *generated,
+                "{} is out of date; rerun with UPDATE_GOLDEN=1",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn sample_test() {
+        let mut plain = load(&sample_program());
+        let mut compiled = load(&sample_program());
+        cpu::request_interrupt(&mut plain, 1);
+        cpu::request_interrupt(&mut compiled, 1);
+
+        let (state, recompiled) = run_side_by_side(plain, compiled, sample::block);
+        assert_eq!(state.pc, 0x0102);
+        // IN 02 reads the port inverted.
+        assert_eq!(state.c, 0xfd);
+        assert_eq!(state.b, 0x7a);
+        assert_eq!(state.a, 0x3d);
+        assert_eq!(state.get_de(), 0x0081);
+        assert!(recompiled.compiled() >= 10);
+        assert!(recompiled.interpreted() >= 10);
+    }
+
+    fn random_state(rng: &mut Rng) -> State {
+        let mut state = load(&opcodes_program());
+        state.pc = 0x0100;
+        state.sp = 0x9000;
+        state.set_bc(rng.word());
+        state.set_de(rng.word());
+        state.set_hl(rng.word());
+        state.a = rng.byte();
+        state.cc.set_psw(rng.byte());
+        state
+    }
+
+    #[test]
+    fn opcodes_test() {
+        let mut rng = Rng::new(8080);
+        for _ in 0..50 {
+            let plain = random_state(&mut rng.clone());
+            let compiled = random_state(&mut rng);
+
+            let (_, recompiled) = run_side_by_side(plain, compiled, opcodes::block);
+            assert!(recompiled.compiled() > 0);
+        }
+    }
+
+    #[test]
+    fn run_for_cycles_test() {
+        let mut state = load(&sample_program());
+        let mut recompiled = Recompiled::new(sample::block);
+
+        let result = recompiled.run_for_cycles(&mut state, &mut TestMachine::default(), 30);
+        // JMP, then LXI SP; EI; MVI B as one block.
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(result.cycles, 10 + 10 + 4 + 7);
+        assert_eq!(state.pc, 0x0046);
+        assert_eq!(recompiled.compiled(), 2);
+
+        let result = recompiled.run_for_cycles(&mut state, &mut TestMachine::default(), 100000);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(recompiled.fallback_steps(), 0);
+
+        // With the tracer on, everything is interpreted.
+        let mut state = load(&sample_program());
+        state.tracer = Tracer::default();
+        let mut recompiled = Recompiled::new(sample::block);
+        recompiled.run_for_cycles(&mut state, &mut TestMachine::default(), 100000);
+        assert_eq!(recompiled.compiled(), 0);
+        assert_eq!(recompiled.fallback_steps(), recompiled.interpreted());
+    }
+}
@@ -0,0 +1,758 @@
+use machine::Machine;
+
+// A second model of the 8080, written from the Intel datasheet without
+// reusing anything from cpu.rs or state.rs, for differential testing. It
+// decodes through a table and keeps the flags as the PSW byte, and is
+// deliberately plain rather than fast.
+
+pub const FLAG_S: u8 = 0x80;
+pub const FLAG_Z: u8 = 0x40;
+pub const FLAG_AC: u8 = 0x10;
+pub const FLAG_P: u8 = 0x04;
+pub const FLAG_CY: u8 = 0x01;
+
+// Bit 1 always reads as one, bits 3 and 5 as zero.
+const FLAGS_FIXED: u8 = 0x02;
+const FLAGS_USED: u8 = FLAG_S | FLAG_Z | FLAG_AC | FLAG_P | FLAG_CY;
+
+// Conditional calls and returns take TAKEN_EXTRA more states than
+// `states` gives them when they go.
+const TAKEN_EXTRA: usize = 6;
+
+// Register codes as they appear in opcodes: B C D E H L M A.
+const M: u8 = 6;
+
+// Register pairs: BC DE HL SP, or PSW in place of SP for PUSH and POP.
+const SP_OR_PSW: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Nop,
+    Lxi(u8),
+    Stax(u8),
+    Ldax(u8),
+    Inx(u8),
+    Dcx(u8),
+    Dad(u8),
+    Inr(u8),
+    Dcr(u8),
+    Mvi(u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Shld,
+    Lhld,
+    Sta,
+    Lda,
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    Mov(u8, u8),
+    Hlt,
+    Alu(u8, u8),
+    AluImmediate(u8),
+    Ret(Option<u8>),
+    Jmp(Option<u8>),
+    Call(Option<u8>),
+    Rst(u8),
+    Pop(u8),
+    Push(u8),
+    Pchl,
+    Sphl,
+    Xthl,
+    Xchg,
+    Out,
+    In,
+    Di,
+    Ei,
+}
+
+// Decodes by the opcode's bit fields, the way the datasheet lays them out,
+// including the undocumented duplicates of NOP, JMP, RET and CALL.
+fn decode(opcode: u8) -> Op {
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let p = y >> 1;
+    match (x, z) {
+        (0, 0) => Op::Nop,
+        (0, 1) if y & 1 == 0 => Op::Lxi(p),
+        (0, 1) => Op::Dad(p),
+        (0, 2) => match y {
+            0 | 2 => Op::Stax(p),
+            1 | 3 => Op::Ldax(p),
+            4 => Op::Shld,
+            5 => Op::Lhld,
+            6 => Op::Sta,
+            _ => Op::Lda,
+        },
+        (0, 3) if y & 1 == 0 => Op::Inx(p),
+        (0, 3) => Op::Dcx(p),
+        (0, 4) => Op::Inr(y),
+        (0, 5) => Op::Dcr(y),
+        (0, 6) => Op::Mvi(y),
+        (0, _) => [
+            Op::Rlc,
+            Op::Rrc,
+            Op::Ral,
+            Op::Rar,
+            Op::Daa,
+            Op::Cma,
+            Op::Stc,
+            Op::Cmc,
+        ][y as usize],
+        (1, 6) if y == 6 => Op::Hlt,
+        (1, _) => Op::Mov(y, z),
+        (2, _) => Op::Alu(y, z),
+        (_, 0) => Op::Ret(Some(y)),
+        (_, 1) => match y {
+            1 | 3 => Op::Ret(None),
+            5 => Op::Pchl,
+            7 => Op::Sphl,
+            _ => Op::Pop(p),
+        },
+        (_, 2) => Op::Jmp(Some(y)),
+        (_, 3) => [
+            Op::Jmp(None),
+            Op::Jmp(None),
+            Op::Out,
+            Op::In,
+            Op::Xthl,
+            Op::Xchg,
+            Op::Di,
+            Op::Ei,
+        ][y as usize],
+        (_, 4) => Op::Call(Some(y)),
+        (_, 5) if y & 1 == 0 => Op::Push(p),
+        (_, 5) => Op::Call(None),
+        (_, 6) => Op::AluImmediate(y),
+        (_, _) => Op::Rst(y),
+    }
+}
+
+// States per instruction, by instruction group as the datasheet's summary
+// lists them. Anything that reads or writes M costs more than the
+// register form.
+fn states(op: Op) -> usize {
+    match op {
+        Op::Nop | Op::Rlc | Op::Rrc | Op::Ral | Op::Rar => 4,
+        Op::Daa | Op::Cma | Op::Stc | Op::Cmc | Op::Xchg | Op::Di | Op::Ei => 4,
+        Op::Lxi(_) | Op::Dad(_) | Op::Pop(_) | Op::Out | Op::In => 10,
+        Op::Stax(_) | Op::Ldax(_) => 7,
+        Op::Inx(_) | Op::Dcx(_) | Op::Sphl | Op::Pchl => 5,
+        Op::Inr(M) | Op::Dcr(M) | Op::Mvi(M) => 10,
+        Op::Inr(_) | Op::Dcr(_) => 5,
+        Op::Mvi(_) | Op::Mov(M, _) | Op::Mov(_, M) | Op::Hlt => 7,
+        Op::Mov(_, _) => 5,
+        Op::Alu(_, M) | Op::AluImmediate(_) => 7,
+        Op::Alu(_, _) => 4,
+        Op::Shld | Op::Lhld => 16,
+        Op::Sta | Op::Lda => 13,
+        Op::Ret(Some(_)) => 5,
+        Op::Ret(None) | Op::Jmp(_) => 10,
+        Op::Call(Some(_)) | Op::Push(_) | Op::Rst(_) => 11,
+        Op::Call(None) => 17,
+        Op::Xthl => 18,
+    }
+}
+
+fn parity_even(n: u8) -> bool {
+    n.count_ones() % 2 == 0
+}
+
+pub struct Cpu {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    // In PUSH PSW layout.
+    pub f: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub inte: bool,
+    pub halted: bool,
+    pub memory: Vec<u8>,
+    // Every byte written by the last step, in order.
+    pub writes: Vec<(u16, u8)>,
+    table: Vec<Op>,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cpu {
+    pub fn new() -> Cpu {
+        Cpu {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            f: FLAGS_FIXED,
+            sp: 0,
+            pc: 0,
+            inte: false,
+            halted: false,
+            memory: vec![0; 0x10000],
+            writes: Vec::new(),
+            table: (0..=255).map(decode).collect(),
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.writes.push((address, value));
+    }
+
+    fn read16(&self, address: u16) -> u16 {
+        u16::from(self.read(address)) | u16::from(self.read(address.wrapping_add(1))) << 8
+    }
+
+    fn write16(&mut self, address: u16, value: u16) {
+        self.write(address, value as u8);
+        self.write(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let byte = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let low = self.fetch();
+        let high = self.fetch();
+        u16::from(low) | u16::from(high) << 8
+    }
+
+    fn hl(&self) -> u16 {
+        u16::from(self.h) << 8 | u16::from(self.l)
+    }
+
+    fn reg(&self, r: u8) -> u8 {
+        match r {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => self.h,
+            5 => self.l,
+            M => self.read(self.hl()),
+            _ => self.a,
+        }
+    }
+
+    fn set_reg(&mut self, r: u8, value: u8) {
+        match r {
+            0 => self.b = value,
+            1 => self.c = value,
+            2 => self.d = value,
+            3 => self.e = value,
+            4 => self.h = value,
+            5 => self.l = value,
+            M => {
+                let address = self.hl();
+                self.write(address, value)
+            }
+            _ => self.a = value,
+        }
+    }
+
+    fn pair(&self, p: u8) -> u16 {
+        match p {
+            0 => u16::from(self.b) << 8 | u16::from(self.c),
+            1 => u16::from(self.d) << 8 | u16::from(self.e),
+            2 => self.hl(),
+            _ => self.sp,
+        }
+    }
+
+    fn set_pair(&mut self, p: u8, value: u16) {
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        match p {
+            0 => {
+                self.b = high;
+                self.c = low;
+            }
+            1 => {
+                self.d = high;
+                self.e = low;
+            }
+            2 => {
+                self.h = high;
+                self.l = low;
+            }
+            _ => self.sp = value,
+        }
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.f & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.f |= flag;
+        } else {
+            self.f &= !flag;
+        }
+    }
+
+    fn set_szp(&mut self, result: u8) {
+        self.set_flag(FLAG_S, result & 0x80 != 0);
+        self.set_flag(FLAG_Z, result == 0);
+        self.set_flag(FLAG_P, parity_even(result));
+    }
+
+    // NZ Z NC C PO PE P M: a flag, then whether it must be set.
+    fn condition(&self, cc: u8) -> bool {
+        let flag = [FLAG_Z, FLAG_CY, FLAG_P, FLAG_S][(cc >> 1) as usize];
+        self.flag(flag) == (cc & 1 == 1)
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        let sp = self.sp;
+        self.write(sp.wrapping_add(1), (value >> 8) as u8);
+        self.write(sp, value as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.read16(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    // x + y + carry, a nibble at a time: AC is the carry out of the low
+    // nibble and CY the carry out of the high one.
+    fn add(&mut self, x: u8, y: u8, carry: bool) -> u8 {
+        let low = (x & 0x0f) + (y & 0x0f) + carry as u8;
+        let high = (x >> 4) + (y >> 4) + (low >> 4);
+        let result = (high << 4) | (low & 0x0f);
+        self.set_szp(result);
+        self.set_flag(FLAG_AC, low > 0x0f);
+        self.set_flag(FLAG_CY, high > 0x0f);
+        result
+    }
+
+    // x - y - borrow. CY is set when the whole subtraction borrows. AC is
+    // the inverse: the datasheet subtracts by adding the two's complement,
+    // so AC is set when the low nibble does not borrow.
+    fn subtract(&mut self, x: u8, y: u8, borrow: bool) -> u8 {
+        let difference = i16::from(x) - i16::from(y) - borrow as i16;
+        let result = difference as u8;
+        self.set_szp(result);
+        self.set_flag(FLAG_AC, x & 0x0f >= (y & 0x0f) + borrow as u8);
+        self.set_flag(FLAG_CY, difference < 0);
+        result
+    }
+
+    fn alu(&mut self, operation: u8, value: u8) {
+        let a = self.a;
+        let carry = self.flag(FLAG_CY);
+        match operation {
+            0 => self.a = self.add(a, value, false),
+            1 => self.a = self.add(a, value, carry),
+            2 => self.a = self.subtract(a, value, false),
+            3 => self.a = self.subtract(a, value, carry),
+            4 => {
+                self.a = a & value;
+                self.set_logic_flags((a | value) & 0x08 != 0);
+            }
+            5 => {
+                self.a = a ^ value;
+                self.set_logic_flags(false);
+            }
+            6 => {
+                self.a = a | value;
+                self.set_logic_flags(false);
+            }
+            _ => {
+                self.subtract(a, value, false);
+            }
+        }
+    }
+
+    fn set_logic_flags(&mut self, ac: bool) {
+        let a = self.a;
+        self.set_szp(a);
+        self.set_flag(FLAG_CY, false);
+        self.set_flag(FLAG_AC, ac);
+    }
+
+    // The datasheet's two steps: if the low nibble is over 9 or AC is set,
+    // add 6 to it, and AC becomes that addition's carry out of bit 3. Then
+    // if the high nibble is over 9 or CY is set, add 6 to it, setting CY
+    // on a carry out; CY is otherwise left alone. The first step can carry
+    // into a fifth high bit, which the second step sees as over 9.
+    fn daa(&mut self) {
+        let mut a = u16::from(self.a);
+        let mut ac = false;
+        if a & 0x0f > 9 || self.flag(FLAG_AC) {
+            ac = (a & 0x0f) + 6 > 0x0f;
+            a += 6;
+        }
+        if a >> 4 > 9 || self.flag(FLAG_CY) {
+            a += 0x60;
+        }
+        if a > 0xff {
+            self.set_flag(FLAG_CY, true);
+        }
+        self.a = a as u8;
+        let result = self.a;
+        self.set_szp(result);
+        self.set_flag(FLAG_AC, ac);
+    }
+
+    // Runs one instruction, or idles while halted, and returns the states
+    // it took.
+    pub fn step(&mut self, m: &mut impl Machine) -> usize {
+        self.writes.clear();
+        if self.halted {
+            return 4;
+        }
+        let opcode = self.fetch();
+        let op = self.table[opcode as usize];
+        let mut states = states(op);
+
+        match op {
+            Op::Nop => (),
+            Op::Lxi(p) => {
+                let value = self.fetch16();
+                self.set_pair(p, value);
+            }
+            Op::Stax(p) => {
+                let (address, a) = (self.pair(p), self.a);
+                self.write(address, a);
+            }
+            Op::Ldax(p) => self.a = self.read(self.pair(p)),
+            Op::Inx(p) => {
+                let value = self.pair(p).wrapping_add(1);
+                self.set_pair(p, value);
+            }
+            Op::Dcx(p) => {
+                let value = self.pair(p).wrapping_sub(1);
+                self.set_pair(p, value);
+            }
+            Op::Dad(p) => {
+                let sum = u32::from(self.hl()) + u32::from(self.pair(p));
+                self.set_pair(2, sum as u16);
+                self.set_flag(FLAG_CY, sum > 0xffff);
+            }
+            Op::Inr(r) => {
+                let value = self.reg(r);
+                let result = value.wrapping_add(1);
+                self.set_szp(result);
+                self.set_flag(FLAG_AC, value & 0x0f == 0x0f);
+                self.set_reg(r, result);
+            }
+            Op::Dcr(r) => {
+                let value = self.reg(r);
+                let result = value.wrapping_sub(1);
+                self.set_szp(result);
+                self.set_flag(FLAG_AC, value & 0x0f != 0);
+                self.set_reg(r, result);
+            }
+            Op::Mvi(r) => {
+                let value = self.fetch();
+                self.set_reg(r, value);
+            }
+            Op::Rlc => {
+                self.set_flag(FLAG_CY, self.a & 0x80 != 0);
+                self.a = self.a.rotate_left(1);
+            }
+            Op::Rrc => {
+                self.set_flag(FLAG_CY, self.a & 0x01 != 0);
+                self.a = self.a.rotate_right(1);
+            }
+            Op::Ral => {
+                let carry = self.flag(FLAG_CY);
+                self.set_flag(FLAG_CY, self.a & 0x80 != 0);
+                self.a = self.a << 1 | carry as u8;
+            }
+            Op::Rar => {
+                let carry = self.flag(FLAG_CY);
+                self.set_flag(FLAG_CY, self.a & 0x01 != 0);
+                self.a = self.a >> 1 | (carry as u8) << 7;
+            }
+            Op::Shld => {
+                let (address, hl) = (self.fetch16(), self.hl());
+                self.write16(address, hl);
+            }
+            Op::Lhld => {
+                let address = self.fetch16();
+                let value = self.read16(address);
+                self.set_pair(2, value);
+            }
+            Op::Sta => {
+                let (address, a) = (self.fetch16(), self.a);
+                self.write(address, a);
+            }
+            Op::Lda => {
+                let address = self.fetch16();
+                self.a = self.read(address);
+            }
+            Op::Daa => self.daa(),
+            Op::Cma => self.a = !self.a,
+            Op::Stc => self.set_flag(FLAG_CY, true),
+            Op::Cmc => {
+                let carry = self.flag(FLAG_CY);
+                self.set_flag(FLAG_CY, !carry);
+            }
+            Op::Mov(d, s) => {
+                let value = self.reg(s);
+                self.set_reg(d, value);
+            }
+            Op::Hlt => self.halted = true,
+            Op::Alu(operation, r) => {
+                let value = self.reg(r);
+                self.alu(operation, value);
+            }
+            Op::AluImmediate(operation) => {
+                let value = self.fetch();
+                self.alu(operation, value);
+            }
+            Op::Ret(cc) => {
+                if cc.map_or(true, |cc| self.condition(cc)) {
+                    self.pc = self.pop();
+                    if cc.is_some() {
+                        states += TAKEN_EXTRA;
+                    }
+                }
+            }
+            Op::Jmp(cc) => {
+                let target = self.fetch16();
+                if cc.map_or(true, |cc| self.condition(cc)) {
+                    self.pc = target;
+                }
+            }
+            Op::Call(cc) => {
+                let target = self.fetch16();
+                if cc.map_or(true, |cc| self.condition(cc)) {
+                    let pc = self.pc;
+                    self.push(pc);
+                    self.pc = target;
+                    if cc.is_some() {
+                        states += TAKEN_EXTRA;
+                    }
+                }
+            }
+            Op::Rst(n) => {
+                let pc = self.pc;
+                self.push(pc);
+                self.pc = u16::from(n) * 8;
+            }
+            Op::Pop(SP_OR_PSW) => {
+                let value = self.pop();
+                self.a = (value >> 8) as u8;
+                self.f = value as u8 & FLAGS_USED | FLAGS_FIXED;
+            }
+            Op::Pop(p) => {
+                let value = self.pop();
+                self.set_pair(p, value);
+            }
+            Op::Push(SP_OR_PSW) => {
+                let value = u16::from(self.a) << 8 | u16::from(self.f);
+                self.push(value);
+            }
+            Op::Push(p) => {
+                let value = self.pair(p);
+                self.push(value);
+            }
+            Op::Pchl => self.pc = self.hl(),
+            Op::Sphl => self.sp = self.hl(),
+            Op::Xthl => {
+                let (sp, hl) = (self.sp, self.hl());
+                let value = self.read16(sp);
+                self.write16(sp, hl);
+                self.set_pair(2, value);
+            }
+            Op::Xchg => {
+                let (de, hl) = (self.pair(1), self.hl());
+                self.set_pair(1, hl);
+                self.set_pair(2, de);
+            }
+            Op::Out => {
+                let port = self.fetch();
+                m.output(port, self.a);
+            }
+            Op::In => {
+                let port = self.fetch();
+                self.a = m.input(port);
+            }
+            Op::Di => self.inte = false,
+            Op::Ei => self.inte = true,
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use singlestep::load_tests;
+    use std::fs;
+    use std::path::Path;
+
+    struct NoPorts;
+
+    impl Machine for NoPorts {
+        fn input(&self, _port: u8) -> u8 {
+            0
+        }
+
+        fn output(&mut self, _port: u8, _val: u8) {}
+    }
+
+    fn run(program: &[u8], steps: usize) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.memory[..program.len()].copy_from_slice(program);
+        for _ in 0..steps {
+            cpu.step(&mut NoPorts);
+        }
+        cpu
+    }
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(decode(0x00), Op::Nop);
+        assert_eq!(decode(0x38), Op::Nop);
+        assert_eq!(decode(0x76), Op::Hlt);
+        assert_eq!(decode(0x7e), Op::Mov(7, M));
+        assert_eq!(decode(0x9e), Op::Alu(3, M));
+        assert_eq!(decode(0xcb), Op::Jmp(None));
+        assert_eq!(decode(0xd9), Op::Ret(None));
+        assert_eq!(decode(0xfd), Op::Call(None));
+        assert_eq!(decode(0xf5), Op::Push(SP_OR_PSW));
+        assert_eq!(decode(0xff), Op::Rst(7));
+    }
+
+    #[test]
+    fn arithmetic_flags_test() {
+        // MVI A,0F; ADI 01
+        let cpu = run(&[0x3e, 0x0f, 0xc6, 0x01], 2);
+        assert_eq!(cpu.a, 0x10);
+        assert_eq!(cpu.f, FLAGS_FIXED | FLAG_AC);
+
+        // MVI A,00; SUI 01
+        let cpu = run(&[0x3e, 0x00, 0xd6, 0x01], 2);
+        assert_eq!(cpu.a, 0xff);
+        assert_eq!(cpu.f, FLAGS_FIXED | FLAG_S | FLAG_P | FLAG_CY);
+
+        // STC; MVI A,01; SBI 00 borrows the carry.
+        let cpu = run(&[0x37, 0x3e, 0x01, 0xde, 0x00], 3);
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.f & FLAG_CY, 0);
+        assert_ne!(cpu.f & FLAG_Z, 0);
+    }
+
+    #[test]
+    fn daa_test() {
+        // MVI A,19; ADI 28; DAA gives BCD 47.
+        let cpu = run(&[0x3e, 0x19, 0xc6, 0x28, 0x27], 3);
+        assert_eq!(cpu.a, 0x47);
+        assert_eq!(cpu.f & FLAG_CY, 0);
+
+        // MVI A,99; ADI 01; DAA wraps to 00 with carry.
+        let cpu = run(&[0x3e, 0x99, 0xc6, 0x01, 0x27], 3);
+        assert_eq!(cpu.a, 0x00);
+        assert_ne!(cpu.f & FLAG_CY, 0);
+
+        // MVI A,FA; DAA carries out of the first step into the second.
+        let cpu = run(&[0x3e, 0xfa, 0x27], 2);
+        assert_eq!(cpu.a, 0x60);
+        assert_eq!(cpu.f, FLAGS_FIXED | FLAG_AC | FLAG_P | FLAG_CY);
+    }
+
+    // The hand-written vectors in testdata/8080 are independent of both
+    // this model and the emulator.
+    #[test]
+    fn vectors_test() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/8080");
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "json"))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+
+        for file in files {
+            for case in load_tests(&file).unwrap() {
+                let (initial, expected) = (&case.initial, &case.expected);
+                let mut cpu = Cpu::new();
+                cpu.pc = initial.pc;
+                cpu.sp = initial.sp;
+                cpu.a = initial.a;
+                cpu.f = initial.f;
+                cpu.b = initial.b;
+                cpu.c = initial.c;
+                cpu.d = initial.d;
+                cpu.e = initial.e;
+                cpu.h = initial.h;
+                cpu.l = initial.l;
+                for &(address, value) in &initial.ram {
+                    cpu.memory[address as usize] = value;
+                }
+
+                let states = cpu.step(&mut NoPorts);
+                let actual = (
+                    [cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l],
+                    cpu.pc,
+                    cpu.sp,
+                    states,
+                );
+                let wanted = (
+                    [
+                        expected.a, expected.f, expected.b, expected.c, expected.d, expected.e,
+                        expected.h, expected.l,
+                    ],
+                    expected.pc,
+                    expected.sp,
+                    case.cycles,
+                );
+                assert_eq!(actual, wanted, "{}", case.name);
+                for &(address, value) in &expected.ram {
+                    assert_eq!(cpu.read(address), value, "{}", case.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stack_test() {
+        // LXI SP,0000; MVI A,12; STC; PUSH PSW; POP B
+        let cpu = run(&[0x31, 0x00, 0x00, 0x3e, 0x12, 0x37, 0xf5, 0xc1], 5);
+        assert_eq!(cpu.sp, 0x0000);
+        assert_eq!((cpu.b, cpu.c), (0x12, 0x03));
+        assert_eq!(cpu.read(0xffff), 0x12);
+        assert_eq!(cpu.read(0xfffe), 0x03);
+    }
+
+    #[test]
+    fn conditional_states_test() {
+        let mut cpu = Cpu::new();
+        // CZ 0000 with Z clear, then set.
+        cpu.memory[..3].copy_from_slice(&[0xcc, 0x00, 0x00]);
+        cpu.sp = 0x100;
+        assert_eq!(cpu.step(&mut NoPorts), 11);
+        cpu.pc = 0;
+        cpu.f |= FLAG_Z;
+        assert_eq!(cpu.step(&mut NoPorts), 17);
+        assert_eq!(cpu.writes, vec![(0x00ff, 0x00), (0x00fe, 0x03)]);
+    }
+}
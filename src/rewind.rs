@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use cpu::{try_emulate_instruction, EmulationError};
+use flags::Flags;
+use machine::Machine;
+use memory::MemoryWrite;
+use savestate;
+use state::State;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewindConfig {
+    // How many instructions can be undone.
+    pub history: usize,
+    // A full save state is taken every this many instructions, so long
+    // rewinds restore one instead of undoing every step.
+    pub keyframe_interval: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        // Roughly a second of Space Invaders, with a keyframe per frame,
+        // in about 25 MB.
+        RewindConfig {
+            history: 300_000,
+            keyframe_interval: 5_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Registers {
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    h: u8,
+    l: u8,
+    sp: u16,
+    pc: u16,
+    flags: u8,
+    int_enable: bool,
+    halted: bool,
+    pending_interrupt: Option<u8>,
+    cycles: u64,
+}
+
+impl Registers {
+    fn capture(s: &State) -> Registers {
+        Registers {
+            a: s.a,
+            b: s.b,
+            c: s.c,
+            d: s.d,
+            e: s.e,
+            h: s.h,
+            l: s.l,
+            sp: s.sp,
+            pc: s.pc,
+            flags: s.cc.serialize(),
+            int_enable: s.int_enable,
+            halted: s.halted,
+            pending_interrupt: s.pending_interrupt,
+            cycles: s.cycles,
+        }
+    }
+
+    fn restore(&self, s: &mut State) {
+        s.a = self.a;
+        s.b = self.b;
+        s.c = self.c;
+        s.d = self.d;
+        s.e = self.e;
+        s.h = self.h;
+        s.l = self.l;
+        s.sp = self.sp;
+        s.pc = self.pc;
+        s.cc = Flags::new();
+        s.cc.deserialize(self.flags);
+        s.int_enable = self.int_enable;
+        s.halted = self.halted;
+        s.pending_interrupt = self.pending_interrupt;
+        s.cycles = self.cycles;
+        // Frames entered after this point are gone; ones that returned since
+        // can't be recovered.
+        s.call_stack.unwind(s.sp);
+    }
+}
+
+// Everything needed to undo one instruction: the registers before it ran
+// and the memory it overwrote.
+#[derive(Debug)]
+struct Step {
+    before: Registers,
+    writes: Vec<MemoryWrite>,
+}
+
+#[derive(Debug)]
+struct Keyframe {
+    position: u64,
+    state: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Rewind {
+    config: RewindConfig,
+    // steps[i] is the instruction at position `first + i`.
+    steps: VecDeque<Step>,
+    first: u64,
+    keyframes: VecDeque<Keyframe>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(RewindConfig::default())
+    }
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Rewind {
+        assert!(
+            config.keyframe_interval > 0,
+            "keyframe interval must be positive"
+        );
+        Rewind {
+            config,
+            steps: VecDeque::new(),
+            first: 0,
+            keyframes: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    // Number of instructions executed since recording started, i.e. the
+    // position of the next instruction.
+    pub fn position(&self) -> u64 {
+        self.first + self.steps.len() as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.first = self.position();
+        self.steps.clear();
+        self.keyframes.clear();
+    }
+
+    // Approximate heap usage of the recorded history in bytes.
+    pub fn memory_usage(&self) -> usize {
+        let steps = self.steps.len() * mem::size_of::<Step>();
+        let writes: usize = self
+            .steps
+            .iter()
+            .map(|step| step.writes.capacity() * mem::size_of::<MemoryWrite>())
+            .sum();
+        let keyframes: usize = self.keyframes.iter().map(|k| k.state.len()).sum();
+        steps + writes + keyframes
+    }
+
+    // Runs one instruction, recording how to undo it.
+    pub fn step(&mut self, s: &mut State, m: &mut impl Machine) -> Result<usize, EmulationError> {
+        let position = self.position();
+        if position % self.config.keyframe_interval as u64 == 0 {
+            // A keyframe left over from before a step back is replaced, as
+            // device state may have moved on since it was taken.
+            if self.keyframes.back().map(|k| k.position) == Some(position) {
+                self.keyframes.pop_back();
+            }
+            self.keyframes.push_back(Keyframe {
+                position,
+                state: savestate::to_bytes(s, m),
+            });
+        }
+
+        let before = Registers::capture(s);
+        let result = try_emulate_instruction(s, m);
+        if result.is_ok() {
+            self.steps.push_back(Step {
+                before,
+                writes: s.memory.writes().to_vec(),
+            });
+            self.trim();
+        } else if self.keyframes.back().map(|k| k.position) == Some(position) {
+            self.keyframes.pop_back();
+        }
+        result
+    }
+
+    // Undoes the most recent instruction. Device state is not affected.
+    pub fn step_back(&mut self, s: &mut State) -> bool {
+        match self.steps.pop_back() {
+            Some(step) => {
+                for write in step.writes.iter().rev() {
+                    s.memory.set(write.address, write.old);
+                }
+                s.memory.clear_writes();
+                step.before.restore(s);
+
+                let position = self.position();
+                while self.keyframes.back().is_some_and(|k| k.position > position) {
+                    self.keyframes.pop_back();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Goes back up to `instructions` steps and returns how many were undone.
+    // When a keyframe lies in the range it is restored first, which also
+    // brings the machine's device state back.
+    pub fn rewind(&mut self, s: &mut State, m: &mut impl Machine, instructions: usize) -> usize {
+        let instructions = instructions.min(self.steps.len());
+        let target = self.position() - instructions as u64;
+
+        let keyframe = self
+            .keyframes
+            .iter()
+            .position(|k| k.position >= target)
+            .filter(|i| self.keyframes[*i].position < self.position());
+        if let Some(index) = keyframe {
+            let position = self.keyframes[index].position;
+            savestate::from_bytes(s, m, &self.keyframes[index].state)
+                .expect("keyframe was written by this build");
+            self.keyframes.truncate(index);
+            self.steps.truncate((position - self.first) as usize);
+        }
+
+        while self.position() > target {
+            self.step_back(s);
+        }
+        instructions
+    }
+
+    // Goes back until at least `cycles` CPU cycles have been undone, or the
+    // history runs out. Returns the number of cycles actually undone.
+    pub fn rewind_cycles(&mut self, s: &mut State, m: &mut impl Machine, cycles: u64) -> u64 {
+        let now = s.cycles;
+        let target = now.saturating_sub(cycles);
+        let instructions = self
+            .steps
+            .iter()
+            .rev()
+            .position(|step| step.before.cycles <= target)
+            .map_or(self.steps.len(), |i| i + 1);
+
+        self.rewind(s, m, instructions);
+        now - s.cycles
+    }
+
+    fn trim(&mut self) {
+        while self.steps.len() > self.config.history {
+            self.steps.pop_front();
+            self.first += 1;
+        }
+        while self
+            .keyframes
+            .front()
+            .is_some_and(|k| k.position < self.first)
+        {
+            self.keyframes.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{state_with_program, TestMachine};
+
+    // 0000: LXI SP,0100
+    // 0003: LXI H,0200
+    // 0006: MVI A,00
+    // 0008: INR A
+    // 0009: MOV M,A
+    // 000a: INX H
+    // 000b: PUSH PSW
+    // 000c: OUT 01
+    // 000e: JMP 0008
+    fn counting_state() -> State {
+        state_with_program(vec![
+            0x31, 0x00, 0x01, 0x21, 0x00, 0x02, 0x3e, 0x00, 0x3c, 0x77, 0x23, 0xf5, 0xd3, 0x01,
+            0xc3, 0x08, 0x00,
+        ])
+    }
+
+    fn run(rewind: &mut Rewind, s: &mut State, m: &mut TestMachine, n: usize) {
+        for _ in 0..n {
+            rewind.step(s, m).unwrap();
+        }
+    }
+
+    #[test]
+    fn step_back_test() {
+        let mut rewind = Rewind::default();
+        let mut state = counting_state();
+        let mut machine = TestMachine::default();
+
+        run(&mut rewind, &mut state, &mut machine, 3);
+        let before = savestate::to_bytes(&state, &machine);
+
+        run(&mut rewind, &mut state, &mut machine, 6);
+        assert_eq!(rewind.position(), 9);
+        assert_eq!(state.memory.get(0x200), 1);
+        assert_eq!(state.memory.get(0xff), 0x01);
+
+        for _ in 0..6 {
+            assert_eq!(rewind.step_back(&mut state), true);
+        }
+        assert_eq!(rewind.position(), 3);
+        // Device state is untouched by single steps.
+        assert_eq!(machine.outputs, vec![(0x01, 0x01)]);
+        machine.outputs.clear();
+        assert_eq!(savestate::to_bytes(&state, &machine), before);
+    }
+
+    #[test]
+    fn step_back_empty_test() {
+        let mut rewind = Rewind::default();
+        let mut state = counting_state();
+
+        assert_eq!(rewind.step_back(&mut state), false);
+    }
+
+    #[test]
+    fn rewind_with_keyframes_test() {
+        let mut rewind = Rewind::new(RewindConfig {
+            history: 1000,
+            keyframe_interval: 10,
+        });
+        let mut state = counting_state();
+        let mut machine = TestMachine::default();
+        let mut history = Vec::new();
+
+        for _ in 0..100 {
+            history.push(savestate::to_bytes(&state, &machine));
+            rewind.step(&mut state, &mut machine).unwrap();
+        }
+
+        assert_eq!(rewind.rewind(&mut state, &mut machine, 37), 37);
+        assert_eq!(rewind.position(), 63);
+        let mut restored = savestate::to_bytes(&state, &machine);
+        // Only keyframes restore device state, so compare CPU and memory.
+        restored.truncate(history[63].len() - machine.save_state().len() - 4);
+        assert!(history[63].starts_with(&restored));
+
+        // Recording resumes from the rewound point.
+        rewind.step(&mut state, &mut machine).unwrap();
+        assert_eq!(rewind.position(), 64);
+
+        assert_eq!(rewind.rewind(&mut state, &mut machine, 1000), 64);
+        assert_eq!(savestate::to_bytes(&state, &machine), history[0]);
+    }
+
+    #[test]
+    fn rewind_cycles_test() {
+        let mut rewind = Rewind::default();
+        let mut state = counting_state();
+        let mut machine = TestMachine::default();
+
+        run(&mut rewind, &mut state, &mut machine, 50);
+        let cycles = state.cycles;
+
+        // INR A (5) + MOV M,A (7) + INX H (5) + PUSH PSW (11) + OUT (10) +
+        // JMP (10) is one pass round the loop.
+        assert_eq!(rewind.rewind_cycles(&mut state, &mut machine, 48), 48);
+        assert_eq!(state.cycles, cycles - 48);
+        assert_eq!(rewind.rewind_cycles(&mut state, &mut machine, 1), 10);
+
+        let remaining = state.cycles;
+        assert_eq!(
+            rewind.rewind_cycles(&mut state, &mut machine, 1_000_000),
+            remaining
+        );
+        assert_eq!(state.cycles, 0);
+        assert_eq!(rewind.is_empty(), true);
+    }
+
+    #[test]
+    fn bounded_history_test() {
+        let mut rewind = Rewind::new(RewindConfig {
+            history: 20,
+            keyframe_interval: 8,
+        });
+        let mut state = counting_state();
+        let mut machine = TestMachine::default();
+
+        run(&mut rewind, &mut state, &mut machine, 100);
+        assert_eq!(rewind.len(), 20);
+        assert_eq!(rewind.position(), 100);
+        assert!(rewind.keyframes.iter().all(|k| k.position >= 80));
+        assert_eq!(rewind.keyframes.len(), 3);
+        assert!(rewind.memory_usage() >= 3 * 65536);
+
+        assert_eq!(rewind.rewind(&mut state, &mut machine, 50), 20);
+        assert_eq!(rewind.position(), 80);
+        assert_eq!(rewind.step_back(&mut state), false);
+    }
+}
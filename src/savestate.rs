@@ -0,0 +1,291 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use machine::Machine;
+use state::State;
+
+const MAGIC: &[u8; 8] = b"V8080SAV";
+pub const VERSION: u16 = 1;
+
+const MEMORY_SIZE: usize = 65536;
+
+const STATUS_INT_ENABLE: u8 = 0x01;
+const STATUS_HALTED: u8 = 0x02;
+const STATUS_PENDING_INTERRUPT: u8 = 0x04;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Device(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::Io(e) => write!(f, "I/O error: {}", e),
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) => {
+                write!(f, "unsupported save state version {}", v)
+            }
+            SaveStateError::Device(e) => write!(f, "device state: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        SaveStateError::Io(e)
+    }
+}
+
+// Layout (all multi-byte values little-endian):
+//   magic "V8080SAV", version u16,
+//   A B C D E H L, SP u16, PC u16, flags u8, status u8, pending RST u8,
+//   cycle count u64, 64K of memory, device state length u32 + bytes.
+pub fn save(s: &State, m: &impl Machine, w: &mut impl Write) -> Result<(), SaveStateError> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+
+    w.write_all(&[s.a, s.b, s.c, s.d, s.e, s.h, s.l])?;
+    w.write_all(&s.sp.to_le_bytes())?;
+    w.write_all(&s.pc.to_le_bytes())?;
+
+    let mut status = 0;
+    if s.int_enable {
+        status |= STATUS_INT_ENABLE;
+    }
+    if s.halted {
+        status |= STATUS_HALTED;
+    }
+    if s.pending_interrupt.is_some() {
+        status |= STATUS_PENDING_INTERRUPT;
+    }
+    w.write_all(&[s.cc.serialize(), status, s.pending_interrupt.unwrap_or(0)])?;
+    w.write_all(&s.cycles.to_le_bytes())?;
+
+    w.write_all(s.memory.as_slice())?;
+
+    let device = m.save_state();
+    w.write_all(&(device.len() as u32).to_le_bytes())?;
+    w.write_all(&device)?;
+
+    Ok(())
+}
+
+// Nothing is modified unless the whole state parses and the machine accepts
+// its device state.
+pub fn load(s: &mut State, m: &mut impl Machine, r: &mut impl Read) -> Result<(), SaveStateError> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SaveStateError::BadMagic);
+    }
+
+    let version = read_u16(r)?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    let mut registers = [0; 7];
+    r.read_exact(&mut registers)?;
+    let sp = read_u16(r)?;
+    let pc = read_u16(r)?;
+
+    let mut status = [0; 3];
+    r.read_exact(&mut status)?;
+    let [flags, status, pending] = status;
+
+    let mut cycles = [0; 8];
+    r.read_exact(&mut cycles)?;
+
+    let mut memory = vec![0; MEMORY_SIZE];
+    r.read_exact(&mut memory)?;
+
+    let mut length = [0; 4];
+    r.read_exact(&mut length)?;
+    // The length isn't trusted to size a buffer up front: a corrupt file
+    // could claim gigabytes.
+    let length = u64::from(u32::from_le_bytes(length));
+    let mut device = Vec::new();
+    r.take(length).read_to_end(&mut device)?;
+    if device.len() as u64 != length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    m.load_state(&device).map_err(SaveStateError::Device)?;
+
+    let [a, b, c, d, e, h, l] = registers;
+    s.a = a;
+    s.b = b;
+    s.c = c;
+    s.d = d;
+    s.e = e;
+    s.h = h;
+    s.l = l;
+    s.sp = sp;
+    s.pc = pc;
+    s.cc.deserialize(flags);
+    s.int_enable = status & STATUS_INT_ENABLE != 0;
+    s.halted = status & STATUS_HALTED != 0;
+    s.pending_interrupt = if status & STATUS_PENDING_INTERRUPT != 0 {
+        Some(pending)
+    } else {
+        None
+    };
+    s.cycles = u64::from_le_bytes(cycles);
+    s.memory.restore(&memory);
+    s.jumped = false;
+    s.call_stack.clear();
+
+    Ok(())
+}
+
+pub fn to_bytes(s: &State, m: &impl Machine) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(MEMORY_SIZE + 64);
+    save(s, m, &mut buffer).expect("writing to a Vec cannot fail");
+    buffer
+}
+
+pub fn from_bytes(s: &mut State, m: &mut impl Machine, data: &[u8]) -> Result<(), SaveStateError> {
+    load(s, m, &mut io::Cursor::new(data))
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buffer = [0; 2];
+    r.read_exact(&mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TestMachine;
+
+    fn busy_state() -> State {
+        let mut state = State::new();
+        state.a = 0x01;
+        state.b = 0x02;
+        state.c = 0x03;
+        state.d = 0x04;
+        state.e = 0x05;
+        state.h = 0x06;
+        state.l = 0x07;
+        state.sp = 0x23fe;
+        state.pc = 0x1a2b;
+        state.cc.z = true;
+        state.cc.cy = true;
+        state.int_enable = true;
+        state.pending_interrupt = Some(2);
+        state.cycles = 0x1_0000_0001;
+        state.memory.set(0x0000, 0xc3);
+        state.memory.set(0xffff, 0x99);
+        state
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let state = busy_state();
+        let machine = TestMachine {
+            outputs: vec![(0xbe, 0xef)],
+        };
+        let data = to_bytes(&state, &machine);
+
+        let mut restored = State::new();
+        let mut restored_machine = TestMachine::default();
+        from_bytes(&mut restored, &mut restored_machine, &data).unwrap();
+
+        assert_eq!(restored.snapshot(), state.snapshot());
+        assert_eq!(restored.cc, state.cc);
+        assert_eq!(restored.int_enable, true);
+        assert_eq!(restored.halted, false);
+        assert_eq!(restored.pending_interrupt, Some(2));
+        assert_eq!(restored.cycles, 0x1_0000_0001);
+        assert_eq!(restored.memory.as_slice(), state.memory.as_slice());
+        assert_eq!(restored_machine.outputs, vec![(0xbe, 0xef)]);
+
+        // Saving the restored state reproduces the original bytes.
+        assert_eq!(to_bytes(&restored, &restored_machine), data);
+    }
+
+    #[test]
+    fn header_test() {
+        let data = to_bytes(&State::new(), &TestMachine::default());
+
+        assert_eq!(&data[0..8], b"V8080SAV");
+        assert_eq!(&data[8..10], &[1, 0]);
+        assert_eq!(data.len(), 10 + 7 + 4 + 3 + 8 + 65536 + 4);
+    }
+
+    #[test]
+    fn bad_magic_test() {
+        let mut data = to_bytes(&State::new(), &TestMachine::default());
+        data[0] = b'X';
+
+        match from_bytes(&mut State::new(), &mut TestMachine::default(), &data) {
+            Err(SaveStateError::BadMagic) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_version_test() {
+        let mut data = to_bytes(&State::new(), &TestMachine::default());
+        data[8] = 7;
+
+        match from_bytes(&mut State::new(), &mut TestMachine::default(), &data) {
+            Err(SaveStateError::UnsupportedVersion(7)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_test() {
+        let data = to_bytes(&busy_state(), &TestMachine::default());
+        let mut state = State::new();
+
+        match from_bytes(&mut state, &mut TestMachine::default(), &data[..1000]) {
+            Err(SaveStateError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(state.pc, 0);
+    }
+
+    #[test]
+    fn huge_device_length_test() {
+        let mut data = to_bytes(
+            &busy_state(),
+            &TestMachine {
+                outputs: vec![(0x01, 0x02)],
+            },
+        );
+        let length = data.len() - 6;
+        data[length..length + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut state = State::new();
+
+        match from_bytes(&mut state, &mut TestMachine::default(), &data) {
+            Err(SaveStateError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(state.pc, 0);
+    }
+
+    #[test]
+    fn device_mismatch_test() {
+        // Device state the machine rejects: half an output log entry.
+        let mut data = to_bytes(&busy_state(), &TestMachine::default());
+        let length = data.len() - 4;
+        data[length..].copy_from_slice(&1u32.to_le_bytes());
+        data.push(0x01);
+        let mut state = State::new();
+
+        match from_bytes(&mut state, &mut TestMachine::default(), &data) {
+            Err(SaveStateError::Device(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(state.pc, 0);
+        assert_eq!(state.memory.get(0xffff), 0);
+    }
+}
@@ -0,0 +1,322 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use cpu::{run_for_cycles, stays_halted, RunResult, StopReason};
+use machine::Machine;
+use state::State;
+
+pub type EventId = u64;
+
+// A callback returns Some(delay) to run again `delay` cycles after the time
+// it was scheduled for, or None to be dropped.
+pub type Callback<M> = Box<dyn FnMut(&mut State, &mut M) -> Option<u64>>;
+
+pub struct Scheduler<M> {
+    // Ordered by (cycle, id) so that events due on the same cycle fire in
+    // the order they were scheduled.
+    queue: BinaryHeap<Reverse<(u64, EventId)>>,
+    callbacks: HashMap<EventId, Callback<M>>,
+    next_id: EventId,
+}
+
+impl<M: Machine> Default for Scheduler<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Machine> Scheduler<M> {
+    pub fn new() -> Scheduler<M> {
+        Scheduler {
+            queue: BinaryHeap::new(),
+            callbacks: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn schedule_at(&mut self, cycle: u64, callback: Callback<M>) -> EventId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push(Reverse((cycle, id)));
+        self.callbacks.insert(id, callback);
+        id
+    }
+
+    pub fn schedule_in(&mut self, s: &State, delay: u64, callback: Callback<M>) -> EventId {
+        self.schedule_at(s.cycles + delay, callback)
+    }
+
+    pub fn cancel(&mut self, id: EventId) -> bool {
+        // The queue entry is left behind and skipped when it comes due.
+        self.callbacks.remove(&id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    pub fn next_event_at(&mut self) -> Option<u64> {
+        while let Some(Reverse((cycle, id))) = self.queue.peek().cloned() {
+            if self.callbacks.contains_key(&id) {
+                return Some(cycle);
+            }
+            self.queue.pop();
+        }
+        None
+    }
+
+    // Fires every event due at or before the current cycle count and
+    // returns how many ran.
+    pub fn dispatch_due(&mut self, s: &mut State, m: &mut M) -> usize {
+        let mut dispatched = 0;
+
+        while let Some(cycle) = self.next_event_at() {
+            if cycle > s.cycles {
+                break;
+            }
+
+            let Reverse((_, id)) = self.queue.pop().unwrap();
+            let mut callback = self.callbacks.remove(&id).unwrap();
+            if let Some(delay) = callback(s, m) {
+                self.queue.push(Reverse((cycle + delay, id)));
+                self.callbacks.insert(id, callback);
+            }
+            dispatched += 1;
+        }
+
+        dispatched
+    }
+
+    // Like cpu::run_for_cycles, but stops at each event boundary to
+    // dispatch it. Instructions are never split, so an event fires at the
+    // first instruction boundary at or after its scheduled cycle.
+    pub fn run_for_cycles(&mut self, s: &mut State, m: &mut M, budget: usize) -> RunResult {
+        let end = s.cycles + budget as u64;
+        let mut cycles = 0;
+
+        loop {
+            self.dispatch_due(s, m);
+
+            if s.cycles >= end {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::BudgetExhausted,
+                };
+            }
+
+            let next_event = self.next_event_at().filter(|at| *at < end);
+
+            if stays_halted(s) {
+                // Nothing happens until an event (normally an interrupt)
+                // wakes the CPU, so skip straight to it.
+                match next_event {
+                    Some(at) => {
+                        cycles += (at - s.cycles) as usize;
+                        s.cycles = at;
+                        continue;
+                    }
+                    None => {
+                        return RunResult {
+                            cycles,
+                            reason: StopReason::Halted,
+                        }
+                    }
+                }
+            }
+
+            // cpu::run_for_cycles ignores a breakpoint at its starting PC, so
+            // one that falls exactly on a slice boundary is checked here.
+            if cycles > 0 && s.breakpoints.contains(&s.pc) {
+                return RunResult {
+                    cycles,
+                    reason: StopReason::Breakpoint(s.pc),
+                };
+            }
+
+            let slice_end = next_event.unwrap_or(end);
+            let result = run_for_cycles(s, m, (slice_end - s.cycles) as usize);
+            cycles += result.cycles;
+
+            if result.reason != StopReason::BudgetExhausted && result.reason != StopReason::Halted {
+                return RunResult {
+                    cycles,
+                    reason: result.reason,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::{request_interrupt, trigger_interrupt};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use testing::{state_with_program, TestMachine};
+
+    fn nop_state() -> State {
+        let mut state = state_with_program(Vec::new());
+        state.rom_limit = None;
+        state
+    }
+
+    #[test]
+    fn schedule_and_dispatch_test() {
+        let mut scheduler = Scheduler::new();
+        let mut state = nop_state();
+        let mut machine = TestMachine::default();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+
+        for (at, name) in &[(20, "b"), (10, "a"), (20, "c")] {
+            let fired = fired.clone();
+            let name = *name;
+            scheduler.schedule_at(
+                *at,
+                Box::new(move |s: &mut State, _: &mut TestMachine| {
+                    fired.borrow_mut().push((s.cycles, name));
+                    None
+                }),
+            );
+        }
+        assert_eq!(scheduler.len(), 3);
+        assert_eq!(scheduler.next_event_at(), Some(10));
+
+        assert_eq!(scheduler.dispatch_due(&mut state, &mut machine), 0);
+
+        state.cycles = 20;
+        assert_eq!(scheduler.dispatch_due(&mut state, &mut machine), 3);
+        assert_eq!(*fired.borrow(), vec![(20, "a"), (20, "b"), (20, "c")]);
+        assert_eq!(scheduler.is_empty(), true);
+    }
+
+    #[test]
+    fn cancel_test() {
+        let mut scheduler: Scheduler<TestMachine> = Scheduler::new();
+
+        let first = scheduler.schedule_at(10, Box::new(|_, _| None));
+        scheduler.schedule_at(30, Box::new(|_, _| None));
+
+        assert_eq!(scheduler.cancel(first), true);
+        assert_eq!(scheduler.cancel(first), false);
+        assert_eq!(scheduler.next_event_at(), Some(30));
+        assert_eq!(scheduler.len(), 1);
+    }
+
+    #[test]
+    fn run_for_cycles_test() {
+        let mut scheduler = Scheduler::new();
+        let mut state = nop_state();
+        let mut machine = TestMachine::default();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+
+        // Alternate mid-screen (RST 1) and VBLANK (RST 2) interrupts every
+        // half frame, the way Space Invaders does.
+        let mut next = 1;
+        let log = fired.clone();
+        scheduler.schedule_at(
+            100,
+            Box::new(move |s: &mut State, _: &mut TestMachine| {
+                log.borrow_mut().push((s.cycles, next));
+                trigger_interrupt(s, next);
+                next = 3 - next;
+                Some(100)
+            }),
+        );
+
+        let result = scheduler.run_for_cycles(&mut state, &mut machine, 350);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(result.cycles, 352);
+        assert_eq!(state.cycles, 352);
+        assert_eq!(*fired.borrow(), vec![(100, 1), (200, 2), (300, 1)]);
+    }
+
+    #[test]
+    fn run_for_cycles_breakpoint_test() {
+        let mut scheduler = Scheduler::new();
+        let mut state = nop_state();
+        let mut machine = TestMachine::default();
+
+        scheduler.schedule_in(&state, 8, Box::new(|_, _| None));
+        state.breakpoints.insert(5);
+
+        let result = scheduler.run_for_cycles(&mut state, &mut machine, 1000);
+        assert_eq!(result.reason, StopReason::Breakpoint(5));
+        assert_eq!(result.cycles, 20);
+        assert_eq!(scheduler.is_empty(), true);
+    }
+
+    #[test]
+    fn breakpoint_on_event_boundary_test() {
+        let mut scheduler = Scheduler::new();
+        let mut state = nop_state();
+        let mut machine = TestMachine::default();
+
+        scheduler.schedule_at(20, Box::new(|_, _| None));
+        state.breakpoints.insert(5);
+
+        let result = scheduler.run_for_cycles(&mut state, &mut machine, 1000);
+        assert_eq!(result.reason, StopReason::Breakpoint(5));
+        assert_eq!(result.cycles, 20);
+    }
+
+    #[test]
+    fn halt_waits_for_event_test() {
+        let mut scheduler = Scheduler::new();
+        // HLT
+        let mut state = nop_state();
+        state.memory.set(0, 0x76);
+        let mut machine = TestMachine::default();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+
+        let log = fired.clone();
+        scheduler.schedule_at(
+            100,
+            Box::new(move |s: &mut State, _: &mut TestMachine| {
+                log.borrow_mut().push(s.cycles);
+                trigger_interrupt(s, 1);
+                None
+            }),
+        );
+
+        let result = scheduler.run_for_cycles(&mut state, &mut machine, 110);
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(*fired.borrow(), vec![100]);
+        assert_eq!(state.halted, false);
+        assert_eq!(state.memory.get(0x0ffe), 0x01);
+        assert_eq!(result.cycles, 112);
+
+        state.pc = 0;
+        let result = scheduler.run_for_cycles(&mut state, &mut machine, 1000);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(result.cycles, 7);
+    }
+
+    #[test]
+    fn halt_waits_for_requested_interrupt_test() {
+        let mut scheduler = Scheduler::new();
+        // EI; HLT, with INR A; HLT at 0008.
+        let mut state = nop_state();
+        state.memory.load(0, vec![0xfb, 0x76]);
+        state.memory.load(0x08, vec![0x3c, 0x76]);
+        let mut machine = TestMachine::default();
+
+        scheduler.schedule_at(
+            100,
+            Box::new(|s: &mut State, _: &mut TestMachine| {
+                request_interrupt(s, 1);
+                None
+            }),
+        );
+
+        let result = scheduler.run_for_cycles(&mut state, &mut machine, 200);
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(result.cycles, 123);
+        assert_eq!((state.a, state.pc), (1, 0x0a));
+        assert_eq!(state.pending_interrupt, None);
+    }
+}
@@ -0,0 +1,541 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use cpu::emulate_instruction;
+use json;
+use json::{JsonError, Value};
+use machine::Machine;
+use state::State;
+
+// Runs per-opcode test vectors in the format of the SingleStepTests 8080
+// set: a JSON array of cases, each with the registers and RAM before and
+// after one instruction and one entry per clock cycle. Optional "ports"
+// list the bytes read from and written to I/O ports.
+
+#[derive(Debug)]
+pub enum VectorError {
+    Io(io::Error),
+    Json(JsonError),
+    Format { case: usize, reason: String },
+}
+
+impl fmt::Display for VectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VectorError::Io(e) => write!(f, "I/O error: {}", e),
+            VectorError::Json(e) => write!(f, "bad JSON at {}", e),
+            VectorError::Format { case, reason } => write!(f, "case {}: {}", case, reason),
+        }
+    }
+}
+
+impl From<io::Error> for VectorError {
+    fn from(e: io::Error) -> Self {
+        VectorError::Io(e)
+    }
+}
+
+impl From<JsonError> for VectorError {
+    fn from(e: JsonError) -> Self {
+        VectorError::Json(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    // In PUSH PSW layout.
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    pub ram: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortAccess {
+    pub port: u8,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: CpuState,
+    pub expected: CpuState,
+    pub cycles: usize,
+    pub ports: Vec<PortAccess>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub field: String,
+    pub expected: u16,
+    pub actual: u16,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.field.as_str() {
+            "cycles" => write!(f, "cycles: expected {}, got {}", self.expected, self.actual),
+            "pc" | "sp" => write!(
+                f,
+                "{}: expected {:04x}, got {:04x}",
+                self.field, self.expected, self.actual
+            ),
+            "f" => write!(
+                f,
+                "f: expected {:02x}, got {:02x} ({})",
+                self.expected,
+                self.actual,
+                flag_names(self.expected ^ self.actual)
+            ),
+            _ => write!(
+                f,
+                "{}: expected {:02x}, got {:02x}",
+                self.field, self.expected, self.actual
+            ),
+        }
+    }
+}
+
+fn flag_names(bits: u16) -> String {
+    let names = [
+        (0x80, "S"),
+        (0x40, "Z"),
+        (0x10, "AC"),
+        (0x04, "P"),
+        (0x01, "CY"),
+    ];
+    let differing: Vec<&str> = names
+        .iter()
+        .filter(|&&(bit, _)| bits & bit != 0)
+        .map(|&(_, name)| name)
+        .collect();
+    differing.join(", ")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub name: String,
+    pub mismatches: Vec<Mismatch>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    pub total: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.total - self.failures.len()
+    }
+
+    pub fn extend(&mut self, other: Report) {
+        self.total += other.total;
+        self.failures.extend(other.failures);
+    }
+
+    // A summary line followed by the first `limit` failures.
+    pub fn write(&self, out: &mut dyn Write, limit: usize) -> io::Result<()> {
+        writeln!(out, "{} of {} passed", self.passed(), self.total)?;
+        for failure in self.failures.iter().take(limit) {
+            writeln!(out, "{}:", failure.name)?;
+            for mismatch in failure.mismatches.iter() {
+                writeln!(out, "    {}", mismatch)?;
+            }
+        }
+        if self.failures.len() > limit {
+            writeln!(out, "... and {} more", self.failures.len() - limit)?;
+        }
+        Ok(())
+    }
+}
+
+struct Fields<'a> {
+    case: usize,
+    value: &'a Value,
+}
+
+impl<'a> Fields<'a> {
+    fn error<T>(&self, reason: String) -> Result<T, VectorError> {
+        Err(VectorError::Format {
+            case: self.case,
+            reason,
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<&'a Value, VectorError> {
+        match self.value.get(key) {
+            Some(value) => Ok(value),
+            None => self.error(format!("missing \"{}\"", key)),
+        }
+    }
+
+    fn number(&self, value: &Value, key: &str, max: u64) -> Result<u64, VectorError> {
+        match value.as_u64() {
+            Some(n) if n <= max => Ok(n),
+            _ => self.error(format!("bad \"{}\"", key)),
+        }
+    }
+
+    fn byte(&self, key: &str) -> Result<u8, VectorError> {
+        self.number(self.get(key)?, key, 0xff).map(|n| n as u8)
+    }
+
+    fn word(&self, key: &str) -> Result<u16, VectorError> {
+        self.number(self.get(key)?, key, 0xffff).map(|n| n as u16)
+    }
+
+    fn array(&self, key: &str) -> Result<&'a [Value], VectorError> {
+        match self.get(key)?.as_array() {
+            Some(items) => Ok(items),
+            None => self.error(format!("\"{}\" is not an array", key)),
+        }
+    }
+
+    // [first, second, ...] tuples, as used for RAM and ports.
+    fn pair(&self, item: &Value, key: &str, max: u64) -> Result<(u64, u8), VectorError> {
+        match item.as_array() {
+            Some(pair) if pair.len() >= 2 => Ok((
+                self.number(&pair[0], key, max)?,
+                self.number(&pair[1], key, 0xff)? as u8,
+            )),
+            _ => self.error(format!("bad \"{}\" entry", key)),
+        }
+    }
+
+    fn cpu_state(&self, key: &str) -> Result<CpuState, VectorError> {
+        let fields = Fields {
+            case: self.case,
+            value: self.get(key)?,
+        };
+        let mut ram = Vec::new();
+        for item in fields.array("ram")? {
+            let (address, value) = fields.pair(item, "ram", 0xffff)?;
+            ram.push((address as u16, value));
+        }
+        Ok(CpuState {
+            pc: fields.word("pc")?,
+            sp: fields.word("sp")?,
+            a: fields.byte("a")?,
+            b: fields.byte("b")?,
+            c: fields.byte("c")?,
+            d: fields.byte("d")?,
+            e: fields.byte("e")?,
+            f: fields.byte("f")?,
+            h: fields.byte("h")?,
+            l: fields.byte("l")?,
+            ram,
+        })
+    }
+}
+
+fn parse_case(case: usize, value: &Value) -> Result<TestCase, VectorError> {
+    let fields = Fields { case, value };
+    let name = match fields.get("name")?.as_str() {
+        Some(name) => name.to_string(),
+        None => return fields.error("bad \"name\"".to_string()),
+    };
+
+    let mut ports = Vec::new();
+    if value.get("ports").is_some() {
+        for item in fields.array("ports")? {
+            let (port, value) = fields.pair(item, "ports", 0xff)?;
+            let write = match item
+                .as_array()
+                .and_then(|a| a.get(2))
+                .and_then(|d| d.as_str())
+            {
+                Some("r") => false,
+                Some("w") => true,
+                _ => return fields.error("bad \"ports\" direction".to_string()),
+            };
+            ports.push(PortAccess {
+                port: port as u8,
+                value,
+                write,
+            });
+        }
+    }
+
+    Ok(TestCase {
+        name,
+        initial: fields.cpu_state("initial")?,
+        expected: fields.cpu_state("final")?,
+        cycles: fields.array("cycles")?.len(),
+        ports,
+    })
+}
+
+pub fn parse_tests(text: &str) -> Result<Vec<TestCase>, VectorError> {
+    let value = json::parse(text)?;
+    match value.as_array() {
+        Some(cases) => cases
+            .iter()
+            .enumerate()
+            .map(|(index, case)| parse_case(index, case))
+            .collect(),
+        None => Err(VectorError::Format {
+            case: 0,
+            reason: "not an array of cases".to_string(),
+        }),
+    }
+}
+
+pub fn load_tests(path: &Path) -> Result<Vec<TestCase>, VectorError> {
+    parse_tests(&fs::read_to_string(path)?)
+}
+
+// Answers IN from the case's port list and records OUT.
+struct Ports<'a> {
+    reads: &'a [PortAccess],
+    writes: Vec<(u8, u8)>,
+}
+
+impl<'a> Machine for Ports<'a> {
+    fn input(&self, port: u8) -> u8 {
+        self.reads
+            .iter()
+            .find(|p| !p.write && p.port == port)
+            .map_or(0xff, |p| p.value)
+    }
+
+    fn output(&mut self, port: u8, val: u8) {
+        self.writes.push((port, val));
+    }
+}
+
+// Runs one case from a fresh CPU and lists every field that came out
+// different; an empty list is a pass.
+pub fn run_test(case: &TestCase) -> Vec<Mismatch> {
+    let initial = &case.initial;
+    let mut s = State::new();
+    s.rom_limit = None;
+    s.pc = initial.pc;
+    s.sp = initial.sp;
+    s.a = initial.a;
+    s.b = initial.b;
+    s.c = initial.c;
+    s.d = initial.d;
+    s.e = initial.e;
+    s.h = initial.h;
+    s.l = initial.l;
+    s.cc.set_psw(initial.f);
+    for &(address, value) in initial.ram.iter() {
+        s.memory.set(address, value);
+    }
+
+    let mut ports = Ports {
+        reads: &case.ports,
+        writes: Vec::new(),
+    };
+    let cycles = emulate_instruction(&mut s, &mut ports);
+
+    let expected = &case.expected;
+    let mut mismatches = Vec::new();
+    {
+        let mut check = |field: &str, expected: u16, actual: u16| {
+            if expected != actual {
+                mismatches.push(Mismatch {
+                    field: field.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        };
+        check("pc", expected.pc, s.pc);
+        check("sp", expected.sp, s.sp);
+        check("a", expected.a.into(), s.a.into());
+        check("b", expected.b.into(), s.b.into());
+        check("c", expected.c.into(), s.c.into());
+        check("d", expected.d.into(), s.d.into());
+        check("e", expected.e.into(), s.e.into());
+        check("f", expected.f.into(), s.cc.to_psw().into());
+        check("h", expected.h.into(), s.h.into());
+        check("l", expected.l.into(), s.l.into());
+        for &(address, value) in expected.ram.iter() {
+            check(
+                &format!("ram[{:04x}]", address),
+                value.into(),
+                s.memory.get(address).into(),
+            );
+        }
+        check("cycles", case.cycles as u16, cycles as u16);
+    }
+
+    let expected_writes: Vec<(u8, u8)> = case
+        .ports
+        .iter()
+        .filter(|p| p.write)
+        .map(|p| (p.port, p.value))
+        .collect();
+    for index in 0..expected_writes.len().max(ports.writes.len()) {
+        let (port, expected, actual) = match (expected_writes.get(index), ports.writes.get(index)) {
+            (Some(&(port, e)), Some(&(_, a))) if e != a => (port, e, a),
+            (Some(_), Some(_)) => continue,
+            // A missing or extra write shows up as a mismatch against
+            // the port it should (not) have gone to.
+            (Some(&(port, e)), None) => (port, e, 0),
+            (None, Some(&(port, a))) => (port, 0, a),
+            (None, None) => unreachable!(),
+        };
+        mismatches.push(Mismatch {
+            field: format!("out[{:02x}]", port),
+            expected: expected.into(),
+            actual: actual.into(),
+        });
+    }
+    mismatches
+}
+
+pub fn run_tests(cases: &[TestCase]) -> Report {
+    let mut report = Report::default();
+    for case in cases {
+        report.total += 1;
+        let mismatches = run_test(case);
+        if !mismatches.is_empty() {
+            report.failures.push(Failure {
+                name: case.name.clone(),
+                mismatches,
+            });
+        }
+    }
+    report
+}
+
+// Runs every .json file in a directory, in name order.
+pub fn run_directory(path: &Path) -> Result<Report, VectorError> {
+    let mut files: Vec<_> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .collect();
+    files.sort();
+
+    let mut report = Report::default();
+    for file in files {
+        report.extend(run_tests(&load_tests(&file)?));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    const CASE: &str = r#"[{
+        "name": "3e 3e 5a",
+        "initial": {"pc": 256, "sp": 0, "a": 1, "b": 2, "c": 3, "d": 4, "e": 5,
+                    "f": 2, "h": 6, "l": 7, "ram": [[256, 62], [257, 90]]},
+        "final": {"pc": 258, "sp": 0, "a": 90, "b": 2, "c": 3, "d": 4, "e": 5,
+                  "f": 2, "h": 6, "l": 7, "ram": [[256, 62], [257, 90]]},
+        "cycles": [[256, 62, "r--m"], [256, 62, "r--m"], [256, 62, "r--m"],
+                   [256, null, "----"], [257, 90, "r--m"], [257, 90, "r--m"],
+                   [257, null, "----"]]
+    }]"#;
+
+    #[test]
+    fn parse_test() {
+        let cases = parse_tests(CASE).unwrap();
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].name, "3e 3e 5a");
+        assert_eq!(cases[0].initial.pc, 0x100);
+        assert_eq!(cases[0].expected.a, 0x5a);
+        assert_eq!(cases[0].initial.ram, vec![(0x100, 0x3e), (0x101, 0x5a)]);
+        assert_eq!(cases[0].cycles, 7);
+
+        match parse_tests(&CASE.replace("\"sp\": 0,", "")) {
+            Err(VectorError::Format { case: 0, reason }) => assert_eq!(reason, "missing \"sp\""),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_tests("{}").is_err());
+    }
+
+    #[test]
+    fn mismatch_test() {
+        let mut case = parse_tests(CASE).unwrap().remove(0);
+        assert_eq!(run_test(&case), vec![]);
+
+        case.expected.a = 0x5b;
+        case.expected.f = 0x43;
+        case.expected.ram[1].1 = 0;
+        case.cycles = 4;
+        let report = run_tests(&[case]);
+
+        let mut out = Vec::new();
+        report.write(&mut out, 10).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0 of 1 passed\n3e 3e 5a:\n    a: expected 5b, got 5a\n    \
+             f: expected 43, got 02 (Z, CY)\n    ram[0101]: expected 00, got 5a\n    \
+             cycles: expected 4, got 7\n"
+        );
+    }
+
+    #[test]
+    fn ports_test() {
+        let out = CASE
+            .replace("[256, 62], [257, 90]", "[256, 211], [257, 16]")
+            .replace("\"a\": 90", "\"a\": 1")
+            .replace("\"cycles\"", "\"ports\": [[16, 1, \"w\"]], \"cycles\"");
+        let mut case = parse_tests(&out).unwrap().remove(0);
+        case.cycles = 10;
+        assert_eq!(run_test(&case), vec![]);
+
+        case.ports[0].value = 2;
+        assert_eq!(
+            run_test(&case),
+            vec![Mismatch {
+                field: "out[10]".to_string(),
+                expected: 2,
+                actual: 1,
+            }]
+        );
+    }
+
+    fn testdata() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/8080")
+    }
+
+    // Hand-written cases from the Intel manual; see testdata/8080/README.
+    #[test]
+    fn vectors_test() {
+        let report = run_directory(&testdata()).unwrap();
+        let mut out = Vec::new();
+        report.write(&mut out, 10).unwrap();
+        assert!(
+            report.failures.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&out)
+        );
+        assert!(report.total > 0);
+    }
+
+    // The full SingleStepTests set is too big to check in; point
+    // SINGLESTEP_8080 at a checkout's v1 directory and run with --ignored.
+    #[test]
+    #[ignore]
+    fn full_vectors_test() {
+        let path = env::var("SINGLESTEP_8080").expect("SINGLESTEP_8080 is not set");
+        let report = run_directory(Path::new(&path)).unwrap();
+        let mut out = Vec::new();
+        report.write(&mut out, 20).unwrap();
+        assert!(
+            report.failures.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&out)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+
+use memory::MemoryWrite;
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+// Enough to see what a program is doing without a patching loop eating all
+// of memory.
+const MAX_EVENTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmcEvent {
+    // A byte that had already run as code was overwritten.
+    Patched { write: MemoryWrite, writer: u16 },
+    // An instruction ran from bytes written since they last ran.
+    ExecutedData { pc: u16, address: u16, writer: u16 },
+}
+
+impl fmt::Display for SmcEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmcEvent::Patched { write, writer } => write!(
+                f,
+                "code at {:04x} patched by {:04x}: {:02x} -> {:02x}",
+                write.address, writer, write.old, write.new
+            ),
+            SmcEvent::ExecutedData {
+                pc,
+                address,
+                writer,
+            } if pc == address => write!(f, "executed {:04x}, written by {:04x}", pc, writer),
+            SmcEvent::ExecutedData {
+                pc,
+                address,
+                writer,
+            } => write!(
+                f,
+                "executed {:04x}, operand {:04x} written by {:04x}",
+                pc, address, writer
+            ),
+        }
+    }
+}
+
+// Tracks which bytes have run as code and which were last written by the
+// program, to catch code being patched and data being run.
+#[derive(Debug, Clone)]
+pub struct SmcDetector {
+    executed: Vec<bool>,
+    // The PC of the last write to each byte since it last ran.
+    written: Vec<Option<u16>>,
+    events: Vec<SmcEvent>,
+    dropped: u64,
+}
+
+impl Default for SmcDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmcDetector {
+    pub fn new() -> SmcDetector {
+        SmcDetector {
+            executed: vec![false; ADDRESS_SPACE],
+            written: vec![None; ADDRESS_SPACE],
+            events: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, event: SmcEvent) {
+        if self.events.len() < MAX_EVENTS {
+            self.events.push(event);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    // Called before an instruction of `length` bytes at `pc` runs.
+    pub fn execute(&mut self, pc: u16, length: u16) {
+        for offset in 0..length {
+            let address = pc.wrapping_add(offset);
+            if let Some(writer) = self.written[address as usize].take() {
+                self.push(SmcEvent::ExecutedData {
+                    pc,
+                    address,
+                    writer,
+                });
+            }
+            self.executed[address as usize] = true;
+        }
+    }
+
+    // Called with the writes an instruction at `pc` made.
+    pub fn written(&mut self, pc: u16, writes: &[MemoryWrite]) {
+        for write in writes {
+            if self.executed[write.address as usize] {
+                self.push(SmcEvent::Patched {
+                    write: *write,
+                    writer: pc,
+                });
+            }
+            self.written[write.address as usize] = Some(pc);
+        }
+    }
+
+    pub fn is_code(&self, address: u16) -> bool {
+        self.executed[address as usize]
+    }
+
+    pub fn events(&self) -> &[SmcEvent] {
+        &self.events
+    }
+
+    // Events past the first MAX_EVENTS are only counted.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(address: u16, old: u8, new: u8) -> MemoryWrite {
+        MemoryWrite { address, old, new }
+    }
+
+    #[test]
+    fn patched_test() {
+        let mut detector = SmcDetector::new();
+        detector.execute(0x0100, 2);
+        detector.written(0x0200, &[write(0x0101, 0x05, 0x07), write(0x2400, 0, 1)]);
+
+        assert!(detector.is_code(0x0101));
+        assert!(!detector.is_code(0x2400));
+        assert_eq!(
+            detector.events(),
+            &[SmcEvent::Patched {
+                write: write(0x0101, 0x05, 0x07),
+                writer: 0x0200,
+            }]
+        );
+        assert_eq!(
+            detector.events()[0].to_string(),
+            "code at 0101 patched by 0200: 05 -> 07"
+        );
+    }
+
+    #[test]
+    fn executed_data_test() {
+        let mut detector = SmcDetector::new();
+        detector.written(0x0100, &[write(0x2000, 0, 0xc9), write(0x2002, 0, 0x12)]);
+        detector.execute(0x2000, 1);
+        detector.execute(0x2001, 3);
+        // Running it again without another write is not news.
+        detector.execute(0x2000, 1);
+
+        let events: Vec<String> = detector.events().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            events,
+            vec![
+                "executed 2000, written by 0100",
+                "executed 2001, operand 2002 written by 0100",
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_test() {
+        let mut detector = SmcDetector::new();
+        detector.execute(0x0000, 1);
+        for _ in 0..MAX_EVENTS + 5 {
+            detector.written(0x0010, &[write(0x0000, 0, 0)]);
+        }
+        assert_eq!(detector.events().len(), MAX_EVENTS);
+        assert_eq!(detector.dropped(), 5);
+    }
+}
@@ -17,13 +17,14 @@ pub trait Stack {
 
 impl Stack for State {
     fn pop8(&mut self) -> u8 {
-        self.sp += 1;
-        self.memory.get(self.sp - 1)
+        let value = self.memory.get(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        value
     }
 
     fn push8(&mut self, value: u8) {
-        self.memory.set(self.sp - 1, value);
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
+        self.memory.set(self.sp, value);
     }
 }
 
@@ -1,10 +1,15 @@
-use std::collections::VecDeque;
+use std::collections::HashSet;
 
 use bytes::*;
+use callstack::{CallStack, Frame, FrameKind};
+use coverage::{is_conditional, Coverage};
 use flags::Flags;
 use memory::Memory;
+use profiler::Profiler;
 use program::Program;
+use smc::SmcDetector;
 use stack::Stack;
+use trace::Tracer;
 
 static INSTRUCTION_LENGTH: [u16; 256] = [
     1, 3, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x00..0x0f
@@ -25,7 +30,11 @@ static INSTRUCTION_LENGTH: [u16; 256] = [
     1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1, // 0xc0..0xcf
 ];
 
-#[derive(Debug, Clone, Copy)]
+pub fn instruction_length(opcode: u8) -> u16 {
+    INSTRUCTION_LENGTH[opcode as usize]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Snapshot {
     pub a: u8,
     pub b: u8,
@@ -53,7 +62,16 @@ pub struct State {
     pub int_enable: bool,
     pub memory: Memory,
     pub jumped: bool,
-    pub trace_history: VecDeque<Snapshot>,
+    pub halted: bool,
+    pub pending_interrupt: Option<u8>,
+    pub cycles: u64,
+    pub rom_limit: Option<u16>,
+    pub breakpoints: HashSet<u16>,
+    pub tracer: Tracer,
+    pub call_stack: CallStack,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub smc: Option<SmcDetector>,
 }
 
 impl Default for State {
@@ -78,7 +96,16 @@ impl State {
             int_enable: false,
             memory: Memory::new(),
             jumped: false,
-            trace_history: VecDeque::with_capacity(50),
+            halted: false,
+            pending_interrupt: None,
+            cycles: 0,
+            rom_limit: Some(0x2000),
+            breakpoints: HashSet::new(),
+            tracer: Tracer::default(),
+            call_stack: CallStack::new(),
+            profiler: None,
+            coverage: None,
+            smc: None,
         }
     }
 
@@ -134,7 +161,7 @@ impl State {
 
     pub fn advance(&mut self, opcode: u8) {
         if !self.jumped {
-            self.pc += INSTRUCTION_LENGTH[opcode as usize];
+            self.pc = self.pc.wrapping_add(INSTRUCTION_LENGTH[opcode as usize]);
         }
         self.jumped = false;
     }
@@ -203,7 +230,7 @@ impl State {
         }
     }
 
-    pub fn predicate_for(opcode: u8) -> impl (Fn(&State) -> bool) {
+    pub fn predicate_for(opcode: u8) -> impl Fn(&State) -> bool {
         match (opcode >> 3) & 0x07 {
             0x0 => State::is_nz,
             0x1 => State::is_z,
@@ -218,9 +245,7 @@ impl State {
     }
 
     pub fn set_flags_no_carry(&mut self, result: u8) {
-        self.cc.set_z(result);
-        self.cc.set_s(result);
-        self.cc.set_p(result);
+        self.cc.set_szp(result);
     }
 
     pub fn set_flags(&mut self, result: u16) {
@@ -229,10 +254,21 @@ impl State {
     }
 
     pub fn add8(&mut self, addend: u8) {
-        let (result, carry) = self.a.overflowing_add(addend);
+        self.a = self.cc.add(self.a, addend, false);
+    }
+
+    pub fn inr(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
         self.set_flags_no_carry(result);
-        self.a = result;
-        self.cc.cy = carry;
+        self.cc.ac = (value & 0x0f) == 0x0f;
+        result
+    }
+
+    pub fn dcr(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.set_flags_no_carry(result);
+        self.cc.ac = (value & 0x0f) != 0;
+        result
     }
 
     pub fn add16(&mut self, addend: u16) {
@@ -242,33 +278,23 @@ impl State {
     }
 
     pub fn adc8(&mut self, addend: u8) {
-        let result = u16::from(self.a) + u16::from(addend) + u16::from(self.cc.z);
-        self.set_flags(result);
-        self.a = low_order_byte(result);
+        self.a = self.cc.add(self.a, addend, self.cc.cy);
     }
 
     pub fn sub8(&mut self, subtractand: u8) {
-        let (result, carry) = self.a.overflowing_sub(subtractand);
-        self.set_flags_no_carry(result);
-        self.cc.cy = carry;
-        self.a = result;
+        self.a = self.cc.subtract(self.a, subtractand, false);
     }
 
     pub fn sbb8(&mut self, subtractand: u8) {
-        let result = self
-            .a
-            .wrapping_sub(subtractand)
-            .wrapping_sub(self.cc.cy as u8);
-
-        self.set_flags_no_carry(result);
-        self.cc.cy = self.a < subtractand;
-        self.a = result;
+        self.a = self.cc.subtract(self.a, subtractand, self.cc.cy);
     }
 
     pub fn and8(&mut self, operand: u8) {
         let result = self.a & operand;
         self.set_flags_no_carry(result);
         self.cc.cy = false;
+        // The 8080 sets AC from bit 3 of either operand.
+        self.cc.ac = ((self.a | operand) & 0x08) != 0;
         self.a = result;
     }
 
@@ -276,6 +302,7 @@ impl State {
         let result = self.a ^ operand;
         self.set_flags_no_carry(result);
         self.cc.cy = false;
+        self.cc.ac = false;
         self.a = result;
     }
 
@@ -283,52 +310,101 @@ impl State {
         let result = self.a | operand;
         self.set_flags_no_carry(result);
         self.cc.cy = false;
+        self.cc.ac = false;
         self.a = result;
     }
 
     pub fn cmp8(&mut self, operand: u8) {
-        let (result, carry) = self.a.overflowing_sub(operand);
-        self.set_flags_no_carry(result);
+        self.cc.subtract(self.a, operand, false);
+    }
+
+    pub fn daa(&mut self) {
+        let (low, high) = (self.a & 0x0f, self.a >> 4);
+        let mut correction = 0;
+        let mut carry = self.cc.cy;
+        if self.cc.ac || low > 9 {
+            correction |= 0x06;
+        }
+        if carry || high > 9 || (high >= 9 && low > 9) {
+            correction |= 0x60;
+            carry = true;
+        }
+        self.a = self.cc.add(self.a, correction, false);
         self.cc.cy = carry;
     }
 
     pub fn jump_if(&mut self, predicate: impl Fn(&State) -> bool) {
         let new_address = assemble_word(self.get_arg(2), self.get_arg(1));
-        if predicate(self) {
+        let taken = predicate(self);
+        self.record_branch(taken);
+        if taken {
             self.pc = new_address;
             self.jumped = true;
         }
     }
 
-    pub fn call_if(&mut self, predicate: impl Fn(&State) -> bool) {
+    pub fn call_if(&mut self, predicate: impl Fn(&State) -> bool) -> bool {
         let new_address = assemble_word(self.get_arg(2), self.get_arg(1));
-        if predicate(self) {
-            let ret = self.pc + 3;
-            self.push16(ret);
-            self.pc = new_address;
-            self.jumped = true;
+        let taken = predicate(self);
+        self.record_branch(taken);
+        if taken {
+            self.enter(FrameKind::Call, new_address, 3);
         }
+        taken
     }
 
     pub fn rst_to(&mut self, target: u16) {
-        let ret = self.pc + 1;
+        self.enter(FrameKind::Rst, target, 1);
+    }
+
+    fn record_branch(&mut self, taken: bool) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            if is_conditional(self.memory.get(self.pc)) {
+                coverage.record_branch(self.pc, taken);
+            }
+        }
+    }
+
+    // Pushes the address of the next instruction (or of the interrupted one
+    // when length is 0) and records the entry on the shadow call stack.
+    pub fn enter(&mut self, kind: FrameKind, target: u16, length: u16) {
+        let ret = self.pc.wrapping_add(length);
         self.push16(ret);
+        self.call_stack.enter(Frame {
+            kind,
+            call_site: self.pc,
+            target,
+            return_address: ret,
+            slot: self.sp,
+        });
         self.pc = target;
         self.jumped = true;
     }
 
-    pub fn ret_if(&mut self, predicate: impl Fn(&State) -> bool) {
-        if predicate(self) {
+    pub fn ret_if(&mut self, predicate: impl Fn(&State) -> bool) -> bool {
+        let taken = predicate(self);
+        self.record_branch(taken);
+        if taken {
+            self.call_stack.ret(self.sp);
             self.pc = self.pop16();
             self.jumped = true;
         }
+        taken
     }
 
-    pub fn stack_debug(&self, n: usize) {
-        println!("Stack pointer: {:04x}", self.sp);
-        for i in 0..n {
-            println!("{:02x}", self.memory.get(self.sp + (i as u16)));
-        }
+    // The top n 16-bit words on the stack with their addresses, starting
+    // at SP.
+    pub fn stack_view(&self, n: usize) -> Vec<(u16, u16)> {
+        (0..n)
+            .map(|i| {
+                let address = self.sp.wrapping_add(2 * i as u16);
+                let word = assemble_word(
+                    self.memory.get(address.wrapping_add(1)),
+                    self.memory.get(address),
+                );
+                (address, word)
+            })
+            .collect()
     }
 
     pub fn operate8(&mut self, opcode: u8, operand: u8) {
@@ -346,6 +422,14 @@ impl State {
     }
 }
 
+// A State can be handed to a worker thread, so everything it owns has to
+// be Send. This fails to compile otherwise.
+fn _assert_send<T: Send>() {}
+
+fn _state_is_send() {
+    _assert_send::<State>();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -588,6 +672,58 @@ mod tests {
         assert_eq!(state.cc.z, false);
         assert_eq!(state.cc.s, true);
         assert_eq!(state.cc.cy, false);
+
+        // Only the carry is added, whatever Z says.
+        state.a = 0x10;
+        state.cc.z = true;
+        state.cc.cy = false;
+        state.adc8(0x01);
+        assert_eq!(state.a, 0x11);
+    }
+
+    #[test]
+    fn test_aux_carry() {
+        let mut state = State::new();
+
+        state.a = 0x0f;
+        state.add8(0x01);
+        assert_eq!(state.cc.ac, true);
+
+        state.a = 0x10;
+        state.sub8(0x01);
+        assert_eq!(state.cc.ac, false);
+        state.a = 0x11;
+        state.sub8(0x01);
+        assert_eq!(state.cc.ac, true);
+
+        assert_eq!(state.inr(0x2f), 0x30);
+        assert_eq!(state.cc.ac, true);
+        assert_eq!(state.dcr(0x30), 0x2f);
+        assert_eq!(state.cc.ac, false);
+
+        state.a = 0x08;
+        state.and8(0x01);
+        assert_eq!(state.cc.ac, true);
+        state.or8(0x08);
+        assert_eq!(state.cc.ac, false);
+    }
+
+    #[test]
+    fn test_daa() {
+        let mut state = State::new();
+
+        state.a = 0x19;
+        state.add8(0x28);
+        state.daa();
+        assert_eq!(state.a, 0x47);
+        assert_eq!(state.cc.cy, false);
+
+        state.a = 0x99;
+        state.add8(0x01);
+        state.daa();
+        assert_eq!(state.a, 0x00);
+        assert_eq!(state.cc.cy, true);
+        assert_eq!(state.cc.z, true);
     }
 
     #[test]
@@ -727,10 +863,10 @@ mod tests {
         state.memory.set(0x22, 0x32);
 
         state.pc = 0x20;
-        state.jump_if(|ref s| s.pc == 0x21);
+        state.jump_if(|s| s.pc == 0x21);
         assert_eq!(state.pc, 0x20);
 
-        state.jump_if(|ref s| s.pc < 0x21);
+        state.jump_if(|s| s.pc < 0x21);
         assert_eq!(state.pc, 0x3216);
     }
 
@@ -745,14 +881,14 @@ mod tests {
         state.memory.set(0x3022, 0x32);
 
         state.pc = 0x3020;
-        state.call_if(|ref s| s.pc > 0x4000);
+        assert_eq!(state.call_if(|s| s.pc > 0x4000), false);
         assert_eq!(state.pc, 0x3020);
         assert_eq!(state.sp, 0xff);
         assert_eq!(state.memory.get(0xfe), 0x00);
         assert_eq!(state.memory.get(0xfd), 0x00);
 
         state.pc = 0x3020;
-        state.call_if(|ref s| s.pc < 0x4000);
+        assert_eq!(state.call_if(|s| s.pc < 0x4000), true);
         assert_eq!(state.pc, 0x3216);
         assert_eq!(state.sp, 0xfd);
         assert_eq!(state.memory.get(0xfe), 0x30);
@@ -767,12 +903,31 @@ mod tests {
         state.push16(0x9876);
 
         state.pc = 0xcafe;
-        state.ret_if(|ref s| s.a != 0);
+        assert_eq!(state.ret_if(|s| s.a != 0), false);
         assert_eq!(state.pc, 0xcafe);
         assert_eq!(state.jumped, false);
 
-        state.ret_if(|ref s| s.a == 0);
+        assert_eq!(state.ret_if(|s| s.a == 0), true);
         assert_eq!(state.pc, 0x9876);
         assert_eq!(state.jumped, true);
     }
+
+    #[test]
+    fn test_stack_view() {
+        let mut state = State::new();
+
+        state.sp = 0x2400;
+        state.push16(0x1234);
+        state.push16(0xabcd);
+
+        assert_eq!(
+            state.stack_view(3),
+            vec![(0x23fc, 0xabcd), (0x23fe, 0x1234), (0x2400, 0x0000)]
+        );
+
+        state.sp = 0xffff;
+        state.memory.set(0xffff, 0x34);
+        state.memory.set(0x0000, 0x12);
+        assert_eq!(state.stack_view(1), vec![(0xffff, 0x1234)]);
+    }
 }
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    Parse { line: usize, reason: String },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Io(e) => write!(f, "I/O error: {}", e),
+            SymbolError::Parse { line, reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl From<io::Error> for SymbolError {
+    fn from(e: io::Error) -> Self {
+        SymbolError::Io(e)
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text
+        .trim_start_matches("0x")
+        .trim_start_matches('$')
+        .trim_end_matches(['h', 'H']);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    by_address: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Default::default()
+    }
+
+    // Accepts both `ADDR NAME` lines and the `.SYM` files written by CP/M
+    // assemblers and linkers, which pack several `ADDR NAME` pairs onto each
+    // line and may end with a ^Z. Comments start with ';' or '#'.
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap();
+            let line = line.split('\x1a').next().unwrap();
+            let mut words = line.split_whitespace();
+
+            while let Some(address) = words.next() {
+                let bad = |reason: String| SymbolError::Parse {
+                    line: index + 1,
+                    reason,
+                };
+                let address = parse_address(address)
+                    .ok_or_else(|| bad(format!("'{}' is not a hex address", address)))?;
+                let name = words
+                    .next()
+                    .ok_or_else(|| bad(format!("no name for {:04x}", address)))?;
+                symbols.insert(address, name);
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols, SymbolError> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    // Later symbols at the same address replace earlier ones.
+    pub fn insert(&mut self, address: u16, name: &str) {
+        if let Some(old) = self.by_address.insert(address, name.to_string()) {
+            self.by_name.remove(&old);
+        }
+        self.by_name.insert(name.to_string(), address);
+    }
+
+    pub fn extend(&mut self, other: &Symbols) {
+        for (&address, name) in other.by_address.iter() {
+            self.insert(address, name);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_address.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.by_address
+            .iter()
+            .map(|(&address, name)| (address, name.as_str()))
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(|name| name.as_str())
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).cloned()
+    }
+
+    // The nearest symbol at or below the address and the offset from it.
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        self.by_address
+            .range(..=address)
+            .next_back()
+            .map(|(&base, name)| (name.as_str(), address - base))
+    }
+
+    // `Name`, `Name+0x12`, or `$1234` when no symbol precedes the address.
+    pub fn format(&self, address: u16) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+            None => format!("${:04x}", address),
+        }
+    }
+
+    // Parses `Name`, `Name+OFFSET` or a hex address. A symbol wins over
+    // bare hex spelled the same, such as `add`; write `$add`, `0xadd` or
+    // `addh` for the address.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        let (base, offset) = match text.find('+') {
+            Some(index) => (&text[..index], Some(&text[index + 1..])),
+            None => (text, None),
+        };
+        let marked = base.starts_with('$') || base.starts_with("0x") || base.ends_with(['h', 'H']);
+        let base = match parse_address(base) {
+            Some(address) if marked => address,
+            number => self.address(base).or(number)?,
+        };
+        match offset {
+            Some(offset) => Some(base.wrapping_add(parse_address(offset)?)),
+            None => Some(base),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_simple_test() {
+        let text = "\
+; Space Invaders
+0000 Reset
+01e4 DrawSprite   # blits a sprite
+$1a5c ClearScreen
+
+0x20c0 isrDelay
+";
+        let symbols = Symbols::parse(text).unwrap();
+
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.name(0x01e4), Some("DrawSprite"));
+        assert_eq!(symbols.address("ClearScreen"), Some(0x1a5c));
+        assert_eq!(symbols.address("isrDelay"), Some(0x20c0));
+    }
+
+    #[test]
+    fn parse_cpm_sym_test() {
+        let text = "0100 START\t0103 LOOP\t0110 MSG\t0005 BDOS\r\n0200 BUFFER\r\n\x1a\x1a\x1a";
+        let symbols = Symbols::parse(text).unwrap();
+
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.name(0x0005), Some("BDOS"));
+        assert_eq!(symbols.address("BUFFER"), Some(0x0200));
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        match Symbols::parse("0100 START\nLOOP 0103\n") {
+            Err(SymbolError::Parse { line: 2, .. }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match Symbols::parse("0100 START 0103\n") {
+            Err(SymbolError::Parse {
+                line: 1,
+                ref reason,
+            }) => {
+                assert_eq!(reason, "no name for 0103")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lookup_test() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x0100, "Start");
+        symbols.insert(0x01e4, "DrawSprite");
+
+        assert_eq!(symbols.lookup(0x00ff), None);
+        assert_eq!(symbols.lookup(0x0100), Some(("Start", 0)));
+        assert_eq!(symbols.lookup(0x01f6), Some(("DrawSprite", 0x12)));
+
+        assert_eq!(symbols.format(0x01e4), "DrawSprite");
+        assert_eq!(symbols.format(0x01f6), "DrawSprite+0x12");
+        assert_eq!(symbols.format(0x0010), "$0010");
+    }
+
+    #[test]
+    fn resolve_test() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x01e4, "DrawSprite");
+
+        assert_eq!(symbols.resolve("DrawSprite"), Some(0x01e4));
+        assert_eq!(symbols.resolve("DrawSprite+12"), Some(0x01f6));
+        assert_eq!(symbols.resolve("DrawSprite+0x12"), Some(0x01f6));
+        assert_eq!(symbols.resolve("2400"), Some(0x2400));
+        assert_eq!(symbols.resolve("Nowhere"), None);
+
+        symbols.insert(0x0200, "add");
+        symbols.insert(0x0300, "Cafe");
+        assert_eq!(symbols.resolve("add"), Some(0x0200));
+        assert_eq!(symbols.resolve("$add"), Some(0x0add));
+        assert_eq!(symbols.resolve("0xadd"), Some(0x0add));
+        assert_eq!(symbols.resolve("addh"), Some(0x0add));
+        assert_eq!(symbols.resolve("Cafe+1"), Some(0x0301));
+        assert_eq!(symbols.resolve("beef"), Some(0xbeef));
+    }
+
+    #[test]
+    fn replace_test() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x0100, "Old");
+        symbols.insert(0x0100, "New");
+
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols.name(0x0100), Some("New"));
+        assert_eq!(symbols.address("Old"), None);
+    }
+}
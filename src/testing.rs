@@ -0,0 +1,46 @@
+// Fixtures shared by the unit tests.
+
+use machine::Machine;
+use state::State;
+
+// Reads return the port number inverted, so a test can tell which port was
+// read, and writes are logged in order. The log is also the machine's save
+// state.
+#[derive(Debug, Default)]
+pub(crate) struct TestMachine {
+    pub outputs: Vec<(u8, u8)>,
+}
+
+impl Machine for TestMachine {
+    fn input(&self, port: u8) -> u8 {
+        port ^ 0xff
+    }
+
+    fn output(&mut self, port: u8, val: u8) {
+        self.outputs.push((port, val));
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.outputs
+            .iter()
+            .flat_map(|&(port, val)| [port, val])
+            .collect()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let pairs = data.chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return Err(format!("odd output log length {}", data.len()));
+        }
+        self.outputs = pairs.map(|pair| (pair[0], pair[1])).collect();
+        Ok(())
+    }
+}
+
+// The program at 0000, with the stack well clear of it.
+pub(crate) fn state_with_program(program: Vec<u8>) -> State {
+    let mut state = State::new();
+    state.memory.load(0, program);
+    state.sp = 0x1000;
+    state
+}
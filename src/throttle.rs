@@ -0,0 +1,387 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpu::{run_for_cycles, RunResult, StopReason};
+use machine::Machine;
+use state::State;
+
+pub const SPACE_INVADERS_HZ: u64 = 2_000_000;
+pub const INTEL_8080_HZ: u64 = 2_000_000;
+pub const INTEL_8080A_1_HZ: u64 = 3_125_000;
+
+pub const DEFAULT_FRAME_RATE: u32 = 60;
+
+// If the host falls further behind than this we stop trying to catch up,
+// otherwise a stall (e.g. a debugger pause) would be followed by a burst
+// of unthrottled emulation.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+pub trait Clock {
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub cycles: u64,
+    pub elapsed: Duration,
+    pub target_hz: f64,
+    pub actual_hz: f64,
+}
+
+impl Stats {
+    pub fn speed_ratio(&self) -> f64 {
+        if self.target_hz == 0.0 {
+            0.0
+        } else {
+            self.actual_hz / self.target_hz
+        }
+    }
+}
+
+pub struct Throttle<C: Clock> {
+    clock: C,
+    clock_hz: u64,
+    frame_rate: u32,
+    speed: f64,
+    turbo: bool,
+    paused: bool,
+    // Pacing is measured from the last resync point.
+    origin: Duration,
+    pending_cycles: u64,
+    // Statistics exclude time spent paused.
+    total_cycles: u64,
+    running_time: Duration,
+    run_started: Duration,
+}
+
+impl Throttle<SystemClock> {
+    pub fn new(clock_hz: u64) -> Throttle<SystemClock> {
+        Throttle::with_clock(clock_hz, SystemClock::new())
+    }
+}
+
+impl<C: Clock> Throttle<C> {
+    pub fn with_clock(clock_hz: u64, clock: C) -> Throttle<C> {
+        let now = clock.now();
+        Throttle {
+            clock,
+            clock_hz,
+            frame_rate: DEFAULT_FRAME_RATE,
+            speed: 1.0,
+            turbo: false,
+            paused: false,
+            origin: now,
+            pending_cycles: 0,
+            total_cycles: 0,
+            running_time: Duration::from_secs(0),
+            run_started: now,
+        }
+    }
+
+    pub fn clock_hz(&self) -> u64 {
+        self.clock_hz
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: u32) {
+        assert!(frame_rate > 0, "frame rate must be positive");
+        self.frame_rate = frame_rate;
+    }
+
+    pub fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    pub fn cycles_per_frame(&self) -> usize {
+        (self.clock_hz / u64::from(self.frame_rate)) as usize
+    }
+
+    pub fn set_speed(&mut self, multiplier: f64) {
+        assert!(multiplier > 0.0, "speed multiplier must be positive");
+        self.speed = multiplier;
+        self.resync();
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+        self.resync();
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.turbo
+    }
+
+    pub fn pause(&mut self) {
+        if !self.paused {
+            self.paused = true;
+            self.running_time += self.clock.now() - self.run_started;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.run_started = self.clock.now();
+            self.resync();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn add_cycles(&mut self, cycles: usize) {
+        self.pending_cycles += cycles as u64;
+        self.total_cycles += cycles as u64;
+    }
+
+    // Sleeps until wall-clock time catches up with the cycles emulated since
+    // the last resync.
+    pub fn sync(&mut self) {
+        if self.turbo || self.paused {
+            return;
+        }
+
+        let target = self.origin + self.emulated_time(self.pending_cycles);
+        let now = self.clock.now();
+        if target > now {
+            self.clock.sleep(target - now);
+        } else if now - target > MAX_LAG {
+            self.resync();
+        }
+    }
+
+    // A paused frame runs nothing, which is how callers can tell it from a
+    // real one, but still takes a frame's worth of time so that a host loop
+    // calling this every frame doesn't spin.
+    pub fn run_frame(&mut self, s: &mut State, m: &mut impl Machine) -> RunResult {
+        if self.paused {
+            self.clock.sleep(Duration::from_secs(1) / self.frame_rate);
+            return RunResult {
+                cycles: 0,
+                reason: StopReason::BudgetExhausted,
+            };
+        }
+
+        let budget = self.cycles_per_frame();
+        let mut result = run_for_cycles(s, m, budget);
+        if result.reason == StopReason::Halted && result.cycles < budget {
+            // A halted CPU idles out the rest of the frame, as the real one
+            // would, so that waiting for an interrupt isn't a busy loop.
+            s.cycles += (budget - result.cycles) as u64;
+            result.cycles = budget;
+        }
+        self.add_cycles(result.cycles);
+        self.sync();
+        result
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut elapsed = self.running_time;
+        if !self.paused {
+            elapsed += self.clock.now() - self.run_started;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        Stats {
+            cycles: self.total_cycles,
+            elapsed,
+            target_hz: self.clock_hz as f64 * self.speed,
+            actual_hz: if seconds > 0.0 {
+                self.total_cycles as f64 / seconds
+            } else {
+                0.0
+            },
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.total_cycles = 0;
+        self.running_time = Duration::from_secs(0);
+        self.run_started = self.clock.now();
+    }
+
+    fn emulated_time(&self, cycles: u64) -> Duration {
+        Duration::from_secs_f64(cycles as f64 / (self.clock_hz as f64 * self.speed))
+    }
+
+    fn resync(&mut self) {
+        self.origin = self.clock.now();
+        self.pending_cycles = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{state_with_program, TestMachine};
+
+    struct FakeClock {
+        now: Duration,
+        slept: Duration,
+    }
+
+    impl FakeClock {
+        fn new() -> FakeClock {
+            FakeClock {
+                now: Duration::from_secs(0),
+                slept: Duration::from_secs(0),
+            }
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.slept += duration;
+        }
+    }
+
+    #[test]
+    fn cycles_per_frame_test() {
+        let mut throttle = Throttle::with_clock(SPACE_INVADERS_HZ, FakeClock::new());
+        assert_eq!(throttle.cycles_per_frame(), 33_333);
+
+        throttle.set_frame_rate(50);
+        assert_eq!(throttle.cycles_per_frame(), 40_000);
+    }
+
+    #[test]
+    fn sync_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080_HZ, FakeClock::new());
+
+        throttle.add_cycles(1_000_000);
+        throttle.sync();
+        assert_eq!(throttle.clock.now, Duration::from_millis(500));
+
+        // Host time already spent counts towards the target.
+        throttle.clock.now += Duration::from_millis(200);
+        throttle.add_cycles(1_000_000);
+        throttle.sync();
+        assert_eq!(throttle.clock.now, Duration::from_secs(1));
+        assert_eq!(throttle.clock.slept, Duration::from_millis(800));
+    }
+
+    #[test]
+    fn speed_multiplier_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080A_1_HZ, FakeClock::new());
+
+        throttle.set_speed(2.0);
+        throttle.add_cycles(3_125_000);
+        throttle.sync();
+        assert_eq!(throttle.clock.now, Duration::from_millis(500));
+        assert_eq!(throttle.stats().target_hz, 6_250_000.0);
+    }
+
+    #[test]
+    fn turbo_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080_HZ, FakeClock::new());
+
+        throttle.set_turbo(true);
+        throttle.add_cycles(2_000_000);
+        throttle.sync();
+        assert_eq!(throttle.clock.slept, Duration::from_secs(0));
+
+        // Leaving turbo does not try to pay back the skipped time.
+        throttle.set_turbo(false);
+        throttle.add_cycles(2_000);
+        throttle.sync();
+        assert_eq!(throttle.clock.slept, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn lag_resync_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080_HZ, FakeClock::new());
+
+        throttle.add_cycles(2_000);
+        throttle.clock.now += Duration::from_secs(5);
+        throttle.sync();
+
+        throttle.add_cycles(2_000);
+        throttle.sync();
+        assert_eq!(throttle.clock.slept, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn pause_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080_HZ, FakeClock::new());
+        let mut state = State::new();
+
+        throttle.pause();
+        assert_eq!(throttle.is_paused(), true);
+        let result = throttle.run_frame(&mut state, &mut TestMachine::default());
+        assert_eq!(result.cycles, 0);
+        assert_eq!(state.pc, 0);
+        // It waits out the frame rather than returning straight away.
+        assert_eq!(throttle.clock.slept, Duration::from_secs(1) / 60);
+
+        throttle.clock.now += Duration::from_secs(10);
+        throttle.resume();
+        assert_eq!(throttle.stats().elapsed, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn run_frame_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080_HZ, FakeClock::new());
+        let mut state = State::new();
+        state.rom_limit = None;
+
+        let result = throttle.run_frame(&mut state, &mut TestMachine::default());
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        assert_eq!(result.cycles, 33_336);
+        assert_eq!(throttle.clock.now, Duration::from_micros(16_668));
+
+        let stats = throttle.stats();
+        assert_eq!(stats.cycles, 33_336);
+        assert!((stats.speed_ratio() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn halted_frame_test() {
+        let mut throttle = Throttle::with_clock(INTEL_8080_HZ, FakeClock::new());
+        // NOP; HLT
+        let mut state = state_with_program(vec![0x00, 0x76]);
+
+        let result = throttle.run_frame(&mut state, &mut TestMachine::default());
+        assert_eq!(result.reason, StopReason::Halted);
+        assert_eq!(result.cycles, 33_333);
+        assert_eq!(state.cycles, 33_333);
+        assert_eq!(throttle.clock.slept, Duration::from_nanos(16_666_500));
+    }
+}
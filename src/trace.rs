@@ -0,0 +1,546 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+use bytes::assemble_word;
+use disasm::{mnemonic, mnemonic_with_symbols};
+use state::{Snapshot, State};
+use symbols::Symbols;
+use tracediff::differing_fields;
+
+pub const DEFAULT_HISTORY: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    pub snapshot: Snapshot,
+    pub psw: u8,
+    pub cycles: u64,
+    pub bytes: [u8; 4],
+}
+
+impl TraceEntry {
+    // Captures the state just before the instruction at PC executes.
+    pub fn capture(s: &State) -> TraceEntry {
+        let mut bytes = [0; 4];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = s.memory.get(s.pc.wrapping_add(offset as u16));
+        }
+
+        TraceEntry {
+            snapshot: s.snapshot(),
+            psw: s.cc.to_psw(),
+            cycles: s.cycles,
+            bytes,
+        }
+    }
+
+    pub fn line(&self) -> TraceLine {
+        let snap = &self.snapshot;
+        TraceLine {
+            pc: snap.pc,
+            af: assemble_word(snap.a, self.psw),
+            bc: assemble_word(snap.b, snap.c),
+            de: assemble_word(snap.d, snap.e),
+            hl: assemble_word(snap.h, snap.l),
+            sp: snap.sp,
+            cycles: self.cycles,
+            bytes: Some(self.bytes),
+        }
+    }
+
+    pub fn disassembly(&self) -> String {
+        mnemonic(&self.bytes)
+    }
+
+    pub fn format(&self, disassembly: bool) -> String {
+        self.format_with_symbols(disassembly, None)
+    }
+
+    // With symbols, the disassembly is prefixed by the location of the
+    // instruction, e.g. `DrawSprite+0x3: CALL ClearScreen`.
+    pub fn format_with_symbols(&self, disassembly: bool, symbols: Option<&Symbols>) -> String {
+        match symbols {
+            _ if !disassembly => self.line().to_string(),
+            Some(symbols) if symbols.lookup(self.snapshot.pc).is_some() => format!(
+                "{} ; {}: {}",
+                self.line(),
+                symbols.format(self.snapshot.pc),
+                mnemonic_with_symbols(&self.bytes, symbols)
+            ),
+            Some(symbols) => format!(
+                "{} ; {}",
+                self.line(),
+                mnemonic_with_symbols(&self.bytes, symbols)
+            ),
+            None => format!("{} ; {}", self.line(), self.disassembly()),
+        }
+    }
+}
+
+// One line of a trace in the format used by common 8080 emulators' debug
+// output, so traces can be diffed against theirs directly:
+//
+//   PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0	(C3 AB 01 00)
+//
+// AF holds the flags in PUSH PSW layout. Anything after a ';' is a comment.
+// The opcode bytes are optional, as not every emulator prints them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceLine {
+    pub pc: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub cycles: u64,
+    pub bytes: Option<[u8; 4]>,
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC: {:04X}, AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}, CYC: {}",
+            self.pc, self.af, self.bc, self.de, self.hl, self.sp, self.cycles
+        )?;
+        if let Some(bytes) = self.bytes {
+            write!(
+                f,
+                "\t({:02X} {:02X} {:02X} {:02X})",
+                bytes[0], bytes[1], bytes[2], bytes[3]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl TraceLine {
+    pub fn parse(line: &str) -> Option<TraceLine> {
+        let line = line.split(';').next().unwrap();
+        let (fields, bytes) = match line.find('(') {
+            Some(open) => (&line[..open], Some(&line[open + 1..])),
+            None => (line, None),
+        };
+
+        let mut parsed = TraceLine {
+            pc: 0,
+            af: 0,
+            bc: 0,
+            de: 0,
+            hl: 0,
+            sp: 0,
+            cycles: 0,
+            bytes: None,
+        };
+        let mut seen = 0;
+        for field in fields.split(',') {
+            let mut parts = field.splitn(2, ':');
+            let key = parts.next()?.trim();
+            let value = parts.next()?.trim();
+            match key {
+                "PC" => parsed.pc = u16::from_str_radix(value, 16).ok()?,
+                "AF" => parsed.af = u16::from_str_radix(value, 16).ok()?,
+                "BC" => parsed.bc = u16::from_str_radix(value, 16).ok()?,
+                "DE" => parsed.de = u16::from_str_radix(value, 16).ok()?,
+                "HL" => parsed.hl = u16::from_str_radix(value, 16).ok()?,
+                "SP" => parsed.sp = u16::from_str_radix(value, 16).ok()?,
+                "CYC" => parsed.cycles = value.parse().ok()?,
+                _ => return None,
+            }
+            seen += 1;
+        }
+        if seen != 7 {
+            return None;
+        }
+
+        if let Some(bytes) = bytes {
+            let bytes = bytes.split(')').next()?;
+            let mut parsed_bytes = [0; 4];
+            for (slot, byte) in parsed_bytes.iter_mut().zip(bytes.split_whitespace()) {
+                *slot = u8::from_str_radix(byte, 16).ok()?;
+            }
+            parsed.bytes = Some(parsed_bytes);
+        }
+
+        Some(parsed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // Number of instructions traced before the mismatching one.
+    pub index: u64,
+    pub expected: TraceLine,
+    pub actual: TraceLine,
+}
+
+// Records executed instructions. A ring of recent entries is kept in
+// memory; optionally every entry is also streamed to a writer and/or
+// checked against a reference trace as it is produced.
+pub struct Tracer {
+    capacity: usize,
+    history: VecDeque<TraceEntry>,
+    sink: Option<Box<dyn Write + Send>>,
+    disassembly: bool,
+    symbols: Option<Arc<Symbols>>,
+    reference: Option<Box<dyn BufRead + Send>>,
+    divergence: Option<Divergence>,
+    error: Option<io::Error>,
+    count: u64,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY)
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("capacity", &self.capacity)
+            .field("count", &self.count)
+            .field("streaming", &self.sink.is_some())
+            .field("comparing", &self.reference.is_some())
+            .field("divergence", &self.divergence)
+            .finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(capacity: usize) -> Tracer {
+        Tracer {
+            capacity,
+            history: VecDeque::with_capacity(capacity),
+            sink: None,
+            disassembly: true,
+            symbols: None,
+            reference: None,
+            divergence: None,
+            error: None,
+            count: 0,
+        }
+    }
+
+    pub fn disabled() -> Tracer {
+        Tracer::new(0)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 || self.sink.is_some() || self.reference.is_some()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.history.len() > capacity {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn set_disassembly(&mut self, disassembly: bool) {
+        self.disassembly = disassembly;
+    }
+
+    pub fn set_symbols(&mut self, symbols: Option<Arc<Symbols>>) {
+        self.symbols = symbols;
+    }
+
+    // Formats an entry the way it is streamed, with symbols if set.
+    pub fn format(&self, entry: &TraceEntry) -> String {
+        entry.format_with_symbols(self.disassembly, self.symbols.as_ref().map(|s| s.as_ref()))
+    }
+
+    pub fn stream_to(&mut self, sink: Box<dyn Write + Send>) {
+        self.sink = Some(sink);
+    }
+
+    pub fn stop_streaming(&mut self) -> Option<Box<dyn Write + Send>> {
+        let mut sink = self.sink.take();
+        if let Some(ref mut w) = sink {
+            if let Err(e) = w.flush() {
+                self.error = Some(e);
+            }
+        }
+        sink
+    }
+
+    pub fn compare_with(&mut self, reference: Box<dyn BufRead + Send>) {
+        self.reference = Some(reference);
+        self.divergence = None;
+    }
+
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    // The first error from the output stream, which is closed when one
+    // occurs rather than failing emulation.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // Oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.history.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.capacity > 0 {
+            if self.history.len() == self.capacity {
+                self.history.pop_front();
+            }
+            self.history.push_back(entry);
+        }
+
+        if self.sink.is_some() {
+            let line = self.format(&entry);
+            let result = writeln!(self.sink.as_mut().unwrap(), "{}", line);
+            if let Err(e) = result {
+                self.sink = None;
+                self.error = Some(e);
+            }
+        }
+
+        if self.reference.is_some() {
+            self.check_reference(&entry);
+        }
+
+        self.count += 1;
+    }
+
+    fn check_reference(&mut self, entry: &TraceEntry) {
+        let expected = loop {
+            let mut line = String::new();
+            match self.reference.as_mut().unwrap().read_line(&mut line) {
+                Ok(0) => {
+                    // The reference ran out; nothing more to compare.
+                    self.reference = None;
+                    return;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    self.reference = None;
+                    self.error = Some(e);
+                    return;
+                }
+            }
+            if let Some(parsed) = TraceLine::parse(&line) {
+                break parsed;
+            }
+        };
+
+        let actual = entry.line();
+        if !differing_fields(&expected, &actual).is_empty() {
+            self.divergence = Some(Divergence {
+                index: self.count,
+                expected,
+                actual,
+            });
+            self.reference = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sample_state() -> State {
+        let mut state = State::new();
+        state.pc = 0x0100;
+        state.a = 0x12;
+        state.cc.cy = true;
+        state.set_bc(0x3456);
+        state.set_hl(0x2400);
+        state.sp = 0xf000;
+        state.cycles = 42;
+        state.memory.load(0x0100, vec![0xc3, 0xab, 0x01, 0x00]);
+        state
+    }
+
+    #[test]
+    fn format_test() {
+        let entry = TraceEntry::capture(&sample_state());
+
+        assert_eq!(
+            entry.format(false),
+            "PC: 0100, AF: 1203, BC: 3456, DE: 0000, HL: 2400, SP: F000, CYC: 42\t(C3 AB 01 00)"
+        );
+        assert_eq!(
+            entry.format(true),
+            "PC: 0100, AF: 1203, BC: 3456, DE: 0000, HL: 2400, SP: F000, CYC: 42\t(C3 AB 01 00) ; JMP $01ab"
+        );
+    }
+
+    #[test]
+    fn format_with_symbols_test() {
+        let entry = TraceEntry::capture(&sample_state());
+        let line =
+            "PC: 0100, AF: 1203, BC: 3456, DE: 0000, HL: 2400, SP: F000, CYC: 42\t(C3 AB 01 00)";
+
+        let mut symbols = Symbols::new();
+        symbols.insert(0x01ab, "Loop");
+        assert_eq!(
+            entry.format_with_symbols(true, Some(&symbols)),
+            format!("{} ; JMP Loop", line)
+        );
+
+        symbols.insert(0x00fe, "Start");
+        assert_eq!(
+            entry.format_with_symbols(true, Some(&symbols)),
+            format!("{} ; Start+0x2: JMP Loop", line)
+        );
+        assert_eq!(entry.format_with_symbols(false, Some(&symbols)), line);
+
+        let mut tracer = Tracer::new(1);
+        tracer.set_symbols(Some(Arc::new(symbols)));
+        assert_eq!(
+            tracer.format(&entry),
+            format!("{} ; Start+0x2: JMP Loop", line)
+        );
+    }
+
+    #[test]
+    fn parse_test() {
+        let entry = TraceEntry::capture(&sample_state());
+
+        assert_eq!(TraceLine::parse(&entry.format(true)), Some(entry.line()));
+        assert_eq!(
+            TraceLine::parse("PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 7"),
+            Some(TraceLine {
+                pc: 0,
+                af: 2,
+                bc: 0,
+                de: 0,
+                hl: 0,
+                sp: 0,
+                cycles: 7,
+                bytes: None,
+            })
+        );
+        assert_eq!(TraceLine::parse(""), None);
+        assert_eq!(TraceLine::parse("PC: 0000, AF: 0002"), None);
+        assert_eq!(
+            TraceLine::parse("PC: zzzz, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 7"),
+            None
+        );
+    }
+
+    #[test]
+    fn history_test() {
+        let mut tracer = Tracer::new(3);
+        let mut state = sample_state();
+
+        for pc in 0..5 {
+            state.pc = pc;
+            tracer.record(TraceEntry::capture(&state));
+        }
+        let pcs: Vec<u16> = tracer.history().map(|e| e.snapshot.pc).collect();
+        assert_eq!(pcs, vec![2, 3, 4]);
+        assert_eq!(tracer.count(), 5);
+
+        tracer.set_capacity(1);
+        let pcs: Vec<u16> = tracer.history().map(|e| e.snapshot.pc).collect();
+        assert_eq!(pcs, vec![4]);
+    }
+
+    #[test]
+    fn disabled_test() {
+        let mut tracer = Tracer::disabled();
+        assert_eq!(tracer.is_enabled(), false);
+
+        tracer.record(TraceEntry::capture(&sample_state()));
+        assert_eq!(tracer.history().count(), 0);
+
+        tracer.stream_to(Box::new(io::sink()));
+        assert_eq!(tracer.is_enabled(), true);
+    }
+
+    #[test]
+    fn stream_test() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut tracer = Tracer::disabled();
+        tracer.set_disassembly(false);
+        tracer.stream_to(Box::new(buffer.clone()));
+
+        let mut state = sample_state();
+        tracer.record(TraceEntry::capture(&state));
+        state.pc = 0x01ab;
+        tracer.record(TraceEntry::capture(&state));
+        tracer.stop_streaming();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("PC: 01AB, "));
+        assert!(tracer.take_error().is_none());
+    }
+
+    #[test]
+    fn compare_test() {
+        let mut state = sample_state();
+        let first = TraceEntry::capture(&state).format(false);
+        let reference = format!(
+            "{}\n\n{}\n",
+            first,
+            "PC: 0103, AF: 1203, BC: 3456, DE: 0000, HL: 2400, SP: F000, CYC: 52\t(00 00 00 00)"
+        );
+
+        let mut tracer = Tracer::disabled();
+        tracer.compare_with(Box::new(io::Cursor::new(reference.into_bytes())));
+
+        tracer.record(TraceEntry::capture(&state));
+        assert_eq!(tracer.divergence(), None);
+
+        state.pc = 0x01ab;
+        state.cycles = 52;
+        tracer.record(TraceEntry::capture(&state));
+        let divergence = tracer.divergence().unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.expected.pc, 0x0103);
+        assert_eq!(divergence.actual.pc, 0x01ab);
+
+        // Only the first divergence is kept.
+        tracer.record(TraceEntry::capture(&state));
+        assert_eq!(tracer.divergence().unwrap().index, 1);
+    }
+
+    #[test]
+    fn compare_short_reference_test() {
+        let state = sample_state();
+        let mut tracer = Tracer::disabled();
+        tracer.compare_with(Box::new(io::Cursor::new(Vec::new())));
+
+        tracer.record(TraceEntry::capture(&state));
+        assert_eq!(tracer.divergence(), None);
+        assert_eq!(tracer.is_enabled(), false);
+    }
+}
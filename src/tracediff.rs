@@ -0,0 +1,469 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+
+use cpu::{try_emulate_instruction, EmulationError};
+use disasm::mnemonic;
+use machine::Machine;
+use memory::MemoryWrite;
+use state::{instruction_length, State};
+use trace::{TraceEntry, TraceLine};
+
+pub const DEFAULT_CONTEXT: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub index: u64,
+    pub line: TraceLine,
+    // Memory written by this instruction, when known. Traces read back
+    // from a file carry no write information.
+    pub writes: Vec<MemoryWrite>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub index: u64,
+    // None when that trace ended before the other.
+    pub expected: Option<TraceLine>,
+    pub actual: Option<TraceLine>,
+    pub fields: Vec<&'static str>,
+    // The matching instructions leading up to the divergence, oldest first.
+    pub context: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub compared: u64,
+    pub divergence: Option<Divergence>,
+    // Why a live run ended before its instruction count, if it failed.
+    pub stopped: Option<EmulationError>,
+}
+
+impl Report {
+    pub fn is_match(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+pub fn differing_fields(expected: &TraceLine, actual: &TraceLine) -> Vec<&'static str> {
+    let flag_bits: [(&'static str, u16); 5] = [
+        ("S", 0x80),
+        ("Z", 0x40),
+        ("AC", 0x10),
+        ("P", 0x04),
+        ("CY", 0x01),
+    ];
+
+    let mut fields = Vec::new();
+    if expected.pc != actual.pc {
+        fields.push("PC");
+    }
+    if expected.af >> 8 != actual.af >> 8 {
+        fields.push("A");
+    }
+    for (name, bit) in flag_bits.iter() {
+        if expected.af & bit != actual.af & bit {
+            fields.push(name);
+        }
+    }
+    if expected.bc != actual.bc {
+        fields.push("BC");
+    }
+    if expected.de != actual.de {
+        fields.push("DE");
+    }
+    if expected.hl != actual.hl {
+        fields.push("HL");
+    }
+    if expected.sp != actual.sp {
+        fields.push("SP");
+    }
+    if expected.cycles != actual.cycles {
+        fields.push("CYC");
+    }
+    // Bytes past the end of the instruction are just whatever follows it
+    // in memory, so only the instruction itself is compared, and only if
+    // the reference recorded it.
+    if let (Some(expected), Some(actual)) = (expected.bytes, actual.bytes) {
+        let length = instruction_length(expected[0]) as usize;
+        if expected[..length] != actual[..length] {
+            fields.push("bytes");
+        }
+    }
+    fields
+}
+
+// Lines up the two traces by instruction count and stops at the first
+// instruction where they disagree.
+pub fn diff<A, E>(actual: A, expected: E, context: usize) -> Report
+where
+    A: IntoIterator<Item = Step>,
+    E: IntoIterator<Item = TraceLine>,
+{
+    let mut actual = actual.into_iter();
+    let mut expected = expected.into_iter();
+    let mut window: VecDeque<Step> = VecDeque::with_capacity(context);
+    let mut compared = 0;
+
+    loop {
+        let (step, line) = match (actual.next(), expected.next()) {
+            (None, None) => {
+                return Report {
+                    compared,
+                    divergence: None,
+                    stopped: None,
+                }
+            }
+            (step, line) => (step, line),
+        };
+
+        let fields = match (&step, &line) {
+            (Some(step), Some(line)) => differing_fields(line, &step.line),
+            _ => Vec::new(),
+        };
+
+        if step.is_none() || line.is_none() || !fields.is_empty() {
+            return Report {
+                compared,
+                divergence: Some(Divergence {
+                    index: compared,
+                    expected: line,
+                    actual: step.map(|s| s.line),
+                    fields,
+                    context: window.into_iter().collect(),
+                }),
+                stopped: None,
+            };
+        }
+
+        compared += 1;
+        if context > 0 {
+            if window.len() == context {
+                window.pop_front();
+            }
+            window.push_back(step.unwrap());
+        }
+    }
+}
+
+// Reads a trace, skipping blank lines and anything else that is not a
+// trace line (headers, comments).
+pub fn read_trace(r: impl BufRead) -> io::Result<Vec<TraceLine>> {
+    let mut lines = Vec::new();
+    for line in r.lines() {
+        if let Some(parsed) = TraceLine::parse(&line?) {
+            lines.push(parsed);
+        }
+    }
+    Ok(lines)
+}
+
+pub fn diff_traces(
+    actual: impl BufRead,
+    expected: impl BufRead,
+    context: usize,
+) -> io::Result<Report> {
+    let actual = read_trace(actual)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, line)| Step {
+            index: index as u64,
+            line,
+            writes: Vec::new(),
+        });
+    Ok(diff(actual, read_trace(expected)?, context))
+}
+
+// Runs up to `instructions` instructions, then compares them against a
+// reference trace. Unlike diff_traces, the context includes memory writes.
+// The run stops early if the CPU halts or hits an emulation error, which
+// the report keeps.
+pub fn run_and_diff(
+    s: &mut State,
+    m: &mut impl Machine,
+    expected: impl BufRead,
+    instructions: u64,
+    context: usize,
+) -> io::Result<Report> {
+    let mut actual = Vec::new();
+    let mut stopped = None;
+    for index in 0..instructions {
+        if s.halted {
+            break;
+        }
+        let entry = TraceEntry::capture(s);
+        if let Err(e) = try_emulate_instruction(s, m) {
+            stopped = Some(e);
+            break;
+        }
+        actual.push(Step {
+            index,
+            line: entry.line(),
+            writes: s.memory.writes().to_vec(),
+        });
+    }
+
+    let mut expected = read_trace(expected)?;
+    expected.truncate(actual.len().max(instructions as usize));
+    Ok(Report {
+        stopped,
+        ..diff(actual, expected, context)
+    })
+}
+
+fn format_flags(af: u16) -> String {
+    format!(
+        "S={} Z={} AC={} P={} CY={}",
+        (af >> 7) & 1,
+        (af >> 6) & 1,
+        (af >> 4) & 1,
+        (af >> 2) & 1,
+        af & 1
+    )
+}
+
+// Traces without opcode bytes have nothing to disassemble.
+fn describe(line: &TraceLine) -> String {
+    match line.bytes {
+        Some(ref bytes) => format!("{} ; {}", line, mnemonic(bytes)),
+        None => line.to_string(),
+    }
+}
+
+fn format_line(line: &Option<TraceLine>) -> String {
+    match line {
+        Some(line) => describe(line),
+        None => "<end of trace>".to_string(),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref e) = self.stopped {
+            writeln!(f, "Run stopped: {}", e)?;
+        }
+        let divergence = match self.divergence {
+            Some(ref d) => d,
+            None => return writeln!(f, "Traces match ({} instructions)", self.compared),
+        };
+
+        writeln!(f, "Traces diverge at instruction {}", divergence.index)?;
+        if !divergence.context.is_empty() {
+            writeln!(f, "Context:")?;
+            for step in &divergence.context {
+                writeln!(f, "  #{:<8} {}", step.index, describe(&step.line))?;
+                for write in &step.writes {
+                    writeln!(
+                        f,
+                        "             write {:04x}: {:02x} -> {:02x}",
+                        write.address, write.old, write.new
+                    )?;
+                }
+            }
+        }
+        writeln!(f, "Expected: {}", format_line(&divergence.expected))?;
+        writeln!(f, "Actual:   {}", format_line(&divergence.actual))?;
+        if !divergence.fields.is_empty() {
+            writeln!(f, "Differs:  {}", divergence.fields.join(", "))?;
+        }
+        if let (Some(expected), Some(actual)) = (divergence.expected, divergence.actual) {
+            if expected.af & 0xff != actual.af & 0xff {
+                writeln!(f, "Flags:    expected {}", format_flags(expected.af))?;
+                writeln!(f, "          actual   {}", format_flags(actual.af))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use testing::{state_with_program, TestMachine};
+
+    // 0000: LXI H,2400
+    // 0003: MVI A,05
+    // 0005: MOV M,A
+    // 0006: DCR A
+    // 0007: JNZ 0005
+    // 000a: HLT
+    fn program_state() -> State {
+        state_with_program(vec![
+            0x21, 0x00, 0x24, 0x3e, 0x05, 0x77, 0x3d, 0xc2, 0x05, 0x00, 0x76,
+        ])
+    }
+
+    fn reference_trace(instructions: usize) -> String {
+        let mut state = program_state();
+        let mut trace = String::from("; reference trace\n");
+        for _ in 0..instructions {
+            trace.push_str(&TraceEntry::capture(&state).format(false));
+            trace.push('\n');
+            try_emulate_instruction(&mut state, &mut TestMachine::default()).unwrap();
+        }
+        trace
+    }
+
+    #[test]
+    fn differing_fields_test() {
+        let line =
+            TraceLine::parse("PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0")
+                .unwrap();
+        let mut other = line;
+        assert!(differing_fields(&line, &other).is_empty());
+
+        other.af = 0x0143;
+        other.hl = 1;
+        assert_eq!(differing_fields(&line, &other), vec!["A", "Z", "CY", "HL"]);
+    }
+
+    #[test]
+    fn matching_run_test() {
+        let mut state = program_state();
+        let reference = reference_trace(12);
+
+        let report = run_and_diff(
+            &mut state,
+            &mut TestMachine::default(),
+            Cursor::new(reference),
+            12,
+            DEFAULT_CONTEXT,
+        )
+        .unwrap();
+        assert_eq!(report.is_match(), true);
+        assert_eq!(report.compared, 12);
+        assert_eq!(report.to_string(), "Traces match (12 instructions)\n");
+    }
+
+    #[test]
+    fn reference_without_bytes_test() {
+        let mut state = program_state();
+        let reference: String = reference_trace(12)
+            .lines()
+            .map(|line| format!("{}\n", line.split('\t').next().unwrap()))
+            .collect();
+
+        let report = run_and_diff(
+            &mut state,
+            &mut TestMachine::default(),
+            Cursor::new(reference),
+            12,
+            DEFAULT_CONTEXT,
+        )
+        .unwrap();
+        assert_eq!(report.is_match(), true);
+        assert_eq!(report.compared, 12);
+    }
+
+    #[test]
+    fn divergent_run_test() {
+        let reference = reference_trace(12);
+        let mut state = program_state();
+        // Make the loop count down from 4 instead of 5.
+        state.memory.set(0x04, 0x04);
+
+        let report = run_and_diff(
+            &mut state,
+            &mut TestMachine::default(),
+            Cursor::new(reference),
+            12,
+            2,
+        )
+        .unwrap();
+        let divergence = report.divergence.as_ref().unwrap();
+        assert_eq!(report.compared, 1);
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.fields, vec!["bytes"]);
+        assert_eq!(divergence.context.len(), 1);
+
+        let text = report.to_string();
+        assert!(text.contains("Traces diverge at instruction 1"));
+        assert!(text.contains("Differs:  bytes"));
+    }
+
+    #[test]
+    fn stopped_run_test() {
+        let reference = reference_trace(12);
+        let mut state = program_state();
+        state.rom_limit = Some(0x0005);
+
+        let report = run_and_diff(
+            &mut state,
+            &mut TestMachine::default(),
+            Cursor::new(reference),
+            12,
+            0,
+        )
+        .unwrap();
+        assert_eq!(report.stopped, Some(EmulationError::PcOutOfRom(0x0006)));
+        let divergence = report.divergence.as_ref().unwrap();
+        assert_eq!(divergence.index, 3);
+        assert_eq!(divergence.actual, None);
+
+        let text = report.to_string();
+        assert!(text.starts_with("Run stopped: PC out of ROM at 0x0006\n"));
+        assert!(text.contains("Actual:   <end of trace>"));
+    }
+
+    #[test]
+    fn memory_write_context_test() {
+        let reference = reference_trace(12);
+        let mut state = program_state();
+        // Corrupt the accumulator partway through the loop.
+        state.memory.set(0x06, 0x3c);
+
+        let report = run_and_diff(
+            &mut state,
+            &mut TestMachine::default(),
+            Cursor::new(reference),
+            12,
+            3,
+        )
+        .unwrap();
+        let divergence = report.divergence.unwrap();
+        assert_eq!(divergence.index, 3);
+        assert_eq!(divergence.context.len(), 3);
+        assert_eq!(
+            divergence.context[2].writes,
+            vec![MemoryWrite {
+                address: 0x2400,
+                old: 0,
+                new: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn length_mismatch_test() {
+        let reference = reference_trace(5);
+        let short = reference_trace(3);
+
+        let report = diff_traces(
+            Cursor::new(short.clone()),
+            Cursor::new(reference.clone()),
+            DEFAULT_CONTEXT,
+        )
+        .unwrap();
+        let divergence = report.divergence.unwrap();
+        assert_eq!(divergence.index, 3);
+        assert_eq!(divergence.actual, None);
+        assert!(divergence.expected.is_some());
+
+        let report = diff_traces(Cursor::new(reference), Cursor::new(short), 0).unwrap();
+        assert_eq!(report.divergence.unwrap().expected, None);
+    }
+
+    #[test]
+    fn flags_report_test() {
+        let expected = "PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\n";
+        let actual = "PC: 0000, AF: 0043, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0\n";
+
+        let report = diff_traces(Cursor::new(actual), Cursor::new(expected), 0).unwrap();
+        let text = report.to_string();
+        assert!(text.contains("Differs:  Z, CY"));
+        assert!(text.contains("expected S=0 Z=0 AC=0 P=0 CY=0"));
+        assert!(text.contains("actual   S=0 Z=1 AC=0 P=0 CY=1"));
+    }
+}
@@ -0,0 +1,423 @@
+use std::io;
+use std::io::Write;
+
+use memory::Memory;
+
+pub const VRAM_START: u16 = 0x2400;
+pub const VRAM_END: u16 = 0x3fff;
+
+// The monitor is mounted rotated 90 degrees counter-clockwise, so the
+// 256x224 bitmap in video RAM is displayed as 224 wide by 256 high.
+pub const WIDTH: usize = 224;
+pub const HEIGHT: usize = 256;
+
+const BYTES_PER_COLUMN: usize = HEIGHT / 8;
+
+pub type Rgb = (u8, u8, u8);
+
+pub const WHITE: Rgb = (0xff, 0xff, 0xff);
+pub const RED: Rgb = (0xff, 0x20, 0x20);
+pub const GREEN: Rgb = (0x20, 0xff, 0x20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+    pub colour: Rgb,
+}
+
+impl Band {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        y >= self.top && y < self.bottom && x >= self.left && x < self.right
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    pub bands: Vec<Band>,
+    pub base: Rgb,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self::monochrome()
+    }
+}
+
+impl Overlay {
+    pub fn monochrome() -> Overlay {
+        Overlay {
+            bands: Vec::new(),
+            base: WHITE,
+        }
+    }
+
+    // Approximates the coloured gel strips on the Space Invaders cabinet:
+    // red across the UFO row, green over the shields and player, and green
+    // over the reserve cannons in the bottom-left corner only.
+    pub fn space_invaders() -> Overlay {
+        Overlay {
+            bands: vec![
+                Band {
+                    top: 32,
+                    bottom: 64,
+                    left: 0,
+                    right: WIDTH,
+                    colour: RED,
+                },
+                Band {
+                    top: 184,
+                    bottom: 240,
+                    left: 0,
+                    right: WIDTH,
+                    colour: GREEN,
+                },
+                Band {
+                    top: 240,
+                    bottom: HEIGHT,
+                    left: 16,
+                    right: 134,
+                    colour: GREEN,
+                },
+            ],
+            base: WHITE,
+        }
+    }
+
+    pub fn colour_at(&self, x: usize, y: usize) -> Rgb {
+        self.bands
+            .iter()
+            .find(|band| band.contains(x, y))
+            .map_or(self.base, |band| band.colour)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Frame {
+    pixels: Vec<bool>,
+}
+
+impl Frame {
+    pub fn decode(vram: &[u8]) -> Frame {
+        assert_eq!(vram.len(), WIDTH * BYTES_PER_COLUMN, "bad video RAM size");
+
+        let mut pixels = vec![false; WIDTH * HEIGHT];
+        for (offset, byte) in vram.iter().enumerate() {
+            let x = offset / BYTES_PER_COLUMN;
+            for bit in 0..8 {
+                if byte & (1 << bit) != 0 {
+                    let y = HEIGHT - 1 - ((offset % BYTES_PER_COLUMN) * 8 + bit);
+                    pixels[y * WIDTH + x] = true;
+                }
+            }
+        }
+
+        Frame { pixels }
+    }
+
+    pub fn from_memory(memory: &Memory) -> Frame {
+        Frame::decode(memory.view(VRAM_START, VRAM_END))
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * WIDTH + x]
+    }
+
+    pub fn lit_pixels(&self) -> usize {
+        self.pixels.iter().filter(|p| **p).count()
+    }
+
+    pub fn to_greyscale(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .map(|p| if *p { 0xff } else { 0x00 })
+            .collect()
+    }
+
+    pub fn to_rgb(&self, overlay: &Overlay) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(WIDTH * HEIGHT * 3);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (r, g, b) = if self.get(x, y) {
+                    overlay.colour_at(x, y)
+                } else {
+                    (0, 0, 0)
+                };
+                buffer.extend_from_slice(&[r, g, b]);
+            }
+        }
+        buffer
+    }
+
+    pub fn to_rgba(&self, overlay: &Overlay) -> Vec<u8> {
+        self.to_rgb(overlay)
+            .chunks(3)
+            .flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 0xff])
+            .collect()
+    }
+
+    pub fn write_pgm(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "P5\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        w.write_all(&self.to_greyscale())
+    }
+
+    pub fn write_ppm(&self, w: &mut impl Write, overlay: &Overlay) -> io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        w.write_all(&self.to_rgb(overlay))
+    }
+
+    pub fn write_png(&self, w: &mut impl Write, overlay: &Overlay) -> io::Result<()> {
+        write_png(w, WIDTH, HEIGHT, &self.to_rgba(overlay))
+    }
+}
+
+impl std::fmt::Debug for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Frame {{ {}x{}, {} lit }}",
+            WIDTH,
+            HEIGHT,
+            self.lit_pixels()
+        )
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const PNG_COLOUR_RGBA: u8 = 6;
+
+// Writes an 8-bit RGBA PNG. The image data uses uncompressed deflate
+// blocks, which keeps the encoder dependency-free at the cost of file size.
+pub fn write_png(w: &mut impl Write, width: usize, height: usize, rgba: &[u8]) -> io::Result<()> {
+    assert_eq!(rgba.len(), width * height * 4, "bad RGBA buffer size");
+
+    w.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, PNG_COLOUR_RGBA, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header)?;
+
+    let mut scanlines = Vec::with_capacity(height * (width * 4 + 1));
+    for row in rgba.chunks(width * 4) {
+        scanlines.push(0); // filter type: none
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(w, b"IDAT", &zlib_stored(&scanlines))?;
+
+    write_chunk(w, b"IEND", &[])
+}
+
+fn write_chunk(w: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    w.write_all(&crc.finish().to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xffff;
+
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in data {
+        a = (a + u32::from(*byte)) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        Crc32 {
+            table,
+            value: 0xffff_ffff,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            let index = ((self.value ^ u32::from(*byte)) & 0xff) as usize;
+            self.value = self.table[index] ^ (self.value >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.value ^ 0xffff_ffff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_vram() -> Vec<u8> {
+        vec![0; (VRAM_END - VRAM_START + 1) as usize]
+    }
+
+    #[test]
+    fn decode_blank_test() {
+        let frame = Frame::decode(&blank_vram());
+
+        assert_eq!(frame.lit_pixels(), 0);
+        assert!(frame.to_greyscale().iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn decode_rotation_test() {
+        let mut vram = blank_vram();
+
+        // First byte, lowest bit: bottom-left corner of the display.
+        vram[0] = 0x01;
+        // Last byte, highest bit: top-right corner of the display.
+        vram[0x1bff] = 0x80;
+        // Second column, second byte, bit 2.
+        vram[32 + 1] = 0x04;
+
+        let frame = Frame::decode(&vram);
+        assert_eq!(frame.lit_pixels(), 3);
+        assert_eq!(frame.get(0, 255), true);
+        assert_eq!(frame.get(223, 0), true);
+        assert_eq!(frame.get(1, 255 - 10), true);
+    }
+
+    #[test]
+    fn from_memory_test() {
+        let mut memory = Memory::new();
+
+        memory.set(VRAM_START, 0x01);
+        memory.set(VRAM_START - 1, 0xff);
+        memory.set(VRAM_END + 1, 0xff);
+
+        let frame = Frame::from_memory(&memory);
+        assert_eq!(frame.lit_pixels(), 1);
+        assert_eq!(frame.get(0, 255), true);
+    }
+
+    #[test]
+    fn overlay_test() {
+        let overlay = Overlay::space_invaders();
+
+        assert_eq!(overlay.colour_at(100, 10), WHITE);
+        assert_eq!(overlay.colour_at(100, 40), RED);
+        assert_eq!(overlay.colour_at(100, 200), GREEN);
+        assert_eq!(overlay.colour_at(20, 250), GREEN);
+        assert_eq!(overlay.colour_at(200, 250), WHITE);
+
+        assert_eq!(Overlay::monochrome().colour_at(100, 40), WHITE);
+    }
+
+    #[test]
+    fn to_rgba_test() {
+        let mut vram = blank_vram();
+        // Column 100, display row 40 is in the red band.
+        vram[100 * 32 + (255 - 40) / 8] = 1 << ((255 - 40) % 8);
+
+        let frame = Frame::decode(&vram);
+        let rgba = frame.to_rgba(&Overlay::space_invaders());
+        assert_eq!(rgba.len(), WIDTH * HEIGHT * 4);
+
+        let offset = (40 * WIDTH + 100) * 4;
+        assert_eq!(&rgba[offset..offset + 4], &[0xff, 0x20, 0x20, 0xff]);
+        assert_eq!(&rgba[0..4], &[0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn write_ppm_test() {
+        let frame = Frame::decode(&blank_vram());
+        let mut out = Vec::new();
+
+        frame.write_ppm(&mut out, &Overlay::default()).unwrap();
+        assert!(out.starts_with(b"P6\n224 256\n255\n"));
+        assert_eq!(out.len(), 15 + WIDTH * HEIGHT * 3);
+
+        out.clear();
+        frame.write_pgm(&mut out).unwrap();
+        assert!(out.starts_with(b"P5\n224 256\n255\n"));
+        assert_eq!(out.len(), 15 + WIDTH * HEIGHT);
+    }
+
+    #[test]
+    fn write_png_test() {
+        let mut out = Vec::new();
+
+        write_png(&mut out, 1, 1, &[0xff, 0x00, 0x00, 0xff]).unwrap();
+        assert_eq!(&out[0..8], &PNG_SIGNATURE);
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[16..24], &[0, 0, 0, 1, 0, 0, 0, 1]);
+        // IEND is always the same twelve bytes.
+        assert_eq!(
+            &out[out.len() - 12..],
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn adler32_test() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn crc32_test() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn zlib_stored_test() {
+        let data = vec![0xaa; 0x10000];
+        let out = zlib_stored(&data);
+
+        assert_eq!(&out[0..2], &[0x78, 0x01]);
+        // Two blocks: one full, one holding the final byte.
+        assert_eq!(out.len(), 2 + 5 + 0xffff + 5 + 1 + 4);
+        assert_eq!(out[2], 0);
+        assert_eq!(out[2 + 5 + 0xffff], 1);
+    }
+}
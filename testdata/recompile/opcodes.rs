@@ -0,0 +1,1302 @@
+// Generated from 30 blocks. Do not edit.
+
+use crate::bytes::assemble_word;
+use crate::bytes::high_order_byte;
+use crate::bytes::low_order_byte;
+use crate::callstack::FrameKind;
+use crate::machine::Machine;
+use crate::recompile::CompiledBlock;
+use crate::stack::Stack;
+use crate::state::State;
+
+pub fn block<M: Machine>(pc: u16) -> Option<CompiledBlock<M>> {
+    Some(match pc {
+        0x0038 => CompiledBlock::new(0x0038, &[0xc9], block_0038),
+        0x0100 => CompiledBlock::new(0x0100, &[0x00, 0x01, 0x80, 0x80, 0x02, 0x03, 0x04, 0x05, 0x06, 0x80, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x80, 0x0f, 0x10, 0x11, 0x80, 0x80, 0x12, 0x13, 0x14, 0x15, 0x16, 0x80, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x80, 0x1f, 0x20, 0x21, 0x80, 0x80, 0x22, 0x80, 0x80, 0x23, 0x24, 0x25, 0x26, 0x80, 0x27, 0x28, 0x29, 0x2a, 0x80, 0x80, 0x2b, 0x2c, 0x2d, 0x2e, 0x80, 0x2f, 0x30, 0x31, 0x80, 0x80, 0x32, 0x80, 0x80, 0x33, 0x34, 0x35, 0x36, 0x80, 0x37, 0x38, 0x39, 0x3a, 0x80, 0x80, 0x3b, 0x3c, 0x3d, 0x3e, 0x80, 0x3f, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x5b, 0x5c, 0x5d, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7c, 0x7d, 0x7e, 0x7f, 0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e, 0x9f, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae, 0xaf, 0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf, 0xc1, 0xc5, 0xc6, 0x80, 0xce, 0x80, 0xd1, 0xd3, 0x80, 0xd5, 0xd6, 0x80, 0xdb, 0x80, 0xde, 0x80, 0xe1, 0xe3, 0xe5, 0xe6, 0x80, 0xeb, 0xee, 0x80, 0xf1, 0xf3, 0xf5, 0xf6, 0x80, 0xf9, 0xfb, 0xfe, 0x80, 0xc2, 0x01, 0x02], block_0100),
+        0x01fb => CompiledBlock::new(0x01fb, &[0xcc, 0x00, 0x04], block_01fb),
+        0x01fe => CompiledBlock::new(0x01fe, &[0xc4, 0x00, 0x04], block_01fe),
+        0x0201 => CompiledBlock::new(0x0201, &[0xca, 0x0a, 0x02], block_0201),
+        0x0204 => CompiledBlock::new(0x0204, &[0xc4, 0x00, 0x04], block_0204),
+        0x0207 => CompiledBlock::new(0x0207, &[0xcc, 0x00, 0x04], block_0207),
+        0x020a => CompiledBlock::new(0x020a, &[0xd2, 0x13, 0x02], block_020a),
+        0x020d => CompiledBlock::new(0x020d, &[0xdc, 0x00, 0x04], block_020d),
+        0x0210 => CompiledBlock::new(0x0210, &[0xd4, 0x00, 0x04], block_0210),
+        0x0213 => CompiledBlock::new(0x0213, &[0xda, 0x1c, 0x02], block_0213),
+        0x0216 => CompiledBlock::new(0x0216, &[0xd4, 0x00, 0x04], block_0216),
+        0x0219 => CompiledBlock::new(0x0219, &[0xdc, 0x00, 0x04], block_0219),
+        0x021c => CompiledBlock::new(0x021c, &[0xe2, 0x25, 0x02], block_021c),
+        0x021f => CompiledBlock::new(0x021f, &[0xec, 0x00, 0x04], block_021f),
+        0x0222 => CompiledBlock::new(0x0222, &[0xe4, 0x00, 0x04], block_0222),
+        0x0225 => CompiledBlock::new(0x0225, &[0xea, 0x2e, 0x02], block_0225),
+        0x0228 => CompiledBlock::new(0x0228, &[0xe4, 0x00, 0x04], block_0228),
+        0x022b => CompiledBlock::new(0x022b, &[0xec, 0x00, 0x04], block_022b),
+        0x022e => CompiledBlock::new(0x022e, &[0xf2, 0x37, 0x02], block_022e),
+        0x0231 => CompiledBlock::new(0x0231, &[0xfc, 0x00, 0x04], block_0231),
+        0x0234 => CompiledBlock::new(0x0234, &[0xf4, 0x00, 0x04], block_0234),
+        0x0237 => CompiledBlock::new(0x0237, &[0xfa, 0x40, 0x02], block_0237),
+        0x023a => CompiledBlock::new(0x023a, &[0xf4, 0x00, 0x04], block_023a),
+        0x023d => CompiledBlock::new(0x023d, &[0xfc, 0x00, 0x04], block_023d),
+        0x0240 => CompiledBlock::new(0x0240, &[0xff], block_0240),
+        0x0241 => CompiledBlock::new(0x0241, &[0x76], block_0241),
+        0x0242 => CompiledBlock::new(0x0242, &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], block_0242),
+        0x0400 => CompiledBlock::new(0x0400, &[0xc0], block_0400),
+        0x0401 => CompiledBlock::new(0x0401, &[0xc8], block_0401),
+        _ => return None,
+    })
+}
+
+fn block_0038<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0038: RET
+    s.call_stack.ret(s.sp);
+    s.pc = s.pop16();
+    s.call_stack.unwind(s.sp);
+    10
+}
+
+fn block_0100<M: Machine>(s: &mut State, m: &mut M) -> usize {
+    // 0100: NOP
+    // 0101: LXI B,$8080
+    s.set_bc(0x8080);
+    // 0104: STAX B
+    s.memory.set(s.get_bc(), s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0105;
+        return 21;
+    }
+    // 0105: INX B
+    s.set_bc(s.get_bc().wrapping_add(1));
+    // 0106: INR B
+    s.b = s.inr(s.b);
+    // 0107: DCR B
+    s.b = s.dcr(s.b);
+    // 0108: MVI B,$80
+    s.b = 0x80;
+    // 010a: RLC
+    s.cc.cy = (s.a & 0x80) != 0;
+    s.a = s.a.rotate_left(1);
+    // 010b: *NOP
+    // 010c: DAD B
+    s.add16(s.get_bc());
+    // 010d: LDAX B
+    s.a = s.memory.get(s.get_bc());
+    // 010e: DCX B
+    s.set_bc(s.get_bc().wrapping_sub(1));
+    // 010f: INR C
+    s.c = s.inr(s.c);
+    // 0110: DCR C
+    s.c = s.dcr(s.c);
+    // 0111: MVI C,$80
+    s.c = 0x80;
+    // 0113: RRC
+    s.cc.cy = (s.a & 0x01) != 0;
+    s.a = s.a.rotate_right(1);
+    // 0114: *NOP
+    // 0115: LXI D,$8080
+    s.set_de(0x8080);
+    // 0118: STAX D
+    s.memory.set(s.get_de(), s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0119;
+        return 115;
+    }
+    // 0119: INX D
+    s.set_de(s.get_de().wrapping_add(1));
+    // 011a: INR D
+    s.d = s.inr(s.d);
+    // 011b: DCR D
+    s.d = s.dcr(s.d);
+    // 011c: MVI D,$80
+    s.d = 0x80;
+    // 011e: RAL
+    let x = s.a;
+    s.a = (s.cc.cy as u8) | (x << 1);
+    s.cc.cy = (x & 0x80) != 0;
+    // 011f: *NOP
+    // 0120: DAD D
+    s.add16(s.get_de());
+    // 0121: LDAX D
+    s.a = s.memory.get(s.get_de());
+    // 0122: DCX D
+    s.set_de(s.get_de().wrapping_sub(1));
+    // 0123: INR E
+    s.e = s.inr(s.e);
+    // 0124: DCR E
+    s.e = s.dcr(s.e);
+    // 0125: MVI E,$80
+    s.e = 0x80;
+    // 0127: RAR
+    let x = s.a;
+    s.a = ((s.cc.cy as u8) << 7) | (x >> 1);
+    s.cc.cy = (x & 0x01) != 0;
+    // 0128: *NOP
+    // 0129: LXI H,$8080
+    s.set_hl(0x8080);
+    // 012c: SHLD $8080
+    s.memory.set(0x8080, s.l);
+    s.memory.set(0x8081, s.h);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x012f;
+        return 218;
+    }
+    // 012f: INX H
+    s.set_hl(s.get_hl_address().wrapping_add(1));
+    // 0130: INR H
+    s.h = s.inr(s.h);
+    // 0131: DCR H
+    s.h = s.dcr(s.h);
+    // 0132: MVI H,$80
+    s.h = 0x80;
+    // 0134: DAA
+    s.daa();
+    // 0135: *NOP
+    // 0136: DAD H
+    s.add16(s.get_hl_address());
+    // 0137: LHLD $8080
+    s.l = s.memory.get(0x8080);
+    s.h = s.memory.get(0x8081);
+    // 013a: DCX H
+    s.set_hl(s.get_hl_address().wrapping_sub(1));
+    // 013b: INR L
+    s.l = s.inr(s.l);
+    // 013c: DCR L
+    s.l = s.dcr(s.l);
+    // 013d: MVI L,$80
+    s.l = 0x80;
+    // 013f: CMA
+    s.a = !s.a;
+    // 0140: *NOP
+    // 0141: LXI SP,$8080
+    s.sp = 0x8080;
+    s.call_stack.unwind(s.sp);
+    // 0144: STA $8080
+    s.memory.set(0x8080, s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0147;
+        return 327;
+    }
+    // 0147: INX SP
+    s.sp = s.sp.wrapping_add(1);
+    s.call_stack.unwind(s.sp);
+    // 0148: INR M
+    let value = s.inr(s.get_m());
+    s.set_m(value);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0149;
+        return 342;
+    }
+    // 0149: DCR M
+    let value = s.dcr(s.get_m());
+    s.set_m(value);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x014a;
+        return 352;
+    }
+    // 014a: MVI M,$80
+    s.set_m(0x80);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x014c;
+        return 362;
+    }
+    // 014c: STC
+    s.cc.cy = true;
+    // 014d: *NOP
+    // 014e: DAD SP
+    s.add16(s.sp);
+    // 014f: LDA $8080
+    s.a = s.memory.get(0x8080);
+    // 0152: DCX SP
+    s.sp = s.sp.wrapping_sub(1);
+    // 0153: INR A
+    s.a = s.inr(s.a);
+    // 0154: DCR A
+    s.a = s.dcr(s.a);
+    // 0155: MVI A,$80
+    s.a = 0x80;
+    // 0157: CMC
+    s.cc.cy = !s.cc.cy;
+    // 0158: MOV B,B
+    // 0159: MOV B,C
+    s.b = s.c;
+    // 015a: MOV B,D
+    s.b = s.d;
+    // 015b: MOV B,E
+    s.b = s.e;
+    // 015c: MOV B,H
+    s.b = s.h;
+    // 015d: MOV B,L
+    s.b = s.l;
+    // 015e: MOV B,M
+    s.b = s.get_m();
+    // 015f: MOV B,A
+    s.b = s.a;
+    // 0160: MOV C,B
+    s.c = s.b;
+    // 0161: MOV C,C
+    // 0162: MOV C,D
+    s.c = s.d;
+    // 0163: MOV C,E
+    s.c = s.e;
+    // 0164: MOV C,H
+    s.c = s.h;
+    // 0165: MOV C,L
+    s.c = s.l;
+    // 0166: MOV C,M
+    s.c = s.get_m();
+    // 0167: MOV C,A
+    s.c = s.a;
+    // 0168: MOV D,B
+    s.d = s.b;
+    // 0169: MOV D,C
+    s.d = s.c;
+    // 016a: MOV D,D
+    // 016b: MOV D,E
+    s.d = s.e;
+    // 016c: MOV D,H
+    s.d = s.h;
+    // 016d: MOV D,L
+    s.d = s.l;
+    // 016e: MOV D,M
+    s.d = s.get_m();
+    // 016f: MOV D,A
+    s.d = s.a;
+    // 0170: MOV E,B
+    s.e = s.b;
+    // 0171: MOV E,C
+    s.e = s.c;
+    // 0172: MOV E,D
+    s.e = s.d;
+    // 0173: MOV E,E
+    // 0174: MOV E,H
+    s.e = s.h;
+    // 0175: MOV E,L
+    s.e = s.l;
+    // 0176: MOV E,M
+    s.e = s.get_m();
+    // 0177: MOV E,A
+    s.e = s.a;
+    // 0178: MOV H,B
+    s.h = s.b;
+    // 0179: MOV H,C
+    s.h = s.c;
+    // 017a: MOV H,D
+    s.h = s.d;
+    // 017b: MOV H,E
+    s.h = s.e;
+    // 017c: MOV H,H
+    // 017d: MOV H,L
+    s.h = s.l;
+    // 017e: MOV H,M
+    s.h = s.get_m();
+    // 017f: MOV H,A
+    s.h = s.a;
+    // 0180: MOV L,B
+    s.l = s.b;
+    // 0181: MOV L,C
+    s.l = s.c;
+    // 0182: MOV L,D
+    s.l = s.d;
+    // 0183: MOV L,E
+    s.l = s.e;
+    // 0184: MOV L,H
+    s.l = s.h;
+    // 0185: MOV L,L
+    // 0186: MOV L,M
+    s.l = s.get_m();
+    // 0187: MOV L,A
+    s.l = s.a;
+    // 0188: MOV M,B
+    s.set_m(s.b);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0189;
+        return 678;
+    }
+    // 0189: MOV M,C
+    s.set_m(s.c);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x018a;
+        return 685;
+    }
+    // 018a: MOV M,D
+    s.set_m(s.d);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x018b;
+        return 692;
+    }
+    // 018b: MOV M,E
+    s.set_m(s.e);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x018c;
+        return 699;
+    }
+    // 018c: MOV M,H
+    s.set_m(s.h);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x018d;
+        return 706;
+    }
+    // 018d: MOV M,L
+    s.set_m(s.l);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x018e;
+        return 713;
+    }
+    // 018e: MOV M,A
+    s.set_m(s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x018f;
+        return 720;
+    }
+    // 018f: MOV A,B
+    s.a = s.b;
+    // 0190: MOV A,C
+    s.a = s.c;
+    // 0191: MOV A,D
+    s.a = s.d;
+    // 0192: MOV A,E
+    s.a = s.e;
+    // 0193: MOV A,H
+    s.a = s.h;
+    // 0194: MOV A,L
+    s.a = s.l;
+    // 0195: MOV A,M
+    s.a = s.get_m();
+    // 0196: MOV A,A
+    // 0197: ADD B
+    s.add8(s.b);
+    // 0198: ADD C
+    s.add8(s.c);
+    // 0199: ADD D
+    s.add8(s.d);
+    // 019a: ADD E
+    s.add8(s.e);
+    // 019b: ADD H
+    s.add8(s.h);
+    // 019c: ADD L
+    s.add8(s.l);
+    // 019d: ADD M
+    s.add8(s.get_m());
+    // 019e: ADD A
+    s.add8(s.a);
+    // 019f: ADC B
+    s.adc8(s.b);
+    // 01a0: ADC C
+    s.adc8(s.c);
+    // 01a1: ADC D
+    s.adc8(s.d);
+    // 01a2: ADC E
+    s.adc8(s.e);
+    // 01a3: ADC H
+    s.adc8(s.h);
+    // 01a4: ADC L
+    s.adc8(s.l);
+    // 01a5: ADC M
+    s.adc8(s.get_m());
+    // 01a6: ADC A
+    s.adc8(s.a);
+    // 01a7: SUB B
+    s.sub8(s.b);
+    // 01a8: SUB C
+    s.sub8(s.c);
+    // 01a9: SUB D
+    s.sub8(s.d);
+    // 01aa: SUB E
+    s.sub8(s.e);
+    // 01ab: SUB H
+    s.sub8(s.h);
+    // 01ac: SUB L
+    s.sub8(s.l);
+    // 01ad: SUB M
+    s.sub8(s.get_m());
+    // 01ae: SUB A
+    s.sub8(s.a);
+    // 01af: SBB B
+    s.sbb8(s.b);
+    // 01b0: SBB C
+    s.sbb8(s.c);
+    // 01b1: SBB D
+    s.sbb8(s.d);
+    // 01b2: SBB E
+    s.sbb8(s.e);
+    // 01b3: SBB H
+    s.sbb8(s.h);
+    // 01b4: SBB L
+    s.sbb8(s.l);
+    // 01b5: SBB M
+    s.sbb8(s.get_m());
+    // 01b6: SBB A
+    s.sbb8(s.a);
+    // 01b7: ANA B
+    s.and8(s.b);
+    // 01b8: ANA C
+    s.and8(s.c);
+    // 01b9: ANA D
+    s.and8(s.d);
+    // 01ba: ANA E
+    s.and8(s.e);
+    // 01bb: ANA H
+    s.and8(s.h);
+    // 01bc: ANA L
+    s.and8(s.l);
+    // 01bd: ANA M
+    s.and8(s.get_m());
+    // 01be: ANA A
+    s.and8(s.a);
+    // 01bf: XRA B
+    s.xor8(s.b);
+    // 01c0: XRA C
+    s.xor8(s.c);
+    // 01c1: XRA D
+    s.xor8(s.d);
+    // 01c2: XRA E
+    s.xor8(s.e);
+    // 01c3: XRA H
+    s.xor8(s.h);
+    // 01c4: XRA L
+    s.xor8(s.l);
+    // 01c5: XRA M
+    s.xor8(s.get_m());
+    // 01c6: XRA A
+    s.xor8(s.a);
+    // 01c7: ORA B
+    s.or8(s.b);
+    // 01c8: ORA C
+    s.or8(s.c);
+    // 01c9: ORA D
+    s.or8(s.d);
+    // 01ca: ORA E
+    s.or8(s.e);
+    // 01cb: ORA H
+    s.or8(s.h);
+    // 01cc: ORA L
+    s.or8(s.l);
+    // 01cd: ORA M
+    s.or8(s.get_m());
+    // 01ce: ORA A
+    s.or8(s.a);
+    // 01cf: CMP B
+    s.cmp8(s.b);
+    // 01d0: CMP C
+    s.cmp8(s.c);
+    // 01d1: CMP D
+    s.cmp8(s.d);
+    // 01d2: CMP E
+    s.cmp8(s.e);
+    // 01d3: CMP H
+    s.cmp8(s.h);
+    // 01d4: CMP L
+    s.cmp8(s.l);
+    // 01d5: CMP M
+    s.cmp8(s.get_m());
+    // 01d6: CMP A
+    s.cmp8(s.a);
+    // 01d7: POP B
+    let word = s.pop16();
+    s.set_bc(word);
+    s.call_stack.unwind(s.sp);
+    // 01d8: PUSH B
+    s.push16(s.get_bc());
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x01d9;
+        return 1063;
+    }
+    // 01d9: ADI $80
+    s.add8(0x80);
+    // 01db: ACI $80
+    s.adc8(0x80);
+    // 01dd: POP D
+    let word = s.pop16();
+    s.set_de(word);
+    s.call_stack.unwind(s.sp);
+    // 01de: OUT $80
+    m.output(0x80, s.a);
+    // 01e0: PUSH D
+    s.push16(s.get_de());
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x01e1;
+        return 1108;
+    }
+    // 01e1: SUI $80
+    s.sub8(0x80);
+    // 01e3: IN $80
+    s.a = m.input(0x80);
+    // 01e5: SBI $80
+    s.sbb8(0x80);
+    // 01e7: POP H
+    let word = s.pop16();
+    s.set_hl(word);
+    s.call_stack.unwind(s.sp);
+    // 01e8: XTHL
+    let word = s.pop16();
+    s.push16(s.get_hl_address());
+    s.set_hl(word);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x01e9;
+        return 1160;
+    }
+    // 01e9: PUSH H
+    s.push16(s.get_hl_address());
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x01ea;
+        return 1171;
+    }
+    // 01ea: ANI $80
+    s.and8(0x80);
+    // 01ec: XCHG
+    std::mem::swap(&mut s.d, &mut s.h);
+    std::mem::swap(&mut s.e, &mut s.l);
+    // 01ed: XRI $80
+    s.xor8(0x80);
+    // 01ef: POP PSW
+    let word = s.pop16();
+    s.a = high_order_byte(word);
+    s.cc.set_psw(low_order_byte(word));
+    s.call_stack.unwind(s.sp);
+    // 01f0: DI
+    s.int_enable = false;
+    // 01f1: PUSH PSW
+    s.push16(assemble_word(s.a, s.cc.to_psw()));
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x01f2;
+        return 1214;
+    }
+    // 01f2: ORI $80
+    s.or8(0x80);
+    // 01f4: SPHL
+    s.sp = s.get_hl_address();
+    s.call_stack.unwind(s.sp);
+    // 01f5: EI
+    s.int_enable = true;
+    if s.pending_interrupt.is_some() {
+        s.pc = 0x01f6;
+        return 1230;
+    }
+    // 01f6: CPI $80
+    s.cmp8(0x80);
+    // 01f8: JNZ $0201
+    s.pc = if State::is_nz(s) { 0x0201 } else { 0x01fb };
+    1247
+}
+
+fn block_01fb<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 01fb: CZ $0400
+    if State::is_z(s) {
+        s.pc = 0x01fb;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x01fe;
+    11
+}
+
+fn block_01fe<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 01fe: CNZ $0400
+    if State::is_nz(s) {
+        s.pc = 0x01fe;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0201;
+    11
+}
+
+fn block_0201<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0201: JZ $020a
+    s.pc = if State::is_z(s) { 0x020a } else { 0x0204 };
+    10
+}
+
+fn block_0204<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0204: CNZ $0400
+    if State::is_nz(s) {
+        s.pc = 0x0204;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0207;
+    11
+}
+
+fn block_0207<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0207: CZ $0400
+    if State::is_z(s) {
+        s.pc = 0x0207;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x020a;
+    11
+}
+
+fn block_020a<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 020a: JNC $0213
+    s.pc = if State::is_nc(s) { 0x0213 } else { 0x020d };
+    10
+}
+
+fn block_020d<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 020d: CC $0400
+    if State::is_c(s) {
+        s.pc = 0x020d;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0210;
+    11
+}
+
+fn block_0210<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0210: CNC $0400
+    if State::is_nc(s) {
+        s.pc = 0x0210;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0213;
+    11
+}
+
+fn block_0213<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0213: JC $021c
+    s.pc = if State::is_c(s) { 0x021c } else { 0x0216 };
+    10
+}
+
+fn block_0216<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0216: CNC $0400
+    if State::is_nc(s) {
+        s.pc = 0x0216;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0219;
+    11
+}
+
+fn block_0219<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0219: CC $0400
+    if State::is_c(s) {
+        s.pc = 0x0219;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x021c;
+    11
+}
+
+fn block_021c<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 021c: JPO $0225
+    s.pc = if State::is_parity_odd(s) { 0x0225 } else { 0x021f };
+    10
+}
+
+fn block_021f<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 021f: CPE $0400
+    if State::is_parity_even(s) {
+        s.pc = 0x021f;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0222;
+    11
+}
+
+fn block_0222<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0222: CPO $0400
+    if State::is_parity_odd(s) {
+        s.pc = 0x0222;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0225;
+    11
+}
+
+fn block_0225<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0225: JPE $022e
+    s.pc = if State::is_parity_even(s) { 0x022e } else { 0x0228 };
+    10
+}
+
+fn block_0228<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0228: CPO $0400
+    if State::is_parity_odd(s) {
+        s.pc = 0x0228;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x022b;
+    11
+}
+
+fn block_022b<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 022b: CPE $0400
+    if State::is_parity_even(s) {
+        s.pc = 0x022b;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x022e;
+    11
+}
+
+fn block_022e<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 022e: JP $0237
+    s.pc = if State::is_plus(s) { 0x0237 } else { 0x0231 };
+    10
+}
+
+fn block_0231<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0231: CM $0400
+    if State::is_minus(s) {
+        s.pc = 0x0231;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0234;
+    11
+}
+
+fn block_0234<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0234: CP $0400
+    if State::is_plus(s) {
+        s.pc = 0x0234;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0237;
+    11
+}
+
+fn block_0237<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0237: JM $0240
+    s.pc = if State::is_minus(s) { 0x0240 } else { 0x023a };
+    10
+}
+
+fn block_023a<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 023a: CP $0400
+    if State::is_plus(s) {
+        s.pc = 0x023a;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x023d;
+    11
+}
+
+fn block_023d<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 023d: CM $0400
+    if State::is_minus(s) {
+        s.pc = 0x023d;
+        s.enter(FrameKind::Call, 0x0400, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0240;
+    11
+}
+
+fn block_0240<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0240: RST 7
+    s.pc = 0x0240;
+    s.enter(FrameKind::Rst, 0x0038, 1);
+    s.jumped = false;
+    11
+}
+
+fn block_0241<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0241: HLT
+    s.halted = true;
+    s.pc = 0x0242;
+    7
+}
+
+fn block_0242<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0242: NOP
+    // 0243: NOP
+    // 0244: NOP
+    // 0245: NOP
+    // 0246: NOP
+    // 0247: NOP
+    // 0248: NOP
+    // 0249: NOP
+    // 024a: NOP
+    // 024b: NOP
+    // 024c: NOP
+    // 024d: NOP
+    // 024e: NOP
+    // 024f: NOP
+    // 0250: NOP
+    // 0251: NOP
+    // 0252: NOP
+    // 0253: NOP
+    // 0254: NOP
+    // 0255: NOP
+    // 0256: NOP
+    // 0257: NOP
+    // 0258: NOP
+    // 0259: NOP
+    // 025a: NOP
+    // 025b: NOP
+    // 025c: NOP
+    // 025d: NOP
+    // 025e: NOP
+    // 025f: NOP
+    // 0260: NOP
+    // 0261: NOP
+    // 0262: NOP
+    // 0263: NOP
+    // 0264: NOP
+    // 0265: NOP
+    // 0266: NOP
+    // 0267: NOP
+    // 0268: NOP
+    // 0269: NOP
+    // 026a: NOP
+    // 026b: NOP
+    // 026c: NOP
+    // 026d: NOP
+    // 026e: NOP
+    // 026f: NOP
+    // 0270: NOP
+    // 0271: NOP
+    // 0272: NOP
+    // 0273: NOP
+    // 0274: NOP
+    // 0275: NOP
+    // 0276: NOP
+    // 0277: NOP
+    // 0278: NOP
+    // 0279: NOP
+    // 027a: NOP
+    // 027b: NOP
+    // 027c: NOP
+    // 027d: NOP
+    // 027e: NOP
+    // 027f: NOP
+    // 0280: NOP
+    // 0281: NOP
+    // 0282: NOP
+    // 0283: NOP
+    // 0284: NOP
+    // 0285: NOP
+    // 0286: NOP
+    // 0287: NOP
+    // 0288: NOP
+    // 0289: NOP
+    // 028a: NOP
+    // 028b: NOP
+    // 028c: NOP
+    // 028d: NOP
+    // 028e: NOP
+    // 028f: NOP
+    // 0290: NOP
+    // 0291: NOP
+    // 0292: NOP
+    // 0293: NOP
+    // 0294: NOP
+    // 0295: NOP
+    // 0296: NOP
+    // 0297: NOP
+    // 0298: NOP
+    // 0299: NOP
+    // 029a: NOP
+    // 029b: NOP
+    // 029c: NOP
+    // 029d: NOP
+    // 029e: NOP
+    // 029f: NOP
+    // 02a0: NOP
+    // 02a1: NOP
+    // 02a2: NOP
+    // 02a3: NOP
+    // 02a4: NOP
+    // 02a5: NOP
+    // 02a6: NOP
+    // 02a7: NOP
+    // 02a8: NOP
+    // 02a9: NOP
+    // 02aa: NOP
+    // 02ab: NOP
+    // 02ac: NOP
+    // 02ad: NOP
+    // 02ae: NOP
+    // 02af: NOP
+    // 02b0: NOP
+    // 02b1: NOP
+    // 02b2: NOP
+    // 02b3: NOP
+    // 02b4: NOP
+    // 02b5: NOP
+    // 02b6: NOP
+    // 02b7: NOP
+    // 02b8: NOP
+    // 02b9: NOP
+    // 02ba: NOP
+    // 02bb: NOP
+    // 02bc: NOP
+    // 02bd: NOP
+    // 02be: NOP
+    // 02bf: NOP
+    // 02c0: NOP
+    // 02c1: NOP
+    // 02c2: NOP
+    // 02c3: NOP
+    // 02c4: NOP
+    // 02c5: NOP
+    // 02c6: NOP
+    // 02c7: NOP
+    // 02c8: NOP
+    // 02c9: NOP
+    // 02ca: NOP
+    // 02cb: NOP
+    // 02cc: NOP
+    // 02cd: NOP
+    // 02ce: NOP
+    // 02cf: NOP
+    // 02d0: NOP
+    // 02d1: NOP
+    // 02d2: NOP
+    // 02d3: NOP
+    // 02d4: NOP
+    // 02d5: NOP
+    // 02d6: NOP
+    // 02d7: NOP
+    // 02d8: NOP
+    // 02d9: NOP
+    // 02da: NOP
+    // 02db: NOP
+    // 02dc: NOP
+    // 02dd: NOP
+    // 02de: NOP
+    // 02df: NOP
+    // 02e0: NOP
+    // 02e1: NOP
+    // 02e2: NOP
+    // 02e3: NOP
+    // 02e4: NOP
+    // 02e5: NOP
+    // 02e6: NOP
+    // 02e7: NOP
+    // 02e8: NOP
+    // 02e9: NOP
+    // 02ea: NOP
+    // 02eb: NOP
+    // 02ec: NOP
+    // 02ed: NOP
+    // 02ee: NOP
+    // 02ef: NOP
+    // 02f0: NOP
+    // 02f1: NOP
+    // 02f2: NOP
+    // 02f3: NOP
+    // 02f4: NOP
+    // 02f5: NOP
+    // 02f6: NOP
+    // 02f7: NOP
+    // 02f8: NOP
+    // 02f9: NOP
+    // 02fa: NOP
+    // 02fb: NOP
+    // 02fc: NOP
+    // 02fd: NOP
+    // 02fe: NOP
+    // 02ff: NOP
+    // 0300: NOP
+    // 0301: NOP
+    // 0302: NOP
+    // 0303: NOP
+    // 0304: NOP
+    // 0305: NOP
+    // 0306: NOP
+    // 0307: NOP
+    // 0308: NOP
+    // 0309: NOP
+    // 030a: NOP
+    // 030b: NOP
+    // 030c: NOP
+    // 030d: NOP
+    // 030e: NOP
+    // 030f: NOP
+    // 0310: NOP
+    // 0311: NOP
+    // 0312: NOP
+    // 0313: NOP
+    // 0314: NOP
+    // 0315: NOP
+    // 0316: NOP
+    // 0317: NOP
+    // 0318: NOP
+    // 0319: NOP
+    // 031a: NOP
+    // 031b: NOP
+    // 031c: NOP
+    // 031d: NOP
+    // 031e: NOP
+    // 031f: NOP
+    // 0320: NOP
+    // 0321: NOP
+    // 0322: NOP
+    // 0323: NOP
+    // 0324: NOP
+    // 0325: NOP
+    // 0326: NOP
+    // 0327: NOP
+    // 0328: NOP
+    // 0329: NOP
+    // 032a: NOP
+    // 032b: NOP
+    // 032c: NOP
+    // 032d: NOP
+    // 032e: NOP
+    // 032f: NOP
+    // 0330: NOP
+    // 0331: NOP
+    // 0332: NOP
+    // 0333: NOP
+    // 0334: NOP
+    // 0335: NOP
+    // 0336: NOP
+    // 0337: NOP
+    // 0338: NOP
+    // 0339: NOP
+    // 033a: NOP
+    // 033b: NOP
+    // 033c: NOP
+    // 033d: NOP
+    // 033e: NOP
+    // 033f: NOP
+    // 0340: NOP
+    // 0341: NOP
+    // 0342: NOP
+    // 0343: NOP
+    // 0344: NOP
+    // 0345: NOP
+    // 0346: NOP
+    // 0347: NOP
+    // 0348: NOP
+    // 0349: NOP
+    // 034a: NOP
+    // 034b: NOP
+    // 034c: NOP
+    // 034d: NOP
+    // 034e: NOP
+    // 034f: NOP
+    // 0350: NOP
+    // 0351: NOP
+    // 0352: NOP
+    // 0353: NOP
+    // 0354: NOP
+    // 0355: NOP
+    // 0356: NOP
+    // 0357: NOP
+    // 0358: NOP
+    // 0359: NOP
+    // 035a: NOP
+    // 035b: NOP
+    // 035c: NOP
+    // 035d: NOP
+    // 035e: NOP
+    // 035f: NOP
+    // 0360: NOP
+    // 0361: NOP
+    // 0362: NOP
+    // 0363: NOP
+    // 0364: NOP
+    // 0365: NOP
+    // 0366: NOP
+    // 0367: NOP
+    // 0368: NOP
+    // 0369: NOP
+    // 036a: NOP
+    // 036b: NOP
+    // 036c: NOP
+    // 036d: NOP
+    // 036e: NOP
+    // 036f: NOP
+    // 0370: NOP
+    // 0371: NOP
+    // 0372: NOP
+    // 0373: NOP
+    // 0374: NOP
+    // 0375: NOP
+    // 0376: NOP
+    // 0377: NOP
+    // 0378: NOP
+    // 0379: NOP
+    // 037a: NOP
+    // 037b: NOP
+    // 037c: NOP
+    // 037d: NOP
+    // 037e: NOP
+    // 037f: NOP
+    // 0380: NOP
+    // 0381: NOP
+    // 0382: NOP
+    // 0383: NOP
+    // 0384: NOP
+    // 0385: NOP
+    // 0386: NOP
+    // 0387: NOP
+    // 0388: NOP
+    // 0389: NOP
+    // 038a: NOP
+    // 038b: NOP
+    // 038c: NOP
+    // 038d: NOP
+    // 038e: NOP
+    // 038f: NOP
+    // 0390: NOP
+    // 0391: NOP
+    // 0392: NOP
+    // 0393: NOP
+    // 0394: NOP
+    // 0395: NOP
+    // 0396: NOP
+    // 0397: NOP
+    // 0398: NOP
+    // 0399: NOP
+    // 039a: NOP
+    // 039b: NOP
+    // 039c: NOP
+    // 039d: NOP
+    // 039e: NOP
+    // 039f: NOP
+    // 03a0: NOP
+    // 03a1: NOP
+    // 03a2: NOP
+    // 03a3: NOP
+    // 03a4: NOP
+    // 03a5: NOP
+    // 03a6: NOP
+    // 03a7: NOP
+    // 03a8: NOP
+    // 03a9: NOP
+    // 03aa: NOP
+    // 03ab: NOP
+    // 03ac: NOP
+    // 03ad: NOP
+    // 03ae: NOP
+    // 03af: NOP
+    // 03b0: NOP
+    // 03b1: NOP
+    // 03b2: NOP
+    // 03b3: NOP
+    // 03b4: NOP
+    // 03b5: NOP
+    // 03b6: NOP
+    // 03b7: NOP
+    // 03b8: NOP
+    // 03b9: NOP
+    // 03ba: NOP
+    // 03bb: NOP
+    // 03bc: NOP
+    // 03bd: NOP
+    // 03be: NOP
+    // 03bf: NOP
+    // 03c0: NOP
+    // 03c1: NOP
+    // 03c2: NOP
+    // 03c3: NOP
+    // 03c4: NOP
+    // 03c5: NOP
+    // 03c6: NOP
+    // 03c7: NOP
+    // 03c8: NOP
+    // 03c9: NOP
+    // 03ca: NOP
+    // 03cb: NOP
+    // 03cc: NOP
+    // 03cd: NOP
+    // 03ce: NOP
+    // 03cf: NOP
+    // 03d0: NOP
+    // 03d1: NOP
+    // 03d2: NOP
+    // 03d3: NOP
+    // 03d4: NOP
+    // 03d5: NOP
+    // 03d6: NOP
+    // 03d7: NOP
+    // 03d8: NOP
+    // 03d9: NOP
+    // 03da: NOP
+    // 03db: NOP
+    // 03dc: NOP
+    // 03dd: NOP
+    // 03de: NOP
+    // 03df: NOP
+    // 03e0: NOP
+    // 03e1: NOP
+    // 03e2: NOP
+    // 03e3: NOP
+    // 03e4: NOP
+    // 03e5: NOP
+    // 03e6: NOP
+    // 03e7: NOP
+    // 03e8: NOP
+    // 03e9: NOP
+    // 03ea: NOP
+    // 03eb: NOP
+    // 03ec: NOP
+    // 03ed: NOP
+    // 03ee: NOP
+    // 03ef: NOP
+    // 03f0: NOP
+    // 03f1: NOP
+    // 03f2: NOP
+    // 03f3: NOP
+    // 03f4: NOP
+    // 03f5: NOP
+    // 03f6: NOP
+    // 03f7: NOP
+    // 03f8: NOP
+    // 03f9: NOP
+    // 03fa: NOP
+    // 03fb: NOP
+    // 03fc: NOP
+    // 03fd: NOP
+    // 03fe: NOP
+    // 03ff: NOP
+    s.pc = 0x0400;
+    1784
+}
+
+fn block_0400<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0400: RNZ
+    if State::is_nz(s) {
+        s.call_stack.ret(s.sp);
+        s.pc = s.pop16();
+        s.call_stack.unwind(s.sp);
+        return 11;
+    }
+    s.pc = 0x0401;
+    5
+}
+
+fn block_0401<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0401: RZ
+    if State::is_z(s) {
+        s.call_stack.ret(s.sp);
+        s.pc = s.pop16();
+        s.call_stack.unwind(s.sp);
+        return 11;
+    }
+    s.pc = 0x0402;
+    5
+}
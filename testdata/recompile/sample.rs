@@ -0,0 +1,209 @@
+// Generated from 12 blocks. Do not edit.
+
+use crate::bytes::assemble_word;
+use crate::callstack::FrameKind;
+use crate::machine::Machine;
+use crate::recompile::CompiledBlock;
+use crate::stack::Stack;
+use crate::state::State;
+
+pub fn block<M: Machine>(pc: u16) -> Option<CompiledBlock<M>> {
+    Some(match pc {
+        0x0000 => CompiledBlock::new(0x0000, &[0xc3, 0x40, 0x00], block_0000),
+        0x0008 => CompiledBlock::new(0x0008, &[0x16, 0x99, 0xc9], block_0008),
+        0x0040 => CompiledBlock::new(0x0040, &[0x31, 0x00, 0x24, 0xfb, 0x06, 0x05], block_0040),
+        0x0046 => CompiledBlock::new(0x0046, &[0xcd, 0x60, 0x00], block_0046),
+        0x0049 => CompiledBlock::new(0x0049, &[0x32, 0x61, 0x00, 0x05, 0xc2, 0x46, 0x00], block_0049),
+        0x0050 => CompiledBlock::new(0x0050, &[0xcc, 0x70, 0x00], block_0050),
+        0x0053 => CompiledBlock::new(0x0053, &[0xf5, 0xe3, 0xd1, 0x21, 0x80, 0x00, 0x5e, 0x23, 0x56, 0xeb, 0xe9], block_0053),
+        0x0060 => CompiledBlock::new(0x0060, &[0x3e, 0x01, 0x80, 0xd0], block_0060),
+        0x0064 => CompiledBlock::new(0x0064, &[0x0c, 0xc9], block_0064),
+        0x0070 => CompiledBlock::new(0x0070, &[0x3e, 0x99, 0xc6, 0x01, 0x27, 0xc9], block_0070),
+        0x00a0 => CompiledBlock::new(0x00a0, &[0xdb, 0x02, 0x4f, 0x3e, 0x3c, 0x32, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x07, 0x47, 0x3e, 0x3c, 0x32, 0x00, 0x01, 0xc3, 0x00, 0x01], block_00a0),
+        0x0100 => CompiledBlock::new(0x0100, &[0x00, 0x76], block_0100),
+        _ => return None,
+    })
+}
+
+fn block_0000<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0000: JMP $0040
+    s.pc = 0x0040;
+    10
+}
+
+fn block_0008<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0008: MVI D,$99
+    s.d = 0x99;
+    // 000a: RET
+    s.call_stack.ret(s.sp);
+    s.pc = s.pop16();
+    s.call_stack.unwind(s.sp);
+    17
+}
+
+fn block_0040<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0040: LXI SP,$2400
+    s.sp = 0x2400;
+    s.call_stack.unwind(s.sp);
+    // 0043: EI
+    s.int_enable = true;
+    if s.pending_interrupt.is_some() {
+        s.pc = 0x0044;
+        return 14;
+    }
+    // 0044: MVI B,$05
+    s.b = 0x05;
+    s.pc = 0x0046;
+    21
+}
+
+fn block_0046<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0046: CALL $0060
+    s.pc = 0x0046;
+    s.enter(FrameKind::Call, 0x0060, 3);
+    s.jumped = false;
+    17
+}
+
+fn block_0049<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0049: STA $0061
+    s.memory.set(0x0061, s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x004c;
+        return 13;
+    }
+    // 004c: DCR B
+    s.b = s.dcr(s.b);
+    // 004d: JNZ $0046
+    s.pc = if State::is_nz(s) { 0x0046 } else { 0x0050 };
+    28
+}
+
+fn block_0050<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0050: CZ $0070
+    if State::is_z(s) {
+        s.pc = 0x0050;
+        s.enter(FrameKind::Call, 0x0070, 3);
+        s.jumped = false;
+        return 17;
+    }
+    s.pc = 0x0053;
+    11
+}
+
+fn block_0053<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0053: PUSH PSW
+    s.push16(assemble_word(s.a, s.cc.to_psw()));
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0054;
+        return 11;
+    }
+    // 0054: XTHL
+    let word = s.pop16();
+    s.push16(s.get_hl_address());
+    s.set_hl(word);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x0055;
+        return 29;
+    }
+    // 0055: POP D
+    let word = s.pop16();
+    s.set_de(word);
+    s.call_stack.unwind(s.sp);
+    // 0056: LXI H,$0080
+    s.set_hl(0x0080);
+    // 0059: MOV E,M
+    s.e = s.get_m();
+    // 005a: INX H
+    s.set_hl(s.get_hl_address().wrapping_add(1));
+    // 005b: MOV D,M
+    s.d = s.get_m();
+    // 005c: XCHG
+    std::mem::swap(&mut s.d, &mut s.h);
+    std::mem::swap(&mut s.e, &mut s.l);
+    // 005d: PCHL
+    s.pc = s.get_hl_address();
+    77
+}
+
+fn block_0060<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0060: MVI A,$01
+    s.a = 0x01;
+    // 0062: ADD B
+    s.add8(s.b);
+    // 0063: RNC
+    if State::is_nc(s) {
+        s.call_stack.ret(s.sp);
+        s.pc = s.pop16();
+        s.call_stack.unwind(s.sp);
+        return 22;
+    }
+    s.pc = 0x0064;
+    16
+}
+
+fn block_0064<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0064: INR C
+    s.c = s.inr(s.c);
+    // 0065: RET
+    s.call_stack.ret(s.sp);
+    s.pc = s.pop16();
+    s.call_stack.unwind(s.sp);
+    15
+}
+
+fn block_0070<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0070: MVI A,$99
+    s.a = 0x99;
+    // 0072: ADI $01
+    s.add8(0x01);
+    // 0074: DAA
+    s.daa();
+    // 0075: RET
+    s.call_stack.ret(s.sp);
+    s.pc = s.pop16();
+    s.call_stack.unwind(s.sp);
+    28
+}
+
+fn block_00a0<M: Machine>(s: &mut State, m: &mut M) -> usize {
+    // 00a0: IN $02
+    s.a = m.input(0x02);
+    // 00a2: MOV C,A
+    s.c = s.a;
+    // 00a3: MVI A,$3c
+    s.a = 0x3c;
+    // 00a5: STA $00aa
+    s.memory.set(0x00aa, s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x00a8;
+        return 35;
+    }
+    // 00a8: NOP
+    // 00a9: NOP
+    // 00aa: NOP
+    // 00ab: RLC
+    s.cc.cy = (s.a & 0x80) != 0;
+    s.a = s.a.rotate_left(1);
+    // 00ac: MOV B,A
+    s.b = s.a;
+    // 00ad: MVI A,$3c
+    s.a = 0x3c;
+    // 00af: STA $0100
+    s.memory.set(0x0100, s.a);
+    if s.memory.has_dirty_pages() {
+        s.pc = 0x00b2;
+        return 76;
+    }
+    // 00b2: JMP $0100
+    s.pc = 0x0100;
+    86
+}
+
+fn block_0100<M: Machine>(s: &mut State, _m: &mut M) -> usize {
+    // 0100: NOP
+    // 0101: HLT
+    s.halted = true;
+    s.pc = 0x0102;
+    11
+}